@@ -54,13 +54,13 @@ License:        ASL 2.0
 %make_install INSTALL="install -p -c"
 
 %post        -n %{crate}
-%systemd_post bootupd.service bootupd.socket
+%systemd_post bootupd.service bootupd.socket bootupd-apply-staged.service
 
 %preun       -n %{crate}
-%systemd_preun bootupd.service bootupd.socket
+%systemd_preun bootupd.service bootupd.socket bootupd-apply-staged.service
 
 %postun      -n %{crate}
-%systemd_postun bootupd.service bootupd.socket
+%systemd_postun bootupd.service bootupd.socket bootupd-apply-staged.service
 
 %changelog
 * Fri Sep 11 2020 Colin Walters <walters@verbum.org> - 0.1.0-3
@@ -0,0 +1,419 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::component::*;
+use crate::model::*;
+use crate::packagesystem;
+use crate::payload::PayloadSource;
+use crate::util;
+
+/// The grub2-install binary, relative to the root
+pub(crate) const GRUB_BIN: &str = "usr/sbin/grub2-install";
+/// The directory holding the BIOS GRUB modules, relative to the root
+pub(crate) const GRUB_MODULES: &str = "usr/lib/grub/i386-pc";
+/// Size of the MBR boot sector
+const MBR_SIZE: usize = 512;
+/// Size of the boot code at the start of the MBR, which is followed by
+/// the disk signature and partition table
+const MBR_BOOT_CODE_SIZE: usize = 440;
+/// Boot signature at the end of a valid MBR
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// String embedded in the GRUB boot.img written into the MBR
+const GRUB_MBR_MARKER: &[u8] = b"GRUB";
+
+pub(crate) struct BIOS {
+    /// Root of the system being managed
+    sysroot: String,
+    /// Disk to install to, instead of the one holding `/boot`; see
+    /// `check_target_disk()`
+    target_disk: Option<String>,
+}
+
+impl BIOS {
+    /// Manage the BIOS component of the system at `sysroot`
+    pub(crate) fn new(sysroot: &str) -> Self {
+        Self {
+            sysroot: sysroot.to_string(),
+            target_disk: None,
+        }
+    }
+
+    /// Install to `disk`, which must have been checked with
+    /// `check_target_disk()`
+    pub(crate) fn with_target_disk(sysroot: &str, disk: &str) -> Self {
+        Self {
+            target_disk: Some(disk.to_string()),
+            ..Self::new(sysroot)
+        }
+    }
+
+    /// Find the whole-disk block device backing the filesystem
+    /// containing `boot` in the target root.
+    fn get_device(&self, dest_root: &str) -> Result<String> {
+        util::find_parent_disk(&Path::new(dest_root).join("boot"))
+    }
+
+    /// The disk the installed `current` was written to, which later
+    /// updates also target.  States from older versions don't record it.
+    fn installed_device(&self, current: &InstalledContent) -> Result<String> {
+        match current.device.as_ref() {
+            Some(d) => Ok(d.clone()),
+            None => self.get_device(&self.sysroot),
+        }
+    }
+
+    /// Install GRUB stage1 into the MBR of `device`, using the modules from
+    /// the booted root and `boot` in the target root.
+    fn run_grub_install(&self, dest_root: &str, device: &str) -> Result<()> {
+        let modules = Path::new("/").join(GRUB_MODULES);
+        if !modules.exists() {
+            bail!("Failed to find {:?}", modules);
+        }
+        let grub_install = Path::new("/").join(GRUB_BIN);
+        if !grub_install.exists() {
+            bail!("Failed to find {:?}", grub_install);
+        }
+        let bootdir = Path::new(dest_root).join("boot");
+        let mut cmd = Command::new(grub_install);
+        cmd.args(&["--target", "i386-pc"])
+            .arg("--boot-directory")
+            .arg(&bootdir)
+            .arg(device);
+        util::cmd_output(&mut cmd)?;
+        Ok(())
+    }
+
+    /// Read the MBR boot sector from the target device
+    fn read_mbr(&self, device: &str) -> Result<[u8; MBR_SIZE]> {
+        let mut f = std::fs::File::open(device).with_context(|| format!("Opening {}", device))?;
+        let mut buf = [0u8; MBR_SIZE];
+        f.read_exact(&mut buf)
+            .with_context(|| format!("Reading MBR of {}", device))?;
+        Ok(buf)
+    }
+
+    /// Digest of the boot code in the MBR of `device`, as recorded in
+    /// `InstalledContent::boot_code`
+    fn read_boot_code_digest(&self, device: &str) -> Result<String> {
+        Ok(boot_code_digest(&self.read_mbr(device)?))
+    }
+}
+
+/// Digest of the boot code in `mbr`, excluding the disk signature and
+/// partition table, which partitioning tools rewrite
+fn boot_code_digest(mbr: &[u8; MBR_SIZE]) -> String {
+    format!(
+        "sha256:{}",
+        hex::encode(openssl::sha::sha256(&mbr[..MBR_BOOT_CODE_SIZE]))
+    )
+}
+
+/// Check that `disk` can be used to install the BIOS boot code for
+/// `dest_root`: it must be a whole-disk block device which holds the
+/// target's `/boot`.  Returns its canonical path.
+pub(crate) fn check_target_disk(dest_root: &str, disk: &str) -> Result<String> {
+    use std::os::unix::fs::FileTypeExt;
+    let path = std::fs::canonicalize(disk).with_context(|| format!("Opening {}", disk))?;
+    let canonical = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", path.display()))?;
+    if !std::fs::metadata(&path)?.file_type().is_block_device() {
+        bail!("{} is not a block device", disk);
+    }
+    let devtype = util::block_device_type(canonical)?;
+    let bootdir = Path::new(dest_root).join("boot");
+    let source = util::find_mount_source(&bootdir)?;
+    let holders = util::block_device_ancestors(&source)?;
+    check_disk_holds(disk, canonical, &devtype, &holders, &bootdir)?;
+    Ok(canonical.to_string())
+}
+
+/// For `check_target_disk()`, check that `disk`, canonically `canonical`,
+/// is of the block device type `devtype` of whole disks and is among the
+/// `holders` of `bootdir`.
+fn check_disk_holds(
+    disk: &str,
+    canonical: &str,
+    devtype: &str,
+    holders: &[String],
+    bootdir: &Path,
+) -> Result<()> {
+    if devtype != "disk" {
+        bail!("{} is not a whole disk", disk);
+    }
+    if !holders.iter().any(|d| d == canonical) {
+        bail!("{} does not hold {}", disk, bootdir.display());
+    }
+    Ok(())
+}
+
+impl Component for BIOS {
+    fn name(&self) -> &'static str {
+        "BIOS"
+    }
+
+    fn install(&self, src_root: &str, dest_root: &str, verbose: bool) -> Result<InstalledContent> {
+        let meta = if let Some(meta) = get_component_update(src_root, self)? {
+            meta
+        } else {
+            bail!("No update metadata for component {} found", self.name());
+        };
+        let device = match self.target_disk.as_ref() {
+            Some(d) => d.clone(),
+            None => self.get_device(dest_root)?,
+        };
+        if verbose {
+            println!("Running grub2-install on {}", device);
+        }
+        self.run_grub_install(dest_root, &device)?;
+        let boot_code = self.read_boot_code_digest(&device)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: None,
+            applied: None,
+            device: Some(device),
+            boot_code: Some(boot_code),
+            mirrors: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        source: &dyn PayloadSource,
+        os_version: Option<&str>,
+    ) -> Result<ContentMetadata> {
+        if !source.exists(Path::new(GRUB_BIN))? {
+            bail!("Failed to find {} in {}", GRUB_BIN, source.describe());
+        }
+        // The stage1 written to the MBR comes from the same package
+        // as grub2-install, so its version identifies the payload.
+        let mut meta = source.query_files(&[Path::new("/").join(GRUB_BIN)])?;
+        meta.source = os_version.map(|s| s.to_string());
+        std::fs::create_dir_all(Path::new(sysroot_path).join(BOOTUPD_UPDATES_DIR))?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self) -> Result<Option<ContentMetadata>> {
+        get_component_update(&self.sysroot, self)
+    }
+
+    fn can_upgrade(&self, from: &ContentMetadata, to: &ContentMetadata) -> bool {
+        // The version is the NEVRA of the single package shipping
+        // grub2-install, which orders rebuilds and backports correctly
+        // where build times might not.
+        match packagesystem::compare_nevra(&from.version, &to.version) {
+            Some(ord) => ord == std::cmp::Ordering::Less,
+            None => from.can_upgrade_to(to),
+        }
+    }
+
+    fn query_devices(&self, current: &InstalledContent) -> Result<Vec<String>> {
+        Ok(vec![self.installed_device(current)?])
+    }
+
+    fn resource_key(&self, current: &InstalledContent) -> Result<String> {
+        self.installed_device(current)
+    }
+
+    fn run_update(
+        &self,
+        current: &InstalledContent,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        // grub2-install is a single step, so there is nothing to resume
+        let updatemeta =
+            get_verified_component_update(&self.sysroot, self)?.expect("update available");
+        let device = self.installed_device(current)?;
+        (ctx.progress)(&format!("Running grub2-install on {}", device));
+        self.run_grub_install(&self.sysroot, &device)?;
+        (ctx.changed)(&device);
+        let boot_code = self.read_boot_code_digest(&device)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: None,
+            applied: None,
+            device: Some(device),
+            boot_code: Some(boot_code),
+            mirrors: Vec::new(),
+        })
+    }
+
+    fn repair(&self, current: &InstalledContent, progress: &dyn Fn(&str)) -> Result<RepairResult> {
+        let mut r = RepairResult::default();
+        let device = self.installed_device(current)?;
+        // grub2-install writes the stage1 from the booted root, so it
+        // only restores what was installed if the version is unchanged.
+        match self.query_update()? {
+            Some(meta) if meta.version == current.meta.version => {
+                progress(&format!("Running grub2-install on {}", device));
+                self.run_grub_install(&self.sysroot, &device)?;
+                r.repaired
+                    .push(format!("Reinstalled GRUB boot code on {}", device));
+            }
+            _ => r.unrepaired.push(format!(
+                "No payload matching {} available to repair {}",
+                current.meta.version, device
+            )),
+        }
+        Ok(r)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let device = self.installed_device(current)?;
+        let mbr = self.read_mbr(&device)?;
+        let mut errs = Vec::new();
+        if mbr[MBR_SIZE - 2..] != MBR_SIGNATURE {
+            errs.push(ValidationError::other(
+                self.name(),
+                format!("Missing MBR boot signature on {}", device),
+            ));
+        }
+        if !mbr
+            .windows(GRUB_MBR_MARKER.len())
+            .any(|w| w == GRUB_MBR_MARKER)
+        {
+            errs.push(ValidationError::other(
+                self.name(),
+                format!("GRUB boot code not found in MBR of {}", device),
+            ));
+        }
+        if let Some(expected) = current.boot_code.as_ref() {
+            if boot_code_digest(&mbr) != *expected {
+                errs.push(ValidationError::other(
+                    self.name(),
+                    format!(
+                        "Boot code in MBR of {} differs from what was installed",
+                        device
+                    ),
+                ));
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    #[test]
+    fn test_check_target_disk() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dest_root = td.path().to_str().unwrap();
+        let f = td.path().join("disk.img");
+        std::fs::write(&f, b"")?;
+        let e = check_target_disk(dest_root, f.to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().ends_with("is not a block device"), "{}", e);
+        let e = check_target_disk(dest_root, "/dev/null").unwrap_err();
+        assert_eq!(e.to_string(), "/dev/null is not a block device");
+        let missing = td.path().join("missing");
+        assert!(check_target_disk(dest_root, missing.to_str().unwrap()).is_err());
+
+        let bootdir = Path::new("/sysroot/boot");
+        let holders = vec!["/dev/vda1".to_string(), "/dev/vda".to_string()];
+        check_disk_holds("/dev/disk/by-id/x", "/dev/vda", "disk", &holders, bootdir)?;
+        let e = check_disk_holds("/dev/vda1", "/dev/vda1", "part", &holders, bootdir).unwrap_err();
+        assert_eq!(e.to_string(), "/dev/vda1 is not a whole disk");
+        let e = check_disk_holds("/dev/vdb", "/dev/vdb", "disk", &holders, bootdir).unwrap_err();
+        assert_eq!(e.to_string(), "/dev/vdb does not hold /sysroot/boot");
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_boot_code() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk.img");
+        let mut mbr = [0u8; MBR_SIZE];
+        mbr[3..3 + GRUB_MBR_MARKER.len()].copy_from_slice(GRUB_MBR_MARKER);
+        mbr[MBR_SIZE - 2..].copy_from_slice(&MBR_SIGNATURE);
+        std::fs::write(&disk, &mbr[..])?;
+        let bios = BIOS::new(td.path().to_str().unwrap());
+        let device = disk.to_str().unwrap();
+        let installed = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: Utc::now(),
+                version: "grub2-tools-1:2.04-31.fc33.x86_64".into(),
+                digest: None,
+                source: None,
+            },
+            filetree: None,
+            applied: None,
+            device: Some(device.into()),
+            boot_code: Some(bios.read_boot_code_digest(device)?),
+            mirrors: Vec::new(),
+        };
+        // Updates write to the recorded disk, not the one found now
+        assert_eq!(bios.resource_key(&installed)?, device);
+        assert!(matches!(
+            bios.validate(&installed)?,
+            ValidationResult::Valid
+        ));
+        // Repartitioning doesn't change the boot code
+        mbr[450] = 0x83;
+        std::fs::write(&disk, &mbr[..])?;
+        assert!(matches!(
+            bios.validate(&installed)?,
+            ValidationResult::Valid
+        ));
+        mbr[100] ^= 0xff;
+        std::fs::write(&disk, &mbr[..])?;
+        match bios.validate(&installed)? {
+            ValidationResult::Errors(errs) => {
+                assert_eq!(errs.len(), 1);
+                assert!(errs[0]
+                    .to_string()
+                    .contains("differs from what was installed"));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        // Content recorded by older versions has no digest to compare
+        let older = InstalledContent {
+            boot_code: None,
+            mirrors: Vec::new(),
+            ..installed
+        };
+        assert!(matches!(bios.validate(&older)?, ValidationResult::Valid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_can_upgrade() {
+        let meta = |version: &str, secs: i64| ContentMetadata {
+            timestamp: Utc.timestamp(1_600_000_000 + secs, 0),
+            version: version.into(),
+            digest: None,
+            source: None,
+        };
+        let bios = BIOS::new("/");
+        // A backport built after the newer release
+        let backport = meta("grub2-tools-1:2.04-40.fc32.x86_64", 100);
+        let newer = meta("grub2-tools-1:2.06-2.fc33.x86_64", 0);
+        assert!(!backport.can_upgrade_to(&newer));
+        assert!(bios.can_upgrade(&backport, &newer));
+        assert!(!bios.can_upgrade(&newer, &backport));
+        match ComponentUpdatable::from_metadata(&bios, &backport, Some(&newer)) {
+            ComponentUpdatable::Upgradable => {}
+            u => panic!("unexpected {:?}", u),
+        }
+        // Fall back to build times if the package changed
+        let renamed = meta("grub2-pc-1:2.06-2.fc33.x86_64", 200);
+        assert!(bios.can_upgrade(&backport, &renamed));
+        assert!(!bios.can_upgrade(&renamed, &backport));
+    }
+}
@@ -1,99 +1,815 @@
-use crate::component::{Component, ValidationResult};
+#[cfg(target_arch = "x86_64")]
+use crate::bios;
+use crate::component::{
+    describe_errors, Component, PruneResult, RepairResult, ValidationError, ValidationResult,
+};
+use crate::compress::{self, Compression};
 use crate::efi;
-use crate::model::{ComponentStatus, ComponentUpdatable, ContentMetadata, SavedState, Status};
-use crate::{component, ipc};
+use crate::error::BootupdError;
+use crate::filecopy;
+use crate::journal::{self, Event, EventResult};
+use crate::model::{
+    ComponentStatus, ComponentUpdatable, ContentMetadata, Counters, InstalledContent,
+    PendingUpdate, RemoteUpdate, SavedState, Status,
+};
+use crate::payload::{FilesystemSource, PayloadSource};
+use crate::{component, ipc, util};
 use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
 use fs2::FileExt;
 use openat_ext::OpenatDirExt;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 /// Stored in /boot to describe our state; think of it like
 /// a tiny rpm/dpkg database.  It's stored in /boot
 pub(crate) const STATEFILE_DIR: &str = "boot";
 pub(crate) const STATEFILE_NAME: &str = "bootupd-state.json";
 pub(crate) const WRITE_LOCK_PATH: &str = "run/bootupd-lock";
+/// How long to wait for another operation to release the write lock
+const WRITE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Environment variable overriding `WRITE_LOCK_TIMEOUT`, in seconds
+const WRITE_LOCK_TIMEOUT_ENV: &str = "BOOTUPD_LOCK_TIMEOUT";
+/// Interval between attempts to take the write lock
+const WRITE_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Written during updates, alongside the write lock; see `Heartbeat`
+pub(crate) const HEARTBEAT_PATH: &str = "run/bootupd-heartbeat.json";
+/// How often, at most, the heartbeat is refreshed as an update makes progress
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// A heartbeat older than this means its writer is no longer making progress
+const HEARTBEAT_STALE: Duration = Duration::from_secs(60);
+/// How long to let a component update run before giving up on it
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Environment variable overriding `UPDATE_TIMEOUT`, in seconds
+const UPDATE_TIMEOUT_ENV: &str = "BOOTUPD_UPDATE_TIMEOUT";
+/// Followed by a component name, e.g. `BOOTUPD_MIN_UPDATE_INTERVAL_EFI`,
+/// the environment variable setting the minimum time between updates of
+/// that component, in seconds.  Unset or 0 means there is no limit.
+const MIN_UPDATE_INTERVAL_ENV_PREFIX: &str = "BOOTUPD_MIN_UPDATE_INTERVAL_";
 
-/// A message sent from client to server
+/// Exit code for `bootupctl status --exit-code` when at least one
+/// component has an update available
+pub(crate) const STATUS_EXIT_UPDATE_AVAILABLE: i32 = 2;
+
+/// Options controlling a component update
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UpdateOptions {
+    /// Only report what would be done
+    pub(crate) dry_run: bool,
+    /// Reinstall the available payload even if it's the installed version
+    pub(crate) force: bool,
+    /// Apply the available payload even if it's older than the installed one
+    pub(crate) allow_downgrade: bool,
+    /// Give up on the update after this many seconds, overriding
+    /// `UPDATE_TIMEOUT_ENV` and `UPDATE_TIMEOUT`.  For parallel updates
+    /// the deadline covers all components.
+    #[serde(default)]
+    pub(crate) timeout: Option<u64>,
+    /// Apply the update even if the component reports that it's known
+    /// not to work on this system; see `Component::check_precondition()`
+    #[serde(default)]
+    pub(crate) ignore_preconditions: bool,
+    /// Update the component even if it was updated less than its minimum
+    /// interval ago; see `MIN_UPDATE_INTERVAL_ENV_PREFIX`
+    #[serde(default)]
+    pub(crate) ignore_min_interval: bool,
+    /// The minimum time between updates of each component, in seconds,
+    /// overriding `MIN_UPDATE_INTERVAL_ENV_PREFIX`; 0 means there is no
+    /// limit
+    #[serde(default)]
+    pub(crate) min_interval: Option<u64>,
+}
+
+/// Optional operations a daemon may support, as reported by
+/// `ClientRequest::Capabilities`; see `ClientRequest::capability()`
+pub(crate) const CAPABILITIES: &[&str] = &[
+    "update-parallel",
+    "update-all",
+    "rollback",
+    "stage",
+    "repair",
+    "uninstall",
+    "adopt",
+    "list-components",
+    "diff",
+    "check",
+    "metrics",
+    "prune",
+    "status-installed",
+    "regenerate-state",
+    "export-state",
+    "import-state",
+    "status-remote",
+];
+
+/// A message sent from client to server.  Requests are encoded by their
+/// position, so new ones must be added at the end, and advertised in
+/// `CAPABILITIES` rather than bumping `ipc::PROTOCOL_VERSION`.
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum ClientRequest {
+    /// Operate on the system at `sysroot` rather than `/` for the
+    /// remaining requests of this connection
+    SetSysroot { sysroot: String },
     /// Update a component
-    Update { component: String },
+    Update {
+        component: String,
+        opts: UpdateOptions,
+    },
+    /// Update several components, concurrently where they don't share
+    /// a resource; see `update_parallel()`.
+    UpdateParallel {
+        components: Vec<String>,
+        opts: UpdateOptions,
+        /// See `worker_count()`
+        max_concurrency: Option<usize>,
+    },
+    /// Update every installed component; see `update_all()`.
+    UpdateAll {
+        opts: UpdateOptions,
+        transactional: bool,
+    },
     /// Validate a component
     Validate { component: String },
-    /// Print the current state
-    Status,
+    /// Restore the version a component was last updated from
+    Rollback { component: String },
+    /// Stage an update of a component to be applied at the next boot
+    Stage { component: String },
+    /// Validate a component and fix any drift from the installed content
+    Repair { component: String },
+    /// Remove a component's files and stop managing it
+    Uninstall { component: String, force: bool },
+    /// Start managing an existing installation
+    Adopt,
+    /// List the components known to bootupd and whether they are
+    /// supported on this platform and installed
+    ListComponents,
+    /// Compare the installed files of a component to the available update
+    Diff { component: String },
+    /// Validate all installed components, without looking for updates
+    Check,
+    /// Report activity counters and whether the write lock is held
+    Metrics,
+    /// Print the current state.  With `bootupctl status --exit-code`, the
+    /// client exits with 0 if no component is upgradable, and with
+    /// `STATUS_EXIT_UPDATE_AVAILABLE` (2) if at least one is.  Failures
+    /// use the exit codes from `BootupdError`.  If `components` isn't
+    /// empty, only those components are reported.
+    Status { components: Vec<String> },
+    /// Remove retained payloads beyond the newest `keep` generations;
+    /// see `prune()`
+    Prune { keep: u32 },
+    /// List the optional operations this daemon supports, i.e.
+    /// `CAPABILITIES`
+    Capabilities,
+    /// Like `Status`, but only report what's installed, without looking
+    /// for updates or querying devices; for frequent polling
+    StatusInstalled { components: Vec<String> },
+    /// Rebuild the saved state from the installed content, replacing any
+    /// existing state; see `regenerate_state()`
+    RegenerateState { confirm: bool, force: bool },
+    /// Return the saved state as JSON, for `ImportState`
+    ExportState,
+    /// Replace the saved state with `state`, as returned by `ExportState`;
+    /// see `import_state()`
+    ImportState { state: String },
+    /// Like `Status`, but also probe the configured remote update source;
+    /// see `status_remote()`
+    StatusRemote { components: Vec<String> },
 }
 
-pub(crate) fn install(source_root: &str, dest_root: &str) -> Result<()> {
-    let statepath = Path::new(dest_root)
-        .join(STATEFILE_DIR)
-        .join(STATEFILE_NAME);
-    if statepath.exists() {
-        bail!("{:?} already exists, cannot re-install", statepath);
+impl ClientRequest {
+    /// Whether the request leaves the system unchanged, and so may be
+    /// handled without the daemon; see `ClientToDaemonConnection::new_direct()`.
+    pub(crate) fn is_read_only(&self) -> bool {
+        match self {
+            ClientRequest::Update { opts, .. }
+            | ClientRequest::UpdateParallel { opts, .. }
+            | ClientRequest::UpdateAll { opts, .. } => opts.dry_run,
+            ClientRequest::Rollback { .. }
+            | ClientRequest::Stage { .. }
+            | ClientRequest::Repair { .. }
+            | ClientRequest::Uninstall { .. }
+            | ClientRequest::Prune { .. }
+            | ClientRequest::Adopt
+            | ClientRequest::RegenerateState { .. }
+            | ClientRequest::ImportState { .. } => false,
+            ClientRequest::SetSysroot { .. }
+            | ClientRequest::Validate { .. }
+            | ClientRequest::ListComponents
+            | ClientRequest::Diff { .. }
+            | ClientRequest::Check
+            | ClientRequest::Metrics
+            | ClientRequest::Status { .. }
+            | ClientRequest::Capabilities
+            | ClientRequest::StatusInstalled { .. }
+            | ClientRequest::ExportState
+            | ClientRequest::StatusRemote { .. } => true,
+        }
+    }
+
+    /// The entry in `CAPABILITIES` a daemon must report for it to handle
+    /// this request, or `None` if every daemon speaking our protocol can.
+    pub(crate) fn capability(&self) -> Option<&'static str> {
+        match self {
+            ClientRequest::SetSysroot { .. }
+            | ClientRequest::Update { .. }
+            | ClientRequest::Validate { .. }
+            | ClientRequest::Status { .. }
+            | ClientRequest::Capabilities => None,
+            ClientRequest::UpdateParallel { .. } => Some("update-parallel"),
+            ClientRequest::UpdateAll { .. } => Some("update-all"),
+            ClientRequest::Rollback { .. } => Some("rollback"),
+            ClientRequest::Stage { .. } => Some("stage"),
+            ClientRequest::Repair { .. } => Some("repair"),
+            ClientRequest::Uninstall { .. } => Some("uninstall"),
+            ClientRequest::Adopt => Some("adopt"),
+            ClientRequest::ListComponents => Some("list-components"),
+            ClientRequest::Diff { .. } => Some("diff"),
+            ClientRequest::Check => Some("check"),
+            ClientRequest::Metrics => Some("metrics"),
+            ClientRequest::Prune { .. } => Some("prune"),
+            ClientRequest::StatusInstalled { .. } => Some("status-installed"),
+            ClientRequest::RegenerateState { .. } => Some("regenerate-state"),
+            ClientRequest::ExportState => Some("export-state"),
+            ClientRequest::ImportState { .. } => Some("import-state"),
+            ClientRequest::StatusRemote { .. } => Some("status-remote"),
+        }
+    }
+}
+
+/// Install all components from `source_root` into `dest_root`; with
+/// `verbose`, print each action taken.  If `target_disk` is set, the
+/// BIOS boot code is written there rather than to the disk holding
+/// `/boot`, and later updates target the same disk.
+pub(crate) fn install(
+    source_root: &str,
+    dest_root: &str,
+    only: &[String],
+    target_disk: Option<&str>,
+    verbose: bool,
+    verify: bool,
+) -> Result<()> {
+    check_dest_root(dest_root)?;
+    let sysroot =
+        openat::Dir::open(dest_root).with_context(|| format!("opening sysroot {}", dest_root))?;
+    check_state_dir(&sysroot, dest_root)?;
+    let statepath = Path::new(STATEFILE_DIR).join(STATEFILE_NAME);
+    if sysroot.exists(&statepath)? {
+        bail!(
+            "{:?} already exists in {}, cannot re-install",
+            statepath,
+            dest_root
+        );
     }
 
-    let components = get_components();
+    // Described components are part of the OS being installed
+    let mut components =
+        select_components(get_components_described_in(dest_root, source_root)?, only)?;
+    if let Some(disk) = target_disk {
+        set_target_disk(&mut components, dest_root, disk)?;
+    }
     if components.is_empty() {
         println!("No components available for this platform.");
         return Ok(());
     }
-    let mut state = SavedState {
-        installed: Default::default(),
-        pending: Default::default(),
-    };
+    check_component_names(&components)?;
+    // Only what we install is recorded, so components managed by other
+    // means are left alone by later updates.
+    let mut state = SavedState::default();
     for component in components {
-        let meta = component.install(source_root, dest_root)?;
+        let meta = install_one(component.as_ref(), source_root, dest_root, verbose, verify)?;
         state.installed.insert(component.name().into(), meta);
     }
 
-    let sysroot = openat::Dir::open(dest_root)?;
-    update_state(&sysroot, &state)?;
+    update_state(&sysroot, &mut state)?;
+
+    Ok(())
+}
+
+/// Have the BIOS component in `components` install to `disk`.
+fn set_target_disk(
+    components: &mut [Box<dyn Component>],
+    dest_root: &str,
+    disk: &str,
+) -> Result<()> {
+    match components.iter_mut().find(|c| c.name() == "BIOS") {
+        #[cfg(target_arch = "x86_64")]
+        Some(bios) => {
+            let disk = bios::check_target_disk(dest_root, disk)?;
+            *bios = Box::new(bios::BIOS::with_target_disk(dest_root, &disk));
+            Ok(())
+        }
+        _ => bail!(
+            "Target disk {} applies only to the BIOS component, which isn't being installed in {}",
+            disk,
+            dest_root
+        ),
+    }
+}
+
+/// Install `component`, and unless `verify` is unset, check what was
+/// written so that a bad copy is caught now rather than at the next boot.
+fn install_one(
+    component: &dyn Component,
+    source_root: &str,
+    dest_root: &str,
+    verbose: bool,
+    verify: bool,
+) -> Result<InstalledContent> {
+    let mut meta = component.install(source_root, dest_root, verbose)?;
+    if verify {
+        if let ValidationResult::Errors(errs) = component
+            .verify_install(&meta)
+            .with_context(|| format!("verifying {}", component.name()))?
+        {
+            bail!(
+                "Installed {} failed validation:\n{}",
+                component.name(),
+                describe_errors(&errs, "\n")
+            );
+        }
+    }
+    meta.applied = Some(Utc::now());
+    Ok(meta)
+}
 
+/// Check that `dest_root` looks like a root to install into, e.g. the
+/// mounted future root of a system being installed.
+fn check_dest_root(dest_root: &str) -> Result<()> {
+    let path = Path::new(dest_root);
+    if !path.is_dir() {
+        bail!("Target root {} is not a directory", dest_root);
+    }
+    if !path.join(STATEFILE_DIR).is_dir() {
+        bail!(
+            "Target root {} has no {} directory; is it fully mounted?",
+            dest_root,
+            STATEFILE_DIR
+        );
+    }
     Ok(())
 }
 
-pub(crate) fn get_components() -> Vec<Box<dyn Component>> {
+/// Like `install()`, but succeed without doing anything if `dest_root`
+/// already has saved state.  Returns whether components were installed.
+pub(crate) fn ensure_installed(
+    source_root: &str,
+    dest_root: &str,
+    only: &[String],
+    target_disk: Option<&str>,
+    verbose: bool,
+    verify: bool,
+) -> Result<bool> {
+    check_dest_root(dest_root)?;
+    let sysroot =
+        openat::Dir::open(dest_root).with_context(|| format!("opening sysroot {}", dest_root))?;
+    check_state_dir(&sysroot, dest_root)?;
+    if sysroot.exists(&Path::new(STATEFILE_DIR).join(STATEFILE_NAME))? {
+        return Ok(false);
+    }
+    install(source_root, dest_root, only, target_disk, verbose, verify)?;
+    Ok(true)
+}
+
+/// Every component bootupd knows about, and the architectures it is
+/// built for; must be kept in sync with `get_components()`.
+const KNOWN_COMPONENTS: &[(&str, &[&str])] =
+    &[("EFI", &["x86_64", "aarch64"]), ("BIOS", &["x86_64"])];
+
+/// Whether `name` is one of the components implemented in bootupd, as
+/// opposed to those described in `filecopy::COMPONENTS_DIR`
+pub(crate) fn is_builtin_component(name: &str) -> bool {
+    KNOWN_COMPONENTS.iter().any(|(n, _)| *n == name)
+}
+
+/// The built-in components for this architecture, followed by those
+/// described in `filecopy::COMPONENTS_DIR` of `sysroot`.
+pub(crate) fn get_components(sysroot: &str) -> Result<Vec<Box<dyn Component>>> {
+    get_components_described_in(sysroot, sysroot)
+}
+
+/// Like `get_components()`, but with the described components of
+/// `descriptor_root`, e.g. the OS tree being installed from.
+fn get_components_described_in(
+    sysroot: &str,
+    descriptor_root: &str,
+) -> Result<Vec<Box<dyn Component>>> {
     let mut components: Vec<Box<dyn Component>> = Vec::new();
 
     #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    components.push(Box::new(efi::EFI::default()));
+    components.push(Box::new(efi::EFI::new(sysroot)));
 
-    // #[cfg(target_arch = "x86_64")]
-    // components.push(Box::new(bios::BIOS::new()));
+    #[cfg(target_arch = "x86_64")]
+    components.push(Box::new(bios::BIOS::new(sysroot)));
 
-    components
+    debug_assert!(check_component_names(&components).is_ok());
+    components.extend(filecopy::get_components(sysroot, descriptor_root)?);
+    Ok(components)
 }
 
-pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
-    for component in get_components() {
-        let v = component.generate_update_metadata(sysroot_path)?;
-        println!(
-            "Generated update layout for {}: {}",
-            component.name(),
-            v.version,
-        );
+/// Sort `components` into the order they're updated in; see
+/// `Component::priority()`.  The sort is stable, so components of equal
+/// priority keep their relative order.
+fn sort_by_priority(components: &mut [Box<dyn Component>]) {
+    components.sort_by_key(|c| c.priority());
+}
+
+/// Sort the component `names` of `sysroot`, given in name order, into
+/// update order.
+fn update_order(sysroot: &str, names: &mut Vec<String>) -> Result<()> {
+    let mut components = names
+        .iter()
+        .map(|n| component::new_from_name(sysroot, n))
+        .collect::<Result<Vec<_>>>()?;
+    sort_by_priority(&mut components);
+    *names = components.iter().map(|c| c.name().to_string()).collect();
+    Ok(())
+}
+
+/// Restrict `components` to those named in `only`, unless it's empty.
+/// Fails if a named component isn't available on this platform.
+fn select_components(
+    components: Vec<Box<dyn Component>>,
+    only: &[String],
+) -> Result<Vec<Box<dyn Component>>> {
+    if only.is_empty() {
+        return Ok(components);
     }
+    for name in only {
+        if !components.iter().any(|c| c.name() == name) {
+            if is_builtin_component(name) {
+                bail!(
+                    "Component {} is not available on {}",
+                    name,
+                    std::env::consts::ARCH
+                );
+            }
+            bail!("Unknown component {}", name);
+        }
+    }
+    Ok(components
+        .into_iter()
+        .filter(|c| only.iter().any(|n| n == c.name()))
+        .collect())
+}
+
+/// The components for `arch`, which unlike `get_components()` needn't
+/// be the architecture we were built for.  Described components apply
+/// to every architecture.
+pub(crate) fn get_components_for_arch(
+    sysroot: &str,
+    arch: &str,
+) -> Result<Vec<Box<dyn Component>>> {
+    let names: Vec<&str> = KNOWN_COMPONENTS
+        .iter()
+        .filter(|(_, arches)| arches.contains(&arch))
+        .map(|(name, _)| *name)
+        .collect();
+    if names.is_empty() {
+        bail!("No components are known for architecture {}", arch);
+    }
+    let mut components: Vec<Box<dyn Component>> = Vec::new();
+    for name in names {
+        // Only the components of our own architecture may be built in
+        match name {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            "EFI" => components.push(Box::new(efi::EFI::new(sysroot))),
+            #[cfg(target_arch = "x86_64")]
+            "BIOS" => components.push(Box::new(bios::BIOS::new(sysroot))),
+            _ => bail!(
+                "The {} component for {} isn't supported by this build",
+                name,
+                arch
+            ),
+        }
+    }
+    components.extend(filecopy::get_components(sysroot, sysroot)?);
+    Ok(components)
+}
 
+/// Components are keyed by name in the saved state, so ensure no two
+/// of them would clobber each other.
+fn check_component_names(components: &[Box<dyn Component>]) -> Result<()> {
+    let mut names = BTreeSet::new();
+    let mut duplicates = BTreeSet::new();
+    for component in components {
+        if !names.insert(component.name()) {
+            duplicates.insert(component.name());
+        }
+    }
+    if !duplicates.is_empty() {
+        let duplicates: Vec<&str> = duplicates.into_iter().collect();
+        bail!("Duplicate component names: {}", duplicates.join(", "));
+    }
     Ok(())
 }
 
+/// Parse an os-release file, returning a description of the OS version
+/// like `fedora 33.20201214.3.0`.  The OSTree version is preferred since
+/// it identifies the exact commit.
+fn parse_os_release(contents: &str) -> Option<String> {
+    let mut fields = BTreeMap::new();
+    for line in contents.lines() {
+        if let Some(i) = line.find('=') {
+            let v = line[i + 1..].trim();
+            let v = v.trim_matches(|c| c == '"' || c == '\'');
+            fields.insert(&line[..i], v);
+        }
+    }
+    let version = fields
+        .get("OSTREE_VERSION")
+        .or_else(|| fields.get("VERSION_ID"))?;
+    Some(match fields.get("ID") {
+        Some(id) => format!("{} {}", id, version),
+        None => version.to_string(),
+    })
+}
+
+/// Describe the OS version of `sysroot_path` from its os-release file, if any
+fn os_release_version(source: &dyn PayloadSource) -> Result<Option<String>> {
+    for p in &["usr/lib/os-release", "etc/os-release"] {
+        if let Some(contents) = source.read_file(Path::new(p))? {
+            let contents = String::from_utf8(contents).with_context(|| format!("reading {}", p))?;
+            return Ok(parse_os_release(&contents));
+        }
+    }
+    Ok(None)
+}
+
+/// Metadata written for a component by `generate_update_metadata()`.
+/// Printed as JSON by `bootupd generate-update-metadata --print-metadata`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct GeneratedUpdate {
+    pub(crate) meta: ContentMetadata,
+    /// File inventory of the payload, for components which have one
+    pub(crate) filetree: Option<crate::filetree::FileTree>,
+}
+
+/// Generate update metadata for all components in `sysroot_path`, taking
+/// their payloads from `src_root` if given.  The OS version recorded as
+/// their source defaults to the one in the os-release of the source.
+/// The components are those for `arch` if given, e.g. when building an
+/// image for another architecture, or else for the one we were built for.
+/// Returns what was generated, keyed by component name.
+pub(crate) fn generate_update_metadata(
+    sysroot_path: &str,
+    src_root: Option<&str>,
+    os_version: Option<&str>,
+    arch: Option<&str>,
+) -> Result<BTreeMap<String, GeneratedUpdate>> {
+    let source = FilesystemSource::new(src_root.unwrap_or(sysroot_path));
+    let os_version = match os_version {
+        Some(v) => Some(v.to_string()),
+        None => os_release_version(&source)?,
+    };
+    let components = match arch {
+        Some(arch) => get_components_for_arch(sysroot_path, arch)?,
+        None => get_components(sysroot_path)?,
+    };
+    check_component_names(&components)?;
+    let mut r = BTreeMap::new();
+    for component in components {
+        let meta =
+            component.generate_update_metadata(sysroot_path, &source, os_version.as_deref())?;
+        let filetree = component.query_update_filetree()?;
+        r.insert(
+            component.name().to_string(),
+            GeneratedUpdate { meta, filetree },
+        );
+    }
+    Ok(r)
+}
+
 /// Hold a lock on the system root; while ordinarily we run
 /// as a systemd unit which implicitly ensures a "singleton"
-/// instance this is a double check.
+/// instance this is a double check.  If another process holds
+/// the lock we retry until the timeout expires, then fail
+/// with `BootupdError::LockContended`.
 fn acquire_write_lock<P: AsRef<Path>>(sysroot: P) -> Result<std::fs::File> {
-    let sysroot = sysroot.as_ref();
-    let lockf = std::fs::OpenOptions::new()
+    acquire_write_lock_timeout(sysroot.as_ref(), write_lock_timeout()?)
+}
+
+fn acquire_write_lock_timeout(sysroot: &Path, timeout: Duration) -> Result<std::fs::File> {
+    let mut lockf = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .open(sysroot.join(WRITE_LOCK_PATH))?;
-    lockf.lock_exclusive()?;
+    let start = Instant::now();
+    loop {
+        match lockf.try_lock_exclusive() {
+            Ok(()) => break,
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                if start.elapsed() >= timeout {
+                    warn_if_stuck(sysroot);
+                    let holder = read_lock_holder(&mut lockf);
+                    return Err(BootupdError::LockContended(holder).into());
+                }
+                std::thread::sleep(WRITE_LOCK_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e).context("Acquiring write lock"),
+        }
+    }
+    // Any heartbeat is from an update which died without cleaning up
+    if let Some(hb) = read_heartbeat(sysroot) {
+        log::warn!(
+            "Update of {} by pid {} was interrupted; last heartbeat at {}",
+            hb.components.join(", "),
+            hb.pid,
+            hb.timestamp
+        );
+        let _ = std::fs::remove_file(sysroot.join(HEARTBEAT_PATH));
+    }
+    // Record our PID so that a contending process can report who holds the lock
+    lockf.set_len(0)?;
+    lockf.seek(std::io::SeekFrom::Start(0))?;
+    write!(lockf, "{}", std::process::id())?;
+    lockf.flush()?;
     Ok(lockf)
 }
 
+/// Written to `HEARTBEAT_PATH` and refreshed while an update runs, so
+/// that a monitor (or the next bootupd) can tell an update which is making
+/// progress apart from one which is stuck holding the write lock.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Heartbeat {
+    pub(crate) pid: u32,
+    /// Components being updated
+    pub(crate) components: Vec<String>,
+    pub(crate) timestamp: DateTime<Utc>,
+}
+
+/// Maintains the heartbeat for the duration of an update, removing it
+/// when dropped.  Failures are logged rather than failing the update.
+struct HeartbeatWriter {
+    path: PathBuf,
+    heartbeat: Heartbeat,
+    last: Instant,
+}
+
+impl HeartbeatWriter {
+    fn new(sysroot: &Path, components: Vec<String>) -> Self {
+        let mut w = Self {
+            path: sysroot.join(HEARTBEAT_PATH),
+            heartbeat: Heartbeat {
+                pid: std::process::id(),
+                components,
+                timestamp: Utc::now(),
+            },
+            last: Instant::now(),
+        };
+        w.write();
+        w
+    }
+
+    fn write(&mut self) {
+        self.heartbeat.timestamp = Utc::now();
+        self.last = Instant::now();
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let r = serde_json::to_vec(&self.heartbeat)
+            .map_err(anyhow::Error::from)
+            .and_then(|buf| Ok(std::fs::write(&tmp, buf)?))
+            .and_then(|_| Ok(std::fs::rename(&tmp, &self.path)?));
+        if let Err(e) = r {
+            log::warn!("Failed to write {:?}: {:#}", self.path, e);
+        }
+    }
+
+    /// Refresh the heartbeat, if it's due, as the update has made progress
+    fn beat(&mut self) {
+        if self.last.elapsed() >= HEARTBEAT_INTERVAL {
+            self.write();
+        }
+    }
+}
+
+impl Drop for HeartbeatWriter {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Failed to remove {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Read the heartbeat of a running or interrupted update, if any.
+fn read_heartbeat(sysroot: &Path) -> Option<Heartbeat> {
+    let buf = std::fs::read(sysroot.join(HEARTBEAT_PATH)).ok()?;
+    serde_json::from_slice(&buf).ok()
+}
+
+/// Log a warning if the holder of the write lock has stopped refreshing
+/// its heartbeat, e.g. because it's wedged on I/O.
+fn warn_if_stuck(sysroot: &Path) {
+    let hb = if let Some(hb) = read_heartbeat(sysroot) {
+        hb
+    } else {
+        return;
+    };
+    let age = Utc::now().signed_duration_since(hb.timestamp);
+    if age.to_std().map_or(false, |age| age > HEARTBEAT_STALE) {
+        log::warn!(
+            "Write lock holder pid {} has not made progress updating {} since {}; it may be stuck",
+            hb.pid,
+            hb.components.join(", "),
+            hb.timestamp
+        );
+    }
+}
+
+/// Parse the write lock timeout from the environment, if set.
+fn write_lock_timeout() -> Result<Duration> {
+    if let Some(v) = crate::util::getenv_utf8(WRITE_LOCK_TIMEOUT_ENV)? {
+        let secs: u64 = v
+            .parse()
+            .with_context(|| format!("Parsing {}={}", WRITE_LOCK_TIMEOUT_ENV, v))?;
+        Ok(Duration::from_secs(secs))
+    } else {
+        Ok(WRITE_LOCK_TIMEOUT)
+    }
+}
+
+/// Check whether another process holds the write lock, and if so which.
+/// This doesn't create the lock file if it's missing.
+fn probe_write_lock(sysroot: &Path) -> Result<(bool, Option<u32>)> {
+    let mut lockf = match std::fs::File::open(sysroot.join(WRITE_LOCK_PATH)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((false, None)),
+        Err(e) => return Err(e).context("Opening write lock"),
+    };
+    match FileExt::try_lock_shared(&lockf) {
+        Ok(()) => {
+            FileExt::unlock(&lockf)?;
+            Ok((false, None))
+        }
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Ok((true, read_lock_holder(&mut lockf)))
+        }
+        Err(e) => Err(e).context("Probing write lock"),
+    }
+}
+
+/// How long `update()` and `update_parallel()` wait for component
+/// updates, from `opts`, the
+/// environment, or the default.
+fn update_timeout(opts: &UpdateOptions) -> Result<Duration> {
+    if let Some(secs) = opts.timeout {
+        Ok(Duration::from_secs(secs))
+    } else if let Some(v) = crate::util::getenv_utf8(UPDATE_TIMEOUT_ENV)? {
+        let secs: u64 = v
+            .parse()
+            .with_context(|| format!("Parsing {}={}", UPDATE_TIMEOUT_ENV, v))?;
+        Ok(Duration::from_secs(secs))
+    } else {
+        Ok(UPDATE_TIMEOUT)
+    }
+}
+
+/// The minimum time between updates of `name`, if limited; from `opts`,
+/// or see `MIN_UPDATE_INTERVAL_ENV_PREFIX`
+fn min_update_interval(name: &str, opts: &UpdateOptions) -> Result<Option<Duration>> {
+    let var = format!("{}{}", MIN_UPDATE_INTERVAL_ENV_PREFIX, name.to_uppercase());
+    let secs = if let Some(secs) = opts.min_interval {
+        secs
+    } else if let Some(v) = crate::util::getenv_utf8(&var)? {
+        v.parse()
+            .with_context(|| format!("Parsing {}={}", var, v))?
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(Duration::from_secs(secs)).filter(|d| *d > Duration::from_secs(0)))
+}
+
+/// If content `applied` at the given time may not be updated until
+/// `interval` has passed, the earliest time after `now` it may be.
+fn rate_limited_until(
+    applied: DateTime<Utc>,
+    interval: Option<Duration>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let next = applied + chrono::Duration::from_std(interval?).ok()?;
+    Some(next).filter(|next| *next > now)
+}
+
+/// Find the PID recorded in the lock file by its holder; this is best-effort
+/// since the holder may be an older version which did not write it.
+fn read_lock_holder(lockf: &mut std::fs::File) -> Option<u32> {
+    let mut buf = String::new();
+    lockf.seek(std::io::SeekFrom::Start(0)).ok()?;
+    lockf.read_to_string(&mut buf).ok()?;
+    buf.trim().parse().ok()
+}
+
 /// Return value from daemon → client for component update
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
@@ -101,251 +817,4717 @@ pub(crate) enum ComponentUpdateResult {
     AtLatestVersion,
     Updated {
         previous: ContentMetadata,
+        /// A previous update of the component which was interrupted
         interrupted: Option<ContentMetadata>,
+        /// Set if the interrupted update was of the same payload, and
+        /// was resumed rather than restarted
+        resumed: bool,
+        new: ContentMetadata,
+        /// Files written or removed, see `UpdateContext::changed`.  Parts
+        /// completed by an interrupted attempt are not included.
+        #[serde(default)]
+        changed_files: Vec<String>,
+    },
+    /// Returned for a dry run; nothing was changed
+    WouldUpdate {
+        previous: ContentMetadata,
         new: ContentMetadata,
     },
+    /// The available payload is older than the installed version, and
+    /// `allow_downgrade` wasn't set
+    RefusedDowngrade {
+        installed: ContentMetadata,
+        available: ContentMetadata,
+    },
+    /// The component was updated too recently, see
+    /// `MIN_UPDATE_INTERVAL_ENV_PREFIX`, and `ignore_min_interval` wasn't
+    /// set
+    RateLimited {
+        installed: ContentMetadata,
+        applied: DateTime<Utc>,
+        /// When the component may next be updated
+        next: DateTime<Utc>,
+    },
+    /// The available payload is known not to work on this system, see
+    /// `Component::check_precondition()`, and `ignore_preconditions`
+    /// wasn't set
+    PreconditionFailed {
+        installed: ContentMetadata,
+        available: ContentMetadata,
+        reason: String,
+    },
 }
 
-/// daemon implementation of component update
-pub(crate) fn update(name: &str) -> Result<ComponentUpdateResult> {
-    let sysroot = openat::Dir::open("/")?;
-    let _lock = acquire_write_lock("/")?;
-    let mut state = get_saved_state("/")?.unwrap_or_else(|| SavedState {
-        ..Default::default()
-    });
-    let component = component::new_from_name(name)?;
-    let inst = if let Some(inst) = state.installed.get(name) {
-        inst.clone()
-    } else {
-        anyhow::bail!("Component {} is not installed", name);
-    };
-    let update = component.query_update()?;
-    let update = match update.as_ref() {
-        Some(p) if inst.meta.can_upgrade_to(&p) => p,
-        _ => return Ok(ComponentUpdateResult::AtLatestVersion),
-    };
-    let mut pending_container = state.pending.take().unwrap_or_default();
-    let interrupted = pending_container.get(component.name()).cloned();
-
-    pending_container.insert(component.name().into(), update.clone());
-    update_state(&sysroot, &state)?;
-    let newinst = component
-        .run_update(&inst)
-        .with_context(|| format!("Failed to update {}", component.name()))?;
-    state.installed.insert(component.name().into(), newinst);
-    pending_container.remove(component.name());
-    update_state(&sysroot, &state)?;
-    Ok(ComponentUpdateResult::Updated {
-        previous: inst.meta,
-        interrupted,
-        new: update.clone(),
-    })
+/// Result of a parallel update, mapping each requested component to
+/// its outcome
+pub(crate) type ParallelUpdateResult =
+    BTreeMap<String, Result<ComponentUpdateResult, BootupdError>>;
+
+/// An update that has been decided on but not yet applied
+struct PlannedUpdate {
+    name: String,
+    inst: InstalledContent,
+    update: ContentMetadata,
+    /// Parts of `update` completed by an interrupted attempt
+    completed: BTreeSet<String>,
 }
 
-/// daemon implementation of component validate
-pub(crate) fn validate(name: &str) -> Result<ValidationResult> {
-    let state = get_saved_state("/")?.unwrap_or_else(|| SavedState {
-        ..Default::default()
-    });
-    let component = component::new_from_name(name)?;
+/// Planned updates grouped by the resource they modify, each with the
+/// metadata of any interrupted attempt
+type UpdateGroups = BTreeMap<String, Vec<(PlannedUpdate, Option<ContentMetadata>)>>;
+
+/// Whether to apply an update to a component
+enum UpdatePlan {
+    Apply(PlannedUpdate),
+    /// Nothing will be done, for the given reason
+    Skip(ComponentUpdateResult),
+}
+
+/// Determine whether `name` should be updated.
+fn plan_update(
+    sysroot_path: &str,
+    state: &SavedState,
+    name: &str,
+    opts: &UpdateOptions,
+) -> Result<UpdatePlan, BootupdError> {
+    let component = component::new_from_name(sysroot_path, name)?;
     let inst = if let Some(inst) = state.installed.get(name) {
         inst.clone()
     } else {
-        anyhow::bail!("Component {} is not installed", name);
+        return Err(BootupdError::ComponentNotInstalled(name.into()));
+    };
+    let update = match component.query_update()? {
+        Some(p) => p,
+        None if opts.force => {
+            return Err(anyhow::anyhow!("No update payload available for {}", name).into())
+        }
+        None => return Ok(UpdatePlan::Skip(ComponentUpdateResult::AtLatestVersion)),
     };
-    component.validate(&inst)
+    match ComponentUpdatable::from_metadata(component.as_ref(), &inst.meta, Some(&update)) {
+        ComponentUpdatable::Upgradable => {}
+        ComponentUpdatable::AtLatestVersion if opts.force => {}
+        ComponentUpdatable::WouldDowngrade if opts.allow_downgrade => {
+            log::warn!(
+                "Downgrading {} from {} to {}",
+                name,
+                inst.meta.version,
+                update.version
+            );
+        }
+        ComponentUpdatable::WouldDowngrade => {
+            log::warn!(
+                "Refusing downgrade of {} from {} to {}",
+                name,
+                inst.meta.version,
+                update.version
+            );
+            return Ok(UpdatePlan::Skip(ComponentUpdateResult::RefusedDowngrade {
+                installed: inst.meta,
+                available: update,
+            }));
+        }
+        _ => return Ok(UpdatePlan::Skip(ComponentUpdateResult::AtLatestVersion)),
+    }
+    // Completing an interrupted update is never held back
+    let interrupted = state
+        .pending
+        .as_ref()
+        .and_then(|p| p.get(name))
+        .map_or(false, |p| !p.staged);
+    if let Some(applied) = inst
+        .applied
+        .filter(|_| !opts.ignore_min_interval && !interrupted)
+    {
+        let interval = min_update_interval(name, opts)?;
+        if let Some(next) = rate_limited_until(applied, interval, Utc::now()) {
+            log::warn!(
+                "Not updating {}, last updated at {}, until {}",
+                name,
+                applied,
+                next
+            );
+            return Ok(UpdatePlan::Skip(ComponentUpdateResult::RateLimited {
+                installed: inst.meta,
+                applied,
+                next,
+            }));
+        }
+    }
+    if let Some(reason) = component.check_precondition(&inst, &update)? {
+        if opts.ignore_preconditions {
+            log::warn!("Ignoring unmet precondition for {}: {}", name, reason);
+        } else {
+            log::warn!("Not updating {} to {}: {}", name, update.version, reason);
+            return Ok(UpdatePlan::Skip(
+                ComponentUpdateResult::PreconditionFailed {
+                    installed: inst.meta,
+                    available: update,
+                    reason,
+                },
+            ));
+        }
+    }
+    Ok(UpdatePlan::Apply(PlannedUpdate {
+        name: name.to_string(),
+        inst,
+        update,
+        completed: BTreeSet::new(),
+    }))
 }
 
-/// Atomically replace the on-disk state with a new version
-fn update_state(sysroot_dir: &openat::Dir, state: &SavedState) -> Result<()> {
-    let subdir = sysroot_dir.sub_dir(STATEFILE_DIR)?;
-    let f = {
-        let f = subdir.new_unnamed_file(0o644)?;
-        let mut buff = std::io::BufWriter::new(f);
-        serde_json::to_writer(&mut buff, state)?;
-        buff.flush()?;
-        buff.into_inner()?
-    };
-    let dest_tmp_name = {
-        // expect OK because we just created the filename above from a constant
-        let mut buf = std::ffi::OsString::from(STATEFILE_NAME);
-        buf.push(".tmp");
-        buf
+/// Mark `p` as in progress in `state`, returning any previous update
+/// of the component which was interrupted.  If that was an update to
+/// the same payload, `p` will resume from where it left off.  Any staged
+/// update is superseded.
+fn begin_update(state: &mut SavedState, p: &mut PlannedUpdate) -> Option<ContentMetadata> {
+    let pending = state.pending.get_or_insert_with(Default::default);
+    let interrupted = match pending.remove(&p.name) {
+        Some(i) if i.staged => {
+            log::warn!(
+                "Discarding staged update of {} to {}",
+                p.name,
+                i.meta.version
+            );
+            None
+        }
+        i => i,
     };
-    let dest_tmp_name = Path::new(&dest_tmp_name);
-    if subdir.exists(dest_tmp_name)? {
-        subdir.remove_file(dest_tmp_name)?;
+    if let Some(i) = interrupted.as_ref() {
+        if i.meta == p.update {
+            log::warn!(
+                "Resuming interrupted update of {} to {}",
+                p.name,
+                i.meta.version
+            );
+            p.completed = i.completed.clone();
+        } else {
+            log::warn!(
+                "Previous update of {} to {} was interrupted",
+                p.name,
+                i.meta.version
+            );
+        }
     }
-    subdir.link_file_at(&f, dest_tmp_name)?;
-    f.sync_all()?;
-    subdir.local_rename(dest_tmp_name, STATEFILE_NAME)?;
-    Ok(())
+    pending.insert(
+        p.name.clone(),
+        PendingUpdate {
+            meta: p.update.clone(),
+            completed: p.completed.clone(),
+            total: None,
+            staged: false,
+        },
+    );
+    log::info!(
+        "Updating {}: {} -> {}",
+        p.name,
+        p.inst.meta.version,
+        p.update.version
+    );
+    interrupted.map(|i| i.meta)
 }
 
-/// Load the JSON file containing on-disk state
-fn get_saved_state(sysroot_path: &str) -> Result<Option<SavedState>> {
-    let sysroot_dir = openat::Dir::open(sysroot_path)
-        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+/// Record that `key` of the in progress update of `name` is complete.
+fn mark_update_completed(
+    sysroot: &openat::Dir,
+    state: &mut SavedState,
+    name: &str,
+    key: &str,
+) -> Result<()> {
+    if let Some(p) = state.pending.as_mut().and_then(|p| p.get_mut(name)) {
+        p.completed.insert(key.to_string());
+    }
+    update_state(sysroot, state)
+}
 
-    let statefile_path = Path::new(STATEFILE_DIR).join(STATEFILE_NAME);
-    let saved_state = if let Some(statusf) = sysroot_dir.open_file_optional(&statefile_path)? {
-        let bufr = std::io::BufReader::new(statusf);
-        let saved_state: SavedState = serde_json::from_reader(bufr)?;
-        Some(saved_state)
-    } else {
-        None
-    };
-    Ok(saved_state)
+/// Record that the in progress update of `name` has `total` parts.
+fn record_update_total(
+    sysroot: &openat::Dir,
+    state: &mut SavedState,
+    name: &str,
+    total: usize,
+) -> Result<()> {
+    if let Some(p) = state.pending.as_mut().and_then(|p| p.get_mut(name)) {
+        p.total = Some(total as u64);
+    }
+    update_state(sysroot, state)
 }
 
-pub(crate) fn status() -> Result<Status> {
-    let mut ret: Status = Default::default();
-    let state = if let Some(state) = get_saved_state("/")? {
+/// Record the successful application of `p` in `state`.
+fn finish_update(
+    state: &mut SavedState,
+    p: PlannedUpdate,
+    mut newinst: InstalledContent,
+    interrupted: Option<ContentMetadata>,
+    changed_files: Vec<String>,
+) -> ComponentUpdateResult {
+    newinst.applied = Some(Utc::now());
+    state.counters.updates_applied += 1;
+    if interrupted.is_some() {
+        state.counters.interrupted_recovered += 1;
+    }
+    state.counters.last_update = newinst.applied;
+    state.installed.insert(p.name.clone(), newinst);
+    let previous = state.previous.get_or_insert_with(Default::default);
+    if let Some(older) = previous.insert(p.name.clone(), p.inst.clone()) {
         state
+            .retained
+            .get_or_insert_with(Default::default)
+            .entry(p.name.clone())
+            .or_default()
+            .insert(0, older);
+    }
+    if let Some(pending) = state.pending.as_mut() {
+        pending.remove(&p.name);
+    }
+    journal::log_event(
+        &Event::change(
+            &p.name,
+            EventResult::Success,
+            &p.inst.meta.version,
+            &p.update.version,
+        ),
+        &format!(
+            "Updated {}: {} -> {}",
+            p.name, p.inst.meta.version, p.update.version
+        ),
+    );
+    ComponentUpdateResult::Updated {
+        previous: p.inst.meta,
+        interrupted,
+        resumed: !p.completed.is_empty(),
+        new: p.update,
+        changed_files,
+    }
+}
+
+/// daemon implementation of component update; see `UpdateOptions`.
+/// Components, the saved state and the write lock are all found relative
+/// to `sysroot_path`.  If the
+/// component doesn't finish within `update_timeout()`, it's cancelled and
+/// the update is left marked as interrupted, to be resumed by the next one;
+/// the write lock is held until the component has stopped.
+pub(crate) fn update(
+    sysroot_path: &str,
+    name: &str,
+    opts: &UpdateOptions,
+    progress: &dyn Fn(&str),
+) -> Result<ComponentUpdateResult, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let lock = if opts.dry_run {
+        None
     } else {
-        return Ok(ret);
+        Some(acquire_write_lock(sysroot_path)?)
     };
-    for (name, ic) in state.installed.iter() {
-        let component = crate::component::new_from_name(&name)?;
-        let component = component.as_ref();
-        let interrupted = state
-            .pending
-            .as_ref()
-            .map(|p| p.get(name.as_str()))
-            .flatten();
-        let update = component.query_update()?;
-        let updatable = ComponentUpdatable::from_metadata(&ic.meta, update.as_ref());
-        ret.components.insert(
-            name.to_string(),
-            ComponentStatus {
-                installed: ic.meta.clone(),
-                interrupted: interrupted.cloned(),
-                update,
-                updatable,
-            },
+    let timeout = update_timeout(opts)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut p = match plan_update(sysroot_path, &state, name, opts)? {
+        UpdatePlan::Apply(p) => p,
+        UpdatePlan::Skip(r) => return Ok(r),
+    };
+    if opts.dry_run {
+        return Ok(ComponentUpdateResult::WouldUpdate {
+            previous: p.inst.meta,
+            new: p.update,
+        });
+    }
+    let interrupted = begin_update(&mut state, &mut p);
+    update_state(&sysroot, &mut state)?;
+    // A wedged update may never notice it's been cancelled, so run it on a
+    // thread we can abandon.  Components aren't `Send`, so the thread instantiates its
+    // own and relays progress back over a channel, as in `update_wave()`.
+    let (tx, rx) = std::sync::mpsc::channel::<WorkerMessage>();
+    let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let worker = {
+        let sysroot_path = sysroot_path.to_string();
+        let name = name.to_string();
+        let inst = p.inst.clone();
+        let completed = p.completed.clone();
+        let cancel = std::sync::Arc::clone(&cancel);
+        std::thread::spawn(move || -> Result<(InstalledContent, Vec<String>)> {
+            // The receiver outlives the thread, relaying its progress
+            // even once we've stopped waiting for it
+            let progress = |msg: &str| {
+                let _ = tx.send(WorkerMessage::Progress(msg.to_string()));
+            };
+            let mark_completed = |key: &str| {
+                let msg = WorkerMessage::Completed(name.clone(), key.to_string());
+                let _ = tx.send(msg);
+            };
+            let planned = |total: usize| {
+                let _ = tx.send(WorkerMessage::Planned(name.clone(), total));
+            };
+            let changed = RefCell::new(Vec::new());
+            let record_changed = |path: &str| changed.borrow_mut().push(path.to_string());
+            let cancelled = || cancel.load(std::sync::atomic::Ordering::Relaxed);
+            let ctx = component::UpdateContext {
+                progress: &progress,
+                completed: &completed,
+                mark_completed: &mark_completed,
+                planned: &planned,
+                changed: &record_changed,
+                cancelled: &cancelled,
+            };
+            let component = component::new_from_name(&sysroot_path, &name)?;
+            let newinst = component
+                .run_update(&inst, &ctx)
+                .with_context(|| format!("Failed to update {}", component.name()))?;
+            Ok((newinst, changed.into_inner()))
+        })
+    };
+    let mut heartbeat = HeartbeatWriter::new(Path::new(sysroot_path), vec![name.to_string()]);
+    let deadline = Instant::now().checked_add(timeout);
+    if !relay_progress(
+        &sysroot,
+        &mut state,
+        &rx,
+        &mut heartbeat,
+        progress,
+        deadline,
+    ) {
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        let e = anyhow::anyhow!(
+            "Update of {} did not complete within {} seconds; it will be resumed by the next update",
+            name,
+            timeout.as_secs()
         );
+        log_update_failure(&p, &e);
+        Abandoned { rx, heartbeat }.reap(sysroot, state, lock);
+        return Err(e.into());
     }
-    Ok(ret)
+    let (newinst, changed) = worker
+        .join()
+        .map_err(|_| anyhow::anyhow!("Update thread panicked"))?
+        .map_err(|e| {
+            log_update_failure(&p, &e);
+            e
+        })?;
+    let r = finish_update(&mut state, p, newinst, interrupted, changed);
+    update_state(&sysroot, &mut state)?;
+    Ok(r)
 }
 
-pub(crate) fn print_status(status: &Status) {
-    for (name, component) in status.components.iter() {
-        println!("Component {}", name);
-        println!("  Installed: {}", component.installed.version);
-
-        if let Some(i) = component.interrupted.as_ref() {
-            println!(
-                "  WARNING: Previous update to {} was interrupted",
-                i.version
-            );
+/// daemon implementation of staging an update of `name`, to be applied
+/// by `apply_staged()` at the next boot.  Returns the staged version, or
+/// `None` if the component is at the latest version.
+pub(crate) fn stage(
+    sysroot_path: &str,
+    name: &str,
+    progress: &dyn Fn(&str),
+) -> Result<Option<ContentMetadata>, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    if let Some(i) = state.pending.as_ref().and_then(|p| p.get(name)) {
+        if !i.staged {
+            return Err(anyhow::anyhow!(
+                "Previous update of {} to {} was interrupted; run update to complete it",
+                name,
+                i.meta.version
+            )
+            .into());
         }
-        let msg = match component.updatable {
-            ComponentUpdatable::NoUpdateAvailable => Cow::Borrowed("No update found"),
-            ComponentUpdatable::AtLatestVersion => Cow::Borrowed("At latest version"),
-            ComponentUpdatable::WouldDowngrade => Cow::Borrowed("Ignoring downgrade"),
-            ComponentUpdatable::Upgradable => Cow::Owned(format!(
-                "Available: {}",
-                component.update.as_ref().expect("update").version
-            )),
-        };
-        println!("  Update: {}", msg);
     }
+    let p = match plan_update(sysroot_path, &state, name, &UpdateOptions::default())? {
+        UpdatePlan::Apply(p) => p,
+        UpdatePlan::Skip(_) => return Ok(None),
+    };
+    let component = component::new_from_name(sysroot_path, name)?;
+    log::info!(
+        "Staging {}: {} -> {}",
+        name,
+        p.inst.meta.version,
+        p.update.version
+    );
+    let staged = component
+        .stage(&p.inst, progress)
+        .with_context(|| format!("Failed to stage {}", component.name()))?;
+    state.pending.get_or_insert_with(Default::default).insert(
+        name.to_string(),
+        PendingUpdate {
+            meta: staged.clone(),
+            completed: BTreeSet::new(),
+            total: None,
+            staged: true,
+        },
+    );
+    update_state(&sysroot, &mut state)?;
+    Ok(Some(staged))
+}
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    {
-        let boot_method = if Path::new("/sys/firmware/efi").exists() {
-            "EFI"
+/// daemon implementation of `apply-staged`, which is run early at boot
+/// before anything else uses the bootloader.  Applies each update
+/// recorded by `stage()`, returning the new versions.
+pub(crate) fn apply_staged(
+    sysroot_path: &str,
+) -> Result<BTreeMap<String, ContentMetadata>, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let staged: Vec<(String, PendingUpdate)> = state
+        .pending
+        .iter()
+        .flatten()
+        .filter(|(_, p)| p.staged)
+        .map(|(name, p)| (name.clone(), p.clone()))
+        .collect();
+    let mut ret = BTreeMap::new();
+    for (name, pending) in staged {
+        let component = component::new_from_name(sysroot_path, &name)?;
+        let inst = if let Some(inst) = state.installed.get(&name) {
+            inst.clone()
         } else {
-            "BIOS"
+            return Err(BootupdError::ComponentNotInstalled(name));
         };
-        println!("Boot method: {}", boot_method);
+        if !pending.completed.is_empty() {
+            log::warn!("Resuming interrupted application of staged {} update", name);
+        }
+        log::info!(
+            "Applying staged {}: {} -> {}",
+            name,
+            inst.meta.version,
+            pending.meta.version
+        );
+        let cell = RefCell::new(state);
+        let newinst = {
+            let mark_completed = |key: &str| {
+                if let Err(e) = mark_update_completed(&sysroot, &mut cell.borrow_mut(), &name, key)
+                {
+                    log::warn!("Failed to record progress of {}: {:#}", name, e);
+                }
+            };
+            let planned = |total: usize| {
+                if let Err(e) = record_update_total(&sysroot, &mut cell.borrow_mut(), &name, total)
+                {
+                    log::warn!("Failed to record progress of {}: {:#}", name, e);
+                }
+            };
+            let progress = |msg: &str| log::info!("{}", msg);
+            let changed = |path: &str| log::info!("Changed: {}", path);
+            let ctx = component::UpdateContext {
+                progress: &progress,
+                completed: &pending.completed,
+                mark_completed: &mark_completed,
+                planned: &planned,
+                changed: &changed,
+                cancelled: &|| false,
+            };
+            component
+                .apply_staged(&inst, &pending.meta, &ctx)
+                .with_context(|| format!("Failed to apply staged {}", component.name()))?
+        };
+        state = cell.into_inner();
+        let p = PlannedUpdate {
+            name: name.clone(),
+            inst,
+            update: pending.meta.clone(),
+            completed: pending.completed,
+        };
+        finish_update(&mut state, p, newinst, None, Vec::new());
+        update_state(&sysroot, &mut state)?;
+        // The state no longer refers to the staged payload, so failing
+        // to clean it up is harmless.
+        if let Err(e) = component.discard_staged(&state.installed[&name]) {
+            log::warn!("Failed to remove staged {} payload: {:#}", name, e);
+        }
+        ret.insert(name, pending.meta);
+    }
+    Ok(ret)
+}
+
+/// Record the failure of the update `p` in the journal.
+fn log_update_failure(p: &PlannedUpdate, e: &anyhow::Error) {
+    journal::log_event(
+        &Event::change(
+            &p.name,
+            EventResult::Failure,
+            &p.inst.meta.version,
+            &p.update.version,
+        ),
+        &format!("{:#}", e),
+    );
+}
+
+/// Record the outcome of validating `name` in the journal.
+fn log_validation(name: &str, r: &ValidationResult) {
+    match r {
+        ValidationResult::Valid => journal::log_event(
+            &Event::new(name, EventResult::Valid),
+            &format!("Validated {}", name),
+        ),
+        ValidationResult::Errors(errs) => journal::log_event(
+            &Event::new(name, EventResult::Invalid),
+            &format!(
+                "Validation of {} failed: {}",
+                name,
+                describe_errors(errs, "; ")
+            ),
+        ),
+    }
+}
+
+/// Sent from the threads of `update()` and `update_parallel()`
+enum WorkerMessage {
+    Progress(String),
+    /// A component name and the part of its update which completed
+    Completed(String, String),
+    /// A component name and the number of parts in its update
+    Planned(String, usize),
+}
+
+/// Relay the messages of update workers on `rx` until they've all exited,
+/// or `deadline` passes first; returns whether they exited.  Progress is
+/// recorded in `state` as it's reported.
+fn relay_progress(
+    sysroot: &openat::Dir,
+    state: &mut SavedState,
+    rx: &std::sync::mpsc::Receiver<WorkerMessage>,
+    heartbeat: &mut HeartbeatWriter,
+    progress: &dyn Fn(&str),
+    deadline: Option<Instant>,
+) -> bool {
+    use std::sync::mpsc::RecvTimeoutError;
+    loop {
+        let msg = match deadline {
+            Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        // Only word from a worker shows that it's making progress
+        if msg.is_ok() {
+            heartbeat.beat();
+        }
+        match msg {
+            Ok(WorkerMessage::Progress(msg)) => progress(&msg),
+            Ok(WorkerMessage::Completed(name, key)) => {
+                if let Err(e) = mark_update_completed(sysroot, state, &name, &key) {
+                    log::warn!("Failed to record progress of {}: {:#}", name, e);
+                }
+            }
+            Ok(WorkerMessage::Planned(name, total)) => {
+                if let Err(e) = record_update_total(sysroot, state, &name, total) {
+                    log::warn!("Failed to record progress of {}: {:#}", name, e);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return true,
+            Err(RecvTimeoutError::Timeout) => return false,
+        }
+    }
+}
+
+/// Update workers which missed their deadline and have been cancelled.
+/// A wedged worker can't be stopped, so they're left to finish by
+/// themselves, e.g. once a write in flight returns.
+struct Abandoned {
+    rx: std::sync::mpsc::Receiver<WorkerMessage>,
+    heartbeat: HeartbeatWriter,
+}
+
+impl Abandoned {
+    /// Keep recording the progress of the workers in `state` in the
+    /// background, and only release `lock` once they've all exited, so
+    /// that another update can't race them.  Their updates stay marked as
+    /// interrupted, to be resumed by the next one.
+    fn reap(self, sysroot: openat::Dir, mut state: SavedState, lock: Option<std::fs::File>) {
+        let Self { rx, mut heartbeat } = self;
+        std::thread::spawn(move || {
+            let progress = |msg: &str| log::info!("{}", msg);
+            relay_progress(&sysroot, &mut state, &rx, &mut heartbeat, &progress, None);
+            log::info!("Cancelled updates have stopped");
+            drop(heartbeat);
+            drop(lock);
+        });
+    }
+}
+
+/// daemon implementation of updating several components at once.  Components
+/// with distinct `Component::resource_key()`s are updated concurrently;
+/// those sharing a key are updated in turn.  Components of a higher
+/// `Component::priority()` aren't started until all those of a lower one
+/// have finished.  A failure of one component doesn't prevent the others
+/// from being updated.  At most `max_concurrency` updates run at once, if
+/// set; see `worker_count()`.  Updates still running after
+/// `update_timeout()` are cancelled as by `update()`, and those not yet
+/// started are left to the next update.
+pub(crate) fn update_parallel(
+    sysroot_path: &str,
+    names: &[String],
+    opts: &UpdateOptions,
+    max_concurrency: Option<usize>,
+    progress: &dyn Fn(&str),
+) -> Result<ParallelUpdateResult, BootupdError> {
+    // Fail before changing anything
+    worker_count(max_concurrency, 1)?;
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = if opts.dry_run {
+        None
+    } else {
+        Some(acquire_write_lock(sysroot_path)?)
+    };
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut results = ParallelUpdateResult::new();
+    // Group the planned updates by priority, then by the resource they modify
+    let mut waves: BTreeMap<u32, UpdateGroups> = BTreeMap::new();
+    for name in names {
+        let mut p = match plan_update(sysroot_path, &state, name, opts) {
+            Ok(UpdatePlan::Apply(p)) => p,
+            Ok(UpdatePlan::Skip(r)) => {
+                results.insert(name.clone(), Ok(r));
+                continue;
+            }
+            Err(e) => {
+                results.insert(name.clone(), Err(e));
+                continue;
+            }
+        };
+        if opts.dry_run {
+            results.insert(
+                name.clone(),
+                Ok(ComponentUpdateResult::WouldUpdate {
+                    previous: p.inst.meta,
+                    new: p.update,
+                }),
+            );
+            continue;
+        }
+        // If we can't tell which resource a component modifies, fall
+        // back to serializing it with every other such component.
+        let component = component::new_from_name(sysroot_path, name)?;
+        let key = component.resource_key(&p.inst).unwrap_or_else(|e| {
+            log::warn!("Failed to find resource for {}: {:#}", name, e);
+            String::new()
+        });
+        let interrupted = begin_update(&mut state, &mut p);
+        waves
+            .entry(component.priority())
+            .or_default()
+            .entry(key)
+            .or_default()
+            .push((p, interrupted));
+    }
+    if waves.is_empty() {
+        return Ok(results);
+    }
+    update_state(&sysroot, &mut state)?;
+
+    // The deadline of `update()` applies to all the waves together
+    let timeout = update_timeout(opts)?;
+    let deadline = Instant::now().checked_add(timeout);
+    let mut waves = waves.into_iter();
+    while let Some((_, groups)) = waves.next() {
+        let (r, abandoned) = update_wave(
+            sysroot_path,
+            &sysroot,
+            &mut state,
+            groups,
+            max_concurrency,
+            deadline,
+            progress,
+        )?;
+        results.extend(r);
+        if let Some(abandoned) = abandoned {
+            let unstarted = waves.flat_map(|(_, groups)| groups.into_values().flatten());
+            for (p, _) in unstarted {
+                let e = anyhow::anyhow!(
+                    "Update of {} was not started within {} seconds; it will be run by the next update",
+                    p.name,
+                    timeout.as_secs()
+                );
+                results.insert(p.name, Err(e.into()));
+            }
+            update_state(&sysroot, &mut state)?;
+            abandoned.reap(sysroot, state, _lock);
+            return Ok(results);
+        }
+    }
+    update_state(&sysroot, &mut state)?;
+    Ok(results)
+}
+
+/// The number of threads updating the `groups` of a wave concurrently.
+/// By default each group gets its own, so that all the resources are
+/// updated at once; `max_concurrency` caps that, e.g. to avoid saturating
+/// the I/O of slow ESPs.
+fn worker_count(max_concurrency: Option<usize>, groups: usize) -> Result<usize> {
+    match max_concurrency {
+        Some(0) => bail!("The maximum concurrency must be at least 1"),
+        Some(n) => Ok(n.min(groups)),
+        None => Ok(groups),
+    }
+}
+
+/// Run the updates in `groups` concurrently for `update_parallel()`,
+/// returning their outcomes.  Each of the threads given by
+/// `worker_count()` takes groups in turn until none remain.  If `deadline`
+/// passes first, the threads are cancelled and also returned, to be reaped
+/// by the caller.
+fn update_wave(
+    sysroot_path: &str,
+    sysroot: &openat::Dir,
+    state: &mut SavedState,
+    groups: UpdateGroups,
+    max_concurrency: Option<usize>,
+    deadline: Option<Instant>,
+    progress: &dyn Fn(&str),
+) -> Result<(ParallelUpdateResult, Option<Abandoned>)> {
+    let names: Vec<String> = groups
+        .values()
+        .flat_map(|g| g.iter().map(|(p, _)| p.name.clone()))
+        .collect();
+    let mut heartbeat = HeartbeatWriter::new(Path::new(sysroot_path), names.clone());
+    // Components aren't `Send`, so each thread instantiates its own and
+    // relays progress back over a channel.
+    let (tx, rx) = std::sync::mpsc::channel::<WorkerMessage>();
+    let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let workers = worker_count(max_concurrency, groups.len())?;
+    let queue: VecDeque<_> = groups.into_iter().map(|(_, g)| g).collect();
+    let queue = std::sync::Arc::new(std::sync::Mutex::new(queue));
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let tx = tx.clone();
+            let queue = std::sync::Arc::clone(&queue);
+            let cancel = std::sync::Arc::clone(&cancel);
+            let sysroot_path = sysroot_path.to_string();
+            std::thread::spawn(move || {
+                let cancelled = || cancel.load(std::sync::atomic::Ordering::Relaxed);
+                let mut done = Vec::new();
+                loop {
+                    let group = match queue.lock().unwrap().pop_front() {
+                        Some(group) => group,
+                        None => break,
+                    };
+                    // Once cancelled, whatever hasn't started is left pending
+                    for (p, interrupted) in group.into_iter().take_while(|_| !cancelled()) {
+                        // The receiver outlives all threads
+                        let progress = |msg: &str| {
+                            let msg = format!("{}: {}", p.name, msg);
+                            let _ = tx.send(WorkerMessage::Progress(msg));
+                        };
+                        let mark_completed = |key: &str| {
+                            let msg = WorkerMessage::Completed(p.name.clone(), key.to_string());
+                            let _ = tx.send(msg);
+                        };
+                        let planned = |total: usize| {
+                            let _ = tx.send(WorkerMessage::Planned(p.name.clone(), total));
+                        };
+                        let changed = RefCell::new(Vec::new());
+                        let record_changed =
+                            |path: &str| changed.borrow_mut().push(path.to_string());
+                        let ctx = component::UpdateContext {
+                            progress: &progress,
+                            completed: &p.completed,
+                            mark_completed: &mark_completed,
+                            planned: &planned,
+                            changed: &record_changed,
+                            cancelled: &cancelled,
+                        };
+                        let r = component::new_from_name(&sysroot_path, &p.name).and_then(|c| {
+                            c.run_update(&p.inst, &ctx)
+                                .with_context(|| format!("Failed to update {}", c.name()))
+                        });
+                        let r = r.map(|newinst| (newinst, changed.into_inner()));
+                        done.push((p, interrupted, r));
+                    }
+                }
+                done
+            })
+        })
+        .collect();
+    drop(tx);
+    let mut results = ParallelUpdateResult::new();
+    if !relay_progress(sysroot, state, &rx, &mut heartbeat, progress, deadline) {
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        // What finished is only known once every thread has, which may be
+        // never; it's all resumed by the next update.
+        for name in names {
+            let e = anyhow::anyhow!(
+                "Update of {} did not complete in time; it will be resumed by the next update",
+                name
+            );
+            log::error!("{:#}", e);
+            results.insert(name, Err(e.into()));
+        }
+        return Ok((results, Some(Abandoned { rx, heartbeat })));
+    }
+    for h in handles {
+        let done = h
+            .join()
+            .map_err(|_| anyhow::anyhow!("Update thread panicked"))?;
+        for (p, interrupted, r) in done {
+            let name = p.name.clone();
+            let r = match r {
+                Ok((newinst, changed)) => {
+                    Ok(finish_update(state, p, newinst, interrupted, changed))
+                }
+                Err(e) => {
+                    log_update_failure(&p, &e);
+                    Err(BootupdError::from(e))
+                }
+            };
+            results.insert(name, r);
+        }
+    }
+    Ok((results, None))
+}
+
+/// daemon implementation of updating every installed component, in
+/// priority order.  Without `transactional`, each is updated as by
+/// `update()`, so a failure leaves the components before it updated.
+/// With it, all the updates are staged first and nothing is applied
+/// unless every one of them staged; if applying one then fails, those
+/// already applied are rolled back, leaving the system either fully
+/// updated or as it was.  Components which can't be staged, e.g. BIOS boot
+/// code, can't take part; they're updated individually once the others
+/// have been committed.
+pub(crate) fn update_all(
+    sysroot_path: &str,
+    opts: &UpdateOptions,
+    transactional: bool,
+    progress: &dyn Fn(&str),
+) -> Result<ParallelUpdateResult, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut names: Vec<String> = state.installed.keys().cloned().collect();
+    update_order(sysroot_path, &mut names)?;
+    if transactional {
+        let mut staged = Vec::new();
+        let mut unstaged = Vec::new();
+        for name in names {
+            if component::new_from_name(sysroot_path, &name)?.can_stage() {
+                staged.push(name);
+            } else {
+                unstaged.push(name);
+            }
+        }
+        let mut results = update_transaction(sysroot_path, &staged, opts, progress)?;
+        for name in unstaged {
+            let r = update(sysroot_path, &name, opts, progress);
+            results.insert(name, r);
+        }
+        return Ok(results);
+    }
+    Ok(names
+        .iter()
+        .map(|name| (name.clone(), update(sysroot_path, name, opts, progress)))
+        .collect())
+}
+
+/// Implementation of `update_all()` with `transactional` set, updating
+/// `names` in the order given.
+fn update_transaction(
+    sysroot_path: &str,
+    names: &[String],
+    opts: &UpdateOptions,
+    progress: &dyn Fn(&str),
+) -> Result<ParallelUpdateResult, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let lock = if opts.dry_run {
+        None
+    } else {
+        Some(acquire_write_lock(sysroot_path)?)
+    };
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut results = ParallelUpdateResult::new();
+    let mut planned = Vec::new();
+    for name in names {
+        // Resuming would leave nothing to roll back to
+        if let Some(i) = state.pending.as_ref().and_then(|p| p.get(name)) {
+            if !i.staged {
+                return Err(anyhow::anyhow!(
+                    "Previous update of {} to {} was interrupted; run a non-transactional update to complete it",
+                    name,
+                    i.meta.version
+                )
+                .into());
+            }
+        }
+        match plan_update(sysroot_path, &state, name, opts)? {
+            UpdatePlan::Apply(p) if opts.dry_run => {
+                let r = ComponentUpdateResult::WouldUpdate {
+                    previous: p.inst.meta,
+                    new: p.update,
+                };
+                results.insert(name.clone(), Ok(r));
+            }
+            UpdatePlan::Apply(p) => {
+                planned.push((component::new_from_name(sysroot_path, name)?, p))
+            }
+            UpdatePlan::Skip(r) => {
+                results.insert(name.clone(), Ok(r));
+            }
+        }
+    }
+    if planned.is_empty() {
+        return Ok(results);
+    }
+
+    // Copy every payload alongside what's installed before changing any of it
+    let discard_all = |planned: &[(Box<dyn Component>, PlannedUpdate)]| {
+        for (component, p) in planned {
+            if let Err(e) = component.discard_staged(&p.inst) {
+                log::warn!("Failed to discard staged {}: {:#}", p.name, e);
+            }
+        }
+    };
+    for (i, (component, p)) in planned.iter().enumerate() {
+        progress(&format!("Staging {}", p.name));
+        if let Err(e) = component.stage(&p.inst, progress) {
+            discard_all(&planned[..=i]);
+            let e = e.context(format!(
+                "Failed to stage {}; no components were updated",
+                p.name
+            ));
+            log_update_failure(p, &e);
+            return Err(e.into());
+        }
+    }
+
+    for (_, p) in planned.iter_mut() {
+        begin_update(&mut state, p);
+    }
+    update_state(&sysroot, &mut state)?;
+    // As in `update()`, apply the updates on a thread we can abandon if
+    // it wedges, which instantiates its own components.
+    let timeout = update_timeout(opts)?;
+    let work: Vec<_> = planned
+        .iter()
+        .map(|(_, p)| {
+            let completed = p.completed.clone();
+            (p.name.clone(), p.inst.clone(), p.update.clone(), completed)
+        })
+        .collect();
+    let (tx, rx) = std::sync::mpsc::channel::<WorkerMessage>();
+    let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let worker = {
+        let sysroot_path = sysroot_path.to_string();
+        let cancel = std::sync::Arc::clone(&cancel);
+        std::thread::spawn(move || {
+            let cancelled = || cancel.load(std::sync::atomic::Ordering::Relaxed);
+            let mut applied = Vec::new();
+            for (name, inst, update, completed) in work {
+                // The receiver outlives the thread
+                let progress = |msg: &str| {
+                    let _ = tx.send(WorkerMessage::Progress(msg.to_string()));
+                };
+                let mark_completed = |key: &str| {
+                    let msg = WorkerMessage::Completed(name.clone(), key.to_string());
+                    let _ = tx.send(msg);
+                };
+                let planned = |total: usize| {
+                    let _ = tx.send(WorkerMessage::Planned(name.clone(), total));
+                };
+                let changed = RefCell::new(Vec::new());
+                let record_changed = |path: &str| changed.borrow_mut().push(path.to_string());
+                let ctx = component::UpdateContext {
+                    progress: &progress,
+                    completed: &completed,
+                    mark_completed: &mark_completed,
+                    planned: &planned,
+                    changed: &record_changed,
+                    cancelled: &cancelled,
+                };
+                progress(&format!("Applying {}", name));
+                let r = component::new_from_name(&sysroot_path, &name)
+                    .and_then(|c| c.apply_staged(&inst, &update, &ctx))
+                    .with_context(|| format!("Failed to update {}", name));
+                match r {
+                    Ok(newinst) => applied.push((newinst, changed.into_inner())),
+                    Err(e) => return (applied, Some(e)),
+                }
+            }
+            (applied, None)
+        })
+    };
+    let names = planned.iter().map(|(_, p)| p.name.clone()).collect();
+    let mut heartbeat = HeartbeatWriter::new(Path::new(sysroot_path), names);
+    let deadline = Instant::now().checked_add(timeout);
+    if !relay_progress(
+        &sysroot,
+        &mut state,
+        &rx,
+        &mut heartbeat,
+        progress,
+        deadline,
+    ) {
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        // Nothing can be rolled back while the thread may still write
+        let e = anyhow::anyhow!(
+            "Transactional update did not complete within {} seconds; it will be completed by the next update",
+            timeout.as_secs()
+        );
+        log::error!("{:#}", e);
+        Abandoned { rx, heartbeat }.reap(sysroot, state, lock);
+        return Err(e.into());
+    }
+    let (applied, failure) = worker
+        .join()
+        .map_err(|_| anyhow::anyhow!("Update thread panicked"))?;
+    if let Some(e) = failure.as_ref() {
+        log_update_failure(&planned[applied.len()].1, e);
+    }
+
+    if let Some(e) = failure {
+        // Undo in reverse order, including whatever the failed
+        // component had written.
+        let mut unrestored = Vec::new();
+        for (i, (component, p)) in planned.iter().enumerate().take(applied.len() + 1).rev() {
+            let touched = state
+                .pending
+                .as_ref()
+                .and_then(|pending| pending.get(&p.name))
+                .map(|u| !u.completed.is_empty())
+                .unwrap_or(false);
+            let current = match applied.get(i) {
+                Some((newinst, _)) => Some(newinst.clone()),
+                None if touched => Some(InstalledContent {
+                    meta: p.update.clone(),
+                    filetree: component.query_update_filetree().unwrap_or(None),
+                    applied: None,
+                    device: p.inst.device.clone(),
+                    boot_code: p.inst.boot_code.clone(),
+                    mirrors: p.inst.mirrors.clone(),
+                }),
+                None => None,
+            };
+            if let Some(current) = current {
+                progress(&format!("Rolling back {}", p.name));
+                if let Err(e) = component.rollback(&current, &p.inst) {
+                    log::error!("Failed to roll back {}: {:#}", p.name, e);
+                    unrestored.push(p.name.as_str());
+                    continue;
+                }
+                // The rollback restored the generations retained before
+                // the transaction, so the saved state still matches them
+            }
+            state
+                .pending
+                .get_or_insert_with(Default::default)
+                .remove(&p.name);
+        }
+        // Components which couldn't be rolled back stay pending, to be
+        // completed by the next update.
+        update_state(&sysroot, &mut state)?;
+        discard_all(&planned);
+        let e = if unrestored.is_empty() {
+            e.context("all components were left at their previous versions")
+        } else {
+            e.context(format!(
+                "failed to roll back {}; run update to complete the update",
+                unrestored.join(", ")
+            ))
+        };
+        return Err(e.into());
+    }
+
+    let mut discard = Vec::new();
+    for ((component, p), (newinst, changed)) in planned.into_iter().zip(applied) {
+        let name = p.name.clone();
+        let r = finish_update(&mut state, p, newinst.clone(), None, changed);
+        results.insert(name, Ok(r));
+        discard.push((component, newinst));
+    }
+    // A single write commits every component
+    update_state(&sysroot, &mut state)?;
+    for (component, newinst) in discard {
+        if let Err(e) = component.discard_staged(&newinst) {
+            log::warn!("Failed to discard staged {}: {:#}", component.name(), e);
+        }
+    }
+    Ok(results)
+}
+
+/// Return value from daemon → client for component rollback
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentRollbackResult {
+    pub(crate) from: ContentMetadata,
+    pub(crate) to: ContentMetadata,
+}
+
+/// daemon implementation of component rollback
+pub(crate) fn rollback(
+    sysroot_path: &str,
+    name: &str,
+) -> Result<ComponentRollbackResult, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let component = component::new_from_name(sysroot_path, name)?;
+    let inst = if let Some(inst) = state.installed.get(name) {
+        inst.clone()
+    } else {
+        return Err(BootupdError::ComponentNotInstalled(name.into()));
+    };
+    let previous = state
+        .previous
+        .as_mut()
+        .and_then(|p| p.remove(name))
+        .ok_or_else(|| anyhow::anyhow!("No previous version of {} retained", name))?;
+    log::info!(
+        "Rolling back {}: {} -> {}",
+        name,
+        inst.meta.version,
+        previous.meta.version
+    );
+    let mut restored = component
+        .rollback(&inst, &previous)
+        .with_context(|| format!("Failed to roll back {}", component.name()))?;
+    // The next older generation, if any, now offers a rollback
+    let older = state
+        .retained
+        .as_mut()
+        .and_then(|r| r.get_mut(name))
+        .filter(|g| !g.is_empty())
+        .map(|g| g.remove(0));
+    if let Some(older) = older {
+        state
+            .previous
+            .get_or_insert_with(Default::default)
+            .insert(name.to_string(), older);
+    }
+    restored.applied = Some(Utc::now());
+    let to = restored.meta.clone();
+    state.installed.insert(component.name().into(), restored);
+    update_state(&sysroot, &mut state)?;
+    journal::log_event(
+        &Event::change(name, EventResult::Success, &inst.meta.version, &to.version),
+        &format!(
+            "Rolled back {}: {} -> {}",
+            name, inst.meta.version, to.version
+        ),
+    );
+    Ok(ComponentRollbackResult {
+        from: inst.meta,
+        to,
+    })
+}
+
+/// Remove the files of component `name` and drop it from the saved state,
+/// returning the version which was installed.  Removing the component
+/// used to boot the running system is refused unless `force` is set.
+pub(crate) fn uninstall(
+    sysroot_path: &str,
+    name: &str,
+    force: bool,
+) -> Result<ContentMetadata, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let component = component::new_from_name(sysroot_path, name)?;
+    let inst = if let Some(inst) = state.installed.get(name) {
+        inst.clone()
+    } else {
+        return Err(BootupdError::ComponentNotInstalled(name.into()));
+    };
+    // Boot methods are named after the component which implements them.
+    // Another sysroot, e.g. an installer's target, didn't boot us.
+    let booted = sysroot_path == "/"
+        && boot_method(Path::new(SYSFS_EFI_PATH)).to_string() == component.name();
+    if booted && !force {
+        return Err(anyhow::anyhow!(
+            "Refusing to uninstall {}, which booted the running system; use --force to override",
+            name
+        )
+        .into());
+    }
+    log::info!("Uninstalling {}: {}", name, inst.meta.version);
+    component
+        .uninstall(&inst)
+        .with_context(|| format!("Failed to uninstall {}", component.name()))?;
+    state.installed.remove(name);
+    if let Some(pending) = state.pending.as_mut() {
+        pending.remove(name);
+    }
+    if let Some(previous) = state.previous.as_mut() {
+        previous.remove(name);
+    }
+    if let Some(retained) = state.retained.as_mut() {
+        retained.remove(name);
+    }
+    update_state(&sysroot, &mut state)?;
+    Ok(inst.meta)
+}
+
+/// daemon implementation of prune; removes the retained payloads of each
+/// installed component beyond the newest `keep` generations.  The newest
+/// generation is the one `rollback()` restores, so at least one must be
+/// kept.  Payloads which the saved state no longer refers to are removed
+/// too, and a rollback whose payload has gone missing is dropped from the
+/// state.
+pub(crate) fn prune(
+    sysroot_path: &str,
+    keep: u32,
+) -> Result<BTreeMap<String, PruneResult>, BootupdError> {
+    if keep == 0 {
+        return Err(anyhow::anyhow!(
+            "At least one generation must be kept to roll back the current version"
+        )
+        .into());
+    }
+    let keep = keep as usize;
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut results = BTreeMap::new();
+    for (name, inst) in state.installed.iter() {
+        let component = component::new_from_name(sysroot_path, name)?;
+        let previous = state.previous.as_ref().and_then(|p| p.get(name));
+        let r = component
+            .prune(inst, previous, keep)
+            .with_context(|| format!("Failed to prune {}", name))?;
+        results.insert(name.clone(), r);
+    }
+    let mut changed = false;
+    for (name, r) in results.iter() {
+        if r.previous_missing {
+            log::warn!("Retained payload of {} is missing; dropping rollback", name);
+            if let Some(previous) = state.previous.as_mut() {
+                changed |= previous.remove(name).is_some();
+            }
+        }
+        if r.freed > 0 {
+            log::info!("Pruned {}: freed {} bytes", name, r.freed);
+        }
+    }
+    if let Some(retained) = state.retained.as_mut() {
+        for (name, generations) in retained.iter_mut() {
+            // These follow the generation in `previous`
+            let rollback = state
+                .previous
+                .as_ref()
+                .map_or(false, |p| p.contains_key(name));
+            let keep = if rollback { keep - 1 } else { 0 };
+            if generations.len() > keep {
+                generations.truncate(keep);
+                changed = true;
+            }
+        }
+        retained.retain(|_, generations| !generations.is_empty());
+    }
+    if changed {
+        update_state(&sysroot, &mut state)?;
+    }
+    Ok(results)
+}
+
+/// A component known to bootupd, see `list_components()`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentInfo {
+    /// Architectures the component is supported on
+    pub(crate) architectures: Vec<String>,
+    /// Whether the component is supported on this platform
+    pub(crate) available: bool,
+    /// Whether the component is recorded in the saved state
+    pub(crate) installed: bool,
+}
+
+/// Return value from daemon → client for listing components
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentList {
+    /// The architecture of this platform
+    pub(crate) architecture: String,
+    /// Maps a component name to its information
+    pub(crate) components: BTreeMap<String, ComponentInfo>,
+    /// Maps the name of a component whose descriptor can't be loaded to
+    /// why; see `Status::invalid_descriptors`
+    #[serde(default)]
+    pub(crate) invalid_descriptors: BTreeMap<String, String>,
+}
+
+/// Return value from daemon → client for `metrics()`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Metrics {
+    /// Printed alongside the other fields; nested here because the IPC
+    /// encoding can't represent flattened structs
+    pub(crate) counters: Counters,
+    /// Whether an operation currently holds the write lock
+    pub(crate) locked: bool,
+    /// The PID of the lock holder, if known
+    pub(crate) lock_holder: Option<u32>,
+    /// Version of the bootupd which last wrote the saved state
+    pub(crate) state_written_by: Option<String>,
+    /// Heartbeat of the running (or a stuck) update, if any
+    #[serde(default)]
+    pub(crate) heartbeat: Option<Heartbeat>,
+}
+
+/// daemon implementation of reporting activity counters; this works
+/// whether or not bootupd has been installed.
+pub(crate) fn metrics(sysroot_path: &str) -> Result<Metrics, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.unwrap_or_default();
+    let (locked, lock_holder) = probe_write_lock(Path::new(sysroot_path))?;
+    Ok(Metrics {
+        counters: state.counters,
+        locked,
+        lock_holder,
+        state_written_by: state.written_by,
+        heartbeat: read_heartbeat(Path::new(sysroot_path)),
+    })
+}
+
+/// daemon implementation of listing components; this works whether or
+/// not bootupd has been installed.
+pub(crate) fn list_components(sysroot_path: &str) -> Result<ComponentList, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.unwrap_or_default();
+    let available = get_components(sysroot_path)?;
+    let available: Vec<&str> = available.iter().map(|c| c.name()).collect();
+    let mut components: BTreeMap<String, ComponentInfo> = KNOWN_COMPONENTS
+        .iter()
+        .map(|(name, arches)| {
+            let info = ComponentInfo {
+                architectures: arches.iter().map(|a| a.to_string()).collect(),
+                available: available.contains(name),
+                installed: state.installed.contains_key(*name),
+            };
+            (name.to_string(), info)
+        })
+        .collect();
+    // Described components aren't tied to an architecture, but are only
+    // known to be usable on this one
+    for name in available.iter().filter(|n| !is_builtin_component(n)) {
+        let info = ComponentInfo {
+            architectures: vec![std::env::consts::ARCH.to_string()],
+            available: true,
+            installed: state.installed.contains_key(*name),
+        };
+        components.insert(name.to_string(), info);
+    }
+    Ok(ComponentList {
+        architecture: std::env::consts::ARCH.to_string(),
+        components,
+        invalid_descriptors: filecopy::invalid_descriptors(sysroot_path)?,
+    })
+}
+
+/// Files which differ between two versions of a component
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FileChanges {
+    pub(crate) added: BTreeSet<String>,
+    pub(crate) removed: BTreeSet<String>,
+    pub(crate) changed: BTreeSet<String>,
+}
+
+/// Return value from daemon → client for comparing a component to its update
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentDiff {
+    /// The installed version
+    pub(crate) installed: ContentMetadata,
+    /// The available update, if any
+    pub(crate) available: Option<ContentMetadata>,
+    /// The changed files, if both versions have a file inventory
+    pub(crate) files: Option<FileChanges>,
+}
+
+/// daemon implementation of comparing the installed files of component
+/// `name` to those of the available update
+pub(crate) fn diff(sysroot_path: &str, name: &str) -> Result<ComponentDiff, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let component = component::new_from_name(sysroot_path, name)?;
+    let inst = state
+        .installed
+        .get(name)
+        .ok_or_else(|| BootupdError::ComponentNotInstalled(name.into()))?;
+    let available = component.query_update()?;
+    let files = match (inst.filetree.as_ref(), available.as_ref()) {
+        (Some(currentf), Some(_)) => match component.query_update_filetree()? {
+            Some(updatef) => {
+                let d = currentf.diff(&updatef)?;
+                Some(FileChanges {
+                    added: d.additions.into_iter().collect(),
+                    removed: d.removals.into_iter().collect(),
+                    changed: d.changes.into_iter().collect(),
+                })
+            }
+            None => None,
+        },
+        _ => None,
+    };
+    Ok(ComponentDiff {
+        installed: inst.meta.clone(),
+        available,
+        files,
+    })
+}
+
+/// Return value from daemon → client for adoption
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AdoptResult {
+    /// Components that were matched to a known version
+    pub(crate) adopted: BTreeMap<String, ContentMetadata>,
+    /// Components whose installed content couldn't be identified
+    pub(crate) unidentified: Vec<String>,
+}
+
+/// daemon implementation of adopting an installation made without bootupd
+pub(crate) fn adopt(sysroot_path: &str) -> Result<AdoptResult, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    if get_saved_state(sysroot_path)?.is_some() {
+        return Err(anyhow::anyhow!("State file already exists, refusing to adopt").into());
+    }
+    let mut state = SavedState::default();
+    let mut ret = AdoptResult::default();
+    for component in get_components(sysroot_path)? {
+        let name = component.name();
+        if let Some(inst) = component
+            .adopt()
+            .with_context(|| format!("Failed to adopt {}", name))?
+        {
+            log::info!("Adopting {} at {}", name, inst.meta.version);
+            ret.adopted.insert(name.into(), inst.meta.clone());
+            state.installed.insert(name.into(), inst);
+        } else {
+            log::warn!("Could not identify installed version of {}", name);
+            ret.unidentified.push(name.into());
+        }
+    }
+    if !state.installed.is_empty() {
+        update_state(&sysroot, &mut state)?;
+    }
+    Ok(ret)
+}
+
+/// The saved state found by `regenerate_state()` before replacing it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PreviousState {
+    Missing,
+    /// The state file couldn't be read, with the error
+    Unreadable(String),
+    Valid,
+}
+
+/// Return value from daemon → client for `regenerate_state()`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RegenerateResult {
+    pub(crate) previous: PreviousState,
+    /// Components whose installed content was matched to a known version,
+    /// and those which couldn't be identified
+    pub(crate) components: AdoptResult,
+    /// Unidentified components whose entries were kept from the previous
+    /// state
+    #[serde(default)]
+    pub(crate) carried_over: Vec<String>,
+}
+
+/// Copy the entries of the `unidentified` components from `previous`, if
+/// it could be read, into `state`.  Returns the components carried over,
+/// and those whose installed content is now unknown: with no previous
+/// state, the unidentified components may or may not be installed.
+fn carry_over(
+    state: &mut SavedState,
+    unidentified: &[String],
+    previous: Option<&SavedState>,
+) -> (Vec<String>, Vec<String>) {
+    let previous = match previous {
+        Some(p) => p,
+        None => return (Vec::new(), unidentified.to_vec()),
+    };
+    let mut carried = Vec::new();
+    for name in unidentified {
+        if let Some(inst) = previous.installed.get(name) {
+            state.installed.insert(name.clone(), inst.clone());
+            carried.push(name.clone());
+        }
+    }
+    (carried, Vec::new())
+}
+
+/// daemon implementation of rebuilding the saved state, e.g. after it was
+/// lost or corrupted.  Like `adopt()`, this matches the installed content
+/// of each component to a known version, but it replaces any existing
+/// state, losing in particular the counters and retained payloads.
+/// Components which can't be identified keep their entries from the
+/// previous state, if it could be read; otherwise nothing is written
+/// unless `force` is set, as they'd no longer be managed.  The state is
+/// left alone if no component could be identified.  As this is
+/// destructive, it's refused unless `confirm` is set.
+pub(crate) fn regenerate_state(
+    sysroot_path: &str,
+    confirm: bool,
+    force: bool,
+) -> Result<RegenerateResult, BootupdError> {
+    if !confirm {
+        return Err(anyhow::anyhow!(
+            "regenerate-state replaces any saved state; pass --confirm to continue"
+        )
+        .into());
+    }
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let (previous, previous_state) = match get_saved_state(sysroot_path) {
+        Ok(Some(s)) => (PreviousState::Valid, Some(s)),
+        Ok(None) => (PreviousState::Missing, None),
+        Err(e) => (PreviousState::Unreadable(format!("{:#}", e)), None),
+    };
+    let mut state = SavedState::default();
+    let mut ret = AdoptResult::default();
+    for component in get_components(sysroot_path)? {
+        let name = component.name();
+        // Identify what we can; a component we can't is no reason to
+        // keep the others from being managed
+        match component.adopt() {
+            Ok(Some(inst)) => {
+                log::info!("Regenerating state of {} at {}", name, inst.meta.version);
+                ret.adopted.insert(name.into(), inst.meta.clone());
+                state.installed.insert(name.into(), inst);
+            }
+            Ok(None) => {
+                log::warn!("Could not identify installed version of {}", name);
+                ret.unidentified.push(name.into());
+            }
+            Err(e) => {
+                log::warn!("Could not identify installed version of {}: {:#}", name, e);
+                ret.unidentified.push(name.into());
+            }
+        }
+    }
+    let (carried_over, lost) = carry_over(&mut state, &ret.unidentified, previous_state.as_ref());
+    if !ret.adopted.is_empty() {
+        if !lost.is_empty() && !force {
+            return Err(anyhow::anyhow!(
+                "Could not identify installed version of {}; pass --force to regenerate the state without them",
+                lost.join(", ")
+            )
+            .into());
+        }
+        update_state(&sysroot, &mut state)?;
+    }
+    Ok(RegenerateResult {
+        previous,
+        components: ret,
+        carried_over,
+    })
+}
+
+/// daemon implementation of exporting the saved state, including the
+/// inventory of each component's files, as JSON
+pub(crate) fn export_state(sysroot_path: &str) -> Result<String, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    Ok(serde_json::to_string_pretty(&state).context("serializing state")?)
+}
+
+/// daemon implementation of importing a state returned by `export_state()`,
+/// e.g. on this system before a risky operation or from a known-good one.
+/// Each imported component is validated against its inventory, and the
+/// result returned so that drift can be reported, but it doesn't prevent
+/// the import.
+pub(crate) fn import_state(
+    sysroot_path: &str,
+    state: &str,
+) -> Result<ValidationSummary, BootupdError> {
+    let sysroot = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    let v = serde_json::from_str(state).context("parsing imported state")?;
+    let mut state = SavedState::from_json(v).context("parsing imported state")?;
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let mut ret = ValidationSummary::default();
+    for (name, inst) in state.installed.iter() {
+        let component = component::new_from_name(sysroot_path, name)?;
+        let r = component.validate(inst).unwrap_or_else(|e| {
+            ValidationResult::Errors(vec![ValidationError::other(name, format!("{:#}", e))])
+        });
+        if let ValidationResult::Errors(errs) = &r {
+            log::warn!(
+                "Imported state of {} doesn't match the installed files: {}",
+                name,
+                describe_errors(errs, "; ")
+            );
+        }
+        ret.components.insert(name.to_string(), r);
+    }
+    update_state(&sysroot, &mut state)?;
+    log::info!("Imported state of {} components", state.installed.len());
+    Ok(ret)
+}
+
+/// daemon implementation of component validate
+pub(crate) fn validate(sysroot_path: &str, name: &str) -> Result<ValidationResult, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let component = component::new_from_name(sysroot_path, name)?;
+    let inst = if let Some(inst) = state.installed.get(name) {
+        inst.clone()
+    } else {
+        return Err(BootupdError::ComponentNotInstalled(name.into()));
+    };
+    let r = component.validate(&inst)?;
+    log_validation(name, &r);
+    Ok(r)
+}
+
+/// daemon implementation of checking the health of all installed
+/// components.  Unlike `status()`, this doesn't look for updates.
+pub(crate) fn check(sysroot_path: &str) -> Result<ValidationSummary, BootupdError> {
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let mut ret = ValidationSummary {
+        invalid_descriptors: filecopy::invalid_descriptors(sysroot_path)?,
+        ..Default::default()
+    };
+    for (name, e) in ret.invalid_descriptors.iter() {
+        log::error!("Invalid descriptor for component {}: {}", name, e);
+    }
+    for (name, inst) in state.installed.iter() {
+        // A component which can't be validated isn't healthy either
+        let r = component::new_from_name(sysroot_path, name)
+            .and_then(|c| c.validate(inst))
+            .unwrap_or_else(|e| {
+                ValidationResult::Errors(vec![ValidationError::other(name, format!("{:#}", e))])
+            });
+        log_validation(name, &r);
+        ret.components.insert(name.to_string(), r);
+    }
+    Ok(ret)
+}
+
+/// daemon implementation of component repair; validation passing
+/// yields an empty `RepairResult`.
+pub(crate) fn repair(
+    sysroot_path: &str,
+    name: &str,
+    progress: &dyn Fn(&str),
+) -> Result<RepairResult, BootupdError> {
+    let _lock = acquire_write_lock(sysroot_path)?;
+    let state = get_saved_state(sysroot_path)?.ok_or(BootupdError::NoState)?;
+    let component = component::new_from_name(sysroot_path, name)?;
+    let inst = if let Some(inst) = state.installed.get(name) {
+        inst.clone()
+    } else {
+        return Err(BootupdError::ComponentNotInstalled(name.into()));
+    };
+    if let ValidationResult::Valid = component.validate(&inst)? {
+        return Ok(RepairResult::default());
+    }
+    log::info!("Repairing {}", name);
+    Ok(component
+        .repair(&inst, progress)
+        .with_context(|| format!("Failed to repair {}", component.name()))?)
+}
+
+/// Lock the directory holding the state file, blocking until the lock
+/// is available.  The lock is released when the returned file is
+/// closed.  Readers
+/// take it shared and writers exclusive, so that a reader never sees the
+/// state in the middle of being replaced; it's taken on the directory
+/// since replacing the file gives it a new inode.
+///
+/// Lock ordering: operations which modify the state take the write lock
+/// (see `acquire_write_lock`) first and hold it throughout.  This lock is
+/// only held around a single read or write of the state file, and no
+/// other lock is taken while holding it.
+fn lock_state_dir(dir: &openat::Dir, exclusive: bool) -> Result<std::fs::File> {
+    use nix::fcntl::OFlag;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    // openat opens directories with O_PATH, which can't be locked
+    let fd = nix::fcntl::openat(
+        dir.as_raw_fd(),
+        ".",
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )
+    .context("Opening state directory")?;
+    // Safety: we just opened the fd and nothing else owns it
+    let f = unsafe { std::fs::File::from_raw_fd(fd) };
+    if exclusive {
+        FileExt::lock_exclusive(&f)
+    } else {
+        FileExt::lock_shared(&f)
+    }
+    .context("Locking state directory")?;
+    Ok(f)
+}
+
+/// Atomically replace the on-disk state with a new version
+fn update_state(sysroot_dir: &openat::Dir, state: &mut SavedState) -> Result<()> {
+    state.written_by = Some(env!("CARGO_PKG_VERSION").to_string());
+    write_state_file(sysroot_dir, state, Compression::for_state()?)
+}
+
+/// Implementation of `update_state`; the state is fully serialized to an
+/// unnamed file before anything is linked into place, so a failure
+/// leaves the existing state file untouched.  The unnamed file vanishes
+/// when closed, and the temporary name it's linked to is removed if it
+/// can't be renamed into place.
+fn write_state_file<S: Serialize>(
+    sysroot_dir: &openat::Dir,
+    state: &S,
+    compression: Compression,
+) -> Result<()> {
+    let subdir = sysroot_dir.sub_dir(STATEFILE_DIR)?;
+    let buf =
+        compress::compress(serde_json::to_vec(state)?, compression).context("compressing state")?;
+    let _lock = lock_state_dir(&subdir, true)?;
+    let f = {
+        let mut f = subdir.new_unnamed_file(0o644)?;
+        f.write_all(&buf)?;
+        f
+    };
+    let dest_tmp_name = {
+        // expect OK because we just created the filename above from a constant
+        let mut buf = std::ffi::OsString::from(STATEFILE_NAME);
+        buf.push(".tmp");
+        buf
+    };
+    let dest_tmp_name = Path::new(&dest_tmp_name);
+    if subdir.exists(dest_tmp_name)? {
+        subdir.remove_file(dest_tmp_name)?;
+    }
+    subdir.link_file_at(&f, dest_tmp_name)?;
+    let r = f
+        .sync_all()
+        .and_then(|()| subdir.local_rename(dest_tmp_name, STATEFILE_NAME));
+    if let Err(e) = r {
+        if let Err(e) = subdir.remove_file(dest_tmp_name) {
+            log::warn!("Failed to remove {:?}: {}", dest_tmp_name, e);
+        }
+        return Err(anyhow::Error::new(e).context("writing state file"));
+    }
+    Ok(())
+}
+
+/// Check that the directory holding the state file is available, so that
+/// an unmounted /boot is diagnosed rather than treated as missing state.
+/// /boot may be part of the root filesystem, so it is only required to
+/// be a mountpoint if the sysroot's fstab says so.
+fn check_state_dir(sysroot_dir: &openat::Dir, sysroot_path: &str) -> Result<()> {
+    let path = Path::new(sysroot_path).join(STATEFILE_DIR);
+    let meta = match sysroot_dir.metadata_optional(STATEFILE_DIR)? {
+        Some(meta) if meta.is_dir() => meta,
+        _ => bail!(
+            "{} is not mounted; cannot access bootupd state",
+            path.display()
+        ),
+    };
+    let fstab = match sysroot_dir.open_file_optional("etc/fstab")? {
+        Some(mut f) => {
+            let mut s = String::new();
+            f.read_to_string(&mut s).context("reading fstab")?;
+            s
+        }
+        None => return Ok(()),
+    };
+    let target = Path::new("/").join(STATEFILE_DIR);
+    let target = target.to_str().expect("utf-8 path");
+    if util::fstab_has_mount(&fstab, target)
+        && meta.stat().st_dev == sysroot_dir.self_metadata()?.stat().st_dev
+    {
+        bail!(
+            "{} is not mounted; cannot access bootupd state",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Load the JSON file containing on-disk state
+fn get_saved_state(sysroot_path: &str) -> Result<Option<SavedState>> {
+    let sysroot_dir = openat::Dir::open(sysroot_path)
+        .with_context(|| format!("opening sysroot {}", sysroot_path))?;
+    check_state_dir(&sysroot_dir, sysroot_path)?;
+
+    let statefile_path = Path::new(STATEFILE_DIR).join(STATEFILE_NAME);
+    let subdir = sysroot_dir.sub_dir(STATEFILE_DIR)?;
+    let buf = {
+        let _lock = lock_state_dir(&subdir, false)?;
+        if let Some(mut statusf) = subdir.open_file_optional(STATEFILE_NAME)? {
+            let mut buf = Vec::new();
+            statusf.read_to_end(&mut buf)?;
+            Some(buf)
+        } else {
+            None
+        }
+    };
+    let saved_state = if let Some(buf) = buf {
+        let buf = compress::decompress(buf)
+            .with_context(|| format!("decompressing {}", statefile_path.display()))?;
+        let saved_state: serde_json::Value = serde_json::from_slice(&buf)?;
+        Some(
+            SavedState::from_json(saved_state)
+                .with_context(|| format!("parsing {}", statefile_path.display()))?,
+        )
+    } else {
+        None
+    };
+    Ok(saved_state)
+}
+
+/// daemon implementation of status; only the components named in `filter`
+/// are reported, or all if it's empty.  Fails if a named component isn't
+/// installed.  Unless `check_updates` is set, available updates, devices
+/// and free space aren't queried, and components are `NotChecked`.  The
+/// firmware's state, e.g. Secure Boot, is only reported for the booted
+/// root; it says nothing about another one.
+pub(crate) fn status(
+    sysroot_path: &str,
+    filter: &[String],
+    check_updates: bool,
+) -> Result<Status, BootupdError> {
+    let mut ret: Status = Default::default();
+    // Only the running system can have been booted from
+    let booted = Path::new(sysroot_path) == Path::new("/");
+    let method = if booted {
+        ret.secure_boot = secure_boot(Path::new(SYSFS_EFI_PATH));
+        let method = boot_method(Path::new(SYSFS_EFI_PATH));
+        ret.boot_method = Some(method.to_string());
+        Some(method)
+    } else {
+        None
+    };
+    ret.invalid_descriptors = filecopy::invalid_descriptors(sysroot_path)?;
+    let state = get_saved_state(sysroot_path)?;
+    if let Some(name) = filter.iter().find(|n| {
+        !state
+            .as_ref()
+            .map_or(false, |s| s.installed.contains_key(*n))
+    }) {
+        return Err(BootupdError::ComponentNotInstalled(name.clone()));
+    }
+    let state = if let Some(state) = state {
+        state
+    } else {
+        return Ok(ret);
+    };
+    ret.state_written_by = state.written_by.clone();
+    ret.boot_method_unmanaged = method.map_or(false, |m| boot_method_unmanaged(m, &state));
+    let loader = method.and_then(|_| loader_partuuid(Path::new(SYSFS_EFI_PATH)));
+    let partuuid = |d: &str| {
+        util::partition_uuid(d)
+            .map_err(|e| log::warn!("Failed to find PARTUUID of {}: {:#}", d, e))
+            .ok()
+    };
+    for (name, ic) in state.installed.iter() {
+        if !filter.is_empty() && !filter.contains(name) {
+            continue;
+        }
+        let component = crate::component::new_from_name(sysroot_path, name)?;
+        let component = component.as_ref();
+        let pending = state
+            .pending
+            .as_ref()
+            .map(|p| p.get(name.as_str()))
+            .flatten();
+        let interrupted = pending.filter(|p| !p.staged);
+        let staged = pending.filter(|p| p.staged).map(|p| &p.meta);
+        let (mut update, mut updatable) = (None, ComponentUpdatable::NotChecked);
+        let (mut devices, mut space, mut bootable) = (Vec::new(), None, None);
+        if check_updates {
+            update = component.query_update()?;
+            updatable = ComponentUpdatable::from_metadata(component, &ic.meta, update.as_ref());
+            devices = component.query_devices(ic).unwrap_or_else(|e| {
+                log::warn!("Failed to find devices for {}: {:#}", name, e);
+                Vec::new()
+            });
+            space = component.query_space(ic).unwrap_or_else(|e| {
+                log::warn!("Failed to find free space for {}: {:#}", name, e);
+                None
+            });
+            if interrupted.is_some() {
+                bootable = interrupted_bootable(component, ic);
+            }
+        }
+        ret.components.insert(
+            name.to_string(),
+            ComponentStatus {
+                installed: ic.meta.clone(),
+                applied: ic.applied,
+                interrupted: interrupted.map(|p| p.meta.clone()),
+                interrupted_progress: interrupted.and_then(|p| p.progress()),
+                interrupted_bootable: bootable,
+                staged: staged.cloned(),
+                update,
+                updatable,
+                active: method.map_or(false, |m| {
+                    is_active(name, m, loader.as_deref(), &devices, &partuuid)
+                }),
+                devices,
+                space,
+                remote: None,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// Like `status()`, but also report what the configured remote update
+/// source offers; see `remote::check_remote()`
+pub(crate) fn status_remote(sysroot_path: &str, filter: &[String]) -> Result<Status, BootupdError> {
+    let mut ret = status(sysroot_path, filter, true)?;
+    crate::remote::check_remote(sysroot_path, &mut ret)?;
+    Ok(ret)
+}
+
+/// Whether the files of `installed` are still in place after an update
+/// was interrupted, so that the system can boot them.  Only the content
+/// is checked, not e.g. firmware boot entries, which an update leaves
+/// alone.
+fn interrupted_bootable(component: &dyn Component, installed: &InstalledContent) -> Option<bool> {
+    match component.verify_install(installed) {
+        Ok(ValidationResult::Valid) => Some(true),
+        Ok(ValidationResult::Errors(_)) => Some(false),
+        Err(e) => {
+            log::warn!("Failed to validate {}: {:#}", component.name(), e);
+            None
+        }
+    }
+}
+
+/// Status of each of `sysroots`, e.g. mounted deployments or snapshots
+/// being audited.  A root which can't be inspected gets its error in
+/// the result rather than failing the whole batch.
+pub(crate) fn status_many(sysroots: &[String]) -> BTreeMap<String, Result<Status, BootupdError>> {
+    sysroots
+        .iter()
+        .map(|sysroot| (sysroot.clone(), status(sysroot, &[], true)))
+        .collect()
+}
+
+/// Print `status`, which was limited to the components named in `filter`
+/// if it isn't empty.
+pub(crate) fn print_status(status: &Status, filter: &[String]) {
+    if status.components.is_empty() {
+        if filter.is_empty() {
+            println!("No components installed.");
+        } else {
+            println!("No installed component matches {}.", filter.join(", "));
+        }
+    }
+    for (name, component) in status.components.iter() {
+        if component.active {
+            println!("Component {} (active)", name);
+        } else {
+            println!("Component {}", name);
+        }
+        if let Some(applied) = component.applied.as_ref() {
+            println!(
+                "  Installed: {} (applied {})",
+                component.installed.version,
+                applied.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+        } else {
+            println!("  Installed: {}", component.installed.version);
+        }
+        if let Some(source) = component.installed.source.as_ref() {
+            println!("  Installed from: {}", source);
+        }
+        if !component.devices.is_empty() {
+            println!("  Device: {}", component.devices.join(", "));
+        }
+        if let Some(space) = component.space.as_ref() {
+            println!(
+                "  Space: {}% full ({} of {} bytes available)",
+                space.percent_used(),
+                space.available,
+                space.total
+            );
+        }
+
+        if let Some(i) = component.interrupted.as_ref() {
+            match component.interrupted_progress.as_ref() {
+                Some(p) => println!(
+                    "  WARNING: Previous update to {} was interrupted after {} of {} steps",
+                    i.version, p.completed, p.total
+                ),
+                None => println!(
+                    "  WARNING: Previous update to {} was interrupted",
+                    i.version
+                ),
+            }
+            match component.interrupted_bootable {
+                Some(true) => println!("  Installed files are intact; the system can still boot"),
+                Some(false) => {
+                    println!("  Installed files were partially replaced; the system may not boot")
+                }
+                None => {}
+            }
+            println!("  Recommended action: run `bootupctl update` to complete the update");
+        }
+        if let Some(s) = component.staged.as_ref() {
+            println!("  Staged: {} (applied at next boot)", s.version);
+        }
+        let msg = match component.updatable {
+            ComponentUpdatable::NoUpdateAvailable => Cow::Borrowed("No update found"),
+            ComponentUpdatable::AtLatestVersion => Cow::Borrowed("At latest version"),
+            ComponentUpdatable::WouldDowngrade => Cow::Borrowed("Ignoring downgrade"),
+            ComponentUpdatable::NotChecked => Cow::Borrowed("Not checked"),
+            ComponentUpdatable::Upgradable => Cow::Owned(format!(
+                "Available: {}",
+                component.update.as_ref().expect("update").version
+            )),
+        };
+        println!("  Update: {}", msg);
+        match component.remote.as_ref() {
+            Some(RemoteUpdate::Found { update, updatable }) => {
+                let msg = match updatable {
+                    ComponentUpdatable::Upgradable => "newer than installed",
+                    ComponentUpdatable::WouldDowngrade => "older than installed",
+                    _ => "installed",
+                };
+                println!("  Remote: {} ({})", update.version, msg);
+            }
+            Some(RemoteUpdate::Unknown) => println!("  Remote: unknown"),
+            None => {}
+        }
+    }
+
+    for (name, e) in status.invalid_descriptors.iter() {
+        println!("WARNING: Ignoring component {}: {}", name, e);
+    }
+
+    // Older daemons don't report the boot method
+    let method = status
+        .boot_method
+        .clone()
+        .unwrap_or_else(|| boot_method(Path::new(SYSFS_EFI_PATH)).to_string());
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    println!("Boot method: {}", method);
+    if status.boot_method_unmanaged {
+        println!(
+            "WARNING: This system boots via {}, which is not managed by bootupd; updates will not affect how it boots",
+            method
+        );
+    }
+    let secure_boot = match status.secure_boot {
+        Some(true) => "enabled",
+        Some(false) => "disabled",
+        None => "unknown",
+    };
+    println!("Secure Boot: {}", secure_boot);
+    if let Some(v) = status.state_written_by.as_ref() {
+        println!("State written by: bootupd {}", v);
+    }
+}
+
+/// Exposed by the kernel when booted via EFI
+pub(crate) const SYSFS_EFI_PATH: &str = "/sys/firmware/efi";
+
+/// How the running system was booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BootMethod {
+    EFI,
+    BIOS,
+}
+
+impl std::fmt::Display for BootMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BootMethod::EFI => write!(f, "EFI"),
+            BootMethod::BIOS => write!(f, "BIOS"),
+        }
+    }
+}
+
+/// Whether components are installed in `state`, but not that for
+/// `method`.  Components are named after the boot method they provide.
+fn boot_method_unmanaged(method: BootMethod, state: &SavedState) -> bool {
+    !state.installed.is_empty() && !state.installed.contains_key(&method.to_string())
+}
+
+/// Determine how the system was booted, given the path of the kernel's
+/// EFI sysfs directory.  aarch64 only boots via EFI.  On x86_64, the
+/// directory is only meaningful if the kernel populated it from the
+/// firmware; an empty one (e.g. a stub from a container runtime) doesn't
+/// indicate an EFI boot.
+pub(crate) fn boot_method(sysfs_efi: &Path) -> BootMethod {
+    if cfg!(target_arch = "aarch64") {
+        return BootMethod::EFI;
+    }
+    let populated = ["systab", "fw_platform_size", "efivars", "runtime"]
+        .iter()
+        .any(|f| sysfs_efi.join(f).exists());
+    if populated {
+        BootMethod::EFI
+    } else {
+        BootMethod::BIOS
+    }
+}
+
+/// The EFI variable holding the Secure Boot state, under `efivars`
+const SECURE_BOOT_VAR: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// The EFI variable in which the boot loader (e.g. systemd-boot, or GRUB
+/// with the bli module) records the PARTUUID of the ESP it was loaded from
+const LOADER_PARTUUID_VAR: &str = "LoaderDevicePartUUID-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// The PARTUUID of the ESP the boot loader was loaded from, given the
+/// path of the kernel's EFI sysfs directory, if recorded.
+fn loader_partuuid(sysfs_efi: &Path) -> Option<String> {
+    let buf = std::fs::read(sysfs_efi.join("efivars").join(LOADER_PARTUUID_VAR)).ok()?;
+    // 4 bytes of attributes, followed by a NUL-terminated UTF-16LE string
+    let chars: Vec<u16> = buf
+        .get(4..)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    Some(String::from_utf16(&chars).ok()?.to_lowercase())
+}
+
+/// Whether the running system was booted from component `name`, written
+/// to `devices`.  Components are named after the boot method they
+/// provide; if the boot loader recorded the ESP it was loaded from as
+/// `loader`, that must also be one of `devices`, whose PARTUUIDs are
+/// found with `partuuid`.
+fn is_active(
+    name: &str,
+    method: BootMethod,
+    loader: Option<&str>,
+    devices: &[String],
+    partuuid: &dyn Fn(&str) -> Option<String>,
+) -> bool {
+    if name != method.to_string() {
+        return false;
+    }
+    match loader {
+        Some(loader) if method == BootMethod::EFI && !devices.is_empty() => devices
+            .iter()
+            .any(|d| partuuid(d).map_or(false, |u| u.eq_ignore_ascii_case(loader))),
+        _ => true,
+    }
+}
+
+/// Determine whether Secure Boot is enabled, given the path of the
+/// kernel's EFI sysfs directory.  Returns `None` if the system wasn't
+/// booted via EFI or the state can't be read.
+pub(crate) fn secure_boot(sysfs_efi: &Path) -> Option<bool> {
+    if boot_method(sysfs_efi) != BootMethod::EFI {
+        return None;
+    }
+    let path = sysfs_efi.join("efivars").join(SECURE_BOOT_VAR);
+    match std::fs::read(&path) {
+        // 4 bytes of attributes, followed by the value
+        Ok(buf) if buf.len() > 4 => Some(buf[4] == 1),
+        Ok(_) => {
+            log::warn!("Unexpected contents of {:?}", path);
+            None
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            log::warn!("Failed to read {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Compute the exit code for `bootupctl status --exit-code`
+pub(crate) fn status_exit_code(status: &Status) -> i32 {
+    let upgradable = status
+        .components
+        .values()
+        .any(|c| matches!(c.updatable, ComponentUpdatable::Upgradable));
+    if upgradable {
+        STATUS_EXIT_UPDATE_AVAILABLE
+    } else {
+        libc::EXIT_SUCCESS
+    }
+}
+
+/// Marker files, relative to the root, with which a deployment opts into
+/// treating bootupd as stable and disables the preview gate; the first
+/// is for administrators, the second for the distribution.
+const STABLE_MARKERS: &[&str] = &["etc/bootupd/stable", "usr/lib/bootupd/stable"];
+
+/// Whether the deployment at `root` has opted out of the preview gate,
+/// either at build time with the `stable` feature or with a marker file.
+fn stable_deployment(root: &Path) -> bool {
+    cfg!(feature = "stable") || STABLE_MARKERS.iter().any(|p| root.join(p).exists())
+}
+
+/// Checks that the user has provided an environment variable to signal
+/// acceptance of our alpha state - use this when performing write operations.
+/// This is a no-op for stable deployments; see `stable_deployment()`.
+fn validate_preview_env() -> Result<(), BootupdError> {
+    if stable_deployment(Path::new("/")) {
+        return Ok(());
+    }
+    let v = "BOOTUPD_ACCEPT_PREVIEW";
+    if std::env::var_os(v).is_none() {
+        Err(BootupdError::PreviewNotAccepted)
+    } else {
+        Ok(())
+    }
+}
+
+/// The outcome for one component of `client_run_update`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UpdateOutcome {
+    Updated,
+    WouldUpdate,
+    AtLatestVersion,
+    RefusedDowngrade,
+    Failed,
+    /// Not selected for update, or no update payload is available
+    Skipped,
+    /// Updated too recently; see `ComponentUpdateResult::RateLimited`
+    RateLimited,
+    /// See `ComponentUpdateResult::PreconditionFailed`
+    PreconditionFailed,
+}
+
+/// Summary of what `client_run_update` did to a component
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentUpdateSummary {
+    pub(crate) outcome: UpdateOutcome,
+    /// Version installed before the update
+    pub(crate) previous: String,
+    /// Version installed by (or for `would-update`, available for) the
+    /// update, if any
+    pub(crate) new: Option<String>,
+    /// Where the `new` payload came from, e.g. the OS version it was
+    /// generated from, if recorded; see `ContentMetadata::source`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<String>,
+    /// Files written or removed by the update
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) changed_files: Vec<String>,
+}
+
+/// Returned by `client_run_update`, and printed by `bootupctl update --json`
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UpdateSummary {
+    /// Maps each installed component name to its outcome
+    pub(crate) components: BTreeMap<String, ComponentUpdateSummary>,
+}
+
+impl UpdateSummary {
+    /// Returns `true` if any component was (or would be) updated
+    pub(crate) fn any_updated(&self) -> bool {
+        self.components
+            .values()
+            .any(|c| c.outcome == UpdateOutcome::Updated || c.outcome == UpdateOutcome::WouldUpdate)
+    }
+}
+
+/// Describes where the payload `meta` came from, to follow its version
+/// in messages; empty if that wasn't recorded.
+fn describe_source(meta: &ContentMetadata) -> String {
+    meta.source
+        .as_ref()
+        .map(|s| format!(" from {}", s))
+        .unwrap_or_default()
+}
+
+/// Print the outcome of updating `name` and summarize it.
+fn handle_update_result(
+    name: &str,
+    cstatus: &ComponentStatus,
+    r: ComponentUpdateResult,
+    quiet: bool,
+    verbose: bool,
+) -> ComponentUpdateSummary {
+    let previous = cstatus.installed.version.clone();
+    match r {
+        ComponentUpdateResult::AtLatestVersion => {
+            // Shouldn't happen unless we raced with another client
+            log::warn!(
+                "Expected update for {}, raced with a different client?",
+                name
+            );
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::AtLatestVersion,
+                previous,
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            }
+        }
+        ComponentUpdateResult::Updated {
+            previous,
+            interrupted,
+            resumed,
+            new,
+            changed_files,
+        } => {
+            if let Some(i) = interrupted {
+                if resumed {
+                    log::warn!("Resumed previous interrupted update: {}", i.version);
+                } else {
+                    log::warn!("Continued from previous interrupted update: {}", i.version,);
+                }
+            }
+            if !quiet {
+                let verb = if previous == new {
+                    "Reinstalled"
+                } else {
+                    "Updated"
+                };
+                let n = changed_files.len();
+                println!(
+                    "{} {}: {}{} ({} file{} changed)",
+                    verb,
+                    name,
+                    new.version,
+                    describe_source(&new),
+                    n,
+                    if n == 1 { "" } else { "s" }
+                );
+                if verbose {
+                    for f in changed_files.iter() {
+                        println!("  {}", f);
+                    }
+                }
+            }
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::Updated,
+                previous: previous.version,
+                new: Some(new.version),
+                source: new.source,
+                changed_files,
+            }
+        }
+        ComponentUpdateResult::WouldUpdate { previous, new } => {
+            if !quiet {
+                println!(
+                    "Would update {}: {} -> {}{}",
+                    name,
+                    previous.version,
+                    new.version,
+                    describe_source(&new)
+                );
+            }
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::WouldUpdate,
+                previous: previous.version,
+                new: Some(new.version),
+                source: new.source,
+                changed_files: Vec::new(),
+            }
+        }
+        ComponentUpdateResult::RefusedDowngrade {
+            installed,
+            available,
+        } => {
+            log::warn!(
+                "Refusing downgrade of {} from {} to {}; use --allow-downgrade to override",
+                name,
+                installed.version,
+                available.version
+            );
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::RefusedDowngrade,
+                previous: installed.version,
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            }
+        }
+        ComponentUpdateResult::RateLimited {
+            installed,
+            applied,
+            next,
+        } => {
+            log::warn!(
+                "Not updating {}, last updated at {}, until {}; use --ignore-min-interval to override",
+                name,
+                applied.to_rfc3339_opts(SecondsFormat::Secs, true),
+                next.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::RateLimited,
+                previous: installed.version,
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            }
+        }
+        ComponentUpdateResult::PreconditionFailed {
+            installed,
+            available,
+            reason,
+        } => {
+            log::warn!(
+                "Not updating {} to {}: {}; use --ignore-preconditions to override",
+                name,
+                available.version,
+                reason
+            );
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::PreconditionFailed,
+                previous: installed.version,
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            }
+        }
+    }
+}
+
+/// Client side options for `client_run_update()`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ClientUpdateOptions {
+    /// Send all components in a single request, to be updated
+    /// concurrently by the daemon where possible
+    pub(crate) parallel: bool,
+    /// Print the returned summary as JSON instead of the usual human
+    /// readable output
+    pub(crate) json: bool,
+    /// Only print warnings and errors
+    pub(crate) quiet: bool,
+    /// List the files changed by each update
+    pub(crate) verbose: bool,
+    /// Don't ask for confirmation before updating
+    pub(crate) assume_yes: bool,
+    /// Update all components or none; see `update_all()`
+    pub(crate) transactional: bool,
+    /// Limit on the components updated at once with `parallel`; see
+    /// `update_parallel()`
+    pub(crate) max_concurrency: Option<usize>,
+}
+
+/// Whether `answer` to a yes/no prompt means yes; anything unrecognized
+/// is taken as no.
+fn is_affirmative(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// List the updates about to be applied to `names` and ask the user to
+/// confirm them.  The prompt goes to stderr so that `--json` output on
+/// stdout stays parseable.
+fn confirm_update(status: &Status, names: &[String]) -> Result<bool> {
+    eprintln!("The following components will be updated:");
+    for name in names {
+        let cstatus = &status.components[name];
+        let new = cstatus
+            .update
+            .as_ref()
+            .map(|u| u.version.as_str())
+            .unwrap_or("?");
+        eprintln!("  {}: {} -> {}", name, cstatus.installed.version, new);
+    }
+    eprint!("Proceed? [y/N] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(is_affirmative(&answer))
+}
+
+/// Update all components, or only `component` if provided; see
+/// `UpdateOptions` and `ClientUpdateOptions`.  When stdin is a terminal,
+/// the user is asked to confirm the updates unless `assume_yes` is set;
+/// otherwise they're applied without asking so as not to break automation.
+/// `sysroot` is the root being updated, as set on `c`.
+pub(crate) fn client_run_update(
+    c: &mut ipc::ClientToDaemonConnection,
+    sysroot: &str,
+    component: Option<&str>,
+    opts: UpdateOptions,
+    copts: ClientUpdateOptions,
+) -> Result<UpdateSummary> {
+    let ClientUpdateOptions {
+        parallel,
+        json,
+        quiet,
+        verbose,
+        assume_yes,
+        transactional,
+        max_concurrency,
+    } = copts;
+    if transactional && (component.is_some() || parallel) {
+        bail!("A transactional update applies to all components, one at a time");
+    }
+    if max_concurrency.is_some() && !parallel {
+        bail!("The maximum concurrency only applies to parallel updates");
+    }
+    worker_count(max_concurrency, 1)?;
+    if !opts.dry_run {
+        validate_preview_env()?;
+    }
+    let status: Status = c.send(&ClientRequest::Status {
+        components: Vec::new(),
+    })?;
+    if let Some(name) = component {
+        if !status.components.contains_key(name) {
+            return Err(BootupdError::ComponentNotInstalled(name.into()).into());
+        }
+    }
+    let mut summary = UpdateSummary::default();
+    let mut names = Vec::new();
+    for (name, cstatus) in status.components.iter() {
+        let selected = component.map(|c| c == name).unwrap_or(true);
+        let wanted = match cstatus.updatable {
+            ComponentUpdatable::Upgradable => true,
+            // Let the daemon decide whether to refuse the downgrade
+            ComponentUpdatable::WouldDowngrade => true,
+            _ => opts.force && cstatus.update.is_some(),
+        };
+        if selected && wanted {
+            names.push(name.clone());
+            continue;
+        }
+        let outcome = match cstatus.updatable {
+            ComponentUpdatable::AtLatestVersion if selected => UpdateOutcome::AtLatestVersion,
+            _ => UpdateOutcome::Skipped,
+        };
+        summary.components.insert(
+            name.clone(),
+            ComponentUpdateSummary {
+                outcome,
+                previous: cstatus.installed.version.clone(),
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            },
+        );
+    }
+    update_order(sysroot, &mut names)?;
+    let interactive = nix::unistd::isatty(libc::STDIN_FILENO).unwrap_or(false);
+    if !opts.dry_run
+        && !assume_yes
+        && interactive
+        && !names.is_empty()
+        && !confirm_update(&status, &names)?
+    {
+        bail!("Update cancelled");
+    }
+    let quiet = quiet || json;
+    let progress = |msg: &str| {
+        if !quiet {
+            println!("  {}", msg)
+        }
+    };
+    let mut first_err = None;
+    if (parallel || transactional) && !names.is_empty() {
+        let req = if transactional {
+            ClientRequest::UpdateAll {
+                opts,
+                transactional,
+            }
+        } else {
+            ClientRequest::UpdateParallel {
+                components: names,
+                opts,
+                max_concurrency,
+            }
+        };
+        let results: ParallelUpdateResult = c.send_with_progress(&req, progress)?;
+        for (name, r) in results {
+            let cstatus = &status.components[&name];
+            let s = match r {
+                Ok(r) => handle_update_result(&name, cstatus, r, quiet, verbose),
+                Err(e) => {
+                    eprintln!("Failed to update {}: {}", name, e);
+                    first_err.get_or_insert(e);
+                    ComponentUpdateSummary {
+                        outcome: UpdateOutcome::Failed,
+                        previous: cstatus.installed.version.clone(),
+                        new: None,
+                        source: None,
+                        changed_files: Vec::new(),
+                    }
+                }
+            };
+            summary.components.insert(name, s);
+        }
+    } else {
+        for name in names {
+            let req = ClientRequest::Update {
+                component: name.to_string(),
+                opts,
+            };
+            let r = c.send_with_progress(&req, progress)?;
+            let s = handle_update_result(&name, &status.components[&name], r, quiet, verbose);
+            summary.components.insert(name, s);
+        }
+    }
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &summary)?;
+        stdout.write_all(b"\n")?;
+    } else if !quiet {
+        if status.components.is_empty() {
+            println!("No components installed.");
+        } else if !summary.any_updated() && first_err.is_none() {
+            if let Some(name) = component {
+                println!("No update available for {}.", name);
+            } else {
+                println!("No update available for any component.");
+            }
+        }
+    }
+    if let Some(e) = first_err {
+        return Err(e.into());
+    }
+    Ok(summary)
+}
+
+pub(crate) fn client_run_rollback(
+    c: &mut ipc::ClientToDaemonConnection,
+    component: &str,
+) -> Result<()> {
+    validate_preview_env()?;
+    let r: ComponentRollbackResult = c.send(&ClientRequest::Rollback {
+        component: component.to_string(),
+    })?;
+    println!(
+        "Rolled back {}: {} -> {}",
+        component, r.from.version, r.to.version
+    );
+    Ok(())
+}
+
+pub(crate) fn client_run_uninstall(
+    c: &mut ipc::ClientToDaemonConnection,
+    component: &str,
+    force: bool,
+) -> Result<()> {
+    validate_preview_env()?;
+    let r: ContentMetadata = c.send(&ClientRequest::Uninstall {
+        component: component.to_string(),
+        force,
+    })?;
+    println!("Uninstalled {}: {}", component, r.version);
+    Ok(())
+}
+
+/// daemon implementation of `SetSysroot`; ensure `sysroot` can be
+/// operated on.
+pub(crate) fn check_sysroot(sysroot: &str) -> Result<(), BootupdError> {
+    let path = Path::new(sysroot);
+    if !path.is_absolute() {
+        return Err(anyhow::anyhow!("sysroot {} is not an absolute path", sysroot).into());
+    }
+    if !path.is_dir() {
+        return Err(anyhow::anyhow!("sysroot {} is not a directory", sysroot).into());
+    }
+    Ok(())
+}
+
+/// Present in the root when booted from Fedora CoreOS live media
+const LIVE_MARKER: &str = "run/ostree-live";
+/// Kernel arguments set when booting from live media
+const LIVE_KARGS: &[&str] = &["rd.live.image", "coreos.liveiso"];
+
+/// Why the system at `root`, booted with kernel command line `cmdline`,
+/// looks like it's running from live or installation media; `None` if
+/// it looks installed.
+fn live_root_reason(root: &Path, cmdline: &str) -> Option<String> {
+    if root.join(LIVE_MARKER).exists() {
+        return Some(format!("/{} exists", LIVE_MARKER));
+    }
+    for arg in cmdline.split_whitespace() {
+        let key = arg.split('=').next().unwrap_or(arg);
+        if LIVE_KARGS.contains(&key) || arg.starts_with("root=live:") {
+            return Some(format!("the kernel was booted with {}", arg));
+        }
+    }
+    None
+}
+
+/// Filesystems which `/` is only mounted from on live media, by their
+/// statfs(2) magic.  Live images usually put an overlay or a snapshot on
+/// top of a squashfs image.
+const LIVE_ROOT_FSTYPES: &[(i64, &str)] = &[
+    (0x0102_1994, "tmpfs"),
+    (0x9660, "iso9660"),
+    (0x794c_7630, "overlayfs"),
+    (0x7371_7368, "squashfs"),
+];
+
+/// The name of the filesystem type with statfs(2) magic `magic`, if `/`
+/// being mounted from it means the system runs from live media
+fn live_root_fstype(magic: i64) -> Option<&'static str> {
+    LIVE_ROOT_FSTYPES
+        .iter()
+        .find(|(m, _)| *m == magic)
+        .map(|(_, name)| *name)
+}
+
+/// Fail if the booted system is running from live or installation
+/// media, where `/` is ephemeral and the ESP found from it may belong
+/// to the media, or to a disk other than the one being installed.
+/// Changes then need an explicit `--sysroot`.
+pub(crate) fn check_not_live_root() -> Result<()> {
+    let root = Path::new("/");
+    let cmdline = std::fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let mut reason = live_root_reason(root, &cmdline);
+    if reason.is_none() {
+        if let Some(fstype) = live_root_fstype(util::fs_magic(root)?) {
+            reason = Some(format!("/ is {}, which is not persistent", fstype));
+        }
+    }
+    if let Some(reason) = reason {
+        bail!(
+            "Refusing to change the booted system, which appears to be running from live media ({}); \
+             pass --sysroot to choose the system to operate on",
+            reason
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn client_set_sysroot(
+    c: &mut ipc::ClientToDaemonConnection,
+    sysroot: &str,
+) -> Result<()> {
+    c.send(&ClientRequest::SetSysroot {
+        sysroot: sysroot.to_string(),
+    })
+}
+
+pub(crate) fn client_run_stage(
+    c: &mut ipc::ClientToDaemonConnection,
+    component: &str,
+) -> Result<()> {
+    validate_preview_env()?;
+    let r: Option<ContentMetadata> = c.send_with_progress(
+        &ClientRequest::Stage {
+            component: component.to_string(),
+        },
+        |msg| println!("  {}", msg),
+    )?;
+    match r {
+        Some(meta) => println!(
+            "Staged {}: {}; it will be applied at the next boot",
+            component, meta.version
+        ),
+        None => println!("{}: At latest version", component),
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_adopt(c: &mut ipc::ClientToDaemonConnection) -> Result<()> {
+    validate_preview_env()?;
+    let r: AdoptResult = c.send(&ClientRequest::Adopt)?;
+    for (name, meta) in r.adopted.iter() {
+        println!("Adopted {}: {}", name, meta.version);
+    }
+    for name in r.unidentified.iter() {
+        println!("Could not identify installed version of {}", name);
+    }
+    if r.adopted.is_empty() {
+        println!("No components adopted.");
+    }
+    Ok(())
+}
+
+/// Rebuild the saved state from the installed content, replacing any
+/// existing state; see `regenerate_state()` for `confirm` and `force`
+pub(crate) fn client_run_regenerate_state(
+    c: &mut ipc::ClientToDaemonConnection,
+    confirm: bool,
+    force: bool,
+) -> Result<()> {
+    validate_preview_env()?;
+    let r: RegenerateResult = c.send(&ClientRequest::RegenerateState { confirm, force })?;
+    let adopted = &r.components.adopted;
+    for (name, meta) in adopted.iter() {
+        println!("Reconstructed {}: {}", name, meta.version);
+    }
+    for name in r.components.unidentified.iter() {
+        if r.carried_over.contains(name) {
+            println!(
+                "Could not identify installed version of {}; kept its previous state",
+                name
+            );
+        } else {
+            println!("Could not identify installed version of {}", name);
+        }
+    }
+    if adopted.is_empty() {
+        println!("No components identified; state left unchanged.");
+        return Ok(());
+    }
+    match r.previous {
+        PreviousState::Missing => println!("Wrote new state file."),
+        PreviousState::Unreadable(e) => println!("Replaced unreadable state file: {}", e),
+        PreviousState::Valid => println!("Replaced existing state file."),
+    }
+    Ok(())
+}
+
+/// Write the saved state to `path`, for `client_run_import_state()`
+pub(crate) fn client_run_export_state(
+    c: &mut ipc::ClientToDaemonConnection,
+    path: &Path,
+) -> Result<()> {
+    let state: String = c.send(&ClientRequest::ExportState)?;
+    std::fs::write(path, state.as_bytes()).with_context(|| format!("writing {:?}", path))?;
+    println!("Exported state to {}", path.display());
+    Ok(())
+}
+
+/// Replace the saved state with the one exported to `path`, warning about
+/// components whose files don't match it
+pub(crate) fn client_run_import_state(
+    c: &mut ipc::ClientToDaemonConnection,
+    path: &Path,
+) -> Result<()> {
+    validate_preview_env()?;
+    let state = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+    let r: ValidationSummary = c.send(&ClientRequest::ImportState { state })?;
+    for (name, result) in r.components.iter() {
+        match result {
+            ValidationResult::Valid => println!("Imported {}", name),
+            ValidationResult::Errors(errs) => {
+                eprintln!(
+                    "warning: Imported {}, but its files don't match the imported state:",
+                    name
+                );
+                for err in errs {
+                    eprintln!("  {}", err);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_list_components(c: &mut ipc::ClientToDaemonConnection) -> Result<()> {
+    let list: ComponentList = c.send(&ClientRequest::ListComponents)?;
+    for (name, info) in list.components.iter() {
+        let msg = match (info.available, info.installed) {
+            (true, true) => "installed".to_string(),
+            (true, false) => "available, not installed".to_string(),
+            (false, _) => format!(
+                "not supported on {}; requires {}",
+                list.architecture,
+                info.architectures.join(" or ")
+            ),
+        };
+        println!("{}: {}", name, msg);
+    }
+    for (name, e) in list.invalid_descriptors.iter() {
+        println!("{}: invalid descriptor: {}", name, e);
+    }
+    if !list.components.values().any(|i| i.available) {
+        println!(
+            "No components available for this platform ({}).",
+            list.architecture
+        );
+    }
+    Ok(())
+}
+
+/// Find the components of `status` which became upgradable since the
+/// last poll, updating `seen`, which maps each upgradable component to
+/// its available version.  Returns the names and versions of those newly
+/// upgradable.
+fn newly_upgradable(status: &Status, seen: &mut BTreeMap<String, String>) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    let mut now = BTreeMap::new();
+    for (name, cstatus) in status.components.iter() {
+        if let (ComponentUpdatable::Upgradable, Some(update)) =
+            (&cstatus.updatable, cstatus.update.as_ref())
+        {
+            if seen.get(name) != Some(&update.version) {
+                ret.push((name.clone(), update.version.clone()));
+            }
+            now.insert(name.clone(), update.version.clone());
+        }
+    }
+    *seen = now;
+    ret
+}
+
+/// Poll for available updates once for `bootupctl watch`, printing a line
+/// for each component which became upgradable since `seen` was updated.
+pub(crate) fn client_poll_updates(
+    c: &mut ipc::ClientToDaemonConnection,
+    seen: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    let status: Status = c.send(&ClientRequest::Status {
+        components: Vec::new(),
+    })?;
+    for (name, version) in newly_upgradable(&status, seen) {
+        println!(
+            "{} Update available for {}: {}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            name,
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Print the activity counters as JSON
+pub(crate) fn client_run_metrics(c: &mut ipc::ClientToDaemonConnection) -> Result<()> {
+    let r: Metrics = c.send(&ClientRequest::Metrics)?;
+    let mut v = serde_json::to_value(&r)?;
+    if let Some(obj) = v.as_object_mut() {
+        if let Some(serde_json::Value::Object(counters)) = obj.remove("counters") {
+            obj.extend(counters);
+        }
+    }
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(&mut stdout, &v)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Print how `component` differs from its available update, as JSON if
+/// `json` is set.
+pub(crate) fn client_run_diff(
+    c: &mut ipc::ClientToDaemonConnection,
+    component: &str,
+    json: bool,
+) -> Result<()> {
+    let r: ComponentDiff = c.send(&ClientRequest::Diff {
+        component: component.to_string(),
+    })?;
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &r)?;
+        stdout.write_all(b"\n")?;
+        return Ok(());
+    }
+    println!("Component {}", component);
+    println!("  Installed: {}", r.installed.version);
+    let available = if let Some(available) = r.available.as_ref() {
+        available
+    } else {
+        println!("  No update available");
+        return Ok(());
+    };
+    println!("  Available: {}", available.version);
+    match r.files.as_ref() {
+        Some(files) => {
+            for f in files.added.iter() {
+                println!("  Added: {}", f);
+            }
+            for f in files.removed.iter() {
+                println!("  Removed: {}", f);
+            }
+            for f in files.changed.iter() {
+                println!("  Changed: {}", f);
+            }
+            if files.added.is_empty() && files.removed.is_empty() && files.changed.is_empty() {
+                println!("  No files changed");
+            }
+        }
+        None => println!("  No file inventory available"),
+    }
+    Ok(())
+}
+
+/// Printed by `bootupctl validate --json`, and returned from daemon → client
+/// for `check()`
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ValidationSummary {
+    /// Maps a component name to the result of validating it
+    pub(crate) components: BTreeMap<String, ValidationResult>,
+    /// Maps the name of a component whose descriptor can't be loaded to
+    /// why; see `Status::invalid_descriptors`
+    #[serde(default)]
+    pub(crate) invalid_descriptors: BTreeMap<String, String>,
+}
+
+impl ValidationSummary {
+    /// Whether every component passed validation
+    pub(crate) fn is_valid(&self) -> bool {
+        self.invalid_descriptors.is_empty()
+            && self
+                .components
+                .values()
+                .all(|r| matches!(r, ValidationResult::Valid))
+    }
+}
+
+/// Check the health of the installed components, printing the results as
+/// JSON if `json` is set.  Fails if any component is unhealthy.
+pub(crate) fn client_run_check(c: &mut ipc::ClientToDaemonConnection, json: bool) -> Result<()> {
+    let r: ValidationSummary = c.send(&ClientRequest::Check)?;
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &r)?;
+        stdout.write_all(b"\n")?;
+    } else {
+        for (name, result) in r.components.iter() {
+            match result {
+                ValidationResult::Valid => println!("{}: ok", name),
+                ValidationResult::Errors(errs) => {
+                    println!("{}: failed", name);
+                    for err in errs {
+                        println!("  {}", err);
+                    }
+                }
+            }
+        }
+        for (name, e) in r.invalid_descriptors.iter() {
+            println!("{}: invalid descriptor: {}", name, e);
+        }
+    }
+    if !r.is_valid() {
+        anyhow::bail!("Some components are unhealthy");
+    }
+    Ok(())
+}
+
+/// Validate all installed components; with `quiet`, only errors are
+/// printed, and with `json` the results are printed as JSON.
+pub(crate) fn client_run_validate(
+    c: &mut ipc::ClientToDaemonConnection,
+    quiet: bool,
+    json: bool,
+) -> Result<()> {
+    let status: Status = c.send(&ClientRequest::Status {
+        components: Vec::new(),
+    })?;
+    let mut summary = ValidationSummary {
+        invalid_descriptors: status.invalid_descriptors.clone(),
+        ..Default::default()
+    };
+    if status.components.is_empty() && summary.invalid_descriptors.is_empty() && !json {
+        if !quiet {
+            println!("No components installed.");
+        }
+        return Ok(());
+    }
+    let mut caught_validation_error = !summary.invalid_descriptors.is_empty();
+    if !json {
+        for (name, e) in summary.invalid_descriptors.iter() {
+            eprintln!("Invalid descriptor for component {}: {}", name, e);
+        }
+    }
+    for (name, _) in status.components.iter() {
+        let r: ValidationResult = c.send(&ClientRequest::Validate {
+            component: name.to_string(),
+        })?;
+        match &r {
+            ValidationResult::Valid => {
+                if !quiet && !json {
+                    println!("Validated: {}", name);
+                }
+            }
+            ValidationResult::Errors(errs) => {
+                if !json {
+                    for err in errs {
+                        eprintln!("{}", err);
+                    }
+                }
+                caught_validation_error = true;
+            }
+        }
+        summary.components.insert(name.to_string(), r);
+    }
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &summary)?;
+        stdout.write_all(b"\n")?;
+    }
+    if caught_validation_error {
+        anyhow::bail!("Caught validation errors");
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_prune(c: &mut ipc::ClientToDaemonConnection, keep: u32) -> Result<()> {
+    validate_preview_env()?;
+    let results: BTreeMap<String, PruneResult> = c.send(&ClientRequest::Prune { keep })?;
+    let mut freed = 0;
+    for (name, r) in results.iter() {
+        for p in r.pruned.iter() {
+            println!("Pruned {}: {}", name, p);
+        }
+        if r.previous_missing {
+            println!(
+                "{}: retained payload is missing; rollback is no longer available",
+                name
+            );
+        }
+        freed += r.freed;
+    }
+    if results.values().all(|r| r.pruned.is_empty()) {
+        println!("Nothing to prune.");
+    } else {
+        println!("Freed {} bytes", freed);
+    }
+    Ok(())
+}
+
+pub(crate) fn client_run_repair(
+    c: &mut ipc::ClientToDaemonConnection,
+    component: Option<&str>,
+) -> Result<()> {
+    validate_preview_env()?;
+    let status: Status = c.send(&ClientRequest::Status {
+        components: Vec::new(),
+    })?;
+    if let Some(name) = component {
+        if !status.components.contains_key(name) {
+            return Err(BootupdError::ComponentNotInstalled(name.into()).into());
+        }
+    }
+    if status.components.is_empty() {
+        println!("No components installed.");
+        return Ok(());
+    }
+    let mut caught_unrepaired = false;
+    for name in status.components.keys() {
+        if component.map_or(false, |c| c != name) {
+            continue;
+        }
+        let r: RepairResult = c.send_with_progress(
+            &ClientRequest::Repair {
+                component: name.to_string(),
+            },
+            |msg| println!("  {}", msg),
+        )?;
+        if r.repaired.is_empty() && r.unrepaired.is_empty() {
+            println!("{}: nothing to repair", name);
+            continue;
+        }
+        for msg in r.repaired.iter() {
+            println!("Repaired {}: {}", name, msg);
+        }
+        for msg in r.unrepaired.iter() {
+            eprintln!("Could not repair {}: {}", name, msg);
+        }
+        caught_unrepaired |= !r.unrepaired.is_empty();
+    }
+    if caught_unrepaired {
+        anyhow::bail!("Some errors could not be repaired");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::{ValidationErrorKind, DEFAULT_PRIORITY};
+    use crate::model::UpdateProgress;
+    use crate::testutil::*;
+
+    /// Create a sysroot containing an empty state directory
+    fn new_sysroot() -> Result<(tempfile::TempDir, openat::Dir)> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join(STATEFILE_DIR))?;
+        let d = openat::Dir::open(td.path())?;
+        Ok((td, d))
+    }
+
+    fn state_with_version(version: &str) -> SavedState {
+        let mut state = SavedState::default();
+        state.installed.insert(
+            "EFI".into(),
+            InstalledContent {
+                meta: ContentMetadata {
+                    timestamp: Utc::now(),
+                    version: version.into(),
+                    digest: None,
+                    source: None,
+                },
+                filetree: None,
+                applied: None,
+                device: None,
+                boot_code: None,
+                mirrors: Vec::new(),
+            },
+        );
+        state
+    }
+
+    fn saved_version(td: &tempfile::TempDir) -> Result<String> {
+        let state =
+            get_saved_state(td.path().to_str().expect("utf-8 tempdir"))?.expect("saved state");
+        Ok(state.installed["EFI"].meta.version.clone())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        let mut state = state_with_version("v1");
+        state.counters.updates_applied = 2;
+        update_state(&d, &mut state)?;
+        // Replies are sent with bincode; see `model::test::test_reply_encoding()`
+        let m: Metrics = bincode::deserialize(&bincode::serialize(&metrics(sysroot)?)?)?;
+        assert_eq!(m.counters.updates_applied, 2);
+        assert!(!m.locked);
+        assert!(m.heartbeat.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_state_dir() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        let e = get_saved_state(root).unwrap_err();
+        assert!(e
+            .to_string()
+            .ends_with("/boot is not mounted; cannot access bootupd state"));
+        std::fs::create_dir(td.path().join(STATEFILE_DIR))?;
+        assert!(get_saved_state(root)?.is_none());
+        // A /boot on the root filesystem is only an error if it should be mounted
+        std::fs::create_dir(td.path().join("etc"))?;
+        std::fs::write(
+            td.path().join("etc/fstab"),
+            "# /boot was on /dev/sda1\nUUID=abc / xfs defaults 0 0\n",
+        )?;
+        assert!(get_saved_state(root)?.is_none());
+        std::fs::write(
+            td.path().join("etc/fstab"),
+            "UUID=abc / xfs defaults 0 0\nUUID=def /boot ext4 defaults 1 2\n",
+        )?;
+        assert!(get_saved_state(root).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_dest_root() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        let e = install("/", root, &[], None, false, true).unwrap_err();
+        assert!(e
+            .to_string()
+            .ends_with("has no boot directory; is it fully mounted?"));
+        let missing = td.path().join("missing");
+        let e = check_dest_root(missing.to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().ends_with("is not a directory"));
+        std::fs::create_dir(td.path().join(STATEFILE_DIR))?;
+        check_dest_root(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_described_from_source() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let srcroot = src.path().to_str().unwrap();
+        let descdir = src.path().join(filecopy::COMPONENTS_DIR);
+        std::fs::create_dir_all(&descdir)?;
+        std::fs::write(
+            descdir.join("firmware.json"),
+            r#"{"name": "firmware", "type": "file-copy", "source": "usr/share/fw", "destination": "boot/fw"}"#,
+        )?;
+        let c = component::new_from_name(srcroot, "firmware")?;
+        let updatedir = component::component_updatedir(srcroot, c.as_ref());
+        std::fs::create_dir_all(&updatedir)?;
+        std::fs::write(updatedir.join("blob.bin"), "v1")?;
+        component::write_update_metadata(srcroot, c.as_ref(), &mock_meta("fw-1"))?;
+
+        // The target has no descriptors of its own yet
+        let (td, _d) = new_sysroot()?;
+        let root = td.path().to_str().unwrap();
+        install(srcroot, root, &["firmware".into()], None, false, true)?;
+        assert_eq!(std::fs::read(td.path().join("boot/fw/blob.bin"))?, b"v1");
+        let state = get_saved_state(root)?.expect("saved state");
+        assert_eq!(state.installed["firmware"].meta.version, "fw-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_installed_existing() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        update_state(&d, &mut state_with_version("v1"))?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        assert!(!ensure_installed("/", root, &[], None, false, true)?);
+        assert!(install("/", root, &[], None, false, true).is_err());
+        assert_eq!(saved_version(&td)?, "v1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_state_stale_tmp() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        update_state(&d, &mut state_with_version("v1"))?;
+        // Simulate a crash after linking the temporary file but before
+        // renaming it into place
+        let tmppath = Path::new(STATEFILE_DIR).join(format!("{}.tmp", STATEFILE_NAME));
+        std::fs::write(td.path().join(&tmppath), "garbage")?;
+        assert_eq!(saved_version(&td)?, "v1");
+
+        update_state(&d, &mut state_with_version("v2"))?;
+        assert!(!d.exists(&tmppath)?);
+        assert_eq!(saved_version(&td)?, "v2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_state_failure_cleanup() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        // A non-empty directory can't be replaced by the rename
+        let statepath = td.path().join(STATEFILE_DIR).join(STATEFILE_NAME);
+        std::fs::create_dir(&statepath)?;
+        std::fs::write(statepath.join("blocker"), "")?;
+        assert!(update_state(&d, &mut state_with_version("v1")).is_err());
+        let mut names: Vec<_> = std::fs::read_dir(td.path().join(STATEFILE_DIR))?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        names.sort();
+        assert_eq!(names, vec![std::ffi::OsString::from(STATEFILE_NAME)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stable_deployment() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(stable_deployment(td.path()), cfg!(feature = "stable"));
+        std::fs::create_dir_all(td.path().join("usr/lib/bootupd"))?;
+        std::fs::write(td.path().join("usr/lib/bootupd/stable"), "")?;
+        assert!(stable_deployment(td.path()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_state_compressed() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        let statepath = td.path().join(STATEFILE_DIR).join(STATEFILE_NAME);
+        write_state_file(&d, &state_with_version("v1"), Compression::Gzip)?;
+        assert!(std::fs::read(&statepath)?.starts_with(&[0x1f, 0x8b]));
+        assert_eq!(saved_version(&td)?, "v1");
+        // Plaintext is still the default
+        update_state(&d, &mut state_with_version("v2"))?;
+        assert_eq!(std::fs::read(&statepath)?[0], b'{');
+        assert_eq!(saved_version(&td)?, "v2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_state_serialize_failure() -> Result<()> {
+        struct Unserializable;
+        impl Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(
+                &self,
+                _: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("injected failure"))
+            }
+        }
+
+        let (td, d) = new_sysroot()?;
+        update_state(&d, &mut state_with_version("v1"))?;
+        let statepath = td.path().join(STATEFILE_DIR).join(STATEFILE_NAME);
+        let orig = std::fs::read(&statepath)?;
+        assert!(write_state_file(&d, &Unserializable, Compression::None).is_err());
+        assert_eq!(std::fs::read(&statepath)?, orig);
+        assert_eq!(d.list_dir(STATEFILE_DIR)?.count(), 1);
+        assert_eq!(saved_version(&td)?, "v1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_summary_json() -> Result<()> {
+        let mut summary = UpdateSummary::default();
+        summary.components.insert(
+            "BIOS".into(),
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::Skipped,
+                previous: "v1".into(),
+                new: None,
+                source: None,
+                changed_files: Vec::new(),
+            },
+        );
+        assert!(!summary.any_updated());
+        summary.components.insert(
+            "EFI".into(),
+            ComponentUpdateSummary {
+                outcome: UpdateOutcome::Updated,
+                previous: "v1".into(),
+                new: Some("v2".into()),
+                source: Some("33.20201214.3.0".into()),
+                changed_files: vec!["fedora/shimx64.efi".into()],
+            },
+        );
+        assert!(summary.any_updated());
+        let v = serde_json::to_value(&summary)?;
+        assert_eq!(v["components"]["BIOS"]["outcome"], "skipped");
+        assert_eq!(v["components"]["EFI"]["outcome"], "updated");
+        assert_eq!(v["components"]["EFI"]["new"], "v2");
+        assert_eq!(
+            v["components"]["EFI"]["changed-files"],
+            serde_json::json!(["fedora/shimx64.efi"])
+        );
+        assert!(v["components"]["BIOS"].get("changed-files").is_none());
+        assert_eq!(v["components"]["EFI"]["source"], "33.20201214.3.0");
+        assert!(v["components"]["BIOS"].get("source").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_source() {
+        let mut meta = mock_meta("v2");
+        assert_eq!(describe_source(&meta), "");
+        meta.source = Some("33.20201214.3.0".into());
+        assert_eq!(describe_source(&meta), " from 33.20201214.3.0");
+    }
+
+    /// Register a mock component `name` of `priority` with no other
+    /// behavior, and instantiate it
+    fn named_mock(name: &str, priority: u32) -> Box<dyn Component> {
+        register_mock(
+            name,
+            MockBehavior {
+                priority: Some(priority),
+                ..Default::default()
+            },
+        );
+        Box::new(MockComponent::new(name).unwrap())
     }
-}
 
-/// Checks that the user has provided an environment variable to signal
-/// acceptance of our alpha state - use this when performing write operations.
-fn validate_preview_env() -> Result<()> {
-    let v = "BOOTUPD_ACCEPT_PREVIEW";
-    if std::env::var_os(v).is_none() {
-        Err(anyhow::anyhow!(
-            "bootupd is currently alpha; set {}=1 in environment to continue",
-            v
-        ))
-    } else {
+    #[test]
+    fn test_check_component_names() {
+        let mut components = vec![
+            named_mock("mock-names-a", DEFAULT_PRIORITY),
+            named_mock("mock-names-b", DEFAULT_PRIORITY),
+        ];
+        check_component_names(&components).unwrap();
+        components.push(named_mock("mock-names-a", DEFAULT_PRIORITY));
+        let e = check_component_names(&components).unwrap_err();
+        assert_eq!(e.to_string(), "Duplicate component names: mock-names-a");
+    }
+
+    #[test]
+    fn test_updatable_scheme() {
+        let meta = |version: &str, secs: i64| ContentMetadata {
+            timestamp: Utc.timestamp(1_600_000_000 + secs, 0),
+            version: version.into(),
+            digest: None,
+            source: None,
+        };
+        let backport = meta("grub2-tools-1:2.04-40.fc32.x86_64", 100);
+        let newer = meta("grub2-tools-1:2.06-2.fc33.x86_64", 0);
+        // By default only build times matter
+        let c = named_mock("mock-updatable-scheme", DEFAULT_PRIORITY);
+        let c = c.as_ref();
+        match ComponentUpdatable::from_metadata(c, &backport, Some(&newer)) {
+            ComponentUpdatable::WouldDowngrade => {}
+            u => panic!("unexpected {:?}", u),
+        }
+        match ComponentUpdatable::from_metadata(c, &newer, Some(&backport)) {
+            ComponentUpdatable::Upgradable => {}
+            u => panic!("unexpected {:?}", u),
+        }
+        match ComponentUpdatable::from_metadata(c, &newer, Some(&newer)) {
+            ComponentUpdatable::AtLatestVersion => {}
+            u => panic!("unexpected {:?}", u),
+        }
+    }
+
+    #[test]
+    fn test_install_verify() -> Result<()> {
+        let name = "mock-install-verify";
+        let (src, dest) = ("/nonexistent-src", "/nonexistent-dest");
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v1")),
+                ..Default::default()
+            },
+        );
+        let c = MockComponent::new(name)?;
+        let inst = install_one(&c, src, dest, false, true)?;
+        assert!(inst.applied.is_some());
+        assert_eq!(inst.meta.version, "v1");
+        assert_eq!(mock_behavior(name).installs, 1);
+        modify_mock(name, |b| b.invalid = vec!["payload".into()]);
+        let e = install_one(&c, src, dest, false, true).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Installed mock-install-verify failed validation:\nChanged: payload"
+        );
+        install_one(&c, src, dest, false, false)?;
+        assert_eq!(mock_behavior(name).installs, 3);
         Ok(())
     }
-}
 
-pub(crate) fn client_run_update(c: &mut ipc::ClientToDaemonConnection) -> Result<()> {
-    validate_preview_env()?;
-    let status: Status = c.send(&ClientRequest::Status)?;
-    if status.components.is_empty() {
-        println!("No components installed.");
-        return Ok(());
+    /// A sysroot whose state has the mock component `name` installed at v1
+    fn mock_sysroot(name: &str) -> Result<(tempfile::TempDir, openat::Dir)> {
+        let (td, d) = new_sysroot()?;
+        std::fs::create_dir(td.path().join("run"))?;
+        let mut state = SavedState::default();
+        state.installed.insert(name.into(), mock_installed("v1"));
+        update_state(&d, &mut state)?;
+        Ok((td, d))
     }
-    let mut updated = false;
-    for (name, cstatus) in status.components.iter() {
-        match cstatus.updatable {
-            ComponentUpdatable::Upgradable => {}
-            _ => continue,
+
+    /// A sysroot whose state has the `stageable` mock components, and
+    /// then the `unstageable` ones, installed at v1 with v2 available
+    fn transaction_sysroot(
+        stageable: &[&str],
+        unstageable: &[&str],
+    ) -> Result<(tempfile::TempDir, openat::Dir)> {
+        let (td, d) = mock_sysroot(stageable[0])?;
+        let mut state = get_saved_state(td.path().to_str().unwrap())?.unwrap();
+        for (i, name) in stageable.iter().chain(unstageable).enumerate() {
+            state
+                .installed
+                .insert(name.to_string(), mock_installed("v1"));
+            register_mock(
+                name,
+                MockBehavior {
+                    update: Some(mock_meta("v2")),
+                    parts: vec![format!("part-{}", i)],
+                    stageable: i < stageable.len(),
+                    ..Default::default()
+                },
+            );
+        }
+        update_state(&d, &mut state)?;
+        Ok((td, d))
+    }
+
+    #[test]
+    fn test_update_transactional() -> Result<()> {
+        let (a, b, c) = ("mock-txn-a", "mock-txn-b", "mock-txn-c");
+        let (td, _d) = transaction_sysroot(&[a, b], &[c])?;
+        let sysroot = td.path().to_str().unwrap();
+        let r = update_all(sysroot, &UpdateOptions::default(), true, &|_| {})?;
+        for name in &[a, b, c] {
+            assert!(
+                matches!(r[*name], Ok(ComponentUpdateResult::Updated { .. })),
+                "{}",
+                name
+            );
+            assert!(!mock_behavior(name).staged);
+            assert_eq!(mock_behavior(name).runs.len(), 1);
+        }
+        let state = get_saved_state(sysroot)?.unwrap();
+        for name in &[a, b, c] {
+            assert_eq!(state.installed[*name].meta.version, "v2");
+        }
+        assert!(state.pending.unwrap_or_default().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retained_generations() -> Result<()> {
+        let name = "mock-generations";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        register_mock(name, MockBehavior::default());
+        for version in &["v2", "v3", "v4"] {
+            modify_mock(name, |m| m.update = Some(mock_meta(version)));
+            update(sysroot, name, &UpdateOptions::default(), &|_| {})?;
+        }
+        let versions = |state: &SavedState| -> (String, Vec<String>) {
+            let previous = state.previous.as_ref().unwrap()[name].meta.version.clone();
+            let retained = state
+                .retained
+                .as_ref()
+                .and_then(|r| r.get(name))
+                .map(|g| g.iter().map(|i| i.meta.version.clone()).collect())
+                .unwrap_or_default();
+            (previous, retained)
         };
-        match c.send(&ClientRequest::Update {
-            component: name.to_string(),
-        })? {
-            ComponentUpdateResult::AtLatestVersion => {
-                // Shouldn't happen unless we raced with another client
-                eprintln!(
-                    "warning: Expected update for {}, raced with a different client?",
-                    name
-                );
-                continue;
-            }
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(
+            versions(&state),
+            ("v3".into(), vec!["v2".into(), "v1".into()])
+        );
+        let e = prune(sysroot, 0).unwrap_err();
+        assert!(e.to_string().contains("At least one generation"), "{}", e);
+        // The third generation goes, the second stays
+        prune(sysroot, 2)?;
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(versions(&state), ("v3".into(), vec!["v2".into()]));
+        // Rolling back makes the next generation available
+        rollback(sysroot, name)?;
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(state.installed[name].meta.version, "v3");
+        assert_eq!(versions(&state), ("v2".into(), Vec::new()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_transactional_rollback() -> Result<()> {
+        let (a, b, c) = ("mock-txn-fail-a", "mock-txn-fail-b", "mock-txn-fail-c");
+        let (td, _d) = transaction_sysroot(&[a, b], &[c])?;
+        let sysroot = td.path().to_str().unwrap();
+        modify_mock(b, |m| m.fail_after = Some(0));
+        let e = update_all(sysroot, &UpdateOptions::default(), true, &|_| {}).unwrap_err();
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(e)),
+            "all components were left at their previous versions: Failed to update mock-txn-fail-b: Injected failure after 0 parts"
+        );
+        // Only what was applied is rolled back
+        assert_eq!(mock_behavior(a).rollbacks, ["v1"]);
+        assert!(mock_behavior(b).rollbacks.is_empty());
+        for name in &[a, b] {
+            assert!(!mock_behavior(name).staged);
+        }
+        // Nothing else is updated once the transaction failed
+        assert!(mock_behavior(c).runs.is_empty());
+        let state = get_saved_state(sysroot)?.unwrap();
+        for name in &[a, b, c] {
+            assert_eq!(state.installed[*name].meta.version, "v1");
+        }
+        assert!(state.pending.unwrap_or_default().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_interrupted() -> Result<()> {
+        let name = "mock-interrupted";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        let parts: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v2")),
+                parts: parts.clone(),
+                fail_after: Some(2),
+                ..Default::default()
+            },
+        );
+        let opts = UpdateOptions::default();
+        let e = update(sysroot, name, &opts, &|_| {}).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Failed to update mock-interrupted: Injected failure after 2 parts"
+        );
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(state.installed[name].meta.version, "v1");
+        let pending = &state.pending.as_ref().unwrap()[name];
+        assert_eq!(pending.meta.version, "v2");
+        assert!(!pending.staged);
+        assert_eq!(pending.completed, parts[..2].iter().cloned().collect());
+        assert_eq!(pending.total, Some(3));
+        let s = status(sysroot, &[], true)?;
+        let c = &s.components[name];
+        assert_eq!(c.interrupted.as_ref().unwrap().version, "v2");
+        assert_eq!(
+            c.interrupted_progress,
+            Some(UpdateProgress {
+                completed: 2,
+                total: 3
+            })
+        );
+
+        // Rerunning resumes the same payload
+        modify_mock(name, |b| b.fail_after = None);
+        match update(sysroot, name, &opts, &|_| {})? {
             ComponentUpdateResult::Updated {
-                previous: _,
                 interrupted,
+                resumed,
                 new,
+                changed_files,
+                ..
             } => {
-                if let Some(i) = interrupted {
-                    eprintln!(
-                        "warning: Continued from previous interrupted update: {}",
-                        i.version,
-                    );
-                }
-                println!("Updated {}: {}", name, new.version);
+                assert_eq!(interrupted.unwrap().version, "v2");
+                assert!(resumed);
+                assert_eq!(new.version, "v2");
+                assert_eq!(changed_files, ["c"]);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(mock_behavior(name).runs[1], pending.completed);
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(state.installed[name].meta.version, "v2");
+        assert!(state.pending.unwrap_or_default().is_empty());
+        assert_eq!(state.counters.interrupted_recovered, 1);
+
+        // An interrupted update of a different payload starts over
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v3")),
+                parts: parts.clone(),
+                fail_after: Some(1),
+                ..Default::default()
+            },
+        );
+        assert!(update(sysroot, name, &opts, &|_| {}).is_err());
+        modify_mock(name, |b| {
+            b.update = Some(mock_meta("v4"));
+            b.fail_after = None;
+        });
+        match update(sysroot, name, &opts, &|_| {})? {
+            ComponentUpdateResult::Updated {
+                interrupted,
+                resumed,
+                changed_files,
+                ..
+            } => {
+                assert_eq!(interrupted.unwrap().version, "v3");
+                assert!(!resumed);
+                assert_eq!(changed_files, parts);
             }
+            r => panic!("unexpected {:?}", r),
         }
-        updated = true;
+        assert!(mock_behavior(name).runs[1].is_empty());
+        Ok(())
     }
-    if !updated {
-        println!("No update available for any component.");
+
+    #[test]
+    fn test_max_concurrency() -> Result<()> {
+        assert_eq!(worker_count(None, 3)?, 3);
+        assert_eq!(worker_count(Some(2), 3)?, 2);
+        assert_eq!(worker_count(Some(8), 3)?, 3);
+        let e = worker_count(Some(0), 3).unwrap_err();
+        assert_eq!(e.to_string(), "The maximum concurrency must be at least 1");
+
+        let names: Vec<String> = vec!["mock-concurrency-a".into(), "mock-concurrency-b".into()];
+        let (td, d) = mock_sysroot(&names[0])?;
+        let sysroot = td.path().to_str().unwrap();
+        let mut state = get_saved_state(sysroot)?.unwrap();
+        let inst = state.installed[&names[0]].clone();
+        state.installed.insert(names[1].clone(), inst);
+        update_state(&d, &mut state)?;
+        for name in &names {
+            register_mock(
+                name,
+                MockBehavior {
+                    update: Some(mock_meta("v2")),
+                    parts: vec!["a".into()],
+                    ..Default::default()
+                },
+            );
+        }
+        let opts = UpdateOptions::default();
+        assert!(update_parallel(sysroot, &names, &opts, Some(0), &|_| {}).is_err());
+        assert!(get_saved_state(sysroot)?.unwrap().pending.is_none());
+        // A single worker updates both components in turn
+        let results = update_parallel(sysroot, &names, &opts, Some(1), &|_| {})?;
+        for name in &names {
+            match &results[name] {
+                Ok(ComponentUpdateResult::Updated { new, .. }) => assert_eq!(new.version, "v2"),
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        Ok(())
     }
-    Ok(())
-}
 
-pub(crate) fn client_run_validate(c: &mut ipc::ClientToDaemonConnection) -> Result<()> {
-    let status: Status = c.send(&ClientRequest::Status)?;
-    if status.components.is_empty() {
-        println!("No components installed.");
-        return Ok(());
+    /// Register `name` to hang after its first part of two, until cancelled
+    fn register_hanging_mock(name: &str) -> Vec<String> {
+        let parts: Vec<String> = vec!["a".into(), "b".into()];
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v2")),
+                parts: parts.clone(),
+                hang_after: Some(1),
+                ..Default::default()
+            },
+        );
+        parts
     }
-    let mut caught_validation_error = false;
-    for (name, _) in status.components.iter() {
-        match c.send(&ClientRequest::Validate {
-            component: name.to_string(),
-        })? {
-            ValidationResult::Valid => {
-                println!("Validated: {}", name);
+
+    /// Once the cancelled update of `name` has stopped and released the
+    /// lock, check that all its completed `parts` were recorded
+    fn check_cancelled_update(sysroot: &str, name: &str, parts: &[String]) -> Result<()> {
+        let lock = acquire_write_lock_timeout(Path::new(sysroot), Duration::from_secs(10))?;
+        drop(lock);
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(state.installed[name].meta.version, "v1");
+        let pending = &state.pending.as_ref().unwrap()[name];
+        assert_eq!(pending.completed, parts.iter().cloned().collect());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_timeout() -> Result<()> {
+        let name = "mock-timeout";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        let parts = register_hanging_mock(name);
+        let opts = UpdateOptions {
+            timeout: Some(0),
+            ..Default::default()
+        };
+        let e = update(sysroot, name, &opts, &|_| {}).unwrap_err();
+        assert!(e.to_string().contains("did not complete"), "{}", e);
+        check_cancelled_update(sysroot, name, &parts)
+    }
+
+    #[test]
+    fn test_update_transactional_timeout() -> Result<()> {
+        let name = "mock-txn-timeout";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        let parts = register_hanging_mock(name);
+        modify_mock(name, |m| m.stageable = true);
+        // Long enough for the update to start before it's cancelled
+        let opts = UpdateOptions {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let e = update_all(sysroot, &opts, true, &|_| {}).unwrap_err();
+        assert!(e.to_string().contains("did not complete"), "{}", e);
+        check_cancelled_update(sysroot, name, &parts)
+    }
+
+    #[test]
+    fn test_update_parallel_timeout() -> Result<()> {
+        let name = "mock-parallel-timeout";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        let parts = register_hanging_mock(name);
+        // Long enough for the update to start before it's cancelled
+        let opts = UpdateOptions {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let results = update_parallel(sysroot, &[name.to_string()], &opts, None, &|_| {})?;
+        let e = results[name].as_ref().unwrap_err();
+        assert!(e.to_string().contains("did not complete"), "{}", e);
+        check_cancelled_update(sysroot, name, &parts)
+    }
+
+    #[test]
+    fn test_update_precondition() -> Result<()> {
+        let name = "mock-precondition";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v2")),
+                precondition: Some("Firmware too old".into()),
+                ..Default::default()
+            },
+        );
+        let mut opts = UpdateOptions::default();
+        match update(sysroot, name, &opts, &|_| {})? {
+            ComponentUpdateResult::PreconditionFailed {
+                installed,
+                available,
+                reason,
+            } => {
+                assert_eq!(installed.version, "v1");
+                assert_eq!(available.version, "v2");
+                assert_eq!(reason, "Firmware too old");
             }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(mock_behavior(name).runs.is_empty());
+        assert!(get_saved_state(sysroot)?.unwrap().pending.is_none());
+        opts.ignore_preconditions = true;
+        match update(sysroot, name, &opts, &|_| {})? {
+            ComponentUpdateResult::Updated { new, .. } => assert_eq!(new.version, "v2"),
+            r => panic!("unexpected {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_mock() -> Result<()> {
+        let name = "mock-validate";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        register_mock(name, MockBehavior::default());
+        assert!(matches!(validate(sysroot, name)?, ValidationResult::Valid));
+        modify_mock(name, |b| b.invalid = vec!["loader.efi".into()]);
+        match validate(sysroot, name)? {
             ValidationResult::Errors(errs) => {
-                for err in errs {
-                    eprintln!("{}", err);
-                }
-                caught_validation_error = true;
+                assert_eq!(describe_errors(&errs, "\n"), "Changed: loader.efi")
             }
+            r => panic!("unexpected {:?}", r),
         }
+        modify_mock(name, |b| b.fail_validate = true);
+        assert!(validate(sysroot, name).is_err());
+        Ok(())
     }
-    if caught_validation_error {
-        anyhow::bail!("Caught validation errors");
+
+    #[test]
+    fn test_interrupted_bootable() -> Result<()> {
+        let name = "mock-interrupted-bootable";
+        register_mock(name, MockBehavior::default());
+        let c = MockComponent::new(name)?;
+        let inst = mock_installed("v1");
+        assert_eq!(interrupted_bootable(&c, &inst), Some(true));
+        // The interrupted update replaced an installed file
+        modify_mock(name, |b| b.invalid = vec!["payload".into()]);
+        assert_eq!(interrupted_bootable(&c, &inst), Some(false));
+        modify_mock(name, |b| b.fail_validate = true);
+        assert_eq!(interrupted_bootable(&c, &inst), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_components() -> Result<()> {
+        let components = || -> Vec<Box<dyn Component>> {
+            vec![
+                named_mock("mock-select-a", DEFAULT_PRIORITY),
+                named_mock("mock-select-b", DEFAULT_PRIORITY),
+            ]
+        };
+        let names = |c: Vec<Box<dyn Component>>| -> Vec<String> {
+            c.iter().map(|c| c.name().to_string()).collect()
+        };
+        assert_eq!(
+            names(select_components(components(), &[])?),
+            ["mock-select-a", "mock-select-b"]
+        );
+        assert_eq!(
+            names(select_components(components(), &["mock-select-a".into()])?),
+            ["mock-select-a"]
+        );
+        let e = select_components(components(), &["mock-select-a".into(), "bogus".into()])
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Unknown component bogus");
+        // BIOS is a built-in component, but not among these
+        let e = select_components(components(), &["BIOS".into()])
+            .map(|_| ())
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Component BIOS is not available on "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct_read_only() {
+        let opts = UpdateOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let req = ClientRequest::Update {
+            component: "EFI".into(),
+            opts,
+        };
+        assert!(req.is_read_only());
+        let mut c = ipc::ClientToDaemonConnection::new_direct();
+        let e = c
+            .send::<_, ComponentRollbackResult>(&ClientRequest::Rollback {
+                component: "EFI".into(),
+            })
+            .unwrap_err();
+        assert!(e.to_string().contains("read-only"), "{}", e);
+    }
+
+    #[test]
+    fn test_newly_upgradable() {
+        let meta = |v: &str| ContentMetadata {
+            timestamp: Utc::now(),
+            version: v.into(),
+            digest: None,
+            source: None,
+        };
+        let status = |updatable, update: &str| {
+            let mut status = Status::default();
+            status.components.insert(
+                "EFI".into(),
+                ComponentStatus {
+                    installed: meta("v1"),
+                    applied: None,
+                    interrupted: None,
+                    interrupted_progress: None,
+                    interrupted_bootable: None,
+                    staged: None,
+                    update: Some(meta(update)),
+                    updatable,
+                    devices: Vec::new(),
+                    space: None,
+                    active: false,
+                    remote: None,
+                },
+            );
+            status
+        };
+        let mut seen = BTreeMap::new();
+        let s = status(ComponentUpdatable::AtLatestVersion, "v1");
+        assert!(newly_upgradable(&s, &mut seen).is_empty());
+        let s = status(ComponentUpdatable::Upgradable, "v2");
+        assert_eq!(
+            newly_upgradable(&s, &mut seen),
+            vec![("EFI".to_string(), "v2".to_string())]
+        );
+        assert!(newly_upgradable(&s, &mut seen).is_empty());
+        let s = status(ComponentUpdatable::Upgradable, "v3");
+        assert_eq!(newly_upgradable(&s, &mut seen).len(), 1);
+        let s = status(ComponentUpdatable::AtLatestVersion, "v3");
+        assert!(newly_upgradable(&s, &mut seen).is_empty());
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn test_is_affirmative() {
+        for a in &["y\n", "Y", " yes\n", "YES"] {
+            assert!(is_affirmative(a), "{:?}", a);
+        }
+        for a in &["", "\n", "n", "no", "yeah", "y es"] {
+            assert!(!is_affirmative(a), "{:?}", a);
+        }
+    }
+
+    #[test]
+    fn test_sort_by_priority() {
+        let mut components: Vec<Box<dyn Component>> = vec![
+            named_mock("mock-sort-config", DEFAULT_PRIORITY + 10),
+            named_mock("mock-sort-grub", DEFAULT_PRIORITY),
+            named_mock("mock-sort-mbr", DEFAULT_PRIORITY),
+            named_mock("mock-sort-shim", DEFAULT_PRIORITY - 10),
+        ];
+        sort_by_priority(&mut components);
+        let names: Vec<_> = components.iter().map(|c| c.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "mock-sort-shim",
+                "mock-sort-grub",
+                "mock-sort-mbr",
+                "mock-sort-config"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_order_of_sysroot() -> Result<()> {
+        // Only the target root describes these components
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().to_str().unwrap();
+        let descdir = td.path().join(filecopy::COMPONENTS_DIR);
+        std::fs::create_dir_all(&descdir)?;
+        std::fs::write(
+            descdir.join("late.json"),
+            r#"{"name": "late", "type": "file-copy", "source": "usr/share/late", "destination": "boot/late", "priority": 200}"#,
+        )?;
+        std::fs::write(
+            descdir.join("early.json"),
+            r#"{"name": "early", "type": "file-copy", "source": "usr/share/early", "destination": "boot/early", "priority": 10}"#,
+        )?;
+        let mut names = vec!["late".to_string(), "early".to_string()];
+        update_order(sysroot, &mut names)?;
+        assert_eq!(names, ["early", "late"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_descriptors_reported() -> Result<()> {
+        let name = "mock-invalid-descriptors";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        register_mock(name, MockBehavior::default());
+        let descdir = td.path().join(filecopy::COMPONENTS_DIR);
+        std::fs::create_dir_all(&descdir)?;
+        std::fs::write(descdir.join("broken.json"), r#"{"name": "broken"}"#)?;
+        let s = status(sysroot, &[], false)?;
+        assert_eq!(s.invalid_descriptors.keys().collect::<Vec<_>>(), ["broken"]);
+        let list = list_components(sysroot)?;
+        assert!(list.invalid_descriptors.contains_key("broken"));
+        // The installed component is fine, but the system isn't healthy
+        let summary = check(sysroot)?;
+        assert!(matches!(summary.components[name], ValidationResult::Valid));
+        assert!(summary.invalid_descriptors.contains_key("broken"));
+        assert!(!summary.is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation_summary_json() -> Result<()> {
+        let mut summary = ValidationSummary::default();
+        summary
+            .components
+            .insert("BIOS".into(), ValidationResult::Valid);
+        assert!(summary.is_valid());
+        summary.components.insert(
+            "EFI".into(),
+            ValidationResult::Errors(vec![ValidationError {
+                expected: Some("sha512:aa".into()),
+                actual: Some("sha512:bb".into()),
+                ..ValidationError::file(
+                    "EFI",
+                    ValidationErrorKind::ContentMismatch,
+                    "fedora/shimx64.efi",
+                )
+            }]),
+        );
+        assert!(!summary.is_valid());
+        assert_eq!(
+            serde_json::to_value(&summary)?,
+            serde_json::json!({
+                "components": {
+                    "BIOS": "valid",
+                    "EFI": { "errors": [{
+                        "component": "EFI",
+                        "kind": "content-mismatch",
+                        "path": "fedora/shimx64.efi",
+                        "location": null,
+                        "expected": "sha512:aa",
+                        "actual": "sha512:bb",
+                        "detail": null,
+                    }] },
+                },
+                "invalid-descriptors": {},
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_components() {
+        let components = get_components("/").unwrap();
+        let names: Vec<&str> = components
+            .iter()
+            .map(|c| c.name())
+            .filter(|n| is_builtin_component(n))
+            .collect();
+        for (name, arches) in KNOWN_COMPONENTS {
+            assert_eq!(
+                names.contains(name),
+                arches.contains(&std::env::consts::ARCH),
+                "{}",
+                name
+            );
+        }
+        for name in names {
+            assert!(KNOWN_COMPONENTS.iter().any(|(n, _)| *n == name));
+        }
+    }
+
+    #[test]
+    fn test_components_for_arch() -> Result<()> {
+        let names = |arch| -> Result<Vec<String>> {
+            Ok(get_components_for_arch("/", arch)?
+                .iter()
+                .map(|c| c.name().to_string())
+                .collect())
+        };
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(names("x86_64")?, ["EFI", "BIOS"]);
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            names("x86_64").unwrap_err().to_string(),
+            "The BIOS component for x86_64 isn't supported by this build"
+        );
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        assert_eq!(names("aarch64")?, ["EFI"]);
+        let e = names("s390x").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "No components are known for architecture s390x"
+        );
+        let host = get_components("/")?;
+        let host: Vec<_> = host.iter().map(|c| c.name()).collect();
+        if !host.is_empty() {
+            assert_eq!(names(std::env::consts::ARCH)?, host);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        let fcos = "NAME=Fedora\nVERSION=\"33.20201214.3.0 (CoreOS)\"\nID=fedora\nVERSION_ID=33\nOSTREE_VERSION='33.20201214.3.0'\n";
+        assert_eq!(
+            parse_os_release(fcos).as_deref(),
+            Some("fedora 33.20201214.3.0")
+        );
+        assert_eq!(
+            parse_os_release("ID=\"rhel\"\nVERSION_ID=\"8.3\"\n").as_deref(),
+            Some("rhel 8.3")
+        );
+        assert_eq!(parse_os_release("ID=fedora\n"), None);
+    }
+
+    #[test]
+    fn test_boot_method() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = td.path().join("efi");
+        let expected_bios = if cfg!(target_arch = "aarch64") {
+            BootMethod::EFI
+        } else {
+            BootMethod::BIOS
+        };
+        assert_eq!(boot_method(&efi), expected_bios);
+        std::fs::create_dir(&efi)?;
+        assert_eq!(boot_method(&efi), expected_bios);
+        std::fs::write(efi.join("fw_platform_size"), "64\n")?;
+        assert_eq!(boot_method(&efi), BootMethod::EFI);
+        assert_eq!(BootMethod::EFI.to_string(), "EFI");
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_method_unmanaged() {
+        let mut state = SavedState::default();
+        assert!(!boot_method_unmanaged(BootMethod::BIOS, &state));
+        state = state_with_version("v1");
+        assert!(!boot_method_unmanaged(BootMethod::EFI, &state));
+        assert!(boot_method_unmanaged(BootMethod::BIOS, &state));
+        let efi = state.installed["EFI"].clone();
+        state.installed.insert("BIOS".into(), efi);
+        assert!(!boot_method_unmanaged(BootMethod::BIOS, &state));
+    }
+
+    #[test]
+    fn test_is_active() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = td.path().join("efi");
+        assert_eq!(loader_partuuid(&efi), None);
+        std::fs::create_dir_all(efi.join("efivars"))?;
+        let mut buf = vec![0x06, 0, 0, 0];
+        for c in "9A4F-01".encode_utf16().chain(std::iter::once(0)) {
+            buf.extend_from_slice(&c.to_le_bytes());
+        }
+        std::fs::write(efi.join("efivars").join(LOADER_PARTUUID_VAR), &buf)?;
+        assert_eq!(loader_partuuid(&efi).as_deref(), Some("9a4f-01"));
+
+        let partuuid = |d: &str| match d {
+            "/dev/vda2" => Some("9A4F-01".to_string()),
+            _ => Some("77e1-02".to_string()),
+        };
+        let devices = |d: &[&str]| d.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let primary = devices(&["/dev/vda2", "/dev/vdb2"]);
+        let mirror = devices(&["/dev/vdb2"]);
+        assert!(is_active("EFI", BootMethod::EFI, None, &mirror, &partuuid));
+        assert!(!is_active(
+            "BIOS",
+            BootMethod::EFI,
+            None,
+            &mirror,
+            &partuuid
+        ));
+        assert!(is_active(
+            "BIOS",
+            BootMethod::BIOS,
+            Some("9a4f-01"),
+            &mirror,
+            &partuuid
+        ));
+        let loader = Some("9a4f-01");
+        assert!(is_active(
+            "EFI",
+            BootMethod::EFI,
+            loader,
+            &primary,
+            &partuuid
+        ));
+        assert!(!is_active(
+            "EFI",
+            BootMethod::EFI,
+            loader,
+            &mirror,
+            &partuuid
+        ));
+        // Devices aren't known if status skipped querying them
+        assert!(is_active("EFI", BootMethod::EFI, loader, &[], &partuuid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_secure_boot() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = td.path().join("efi");
+        if !cfg!(target_arch = "aarch64") {
+            assert_eq!(secure_boot(&efi), None);
+        }
+        std::fs::create_dir_all(efi.join("efivars"))?;
+        assert_eq!(secure_boot(&efi), None);
+        let var = efi.join("efivars").join(SECURE_BOOT_VAR);
+        std::fs::write(&var, &[0x06, 0, 0, 0, 1])?;
+        assert_eq!(secure_boot(&efi), Some(true));
+        std::fs::write(&var, &[0x06, 0, 0, 0, 0])?;
+        assert_eq!(secure_boot(&efi), Some(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_no_state() -> Result<()> {
+        let (td, _) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        assert!(get_saved_state(sysroot)?.is_none());
+        assert!(status(sysroot, &[], true)?.components.is_empty());
+        match status(sysroot, &["EFI".to_string()], true) {
+            Err(BootupdError::ComponentNotInstalled(ref n)) if n == "EFI" => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match validate(sysroot, "EFI") {
+            Err(BootupdError::NoState) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_root_fstype() -> Result<()> {
+        assert_eq!(live_root_fstype(0x794c_7630), Some("overlayfs"));
+        assert_eq!(live_root_fstype(0x7371_7368), Some("squashfs"));
+        assert_eq!(live_root_fstype(0x0102_1994), Some("tmpfs"));
+        // ext4 and xfs are installed roots
+        assert_eq!(live_root_fstype(0xef53), None);
+        assert_eq!(live_root_fstype(0x5846_5342), None);
+        // The magic matches nix's idea of the filesystem type
+        let td = tempfile::tempdir()?;
+        let fstype = nix::sys::statfs::statfs(td.path())?.filesystem_type();
+        let magic = util::fs_magic(td.path())?;
+        assert_eq!(
+            fstype == nix::sys::statfs::TMPFS_MAGIC,
+            live_root_fstype(magic) == Some("tmpfs")
+        );
+        assert!(util::fs_magic(&td.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_root_reason() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let cmdline = "BOOT_IMAGE=/vmlinuz root=UUID=1234 ro";
+        assert_eq!(live_root_reason(td.path(), cmdline), None);
+        assert_eq!(
+            live_root_reason(td.path(), "BOOT_IMAGE=/vmlinuz rd.live.image quiet").as_deref(),
+            Some("the kernel was booted with rd.live.image")
+        );
+        assert_eq!(
+            live_root_reason(
+                td.path(),
+                "coreos.liveiso=fedora-coreos-33 ignition.firstboot"
+            )
+            .as_deref(),
+            Some("the kernel was booted with coreos.liveiso=fedora-coreos-33")
+        );
+        assert!(live_root_reason(td.path(), "root=live:CDLABEL=Fedora").is_some());
+        // Only whole arguments count
+        assert_eq!(live_root_reason(td.path(), "rd.live.imagex"), None);
+        std::fs::create_dir(td.path().join("run"))?;
+        std::fs::write(td.path().join(LIVE_MARKER), "")?;
+        assert_eq!(
+            live_root_reason(td.path(), cmdline).as_deref(),
+            Some("/run/ostree-live exists")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_many() -> Result<()> {
+        let (td, _) = new_sysroot()?;
+        let good = td.path().to_str().expect("utf-8 tempdir").to_string();
+        let unmounted = td.path().join("unmounted");
+        std::fs::create_dir(&unmounted)?;
+        let unmounted = unmounted.to_str().expect("utf-8 tempdir").to_string();
+        let missing = td.path().join("missing").to_str().unwrap().to_string();
+        let r = status_many(&[good.clone(), unmounted.clone(), missing.clone()]);
+        assert_eq!(r.len(), 3);
+        assert!(r[&good].as_ref().expect("status").components.is_empty());
+        match &r[&unmounted] {
+            Err(BootupdError::Failed(msg)) => assert!(msg.contains("is not mounted"), "{}", msg),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(r[&missing].is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_lock_contended() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("run"))?;
+        let _held = acquire_write_lock_timeout(td.path(), Duration::from_secs(0))?;
+        let e = acquire_write_lock_timeout(td.path(), Duration::from_millis(200)).unwrap_err();
+        match e.downcast::<BootupdError>()? {
+            BootupdError::LockContended(Some(pid)) if pid == std::process::id() => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(
+            probe_write_lock(td.path())?,
+            (true, Some(std::process::id()))
+        );
+        drop(_held);
+        assert_eq!(probe_write_lock(td.path())?, (false, None));
+        let _held = acquire_write_lock_timeout(td.path(), Duration::from_secs(0))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limited_until() {
+        let applied = Utc.timestamp(1_600_000_000, 0);
+        let hour = Some(Duration::from_secs(3600));
+        let soon = applied + chrono::Duration::minutes(10);
+        assert_eq!(
+            rate_limited_until(applied, hour, soon),
+            Some(applied + chrono::Duration::hours(1))
+        );
+        let later = applied + chrono::Duration::hours(2);
+        assert_eq!(rate_limited_until(applied, hour, later), None);
+        assert_eq!(rate_limited_until(applied, None, soon), None);
+    }
+
+    #[test]
+    fn test_update_rate_limited() -> Result<()> {
+        let name = "mock-rate-limited";
+        let (td, _d) = mock_sysroot(name)?;
+        let sysroot = td.path().to_str().unwrap();
+        register_mock(
+            name,
+            MockBehavior {
+                update: Some(mock_meta("v2")),
+                parts: vec!["a".into(), "b".into()],
+                ..Default::default()
+            },
+        );
+        let updated = |opts: &UpdateOptions| -> Result<bool> {
+            match update(sysroot, name, opts, &|_| {})? {
+                ComponentUpdateResult::Updated { .. } => Ok(true),
+                ComponentUpdateResult::RateLimited { .. } => Ok(false),
+                r => panic!("unexpected {:?}", r),
+            }
+        };
+        let limited = UpdateOptions {
+            min_interval: Some(3600),
+            ..Default::default()
+        };
+        // Never updated by us, so there's nothing to wait for
+        assert!(updated(&limited)?);
+        modify_mock(name, |b| b.update = Some(mock_meta("v3")));
+        assert!(!updated(&limited)?);
+        let force = UpdateOptions {
+            force: true,
+            ..limited
+        };
+        assert!(!updated(&force)?);
+        let ignore = UpdateOptions {
+            ignore_min_interval: true,
+            ..limited
+        };
+        assert!(updated(&ignore)?);
+
+        // Completing an interrupted update isn't held back
+        modify_mock(name, |b| {
+            b.update = Some(mock_meta("v4"));
+            b.fail_after = Some(1);
+        });
+        assert!(update(sysroot, name, &ignore, &|_| {}).is_err());
+        modify_mock(name, |b| b.fail_after = None);
+        assert!(updated(&limited)?);
+        let state = get_saved_state(sysroot)?.unwrap();
+        assert_eq!(state.installed[name].meta.version, "v4");
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeat() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("run"))?;
+        let hb = HeartbeatWriter::new(td.path(), vec!["EFI".into()]);
+        let r = read_heartbeat(td.path()).expect("heartbeat");
+        assert_eq!(r.pid, std::process::id());
+        assert_eq!(r.components, vec!["EFI"]);
+        drop(hb);
+        assert!(read_heartbeat(td.path()).is_none());
+
+        // A heartbeat left behind, as by a crash, is cleaned up by the next
+        // holder of the lock
+        std::mem::forget(HeartbeatWriter::new(td.path(), vec!["BIOS".into()]));
+        assert!(read_heartbeat(td.path()).is_some());
+        let _held = acquire_write_lock_timeout(td.path(), Duration::from_secs(0))?;
+        assert!(read_heartbeat(td.path()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_lock_serializes_updates() -> Result<()> {
+        const ROUNDS: u32 = 20;
+        let names = ["mock-lock-a", "mock-lock-b"];
+        let (td, d) = new_sysroot()?;
+        std::fs::create_dir(td.path().join("run"))?;
+        let mut state = SavedState::default();
+        for name in &names {
+            state
+                .installed
+                .insert(name.to_string(), mock_installed("0"));
+            register_mock(
+                name,
+                MockBehavior {
+                    parts: vec!["a".into()],
+                    ..Default::default()
+                },
+            );
+        }
+        update_state(&d, &mut state)?;
+        let sysroot_path = td.path().to_str().expect("utf-8 tempdir").to_string();
+        // Each update() reads the state, holds it in memory while the
+        // component runs, then writes it back.  Without the lock, one would
+        // overwrite the other's progress.
+        let threads: Vec<_> = names
+            .iter()
+            .map(|&name| {
+                let sysroot_path = sysroot_path.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    let opts = UpdateOptions::default();
+                    for i in 1..=ROUNDS {
+                        modify_mock(name, |b| b.update = Some(mock_meta(&i.to_string())));
+                        match update(&sysroot_path, name, &opts, &|_| {})? {
+                            ComponentUpdateResult::Updated { previous, new, .. } => {
+                                assert_eq!(previous.version, (i - 1).to_string());
+                                assert_eq!(new.version, i.to_string());
+                            }
+                            r => panic!("unexpected {:?}", r),
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("thread panicked")?;
+        }
+        let state = get_saved_state(&sysroot_path)?.expect("saved state");
+        for name in &names {
+            assert_eq!(state.installed[*name].meta.version, ROUNDS.to_string());
+        }
+        assert_eq!(state.counters.updates_applied, 2 * u64::from(ROUNDS));
+        assert_eq!(state.counters.interrupted_recovered, 0);
+        assert!(state.counters.last_update.is_some());
+        assert_eq!(state.written_by.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert!(state.pending.unwrap_or_default().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_interrupted_progress() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        let mut state = state_with_version("v1");
+        let mut pending = PendingUpdate {
+            meta: state_with_version("v2").installed["EFI"].meta.clone(),
+            completed: ["esp:a", "esp:b", "esp:c"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            total: None,
+            staged: false,
+        };
+        // Without a total, only the version is known
+        state.pending = Some(std::iter::once(("EFI".into(), pending.clone())).collect());
+        update_state(&d, &mut state)?;
+        let s = status(sysroot, &[], true)?;
+        let c = &s.components["EFI"];
+        assert_eq!(c.interrupted.as_ref().expect("interrupted").version, "v2");
+        assert!(c.interrupted_progress.is_none());
+        // There's no ESP to check the installed files on
+        assert!(c.interrupted_bootable.is_none());
+        let v = serde_json::to_value(&s)?;
+        assert_eq!(v["components"]["EFI"]["interrupted"]["version"], "v2");
+        assert!(v["components"]["EFI"]["interrupted-progress"].is_null());
+
+        pending.total = Some(10);
+        state.pending = Some(std::iter::once(("EFI".into(), pending)).collect());
+        update_state(&d, &mut state)?;
+        let s = status(sysroot, &[], true)?;
+        // Replies are sent with bincode; see `model::test::test_reply_encoding()`
+        let s: Status = bincode::deserialize(&bincode::serialize(&s)?)?;
+        assert_eq!(
+            s.components["EFI"].interrupted_progress,
+            Some(UpdateProgress {
+                completed: 3,
+                total: 10
+            })
+        );
+        let v = serde_json::to_value(&s)?;
+        let progress = &v["components"]["EFI"]["interrupted-progress"];
+        assert_eq!(progress["completed"], 3);
+        assert_eq!(progress["total"], 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import_state() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        std::fs::create_dir(td.path().join("run"))?;
+        assert!(matches!(export_state(sysroot), Err(BootupdError::NoState)));
+        update_state(&d, &mut state_with_version("v1"))?;
+        let exported = export_state(sysroot)?;
+        update_state(&d, &mut state_with_version("v2"))?;
+        // There's no ESP here, so the imported EFI inventory has drifted
+        let r = import_state(sysroot, &exported)?;
+        assert!(!r.is_valid());
+        let state = get_saved_state(sysroot)?.expect("state");
+        assert_eq!(state.installed["EFI"].meta.version, "v1");
+        assert!(import_state(sysroot, "{}").is_err());
+        assert!(import_state(sysroot, r#"{"version": 1000, "installed": {}}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_carry_over() {
+        let unidentified = vec!["EFI".to_string(), "BIOS".to_string()];
+        let mut state = SavedState::default();
+        let (carried, lost) = carry_over(&mut state, &unidentified, None);
+        assert!(carried.is_empty());
+        assert_eq!(lost, unidentified);
+        assert!(state.installed.is_empty());
+
+        // BIOS wasn't installed before, so it isn't lost either
+        let previous = state_with_version("v1");
+        let (carried, lost) = carry_over(&mut state, &unidentified, Some(&previous));
+        assert_eq!(carried, vec!["EFI"]);
+        assert!(lost.is_empty());
+        assert_eq!(state.installed["EFI"].meta.version, "v1");
+        assert!(!state.installed.contains_key("BIOS"));
+    }
+
+    #[test]
+    fn test_regenerate_state_unidentified() -> Result<()> {
+        let (td, _) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        std::fs::create_dir(td.path().join("run"))?;
+        let statepath = td.path().join(STATEFILE_DIR).join(STATEFILE_NAME);
+        std::fs::write(&statepath, "{ truncated")?;
+        // Nothing happens without confirmation
+        let e = regenerate_state(sysroot, false, false).unwrap_err();
+        assert!(e.to_string().contains("pass --confirm"), "{}", e);
+        // Nothing is installed here, so the broken state is left alone
+        let r = regenerate_state(sysroot, true, false)?;
+        assert!(matches!(r.previous, PreviousState::Unreadable(_)));
+        assert!(r.components.adopted.is_empty());
+        assert!(!r.components.unidentified.is_empty());
+        assert_eq!(std::fs::read(&statepath)?, b"{ truncated");
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_installed_only() -> Result<()> {
+        let (td, d) = new_sysroot()?;
+        let sysroot = td.path().to_str().expect("utf-8 tempdir");
+        update_state(&d, &mut state_with_version("v1"))?;
+        let s = status(sysroot, &[], false)?;
+        let c = &s.components["EFI"];
+        assert_eq!(c.installed.version, "v1");
+        assert!(c.update.is_none());
+        assert!(matches!(c.updatable, ComponentUpdatable::NotChecked));
+        assert!(c.devices.is_empty());
+        assert!(!c.active);
+        assert_eq!(status_exit_code(&s), libc::EXIT_SUCCESS);
+        // Nothing is reported about the firmware of the host we're running on
+        assert!(s.secure_boot.is_none());
+        assert!(s.boot_method.is_none());
+        assert!(!s.boot_method_unmanaged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_during_updates() -> Result<()> {
+        const ROUNDS: u32 = 200;
+        let (td, d) = new_sysroot()?;
+        let mut state = state_with_version("0");
+        update_state(&d, &mut state)?;
+        let sysroot_path = td.path().to_str().expect("utf-8 tempdir").to_string();
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let sysroot_path = sysroot_path.clone();
+            let done = done.clone();
+            std::thread::spawn(move || -> Result<u32> {
+                let mut last = 0;
+                let mut reads = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    let s = status(&sysroot_path, &[], true)?;
+                    let v: u32 = s.components["EFI"].installed.version.parse()?;
+                    assert!(v >= last, "went back from {} to {}", last, v);
+                    last = v;
+                    reads += 1;
+                }
+                Ok(reads)
+            })
+        };
+        for i in 1..=ROUNDS {
+            update_state(&d, &mut state_with_version(&i.to_string()))?;
+        }
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(reader.join().expect("thread panicked")? > 0);
+        let s = status(&sysroot_path, &[], true)?;
+        assert_eq!(s.components["EFI"].installed.version, ROUNDS.to_string());
+        Ok(())
     }
-    Ok(())
 }
@@ -3,6 +3,12 @@ use crate::ipc::ClientToDaemonConnection;
 use crate::model::Status;
 use anyhow::Result;
 use log::LevelFilter;
+use nix::sys::signal;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
@@ -14,6 +20,21 @@ pub struct CtlCommand {
     #[structopt(short = "v", parse(from_occurrences), global = true)]
     verbosity: u8,
 
+    /// Only print warnings and errors
+    #[structopt(short = "q", long, global = true)]
+    quiet: bool,
+
+    /// Operate on the system mounted at this path, e.g. the target of
+    /// an installer, rather than the booted one.  Required for changes
+    /// when booted from live media.
+    #[structopt(name = "target-sysroot", long = "sysroot", global = true)]
+    pub sysroot: Option<String>,
+
+    /// Handle read-only requests in this process instead of through the
+    /// daemon, e.g. if socket activation is broken
+    #[structopt(long, global = true)]
+    direct: bool,
+
     /// CLI sub-command.
     #[structopt(subcommand)]
     pub cmd: CtlVerb,
@@ -40,10 +61,62 @@ pub enum CtlVerb {
     Backend(CtlBackend),
     #[structopt(name = "status", about = "Show components status")]
     Status(StatusOpts),
-    #[structopt(name = "update", about = "Update all components")]
-    Update,
+    #[structopt(name = "update", about = "Update components")]
+    Update(UpdateOpts),
     #[structopt(name = "validate", about = "Validate system state")]
-    Validate,
+    Validate(ValidateOpts),
+    #[structopt(
+        name = "check",
+        about = "Check installed components without looking for updates"
+    )]
+    Check(ValidateOpts),
+    #[structopt(
+        name = "rollback",
+        about = "Restore the previous version of a component"
+    )]
+    Rollback(RollbackOpts),
+    #[structopt(
+        name = "stage",
+        about = "Stage a component update to be applied at the next boot"
+    )]
+    Stage(StageOpts),
+    #[structopt(name = "repair", about = "Validate components and fix any errors")]
+    Repair(RepairOpts),
+    #[structopt(name = "uninstall", about = "Remove a component and stop managing it")]
+    Uninstall(UninstallOpts),
+    #[structopt(
+        name = "prune",
+        about = "Remove retained payloads of previous versions"
+    )]
+    Prune(PruneOpts),
+    #[structopt(name = "adopt", about = "Start managing an existing installation")]
+    Adopt,
+    #[structopt(
+        name = "regenerate-state",
+        about = "Rebuild lost or corrupted state from the installed files"
+    )]
+    RegenerateState(RegenerateStateOpts),
+    #[structopt(name = "export-state", about = "Save the state to a file, for backup")]
+    ExportState(StateFileOpts),
+    #[structopt(
+        name = "import-state",
+        about = "Replace the state with one saved by export-state"
+    )]
+    ImportState(StateFileOpts),
+    #[structopt(
+        name = "list-components",
+        about = "List components supported on this platform"
+    )]
+    ListComponents,
+    #[structopt(
+        name = "diff",
+        about = "Show the files changed by the available update"
+    )]
+    Diff(DiffOpts),
+    #[structopt(name = "metrics", about = "Show activity counters as JSON")]
+    Metrics,
+    #[structopt(name = "watch", about = "Poll for updates as they become available")]
+    Watch(WatchOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -52,65 +125,453 @@ pub enum CtlBackend {
     Generate(super::bootupd::GenerateOpts),
     #[structopt(name = "install", setting = AppSettings::Hidden)]
     Install(super::bootupd::InstallOpts),
+    #[structopt(name = "ensure-installed", setting = AppSettings::Hidden)]
+    EnsureInstalled(super::bootupd::InstallOpts),
 }
 
 #[derive(Debug, StructOpt)]
 pub struct StatusOpts {
-    // Output JSON
+    /// Output JSON
+    #[structopt(long)]
+    json: bool,
+    /// Exit with 2 if an update is available for any component
+    #[structopt(long)]
+    exit_code: bool,
+    /// Only show the named component; may be repeated
+    #[structopt(long, number_of_values = 1)]
+    component: Vec<String>,
+    /// Only show what's installed, without looking for updates
+    #[structopt(long, conflicts_with = "exit-code")]
+    installed_only: bool,
+    /// Also probe the remote update source configured in
+    /// /etc/bootupd/remote.json
+    #[structopt(long, conflicts_with = "installed-only")]
+    check_remote: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct UpdateOpts {
+    /// Only update the named component
+    #[structopt(long)]
+    component: Option<String>,
+    /// Only print what would be updated
+    #[structopt(long)]
+    dry_run: bool,
+    /// Reinstall components even if already at the latest version
+    #[structopt(long)]
+    force: bool,
+    /// Update components even if updated too recently, as set by
+    /// BOOTUPD_MIN_UPDATE_INTERVAL_<COMPONENT>
+    #[structopt(long)]
+    ignore_min_interval: bool,
+    /// Don't update components updated less than this many seconds ago,
+    /// overriding BOOTUPD_MIN_UPDATE_INTERVAL_<COMPONENT>; 0 means no limit
+    #[structopt(long, value_name = "SECS", conflicts_with = "ignore-min-interval")]
+    min_interval: Option<u64>,
+    /// Permit updating to a payload older than the installed version
+    #[structopt(long)]
+    allow_downgrade: bool,
+    /// Apply updates known not to work on this system, e.g. with its
+    /// firmware
+    #[structopt(long)]
+    ignore_preconditions: bool,
+    /// Update components which don't share a disk concurrently
+    #[structopt(long)]
+    parallel: bool,
+    /// Update at most this many components at once with --parallel;
+    /// defaults to one per disk
+    #[structopt(long, value_name = "N", requires = "parallel")]
+    max_concurrency: Option<usize>,
+    /// Abort an update which takes longer than this many seconds
+    #[structopt(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    /// Output a JSON summary of the update
     #[structopt(long)]
     json: bool,
+    /// Don't ask for confirmation when run interactively
+    #[structopt(long, short = "y")]
+    assume_yes: bool,
+    /// Update all components or none, rolling back on failure; those which
+    /// can't be staged, e.g. BIOS, are updated afterwards
+    #[structopt(long, conflicts_with_all = &["component", "parallel"])]
+    transactional: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RollbackOpts {
+    /// Component to roll back
+    component: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StageOpts {
+    /// Component to stage an update of
+    component: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ValidateOpts {
+    /// Output JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffOpts {
+    /// Component to compare
+    component: String,
+
+    /// Output JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct UninstallOpts {
+    /// Component to uninstall
+    component: String,
+
+    /// Uninstall even if the component booted the running system
+    #[structopt(long)]
+    force: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WatchOpts {
+    /// Seconds between polls
+    #[structopt(long, default_value = "60")]
+    interval: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PruneOpts {
+    /// Generations of retained payloads to keep for each component; the
+    /// newest, needed to roll back the current version, is always kept
+    #[structopt(long, default_value = "1")]
+    keep: u32,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StateFileOpts {
+    /// File holding the exported state
+    path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RegenerateStateOpts {
+    /// Overwrite any existing state; required
+    #[structopt(long)]
+    confirm: bool,
+    /// Write the state even if some components couldn't be identified,
+    /// leaving them unmanaged
+    #[structopt(long)]
+    force: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RepairOpts {
+    /// Only repair the named component
+    #[structopt(long)]
+    component: Option<String>,
+}
+
+/// Set when `watch` receives SIGINT
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// How often `watch` checks for SIGINT between polls
+const WATCH_SLEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+extern "C" fn handle_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// The system a command operates on, and how to reach the daemon
+struct Target<'a> {
+    sysroot: &'a str,
+    direct: bool,
+}
+
+impl CtlVerb {
+    /// Whether the verb may change the target system
+    fn modifies_system(&self) -> bool {
+        match self {
+            CtlVerb::Update(opts) => !opts.dry_run,
+            CtlVerb::Rollback(_)
+            | CtlVerb::Stage(_)
+            | CtlVerb::Repair(_)
+            | CtlVerb::Uninstall(_)
+            | CtlVerb::Prune(_)
+            | CtlVerb::Adopt
+            | CtlVerb::RegenerateState(_)
+            | CtlVerb::ImportState(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl CtlCommand {
-    /// Run CLI application.
-    pub fn run(self) -> Result<()> {
-        match self.cmd {
-            CtlVerb::Status(opts) => Self::run_status(opts),
-            CtlVerb::Update => Self::run_update(),
-            CtlVerb::Validate => Self::run_validate(),
+    /// The system to operate on
+    pub fn sysroot(&self) -> &str {
+        self.sysroot.as_deref().unwrap_or("/")
+    }
+
+    /// Run CLI application, returning the process exit code.
+    pub fn run(self) -> Result<i32> {
+        // Changing the booted system is only the default if it's installed
+        if self.sysroot.is_none() && self.cmd.modifies_system() {
+            bootupd::check_not_live_root()?;
+        }
+        let sysroot = self.sysroot().to_string();
+        let target = Target {
+            sysroot: &sysroot,
+            direct: self.direct,
+        };
+        let r = match self.cmd {
+            CtlVerb::Status(opts) => return Self::run_status(opts, &target),
+            CtlVerb::Update(opts) => {
+                Self::run_update(opts, &target, self.quiet, self.verbosity > 0)
+            }
+            CtlVerb::Validate(opts) => Self::run_validate(opts, &target, self.quiet),
+            CtlVerb::Check(opts) => Self::run_check(opts, &target),
+            CtlVerb::Rollback(opts) => Self::run_rollback(opts, &target),
+            CtlVerb::Stage(opts) => Self::run_stage(opts, &target),
+            CtlVerb::Repair(opts) => Self::run_repair(opts, &target),
+            CtlVerb::Uninstall(opts) => Self::run_uninstall(opts, &target),
+            CtlVerb::Prune(opts) => Self::run_prune(opts, &target),
+            CtlVerb::Adopt => Self::run_adopt(&target),
+            CtlVerb::RegenerateState(opts) => Self::run_regenerate_state(opts, &target),
+            CtlVerb::ExportState(opts) => Self::run_export_state(opts, &target),
+            CtlVerb::ImportState(opts) => Self::run_import_state(opts, &target),
+            CtlVerb::ListComponents => Self::run_list_components(&target),
+            CtlVerb::Diff(opts) => Self::run_diff(opts, &target),
+            CtlVerb::Metrics => Self::run_metrics(&target),
+            CtlVerb::Watch(opts) => Self::run_watch(opts, &target),
             CtlVerb::Backend(CtlBackend::Generate(opts)) => {
                 super::bootupd::DCommand::run_generate_meta(opts)
             }
             CtlVerb::Backend(CtlBackend::Install(opts)) => {
                 super::bootupd::DCommand::run_install(opts)
             }
+            CtlVerb::Backend(CtlBackend::EnsureInstalled(opts)) => {
+                super::bootupd::DCommand::run_ensure_installed(opts)
+            }
+        };
+        r.map(|_| libc::EXIT_SUCCESS)
+    }
+
+    /// Connect to the daemon, or not for `--direct`, and have it operate
+    /// on the target sysroot.
+    fn connect(target: &Target) -> Result<ClientToDaemonConnection> {
+        let mut client = if target.direct {
+            ClientToDaemonConnection::new_direct()
+        } else {
+            ClientToDaemonConnection::new()
+        };
+        client.connect()?;
+        if target.sysroot != "/" {
+            bootupd::client_set_sysroot(&mut client, target.sysroot)?;
         }
+        Ok(client)
     }
 
     /// Runner for `status` verb.
-    fn run_status(opts: StatusOpts) -> Result<()> {
-        let mut client = ClientToDaemonConnection::new();
-        client.connect()?;
+    fn run_status(opts: StatusOpts, target: &Target) -> Result<i32> {
+        let mut client = Self::connect(target)?;
 
-        let r: Status = client.send(&bootupd::ClientRequest::Status)?;
+        let r: Status = if opts.installed_only {
+            client.send(&bootupd::ClientRequest::StatusInstalled {
+                components: opts.component.clone(),
+            })?
+        } else if opts.check_remote {
+            client.send(&bootupd::ClientRequest::StatusRemote {
+                components: opts.component.clone(),
+            })?
+        } else {
+            client.send(&bootupd::ClientRequest::Status {
+                components: opts.component.clone(),
+            })?
+        };
         if opts.json {
             let stdout = std::io::stdout();
             let mut stdout = stdout.lock();
             serde_json::to_writer_pretty(&mut stdout, &r)?;
+            stdout.write_all(b"\n")?;
         } else {
-            bootupd::print_status(&r);
+            bootupd::print_status(&r, &opts.component);
         }
 
         client.shutdown()?;
-        Ok(())
+        if opts.exit_code {
+            Ok(bootupd::status_exit_code(&r))
+        } else {
+            Ok(libc::EXIT_SUCCESS)
+        }
     }
 
     /// Runner for `update` verb.
-    fn run_update() -> Result<()> {
-        let mut client = ClientToDaemonConnection::new();
-        client.connect()?;
+    /// With `verbose`, the files changed by each update are listed.
+    fn run_update(opts: UpdateOpts, target: &Target, quiet: bool, verbose: bool) -> Result<()> {
+        let mut client = Self::connect(target)?;
 
-        bootupd::client_run_update(&mut client)?;
+        bootupd::client_run_update(
+            &mut client,
+            target.sysroot,
+            opts.component.as_deref(),
+            bootupd::UpdateOptions {
+                dry_run: opts.dry_run,
+                force: opts.force,
+                allow_downgrade: opts.allow_downgrade,
+                timeout: opts.timeout,
+                ignore_preconditions: opts.ignore_preconditions,
+                ignore_min_interval: opts.ignore_min_interval,
+                min_interval: opts.min_interval,
+            },
+            bootupd::ClientUpdateOptions {
+                parallel: opts.parallel,
+                json: opts.json,
+                quiet,
+                verbose,
+                assume_yes: opts.assume_yes,
+                transactional: opts.transactional,
+                max_concurrency: opts.max_concurrency,
+            },
+        )?;
 
         client.shutdown()?;
         Ok(())
     }
 
     /// Runner for `validate` verb.
-    fn run_validate() -> Result<()> {
-        let mut client = ClientToDaemonConnection::new();
-        client.connect()?;
-        bootupd::client_run_validate(&mut client)?;
+    fn run_validate(opts: ValidateOpts, target: &Target, quiet: bool) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_validate(&mut client, quiet, opts.json)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `check` verb.
+    fn run_check(opts: ValidateOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_check(&mut client, opts.json)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `rollback` verb.
+    fn run_rollback(opts: RollbackOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_rollback(&mut client, &opts.component)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `stage` verb.
+    fn run_stage(opts: StageOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_stage(&mut client, &opts.component)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `uninstall` verb.
+    fn run_uninstall(opts: UninstallOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_uninstall(&mut client, &opts.component, opts.force)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `prune` verb.
+    fn run_prune(opts: PruneOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_prune(&mut client, opts.keep)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `repair` verb.
+    fn run_repair(opts: RepairOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_repair(&mut client, opts.component.as_deref())?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `list-components` verb.
+    fn run_list_components(target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_list_components(&mut client)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `watch` verb.  Each poll uses a new connection, so that
+    /// the daemon is free to serve other clients in between.
+    fn run_watch(opts: WatchOpts, target: &Target) -> Result<()> {
+        let action = signal::SigAction::new(
+            signal::SigHandler::Handler(handle_sigint),
+            signal::SaFlags::SA_RESTART,
+            signal::SigSet::empty(),
+        );
+        // Safety: the handler only stores to an atomic
+        unsafe { signal::sigaction(signal::Signal::SIGINT, &action)? };
+        let interval = Duration::from_secs(opts.interval);
+        let mut seen = BTreeMap::new();
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            let mut client = Self::connect(target)?;
+            bootupd::client_poll_updates(&mut client, &mut seen)?;
+            client.shutdown()?;
+            let start = Instant::now();
+            while start.elapsed() < interval && !INTERRUPTED.load(Ordering::SeqCst) {
+                std::thread::sleep(WATCH_SLEEP_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runner for `metrics` verb.
+    fn run_metrics(target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_metrics(&mut client)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `diff` verb.
+    fn run_diff(opts: DiffOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_diff(&mut client, &opts.component, opts.json)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `adopt` verb.
+    fn run_adopt(target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_adopt(&mut client)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `export-state` verb.
+    fn run_export_state(opts: StateFileOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_export_state(&mut client, &opts.path)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `import-state` verb.
+    fn run_import_state(opts: StateFileOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_import_state(&mut client, &opts.path)?;
+        client.shutdown()?;
+        Ok(())
+    }
+
+    /// Runner for `regenerate-state` verb.
+    fn run_regenerate_state(opts: RegenerateStateOpts, target: &Target) -> Result<()> {
+        let mut client = Self::connect(target)?;
+        bootupd::client_run_regenerate_state(&mut client, opts.confirm, opts.force)?;
         client.shutdown()?;
         Ok(())
     }
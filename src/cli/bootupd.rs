@@ -1,6 +1,8 @@
 use crate::bootupd;
 use anyhow::{Context, Result};
 use log::LevelFilter;
+use std::io::Write;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 /// `bootupd` sub-commands.
@@ -11,6 +13,11 @@ pub struct DCommand {
     #[structopt(short = "v", parse(from_occurrences), global = true)]
     verbosity: u8,
 
+    /// Load component descriptors from this directory, instead of
+    /// /usr/lib/bootupd/components.d in the root being operated on
+    #[structopt(long, global = true)]
+    components_dir: Option<PathBuf>,
+
     /// CLI sub-command.
     #[structopt(subcommand)]
     pub cmd: DVerb,
@@ -37,6 +44,21 @@ pub enum DVerb {
     GenerateUpdateMetadata(GenerateOpts),
     #[structopt(name = "install", about = "Install components")]
     Install(InstallOpts),
+    #[structopt(
+        name = "ensure-installed",
+        about = "Install components unless already installed"
+    )]
+    EnsureInstalled(InstallOpts),
+    #[structopt(
+        name = "apply-staged",
+        about = "Apply staged component updates; run early at boot"
+    )]
+    ApplyStaged,
+    #[structopt(
+        name = "inspect",
+        about = "Show the status of other roots, e.g. mounted deployments"
+    )]
+    Inspect(InspectOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -44,36 +66,186 @@ pub struct InstallOpts {
     /// Source root
     #[structopt(long, default_value = "/")]
     src_root: String,
-    /// Target root
-    dest_root: String,
+    /// Target root, e.g. the mounted root of a system being installed;
+    /// must contain a `boot` directory
+    #[structopt(long = "dest-root", name = "dest-root")]
+    dest_root: Option<String>,
+    /// Target root, as an alternative to --dest-root
+    #[structopt(
+        name = "DEST_ROOT",
+        conflicts_with = "dest-root",
+        required_unless = "dest-root"
+    )]
+    dest_root_arg: Option<String>,
+    /// Only install the named component; may be repeated.  Other
+    /// components are left unmanaged.
+    #[structopt(long, number_of_values = 1)]
+    component: Vec<String>,
+    /// Write the BIOS boot code to this disk instead of the one holding
+    /// `/boot`; later updates use the same disk
+    #[structopt(long)]
+    target_disk: Option<String>,
+    /// Don't check the installed files afterward
+    #[structopt(long)]
+    skip_verify: bool,
+    /// Print each file installed
+    #[structopt(long)]
+    verbose: bool,
+}
+
+impl InstallOpts {
+    fn dest_root(&self) -> &str {
+        // structopt requires one of the two
+        self.dest_root
+            .as_deref()
+            .or(self.dest_root_arg.as_deref())
+            .expect("target root")
+    }
 }
 
 #[derive(Debug, StructOpt)]
 pub struct GenerateOpts {
     /// Physical root mountpoint
     sysroot: String,
+    /// Take update payloads from this root, e.g. a mounted container
+    /// image, instead of the sysroot
+    #[structopt(long)]
+    src_root: Option<String>,
+    /// OS version to record as the source of the payloads, instead of
+    /// the one from os-release
+    #[structopt(long)]
+    os_version: Option<String>,
+    /// Print the generated metadata and file inventories as JSON
+    #[structopt(long)]
+    print_metadata: bool,
+    /// Generate metadata for the components of this architecture, e.g.
+    /// `aarch64`, instead of the host's
+    #[structopt(long)]
+    arch: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InspectOpts {
+    /// Roots to inspect
+    #[structopt(required = true)]
+    sysroots: Vec<String>,
+    /// Output JSON, mapping each root to its status or error
+    #[structopt(long)]
+    json: bool,
 }
 
 impl DCommand {
     /// Run CLI application.
     pub fn run(self) -> Result<()> {
+        if let Some(dir) = self.components_dir.as_ref() {
+            crate::filecopy::set_components_dir(dir);
+        }
         match self.cmd {
             DVerb::Daemon => crate::daemon::run(),
             DVerb::Install(opts) => Self::run_install(opts),
+            DVerb::EnsureInstalled(opts) => Self::run_ensure_installed(opts),
             DVerb::GenerateUpdateMetadata(opts) => Self::run_generate_meta(opts),
+            DVerb::ApplyStaged => Self::run_apply_staged(),
+            DVerb::Inspect(opts) => Self::run_inspect(opts),
         }
     }
 
     /// Runner for `generate-install-metadata` verb.
     pub(crate) fn run_generate_meta(opts: GenerateOpts) -> Result<()> {
-        bootupd::generate_update_metadata(&opts.sysroot).context("generating metadata failed")?;
+        let generated = bootupd::generate_update_metadata(
+            &opts.sysroot,
+            opts.src_root.as_deref(),
+            opts.os_version.as_deref(),
+            opts.arch.as_deref(),
+        )
+        .context("generating metadata failed")?;
+        if opts.print_metadata {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            serde_json::to_writer_pretty(&mut stdout, &generated)?;
+            stdout.write_all(b"\n")?;
+        } else {
+            for (name, g) in generated.iter() {
+                println!("Generated update layout for {}: {}", name, g.meta.version);
+            }
+        }
         Ok(())
     }
 
     /// Runner for `install` verb.
     pub(crate) fn run_install(opts: InstallOpts) -> Result<()> {
-        bootupd::install(&opts.src_root, &opts.dest_root)
-            .context("boot data installation failed")?;
+        bootupd::install(
+            &opts.src_root,
+            opts.dest_root(),
+            &opts.component,
+            opts.target_disk.as_deref(),
+            opts.verbose,
+            !opts.skip_verify,
+        )
+        .context("boot data installation failed")?;
+        Ok(())
+    }
+
+    /// Runner for `apply-staged` verb.
+    pub(crate) fn run_apply_staged() -> Result<()> {
+        let applied = bootupd::apply_staged("/").context("applying staged updates failed")?;
+        for (name, meta) in applied.iter() {
+            println!("Applied staged {}: {}", name, meta.version);
+        }
+        Ok(())
+    }
+
+    /// Runner for `inspect` verb.
+    pub(crate) fn run_inspect(opts: InspectOpts) -> Result<()> {
+        let results = bootupd::status_many(&opts.sysroots);
+        if opts.json {
+            let out: serde_json::Map<_, _> = results
+                .iter()
+                .map(|(root, r)| {
+                    let v = match r {
+                        Ok(status) => serde_json::json!({ "status": status }),
+                        Err(e) => serde_json::json!({ "error": e.to_string() }),
+                    };
+                    (root.clone(), v)
+                })
+                .collect();
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            serde_json::to_writer_pretty(&mut stdout, &out)?;
+            stdout.write_all(b"\n")?;
+        } else {
+            for (root, r) in results.iter() {
+                println!("Root {}", root);
+                match r {
+                    Ok(status) => bootupd::print_status(status, &[]),
+                    Err(e) => println!("  Error: {}", e),
+                }
+            }
+        }
+        let failed = results.values().filter(|r| r.is_err()).count();
+        if failed > 0 {
+            anyhow::bail!("Failed to inspect {} of {} roots", failed, results.len());
+        }
+        Ok(())
+    }
+
+    /// Runner for `ensure-installed` verb.
+    pub(crate) fn run_ensure_installed(opts: InstallOpts) -> Result<()> {
+        let dest_root = opts.dest_root();
+        let installed = bootupd::ensure_installed(
+            &opts.src_root,
+            dest_root,
+            &opts.component,
+            opts.target_disk.as_deref(),
+            opts.verbose,
+            !opts.skip_verify,
+        )
+        .context("boot data installation failed")?;
+        if installed {
+            println!("Installed components in {}", dest_root);
+        } else {
+            println!("Found existing installation in {}", dest_root);
+        }
         Ok(())
     }
 }
@@ -31,10 +31,11 @@ impl MultiCall {
         }
     }
 
-    pub fn run(self) -> Result<()> {
+    /// Run the CLI, returning the process exit code.
+    pub fn run(self) -> Result<i32> {
         match self {
             MultiCall::Ctl(ctl_cmd) => ctl_cmd.run(),
-            MultiCall::D(d_cmd) => d_cmd.run(),
+            MultiCall::D(d_cmd) => d_cmd.run().map(|_| libc::EXIT_SUCCESS),
         }
     }
 
@@ -79,6 +80,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sysroot() {
+        let argv = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        match MultiCall::from_args(argv(&["bootupctl", "status"])) {
+            MultiCall::Ctl(cmd) => {
+                assert_eq!(cmd.sysroot(), "/");
+                assert!(cmd.sysroot.is_none());
+            }
+            MultiCall::D(cmd) => panic!(cmd),
+        };
+        match MultiCall::from_args(argv(&["bootupctl", "update", "--sysroot", "/mnt"])) {
+            MultiCall::Ctl(cmd) => assert_eq!(cmd.sysroot(), "/mnt"),
+            MultiCall::D(cmd) => panic!(cmd),
+        };
+        // Distinct from the positional sysroot of the backend verbs
+        match MultiCall::from_args(argv(&[
+            "bootupctl",
+            "backend",
+            "generate-update-metadata",
+            "/sysroot",
+        ])) {
+            MultiCall::Ctl(cmd) => assert_eq!(cmd.sysroot(), "/"),
+            MultiCall::D(cmd) => panic!(cmd),
+        };
+    }
+
     #[test]
     fn test_verbosity() {
         let default = MultiCall::from_args(vec!["bootupd".to_string(), "daemon".to_string()]);
@@ -4,26 +4,168 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::Write as IoWrite;
 use std::path::{Path, PathBuf};
 
 use crate::model::*;
+use crate::payload::PayloadSource;
 
 #[serde(rename_all = "kebab-case")]
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum ValidationResult {
     Valid,
-    Errors(Vec<String>),
+    Errors(Vec<ValidationError>),
+}
+
+/// The kind of problem found by validating a component
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ValidationErrorKind {
+    /// A tracked file is missing
+    Missing,
+    /// A tracked file's content doesn't match its recorded digest
+    ContentMismatch,
+    /// A file not tracked by the component sits among its files
+    Extra,
+    /// A boot binary is unsigned while Secure Boot is enforcing
+    Unsigned,
+    /// Anything not about a single file, e.g. the filesystem or firmware
+    Other,
+}
+
+/// A problem found by validating a component.  `Display` gives the
+/// one-line description shown to users.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ValidationError {
+    /// Name of the component
+    pub(crate) component: String,
+    pub(crate) kind: ValidationErrorKind,
+    /// The file concerned, relative to the component's directory
+    pub(crate) path: Option<String>,
+    /// Where `path` is, if not in the component's primary location
+    /// (e.g. a mirrored ESP)
+    pub(crate) location: Option<String>,
+    /// Digest recorded for `path` when it was installed
+    pub(crate) expected: Option<String>,
+    /// Digest of `path` as found
+    pub(crate) actual: Option<String>,
+    /// Description replacing the one derived from `kind` and `path`
+    pub(crate) detail: Option<String>,
+}
+
+impl ValidationError {
+    /// An error of `kind` about the file `path` of `component`
+    pub(crate) fn file(component: &str, kind: ValidationErrorKind, path: &str) -> Self {
+        Self {
+            component: component.to_string(),
+            kind,
+            path: Some(path.to_string()),
+            location: None,
+            expected: None,
+            actual: None,
+            detail: None,
+        }
+    }
+
+    /// An error not about a single file, described by `detail`
+    pub(crate) fn other(component: &str, detail: impl Into<String>) -> Self {
+        Self {
+            component: component.to_string(),
+            kind: ValidationErrorKind::Other,
+            path: None,
+            location: None,
+            expected: None,
+            actual: None,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(detail) = self.detail.as_ref() {
+            return write!(f, "{}", detail);
+        }
+        let label = match self.kind {
+            ValidationErrorKind::Missing => "Removed",
+            ValidationErrorKind::ContentMismatch => "Changed",
+            ValidationErrorKind::Extra => "Untracked",
+            ValidationErrorKind::Unsigned => "Unsigned",
+            ValidationErrorKind::Other => "Invalid",
+        };
+        write!(f, "{}: ", label)?;
+        if let Some(location) = self.location.as_ref() {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}", self.path.as_deref().unwrap_or(&self.component))
+    }
+}
+
+/// Join the descriptions of `errs` with `sep`
+pub(crate) fn describe_errors(errs: &[ValidationError], sep: &str) -> String {
+    errs.iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// Outcome of repairing a component which failed validation
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RepairResult {
+    /// Problems which were fixed
+    pub(crate) repaired: Vec<String>,
+    /// Problems which could not be fixed automatically
+    pub(crate) unrepaired: Vec<String>,
+}
+
+/// Outcome of pruning the retained payloads of a component
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PruneResult {
+    /// Retained payloads which were removed
+    pub(crate) pruned: Vec<String>,
+    /// Bytes freed by removing them
+    pub(crate) freed: u64,
+    /// Set if the payload retained for rolling back is missing, so the
+    /// saved state should no longer offer a rollback
+    pub(crate) previous_missing: bool,
+}
+
+/// Returned by `Component::priority` unless overridden
+pub(crate) const DEFAULT_PRIORITY: u32 = 100;
+
+/// Passed to `Component::run_update`
+pub(crate) struct UpdateContext<'a> {
+    /// Receives human readable status messages for long-running steps
+    pub(crate) progress: &'a dyn Fn(&str),
+    /// Component-specific identifiers of the parts of this update already
+    /// completed by an interrupted run, which may be skipped
+    pub(crate) completed: &'a BTreeSet<String>,
+    /// Receives the identifier of each part of the update once it has been
+    /// durably written, so that an interruption can be resumed
+    pub(crate) mark_completed: &'a dyn Fn(&str),
+    /// Receives the number of parts in the update, counting those in
+    /// `completed`, once the component has worked out what to write
+    pub(crate) planned: &'a dyn Fn(usize),
+    /// Receives each file (or device) written or removed by this run of
+    /// the update, for reporting
+    pub(crate) changed: &'a dyn Fn(&str),
+    /// Returns true once the update has been given up on, e.g. for taking
+    /// too long; components should then stop before the next part
+    pub(crate) cancelled: &'a dyn Fn() -> bool,
 }
 
 /// A component along with a possible update
 pub(crate) trait Component {
     /// Returns the name of the component; this will be used for serialization
     /// and should remain stable.
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
 
     /// Implementation of `bootupd install` for a given component.  This should
     /// gather data (or run binaries) from the source root, and install them
@@ -31,31 +173,192 @@ pub(crate) trait Component {
     /// are mounted at the expected place.  For operations that require a block device instead
     /// of a filesystem root, the component should query the mount point to
     /// determine the block device.
-    /// This will be run during a disk image build process.
-    fn install(&self, src_root: &str, dest_root: &str) -> Result<InstalledContent>;
+    /// This will be run during a disk image build process.  With `verbose`,
+    /// each file or device written should be printed.
+    fn install(&self, src_root: &str, dest_root: &str, verbose: bool) -> Result<InstalledContent>;
 
     /// Implementation of `bootupd generate-update-metadata` for a given component.
     /// This expects to be run during an "image update build" process.  For CoreOS
     /// this is an `rpm-ostree compose tree` for example.  For a dual-partition
     /// style updater, this would be run as part of a postprocessing step
     /// while the filesystem for the partition is mounted.
-    fn generate_update_metadata(&self, sysroot: &str) -> Result<ContentMetadata>;
+    /// The payload is taken from `source`, which may be distinct from
+    /// `sysroot` e.g. for a mounted container image; `os_version` is
+    /// recorded in the metadata as its source.
+    fn generate_update_metadata(
+        &self,
+        sysroot: &str,
+        source: &dyn PayloadSource,
+        os_version: Option<&str>,
+    ) -> Result<ContentMetadata>;
 
     /// Used on the client to query for an update cached in the current booted OS.
     fn query_update(&self) -> Result<Option<ContentMetadata>>;
 
-    /// Used on the client to run an update.
-    fn run_update(&self, current: &InstalledContent) -> Result<InstalledContent>;
+    /// Whether `to` is a newer version of this component's content than
+    /// `from`.  By default the newer build timestamp wins; components whose
+    /// versions carry an ordering of their own should compare those instead.
+    fn can_upgrade(&self, from: &ContentMetadata, to: &ContentMetadata) -> bool {
+        from.can_upgrade_to(to)
+    }
+
+    /// Used on the client to list the files of the cached update, for
+    /// components which track a file inventory.
+    fn query_update_filetree(&self) -> Result<Option<crate::filetree::FileTree>> {
+        Ok(None)
+    }
+
+    /// Used on the client to find the block devices holding `current`,
+    /// for display.  This runs on every status query, so it mustn't mount
+    /// anything: report what's mounted or recorded in `current`.
+    fn query_devices(&self, _current: &InstalledContent) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Used on the client to report the space on the filesystem holding
+    /// `current`, for display.  Components which aren't stored in a
+    /// filesystem, or whose filesystem isn't mounted, return `None`.
+    fn query_space(&self, _current: &InstalledContent) -> Result<Option<SpaceUsage>> {
+        Ok(None)
+    }
+
+    /// Components are updated in increasing order of priority, e.g. so
+    /// that a loader is in place before the configuration which needs it.
+    /// Components of equal priority are updated in name order.
+    fn priority(&self) -> u32 {
+        DEFAULT_PRIORITY
+    }
+
+    /// Identifies the resource (typically a disk) modified by `run_update`
+    /// of `current`; updates of components sharing a key are never run
+    /// concurrently.
+    fn resource_key(&self, _current: &InstalledContent) -> Result<String> {
+        Ok(self.name().to_string())
+    }
+
+    /// Used on the client before updating `current` to `update`, which
+    /// doesn't need to have been verified yet.  Returns why the update is
+    /// known not to work on this system, e.g. with its firmware, if so.
+    fn check_precondition(
+        &self,
+        _current: &InstalledContent,
+        _update: &ContentMetadata,
+    ) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Used on the client to run an update, resuming from `ctx.completed`
+    /// if a previous attempt to apply the same payload was interrupted.
+    fn run_update(
+        &self,
+        current: &InstalledContent,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent>;
+
+    /// Whether `stage` is supported, so that the component can take part in
+    /// a transactional update.
+    fn can_stage(&self) -> bool {
+        false
+    }
+
+    /// Used on the client to copy the available update alongside `current`
+    /// without applying it, so that `apply_staged` can swap it in before
+    /// anything uses it, e.g. early at the next boot.
+    fn stage(
+        &self,
+        _current: &InstalledContent,
+        _progress: &dyn Fn(&str),
+    ) -> Result<ContentMetadata> {
+        anyhow::bail!("Staging is not supported for component {}", self.name())
+    }
+
+    /// Used on the client to apply an update previously staged by `stage`,
+    /// resuming from `ctx.completed` as for `run_update`.
+    fn apply_staged(
+        &self,
+        _current: &InstalledContent,
+        _staged: &ContentMetadata,
+        _ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        anyhow::bail!("Staging is not supported for component {}", self.name())
+    }
+
+    /// Used on the client to remove any staged update, once it has been
+    /// applied into `current`.
+    fn discard_staged(&self, _current: &InstalledContent) -> Result<()> {
+        Ok(())
+    }
 
     /// Used on the client to validate an installed version.
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult>;
+
+    /// Used by `install` to check what was just written for `current`.
+    /// Components whose validation also covers the state of the booted
+    /// system, e.g. firmware settings, should limit this to their content.
+    fn verify_install(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        self.validate(current)
+    }
+
+    /// Used on the client to start managing a pre-existing installation; returns
+    /// `None` if the installed content can't be matched to a known version.
+    fn adopt(&self) -> Result<Option<InstalledContent>> {
+        Ok(None)
+    }
+
+    /// Used on the client to restore `previous`, whose payload was retained
+    /// by `run_update`.
+    fn rollback(
+        &self,
+        _current: &InstalledContent,
+        _previous: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        anyhow::bail!("Rollback is not supported for component {}", self.name())
+    }
+
+    /// Used on the client to restore content which has drifted from `current`,
+    /// using the available update payload where it matches what was installed.
+    fn repair(
+        &self,
+        _current: &InstalledContent,
+        _progress: &dyn Fn(&str),
+    ) -> Result<RepairResult> {
+        anyhow::bail!("Repair is not supported for component {}", self.name())
+    }
+
+    /// Used on the client to remove retained payloads beyond the newest
+    /// `keep` generations.  The newest is needed to roll back to `previous`
+    /// and is always kept; without `previous`, all of them are removed.
+    fn prune(
+        &self,
+        _current: &InstalledContent,
+        _previous: Option<&InstalledContent>,
+        _keep: usize,
+    ) -> Result<PruneResult> {
+        Ok(PruneResult::default())
+    }
+
+    /// Used on the client to remove the files placed for `current`.
+    fn uninstall(&self, _current: &InstalledContent) -> Result<()> {
+        anyhow::bail!("Uninstall is not supported for component {}", self.name())
+    }
 }
 
-/// Given a component name, create an implementation.
-pub(crate) fn new_from_name(name: &str) -> Result<Box<dyn Component>> {
+/// Given a component name, create an implementation managing the system
+/// at `sysroot`.
+pub(crate) fn new_from_name(sysroot: &str, name: &str) -> Result<Box<dyn Component>> {
     let r: Box<dyn Component> = match name {
-        "EFI" => Box::new(crate::efi::EFI::default()),
-        _ => anyhow::bail!("No component {}", name),
+        "EFI" => Box::new(crate::efi::EFI::new(sysroot)),
+        #[cfg(target_arch = "x86_64")]
+        "BIOS" => Box::new(crate::bios::BIOS::new(sysroot)),
+        #[cfg(test)]
+        n if n.starts_with(crate::testutil::MOCK_PREFIX) => {
+            Box::new(crate::testutil::MockComponent::new(n)?)
+        }
+        _ => {
+            let d = crate::filecopy::load_descriptor(sysroot, name)?
+                .ok_or_else(|| anyhow::anyhow!("No component {}", name))?;
+            Box::new(crate::filecopy::FileCopy::new(sysroot, d))
+        }
     };
     Ok(r)
 }
@@ -102,3 +405,28 @@ pub(crate) fn get_component_update(
     let u = serde_json::from_reader(&mut f)?;
     Ok(Some(u))
 }
+
+/// Like `get_component_update`, but if signing keys are configured in
+/// `signature::KEYS_DIR` the metadata must carry a valid detached
+/// signature.  Used before applying an update.
+pub(crate) fn get_verified_component_update(
+    sysroot: &str,
+    component: &dyn Component,
+) -> Result<Option<ContentMetadata>> {
+    let metap = component_update_metapath(sysroot, component);
+    if !metap.exists() {
+        return Ok(None);
+    }
+    // Parse exactly the bytes that were verified
+    let data = std::fs::read(&metap).with_context(|| format!("reading {:?}", metap))?;
+    let keys = crate::signature::load_keys(sysroot)?;
+    if !keys.is_empty() {
+        let mut sigp = metap.clone().into_os_string();
+        sigp.push(crate::signature::SIGNATURE_SUFFIX);
+        let sig = std::fs::read(&sigp).with_context(|| format!("reading signature {:?}", sigp))?;
+        crate::signature::verify_detached(&keys, &data, &sig)
+            .with_context(|| format!("verifying {:?}", metap))?;
+    }
+    let u: ContentMetadata = serde_json::from_slice(&data)?;
+    Ok(Some(u))
+}
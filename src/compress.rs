@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Optional compression of the saved state.  Compressed files are
+//! recognized by their magic bytes, so plaintext state from older
+//! versions continues to load.
+
+use std::io::prelude::*;
+
+use anyhow::{bail, Context, Result};
+
+/// Environment variable selecting the compression used when writing
+/// the state; one of `none` (the default), `gzip`, or `zstd`
+pub(crate) const STATE_COMPRESSION_ENV: &str = "BOOTUPD_STATE_COMPRESSION";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("Unknown compression {:?}", s),
+        }
+    }
+}
+
+impl Compression {
+    /// The compression to write the state with, from the environment
+    pub(crate) fn for_state() -> Result<Self> {
+        if let Some(v) = crate::util::getenv_utf8(STATE_COMPRESSION_ENV)? {
+            v.parse()
+                .with_context(|| format!("Parsing {}={}", STATE_COMPRESSION_ENV, v))
+        } else {
+            Ok(Compression::None)
+        }
+    }
+
+    /// The compression of `buf`, from its magic bytes
+    pub(crate) fn detect(buf: &[u8]) -> Self {
+        if buf.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if buf.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Compress `buf` with `compression`
+pub(crate) fn compress(buf: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(buf),
+        Compression::Gzip => {
+            let mut e = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            e.write_all(&buf)?;
+            e.finish().context("gzip compression")
+        }
+        Compression::Zstd => zstd::encode_all(buf.as_slice(), 0).context("zstd compression"),
+    }
+}
+
+/// Decompress `buf`, whose format is detected from its contents
+pub(crate) fn decompress(buf: Vec<u8>) -> Result<Vec<u8>> {
+    match Compression::detect(&buf) {
+        Compression::None => Ok(buf),
+        Compression::Gzip => {
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(buf.as_slice())
+                .read_to_end(&mut out)
+                .context("gzip decompression")?;
+            Ok(out)
+        }
+        Compression::Zstd => zstd::decode_all(buf.as_slice()).context("zstd decompression"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        let data = br#"{"installed":{}}"#.to_vec();
+        assert_eq!(compress(data.clone(), Compression::None)?, data);
+        assert_eq!(decompress(data.clone())?, data);
+        for c in &[Compression::Gzip, Compression::Zstd] {
+            let compressed = compress(data.clone(), *c)?;
+            assert_eq!(Compression::detect(&compressed), *c);
+            assert_eq!(decompress(compressed)?, data);
+        }
+        assert_eq!("zstd".parse::<Compression>()?, Compression::Zstd);
+        assert!("xz".parse::<Compression>().is_err());
+        Ok(())
+    }
+}
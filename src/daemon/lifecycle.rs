@@ -0,0 +1,154 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Lifecycle of the daemon under systemd: notifying readiness, and
+//! stopping on SIGTERM without interrupting an operation in progress,
+//! which would leave a component half-updated.
+
+use anyhow::Result;
+use libsystemd::daemon::{self, NotifyState};
+use nix::sys::signal::{self, SigSet, SigmaskHow, Signal};
+use std::ffi::OsString;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// Where systemd listens for service notifications
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Set once SIGTERM has been received
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+/// The connection being served, shut down on SIGTERM
+static CLIENT_FD: AtomicI32 = AtomicI32::new(-1);
+/// `NOTIFY_SOCKET_ENV`, kept out of the environment after notifying
+/// readiness so that the commands we run don't inherit it
+static NOTIFY_SOCKET: Mutex<Option<OsString>> = Mutex::new(None);
+
+extern "C" fn handle_sigterm(_: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+    // Make a pending or future recv() of the next request return as if
+    // the client had disconnected.  SIGTERM is blocked while a request is
+    // handled, so this doesn't affect its reply.
+    let fd = CLIENT_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // Safety: shutdown(2) is async-signal-safe
+        unsafe { libc::shutdown(fd, libc::SHUT_RD) };
+    }
+}
+
+/// Handle SIGTERM while serving the client on `fd`.  Until this is called
+/// the default action applies, which is fine as long as no request has
+/// been received.
+pub(crate) fn handle_termination(fd: RawFd) -> Result<()> {
+    CLIENT_FD.store(fd, Ordering::SeqCst);
+    let action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_sigterm),
+        signal::SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safety: the handler only uses atomics and async-signal-safe calls
+    unsafe { signal::sigaction(Signal::SIGTERM, &action)? };
+    Ok(())
+}
+
+/// Whether SIGTERM has been received
+pub(crate) fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+/// Defers SIGTERM until dropped, so that the operation in progress can
+/// complete and release the write lock.  Threads spawned meanwhile
+/// inherit the deferral.
+pub(crate) struct DeferTermination {
+    old: SigSet,
+}
+
+impl DeferTermination {
+    pub(crate) fn new() -> Result<Self> {
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGTERM);
+        let mut old = SigSet::empty();
+        signal::pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&set), Some(&mut old))?;
+        Ok(Self { old })
+    }
+}
+
+impl Drop for DeferTermination {
+    fn drop(&mut self) {
+        if let Err(e) = signal::pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&self.old), None) {
+            log::warn!("failed to restore signal mask: {}", e);
+        }
+    }
+}
+
+/// Tell systemd we're ready to accept a client, then remove
+/// `NOTIFY_SOCKET_ENV` from the environment until `notify_stopping()`
+pub(crate) fn notify_ready() -> Result<()> {
+    let sent = daemon::notify(false, &[NotifyState::Ready])
+        .map_err(|e| anyhow::anyhow!("failed to notify ready-state: {}", e))?;
+    *NOTIFY_SOCKET.lock().unwrap() = std::env::var_os(NOTIFY_SOCKET_ENV);
+    std::env::remove_var(NOTIFY_SOCKET_ENV);
+    if !sent {
+        log::warn!("failed to notify ready-state: service notifications not supported");
+    }
+    Ok(())
+}
+
+/// Tell systemd we're exiting, e.g. so that it doesn't count a SIGTERM
+/// we handled as a failure
+pub(crate) fn notify_stopping() {
+    let socket = match NOTIFY_SOCKET.lock().unwrap().take() {
+        Some(socket) => socket,
+        None => return,
+    };
+    std::env::set_var(NOTIFY_SOCKET_ENV, socket);
+    if let Err(e) = daemon::notify(true, &[NotifyState::Stopping]) {
+        log::warn!("failed to notify stopping-state: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket as nixsocket;
+
+    #[test]
+    fn test_defer_termination() -> Result<()> {
+        let (ours, theirs) = nixsocket::socketpair(
+            nixsocket::AddressFamily::Unix,
+            nixsocket::SockType::SeqPacket,
+            None,
+            nixsocket::SockFlag::SOCK_CLOEXEC,
+        )?;
+        handle_termination(ours)?;
+        let guard = DeferTermination::new()?;
+        // Sent to this thread, which is blocking it
+        signal::raise(Signal::SIGTERM)?;
+        assert!(!terminating());
+        nixsocket::send(ours, b"reply", nixsocket::MsgFlags::empty())?;
+        drop(guard);
+        assert!(terminating());
+        let mut buf = [0u8; 8];
+        let n = nixsocket::recv(ours, &mut buf, nixsocket::MsgFlags::empty())?;
+        assert_eq!(n, 0);
+        let n = nixsocket::recv(theirs, &mut buf, nixsocket::MsgFlags::empty())?;
+        assert_eq!(&buf[..n], b"reply");
+        nix::unistd::close(ours)?;
+        nix::unistd::close(theirs)?;
+
+        // Leave the process as other tests expect it
+        let action = signal::SigAction::new(
+            signal::SigHandler::SigDfl,
+            signal::SaFlags::empty(),
+            SigSet::empty(),
+        );
+        // Safety: restores the default action
+        unsafe { signal::sigaction(Signal::SIGTERM, &action)? };
+        TERMINATING.store(false, Ordering::SeqCst);
+        CLIENT_FD.store(-1, Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 //! Daemon logic.
 
-use crate::component::ValidationResult;
-use crate::model::Status;
+mod lifecycle;
+
+use crate::component::{PruneResult, RepairResult, ValidationResult};
+use crate::model::{ContentMetadata, Status};
 use crate::{bootupd, ipc};
 use anyhow::{bail, Context, Result};
 use nix::sys::socket as nixsocket;
@@ -29,6 +31,11 @@ pub fn run() -> Result<()> {
     if let Err(e) = process_client_requests(client) {
         log::error!("failed to process request from client: {}", e);
     }
+    if lifecycle::terminating() {
+        log::info!("stopping on SIGTERM");
+        lifecycle::notify_stopping();
+        return Ok(());
+    }
 
     // Sleep for a half second to avoid triggering systemd service
     // restart limits.
@@ -42,7 +49,7 @@ pub fn run() -> Result<()> {
 /// This ensures that the system is running under systemd, then receives the
 /// socket-FD for main IPC logic, and notifies systemd about ready-state.
 fn systemd_activation() -> Result<RawFd> {
-    use libsystemd::daemon::{self, NotifyState};
+    use libsystemd::daemon;
     use std::os::unix::io::IntoRawFd;
 
     if !daemon::booted() {
@@ -60,11 +67,7 @@ fn systemd_activation() -> Result<RawFd> {
         srvsock_fd.into_raw_fd()
     };
 
-    let sent = daemon::notify(true, &[NotifyState::Ready])
-        .map_err(|e| anyhow::anyhow!("failed to notify ready-state: {}", e))?;
-    if !sent {
-        log::warn!("failed to notify ready-state: service notifications not supported");
-    }
+    lifecycle::notify_ready()?;
 
     Ok(srvsock_fd)
 }
@@ -82,11 +85,14 @@ fn accept_authenticate_client(srvsock_fd: RawFd) -> Result<ipc::AuthenticatedCli
 /// Process all requests from a given client.
 ///
 /// This sequentially processes all requests from a client, until it
-/// disconnects or a connection error is encountered.
+/// disconnects, a connection error is encountered, or we're asked to
+/// stop.  A request being handled when SIGTERM arrives is completed
+/// first.
 fn process_client_requests(client: ipc::AuthenticatedClient) -> Result<()> {
-    use crate::bootupd::ClientRequest;
-
+    lifecycle::handle_termination(client.fd)?;
     let mut buf = [0u8; ipc::MSGSIZE];
+    // May be changed by the client with `SetSysroot`
+    let mut sysroot = "/".to_string();
     loop {
         let n = nixsocket::recv(client.fd, &mut buf, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)?;
         let buf = &buf[0..n];
@@ -95,30 +101,18 @@ fn process_client_requests(client: ipc::AuthenticatedClient) -> Result<()> {
             break;
         }
 
+        let _defer = lifecycle::DeferTermination::new()?;
+        if lifecycle::terminating() {
+            log::trace!("not starting request received while stopping");
+            break;
+        }
         let msg = bincode::deserialize(&buf)?;
-        let r = match msg {
-            ClientRequest::Update { component } => {
-                log::trace!("processing 'update' request");
-                bincode::serialize(&match bootupd::update(component.as_str()) {
-                    Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ComponentUpdateResult>(v),
-                    Err(e) => ipc::DaemonToClientReply::Failure(format!("{:#}", e)),
-                })?
-            }
-            ClientRequest::Validate { component } => {
-                log::trace!("processing 'validate' request");
-                bincode::serialize(&match bootupd::validate(component.as_str()) {
-                    Ok(v) => ipc::DaemonToClientReply::Success::<ValidationResult>(v),
-                    Err(e) => ipc::DaemonToClientReply::Failure(format!("{:#}", e)),
-                })?
-            }
-            ClientRequest::Status => {
-                log::trace!("processing 'status' request");
-                bincode::serialize(&match bootupd::status() {
-                    Ok(v) => ipc::DaemonToClientReply::Success::<Status>(v),
-                    Err(e) => ipc::DaemonToClientReply::Failure(format!("{:#}", e)),
-                })?
+        let progress = |msg: &str| {
+            if let Err(e) = client.send_progress(msg) {
+                log::warn!("failed to send progress to client: {}", e);
             }
         };
+        let r = handle_request(&mut sysroot, msg, &progress)?;
         let written = nixsocket::send(client.fd, &r, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)?;
         if written != r.len() {
             bail!("wrote {} bytes to client, expected {}", written, r.len());
@@ -126,3 +120,202 @@ fn process_client_requests(client: ipc::AuthenticatedClient) -> Result<()> {
     }
     Ok(())
 }
+
+/// Process a single request, returning the serialized reply.  `sysroot`
+/// is the root the connection operates on, which the request may change.
+/// Progress messages are passed to `progress`.
+pub(crate) fn handle_request(
+    sysroot: &mut String,
+    msg: bootupd::ClientRequest,
+    progress: &dyn Fn(&str),
+) -> Result<Vec<u8>> {
+    use crate::bootupd::ClientRequest;
+
+    let r = match msg {
+        ClientRequest::SetSysroot { sysroot: path } => {
+            log::trace!("processing 'set-sysroot' request");
+            bincode::serialize(&match bootupd::check_sysroot(&path) {
+                Ok(()) => {
+                    log::info!("Operating on sysroot {}", path);
+                    *sysroot = path;
+                    ipc::DaemonToClientReply::Success::<()>(())
+                }
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Update { component, opts } => {
+            log::trace!("processing 'update' request");
+            bincode::serialize(
+                &match bootupd::update(sysroot, component.as_str(), &opts, progress) {
+                    Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ComponentUpdateResult>(v),
+                    Err(e) => ipc::DaemonToClientReply::Failure(e),
+                },
+            )?
+        }
+        ClientRequest::UpdateParallel {
+            components,
+            opts,
+            max_concurrency,
+        } => {
+            log::trace!("processing 'update-parallel' request");
+            bincode::serialize(&match bootupd::update_parallel(
+                sysroot,
+                &components,
+                &opts,
+                max_concurrency,
+                progress,
+            ) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ParallelUpdateResult>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::UpdateAll {
+            opts,
+            transactional,
+        } => {
+            log::trace!("processing 'update-all' request");
+            bincode::serialize(
+                &match bootupd::update_all(sysroot, &opts, transactional, progress) {
+                    Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ParallelUpdateResult>(v),
+                    Err(e) => ipc::DaemonToClientReply::Failure(e),
+                },
+            )?
+        }
+        ClientRequest::Validate { component } => {
+            log::trace!("processing 'validate' request");
+            bincode::serialize(&match bootupd::validate(sysroot, component.as_str()) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<ValidationResult>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Rollback { component } => {
+            log::trace!("processing 'rollback' request");
+            bincode::serialize(&match bootupd::rollback(sysroot, component.as_str()) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ComponentRollbackResult>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Stage { component } => {
+            log::trace!("processing 'stage' request");
+            bincode::serialize(
+                &match bootupd::stage(sysroot, component.as_str(), progress) {
+                    Ok(v) => ipc::DaemonToClientReply::Success::<Option<ContentMetadata>>(v),
+                    Err(e) => ipc::DaemonToClientReply::Failure(e),
+                },
+            )?
+        }
+        ClientRequest::Uninstall { component, force } => {
+            log::trace!("processing 'uninstall' request");
+            bincode::serialize(
+                &match bootupd::uninstall(sysroot, component.as_str(), force) {
+                    Ok(v) => ipc::DaemonToClientReply::Success::<ContentMetadata>(v),
+                    Err(e) => ipc::DaemonToClientReply::Failure(e),
+                },
+            )?
+        }
+        ClientRequest::Prune { keep } => {
+            log::trace!("processing 'prune' request");
+            bincode::serialize(&match bootupd::prune(sysroot, keep) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<
+                    std::collections::BTreeMap<String, PruneResult>,
+                >(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Capabilities => {
+            log::trace!("processing 'capabilities' request");
+            let caps: Vec<String> = bootupd::CAPABILITIES
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            bincode::serialize(&ipc::DaemonToClientReply::Success::<Vec<String>>(caps))?
+        }
+        ClientRequest::Repair { component } => {
+            log::trace!("processing 'repair' request");
+            bincode::serialize(
+                &match bootupd::repair(sysroot, component.as_str(), progress) {
+                    Ok(v) => ipc::DaemonToClientReply::Success::<RepairResult>(v),
+                    Err(e) => ipc::DaemonToClientReply::Failure(e),
+                },
+            )?
+        }
+        ClientRequest::Adopt => {
+            log::trace!("processing 'adopt' request");
+            bincode::serialize(&match bootupd::adopt(sysroot) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::AdoptResult>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::RegenerateState { confirm, force } => {
+            log::trace!("processing 'regenerate-state' request");
+            bincode::serialize(&match bootupd::regenerate_state(sysroot, confirm, force) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::RegenerateResult>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::ExportState => {
+            log::trace!("processing 'export-state' request");
+            bincode::serialize(&match bootupd::export_state(sysroot) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<String>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::ImportState { state } => {
+            log::trace!("processing 'import-state' request");
+            bincode::serialize(&match bootupd::import_state(sysroot, &state) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ValidationSummary>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::ListComponents => {
+            log::trace!("processing 'list-components' request");
+            bincode::serialize(&match bootupd::list_components(sysroot) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ComponentList>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Diff { component } => {
+            log::trace!("processing 'diff' request");
+            bincode::serialize(&match bootupd::diff(sysroot, component.as_str()) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ComponentDiff>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Check => {
+            log::trace!("processing 'check' request");
+            bincode::serialize(&match bootupd::check(sysroot) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::ValidationSummary>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Metrics => {
+            log::trace!("processing 'metrics' request");
+            bincode::serialize(&match bootupd::metrics(sysroot) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<bootupd::Metrics>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::Status { components } => {
+            log::trace!("processing 'status' request");
+            bincode::serialize(&match bootupd::status(sysroot, &components, true) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<Status>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::StatusRemote { components } => {
+            log::trace!("processing 'status-remote' request");
+            bincode::serialize(&match bootupd::status_remote(sysroot, &components) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<Status>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+        ClientRequest::StatusInstalled { components } => {
+            log::trace!("processing 'status-installed' request");
+            bincode::serialize(&match bootupd::status(sysroot, &components, false) {
+                Ok(v) => ipc::DaemonToClientReply::Success::<Status>(v),
+                Err(e) => ipc::DaemonToClientReply::Failure(e),
+            })?
+        }
+    };
+    Ok(r)
+}
@@ -4,34 +4,104 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeSet, HashSet};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{bail, Context, Result};
+use nix::sys::statfs::{self, FsType};
+use openat_ext::OpenatDirExt;
 
-use chrono::prelude::*;
-
+use crate::bootupd;
 use crate::component::*;
 use crate::filetree;
 use crate::model::*;
 use crate::ostreeutil;
+use crate::payload::PayloadSource;
+use crate::signature;
 use crate::util;
 use crate::util::CommandRunExt;
 
 /// The path to the ESP mount
 pub(crate) const MOUNT_PATH: &str = "boot/efi";
+/// Partition type GUID of an EFI System Partition
+pub(crate) const ESP_TYPE_GUID: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+/// Directory in the ESP where the payload replaced by the last update
+/// is retained for rollback; mirrors the layout of `EFI`
+pub(crate) const PREVIOUS_DIR: &str = ".previous";
+/// Directory in the ESP holding an update staged for the next boot;
+/// mirrors the layout of `EFI`
+pub(crate) const STAGED_DIR: &str = ".staged";
+/// Set to 1 to let updates look for unrecorded ESPs mirroring the primary
+/// one; see `Mirrors::Discover`
+const DISCOVER_MIRRORS_ENV: &str = "BOOTUPD_DISCOVER_ESP_MIRRORS";
+/// The UEFI word size of the firmware, relative to `SYSFS_EFI_PATH`
+const FW_PLATFORM_SIZE: &str = "fw_platform_size";
+/// Files maintained by GRUB tooling alongside our payload, which are
+/// expected to be absent from the installed inventory
+const UNTRACKED_FILES: &[&str] = &["grub.cfg", "grubenv"];
+
+pub(crate) struct EFI {
+    /// Root of the system being managed
+    sysroot: String,
+}
+
+impl EFI {
+    /// Manage the EFI component of the system at `sysroot`
+    pub(crate) fn new(sysroot: &str) -> Self {
+        Self {
+            sysroot: sysroot.to_string(),
+        }
+    }
+
+    /// Whether our root is the one the system booted from, and so the one
+    /// the firmware's boot entries and Secure Boot state describe
+    fn is_booted(&self) -> bool {
+        Path::new(&self.sysroot) == Path::new("/")
+    }
 
-#[derive(Default)]
-pub(crate) struct EFI {}
+    /// Open the update payload, and check it against the digest in its
+    /// verified metadata.
+    fn verified_update(&self) -> Result<(ContentMetadata, openat::Dir, filetree::FileTree)> {
+        let updatemeta =
+            get_verified_component_update(&self.sysroot, self)?.expect("update available");
+        let updated = openat::Dir::open(&component_updatedir(&self.sysroot, self))
+            .context("opening update dir")?;
+        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        if let Some(digest) = updatemeta.digest.as_ref() {
+            updatef
+                .verify_digest(digest)
+                .context("verifying update payload")?;
+        } else if !signature::load_keys(&self.sysroot)?.is_empty() {
+            // The signature only covers the payload via its digest
+            bail!(
+                "Signed update metadata for {} has no payload digest",
+                self.name()
+            );
+        }
+        Ok((updatemeta, updated, updatef))
+    }
+
+    /// Find the expected files of `current` from the update payload, for
+    /// installations without a stored inventory.  This requires the
+    /// payload to be the installed version.
+    fn derive_filetree(&self, current: &InstalledContent) -> Result<filetree::FileTree> {
+        match self.query_update()? {
+            Some(meta) if meta.version == current.meta.version => {}
+            _ => bail!("No filetree for installed EFI found!"),
+        }
+        self.query_update_filetree()?
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))
+    }
+}
 
 impl Component for EFI {
     fn name(&self) -> &'static str {
         "EFI"
     }
 
-    fn install(&self, src_root: &str, dest_root: &str) -> Result<InstalledContent> {
+    fn install(&self, src_root: &str, dest_root: &str, verbose: bool) -> Result<InstalledContent> {
         let meta = if let Some(meta) = get_component_update(src_root, self)? {
             meta
         } else {
@@ -40,51 +110,600 @@ impl Component for EFI {
         let srcdir = component_updatedir(src_root, self);
         let srcd = openat::Dir::open(&srcdir)?;
         let ft = crate::filetree::FileTree::new_from_dir(&srcd)?;
+        if let Some(digest) = meta.digest.as_ref() {
+            ft.verify_digest(digest)
+                .context("verifying update payload")?;
+        }
         let destdir = Path::new(dest_root).join(MOUNT_PATH);
         {
             let destd = openat::Dir::open(&destdir)?;
             validate_esp(&destd)?;
         }
-        let r = std::process::Command::new("cp")
-            .args(&["-rp", "--reflink=auto"])
-            .arg(&srcdir)
-            .arg(&destdir)
-            .status()?;
-        if !r.success() {
-            anyhow::bail!("Failed to copy");
+        let device = mount_source(&destdir);
+        // Copy file by file rather than with cp, so that installs of the
+        // same payload produce identical ESPs.  This also persists the
+        // payload before the caller records it as installed.
+        let destefi = destdir.join("EFI");
+        std::fs::create_dir_all(&destefi)?;
+        let destefid = openat::Dir::open(&destefi)?;
+        let diff = filetree::FileTree::default().diff(&ft)?;
+        filetree::apply_diff(&srcd, &destefid, &diff, None).context("copying payload")?;
+        if verbose {
+            for path in ft.children.keys() {
+                let dest = destefi.join(path);
+                let size = std::fs::metadata(&dest)
+                    .with_context(|| format!("querying {:?}", dest))?
+                    .len();
+                println!("{:?} -> {:?} ({} bytes)", srcdir.join(path), dest, size);
+            }
         }
         Ok(InstalledContent {
             meta,
             filetree: Some(ft),
+            applied: None,
+            device,
+            boot_code: None,
+            mirrors: Vec::new(),
         })
     }
 
-    fn run_update(&self, current: &InstalledContent) -> Result<InstalledContent> {
+    fn query_devices(&self, current: &InstalledContent) -> Result<Vec<String>> {
+        // Status mustn't mount anything, so report the primary ESP as
+        // mounted, and the mirrors as recorded.
+        let primary = Path::new(&self.sysroot).join(MOUNT_PATH);
+        let mut devices: Vec<String> = mount_source(&primary)
+            .or_else(|| current.device.clone())
+            .into_iter()
+            .collect();
+        for device in current.mirrors.iter() {
+            if !devices.contains(device) {
+                devices.push(device.clone());
+            }
+        }
+        Ok(devices)
+    }
+
+    fn query_space(&self, _current: &InstalledContent) -> Result<Option<SpaceUsage>> {
+        // Like query_devices(), this mustn't mount anything; space is only
+        // known for a primary ESP which is already mounted.
+        let primary = Path::new(&self.sysroot).join(MOUNT_PATH);
+        if mount_source(&primary).is_none() {
+            return Ok(None);
+        }
+        let efidir = openat::Dir::open(&primary.join("EFI")).context("opening EFI dir")?;
+        let stat = nix::sys::statvfs::fstatvfs(&efidir)?;
+        let blocksize = stat.fragment_size().max(1) as u64;
+        Ok(Some(SpaceUsage {
+            available: stat.blocks_available() as u64 * blocksize,
+            total: stat.blocks() as u64 * blocksize,
+        }))
+    }
+
+    fn check_precondition(
+        &self,
+        _current: &InstalledContent,
+        _update: &ContentMetadata,
+    ) -> Result<Option<String>> {
+        let updatef = match self.query_update_filetree()? {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let size = firmware_platform_size(Path::new(bootupd::SYSFS_EFI_PATH));
+        let incompatible = check_firmware_compat(size, &updatef);
+        // Our firmware says nothing about the one another root boots on
+        if !self.is_booted() {
+            if let Some(reason) = incompatible {
+                log::warn!("Ignoring for {}: {}", self.sysroot, reason);
+            }
+            return Ok(None);
+        }
+        Ok(incompatible)
+    }
+
+    fn resource_key(&self, _current: &InstalledContent) -> Result<String> {
+        util::find_parent_disk(&Path::new(&self.sysroot).join(MOUNT_PATH))
+    }
+
+    fn run_update(
+        &self,
+        current: &InstalledContent,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        let progress = ctx.progress;
         let currentf = current
             .filetree
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
-        let updatemeta = self.query_update()?.expect("update available");
-        let updated =
-            openat::Dir::open(&component_updatedir("/", self)).context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
-        let diff = currentf.diff(&updatef)?;
-        let destdir = openat::Dir::open(&Path::new("/").join(MOUNT_PATH).join("EFI"))
-            .context("opening EFI dir")?;
-        validate_esp(&destdir)?;
-        filetree::apply_diff(&updated, &destdir, &diff, None)
-            .context("applying filesystem changes")?;
+        let (updatemeta, updated, updatef) = self.verified_update()?;
+        let basediff = currentf.diff(&updatef)?;
+        // Compute the changes for every ESP and check that they fit
+        // before writing to any of them.
+        let mut plans = Vec::new();
+        for esp in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            true,
+        )? {
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(&esp, &destdir)?;
+            let mut diff = esp_diff(&basediff, &updatef, &destdir)?;
+            // Completed parts are tracked per ESP, as "<esp>:<path>"
+            let key = |path: &str| format!("{}:{}", esp, path);
+            // Retaining again after an interruption would overwrite the
+            // previous payload with a partially updated one.
+            let retain = !ctx.completed.contains(&key(PREVIOUS_DIR));
+            diff.additions.retain(|p| !ctx.completed.contains(&key(p)));
+            diff.changes.retain(|p| !ctx.completed.contains(&key(p)));
+            // A removal may have completed without being recorded
+            let mut removals = std::mem::take(&mut diff.removals);
+            removals.retain(|p| !ctx.completed.contains(&key(p)));
+            for p in removals {
+                if destdir.exists(p.as_str())? {
+                    diff.removals.insert(p);
+                }
+            }
+            let retained = if retain { Some(currentf) } else { None };
+            check_esp_space(&esp, &destdir, &diff, &updatef, retained, false)?;
+            plans.push((esp, destdir, diff, retain));
+        }
+        let remaining: usize = plans
+            .iter()
+            .map(|(_, _, diff, retain)| {
+                diff.additions.len() + diff.changes.len() + diff.removals.len() + *retain as usize
+            })
+            .sum();
+        (ctx.planned)(ctx.completed.len() + remaining);
+        let device = plans.first().and_then(|(esp, ..)| esp.device.clone());
+        let mirrors = plans
+            .iter()
+            .skip(1)
+            .filter_map(|(esp, ..)| esp.device.clone())
+            .collect();
+        for (i, (esp, destdir, diff, retain)) in plans.into_iter().enumerate() {
+            progress(&format!("Updating ESP {}", esp));
+            let written = diff.additions.len() + diff.changes.len();
+            progress(&format!(
+                "Writing {} files, skipping {} already up to date",
+                written,
+                updatef.children.len().saturating_sub(written)
+            ));
+            let key = |path: &str| format!("{}:{}", esp, path);
+            if retain {
+                progress("Retaining previous payload");
+                retain_previous(&esp.path, &destdir, currentf)
+                    .context("retaining previous payload")?;
+                (ctx.mark_completed)(&key(PREVIOUS_DIR));
+            } else {
+                progress("Resuming interrupted update");
+            }
+            let prefix = changed_prefix(i, &esp);
+            let mark_completed = |path: &str| {
+                (ctx.mark_completed)(&key(path));
+                (ctx.changed)(&format!("{}{}", prefix, path));
+            };
+            let opts = filetree::ApplyUpdateOptions {
+                progress: Some(progress),
+                completed: Some(&mark_completed),
+                retries: filetree::write_retries()?,
+                cancelled: Some(ctx.cancelled),
+                ..Default::default()
+            };
+            filetree::apply_diff(&updated, &destdir, &diff, Some(&opts))
+                .with_context(|| format!("applying filesystem changes to {}", esp))?;
+            // Anything staged for the next boot is superseded
+            let stagedpath = esp.path.join(STAGED_DIR);
+            if stagedpath.exists() {
+                std::fs::remove_dir_all(&stagedpath).context("removing staged payload")?;
+            }
+        }
         Ok(InstalledContent {
             meta: updatemeta,
             filetree: Some(updatef),
+            applied: None,
+            device,
+            boot_code: None,
+            mirrors,
         })
     }
 
-    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+    fn can_stage(&self) -> bool {
+        true
+    }
+
+    fn stage(
+        &self,
+        current: &InstalledContent,
+        progress: &dyn Fn(&str),
+    ) -> Result<ContentMetadata> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let (updatemeta, updated, updatef) = self.verified_update()?;
+        let basediff = currentf.diff(&updatef)?;
+        // The whole payload is copied into the staging directory
+        let payload = filetree::FileTreeDiff {
+            additions: updatef.children.keys().cloned().collect(),
+            removals: HashSet::new(),
+            changes: HashSet::new(),
+        };
+        let esps = find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            true,
+        )?;
+        for esp in esps.iter() {
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(esp, &destdir)?;
+            check_esp_space(esp, &destdir, &basediff, &updatef, Some(currentf), true)?;
+        }
+        for esp in esps.iter() {
+            progress(&format!("Staging update on ESP {}", esp));
+            let espdir = openat::Dir::open(&esp.path)?;
+            if espdir.exists(STAGED_DIR)? {
+                std::fs::remove_dir_all(esp.path.join(STAGED_DIR))
+                    .context("removing staged payload")?;
+            }
+            espdir.create_dir(STAGED_DIR, 0o755)?;
+            let stageddir = espdir.sub_dir(STAGED_DIR)?;
+            let opts = filetree::ApplyUpdateOptions {
+                progress: Some(progress),
+                retries: filetree::write_retries()?,
+                ..Default::default()
+            };
+            filetree::apply_diff(&updated, &stageddir, &payload, Some(&opts))
+                .with_context(|| format!("staging update on {}", esp))?;
+        }
+        Ok(updatemeta)
+    }
+
+    fn apply_staged(
+        &self,
+        current: &InstalledContent,
+        staged: &ContentMetadata,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        let progress = ctx.progress;
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let mut applied = None;
+        let mut device = None;
+        let mut mirrors = Vec::new();
+        for (i, esp) in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            true,
+        )?
+        .into_iter()
+        .enumerate()
+        {
+            let stagedpath = esp.path.join(STAGED_DIR);
+            let stageddir = openat::Dir::open(&stagedpath)
+                .with_context(|| format!("opening staged payload on {}", esp))?;
+            let stagedf =
+                filetree::FileTree::new_from_dir(&stageddir).context("reading staged payload")?;
+            if let Some(digest) = staged.digest.as_ref() {
+                stagedf
+                    .verify_digest(digest)
+                    .with_context(|| format!("verifying staged payload on {}", esp))?;
+            }
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(&esp, &destdir)?;
+            progress(&format!("Applying staged update to ESP {}", esp));
+            // Completed parts are tracked per ESP as in run_update(); the
+            // staged payload itself is only removed by discard_staged().
+            let key = |path: &str| format!("{}:{}", esp, path);
+            if !ctx.completed.contains(&key(PREVIOUS_DIR)) {
+                retain_previous(&esp.path, &destdir, currentf)
+                    .context("retaining previous payload")?;
+                (ctx.mark_completed)(&key(PREVIOUS_DIR));
+            }
+            let mut diff = currentf.diff(&stagedf)?;
+            diff.additions.retain(|p| !ctx.completed.contains(&key(p)));
+            diff.changes.retain(|p| !ctx.completed.contains(&key(p)));
+            let mut removals = std::mem::take(&mut diff.removals);
+            removals.retain(|p| !ctx.completed.contains(&key(p)));
+            for p in removals {
+                if destdir.exists(p.as_str())? {
+                    diff.removals.insert(p);
+                }
+            }
+            let prefix = changed_prefix(i, &esp);
+            let mark_completed = |path: &str| {
+                (ctx.mark_completed)(&key(path));
+                (ctx.changed)(&format!("{}{}", prefix, path));
+            };
+            let opts = filetree::ApplyUpdateOptions {
+                progress: Some(progress),
+                completed: Some(&mark_completed),
+                retries: filetree::write_retries()?,
+                cancelled: Some(ctx.cancelled),
+                ..Default::default()
+            };
+            filetree::apply_diff(&stageddir, &destdir, &diff, Some(&opts))
+                .with_context(|| format!("applying staged update to {}", esp))?;
+            // The primary ESP is first; its contents define what is installed.
+            if applied.is_none() {
+                applied = Some(stagedf);
+                device = esp.device.clone();
+            } else if let Some(d) = esp.device {
+                mirrors.push(d);
+            }
+        }
+        let stagedf = applied.expect("primary ESP");
+        Ok(InstalledContent {
+            meta: staged.clone(),
+            filetree: Some(stagedf),
+            applied: None,
+            device,
+            boot_code: None,
+            mirrors,
+        })
+    }
+
+    fn discard_staged(&self, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        for esp in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            true,
+        )? {
+            let stagedpath = esp.path.join(STAGED_DIR);
+            if stagedpath.exists() {
+                std::fs::remove_dir_all(&stagedpath)
+                    .with_context(|| format!("removing staged payload from {}", esp))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn adopt(&self) -> Result<Option<InstalledContent>> {
+        let updatemeta = if let Some(meta) = self.query_update()? {
+            meta
+        } else {
+            return Ok(None);
+        };
+        let updated = openat::Dir::open(&component_updatedir(&self.sysroot, self))
+            .context("opening update dir")?;
+        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let esppath = Path::new(&self.sysroot).join(MOUNT_PATH);
+        let efidir = openat::Dir::open(&esppath.join("EFI")).context("opening EFI dir")?;
+        validate_esp(&efidir)?;
+        let diff = updatef.relative_diff_to(&efidir)?;
+        if !diff.changes.is_empty() || !diff.removals.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(updatef),
+            applied: None,
+            device: mount_source(&esppath),
+            boot_code: None,
+            mirrors: Vec::new(),
+        }))
+    }
+
+    fn rollback(
+        &self,
+        current: &InstalledContent,
+        previous: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let mut restored = None;
+        let mut device = None;
+        let mut mirrors = Vec::new();
+        for esp in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            true,
+        )? {
+            let prevpath = esp.path.join(PREVIOUS_DIR);
+            let prevdir = openat::Dir::open(&prevpath)
+                .with_context(|| format!("opening retained payload on {}", esp))?;
+            let prevf =
+                filetree::FileTree::new_from_dir(&prevdir).context("reading retained payload")?;
+            let mut diff = currentf.diff(&prevf)?;
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(&esp, &destdir)?;
+            // Files of `current` might never have been written, if it's
+            // an update which failed partway through.
+            diff.removals
+                .retain(|p| destdir.exists(p.as_str()).unwrap_or(true));
+            filetree::apply_diff(&prevdir, &destdir, &diff, None)
+                .with_context(|| format!("applying filesystem changes to {}", esp))?;
+            drop(prevdir);
+            std::fs::remove_dir_all(&prevpath).context("removing retained payload")?;
+            promote_generations(&esp.path).context("promoting retained payloads")?;
+            // The primary ESP is first; its contents define what is installed.
+            if restored.is_none() {
+                restored = Some(prevf);
+                device = esp.device.clone();
+            } else if let Some(d) = esp.device {
+                mirrors.push(d);
+            }
+        }
+        let prevf = restored.expect("primary ESP");
+        Ok(InstalledContent {
+            meta: previous.meta.clone(),
+            filetree: Some(prevf),
+            applied: None,
+            device,
+            boot_code: None,
+            mirrors,
+        })
+    }
+
+    fn repair(&self, current: &InstalledContent, progress: &dyn Fn(&str)) -> Result<RepairResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let updated = openat::Dir::open(&component_updatedir(&self.sysroot, self))
+            .context("opening update dir")?;
+        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let mut r = RepairResult::default();
+        for (i, esp) in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Recorded,
+            true,
+        )?
+        .iter()
+        .enumerate()
+        {
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(esp, &destdir)?;
+            let prefix = if i == 0 {
+                "".to_string()
+            } else {
+                format!("{}: ", esp)
+            };
+            let drift = currentf.relative_diff_to(&destdir)?;
+            let mut diff = filetree::FileTreeDiff {
+                additions: HashSet::new(),
+                removals: HashSet::new(),
+                changes: HashSet::new(),
+            };
+            for path in drift.changes.into_iter().chain(drift.removals) {
+                // We can only restore files whose installed content is
+                // still shipped in the update payload.
+                if updatef.children.get(&path) == currentf.children.get(&path) {
+                    r.repaired.push(format!("Restored: {}{}", prefix, path));
+                    diff.changes.insert(path);
+                } else {
+                    r.unrepaired
+                        .push(format!("No matching payload for {}{}", prefix, path));
+                }
+            }
+            if diff.changes.is_empty() {
+                continue;
+            }
+            let opts = filetree::ApplyUpdateOptions {
+                progress: Some(progress),
+                retries: filetree::write_retries()?,
+                ..Default::default()
+            };
+            filetree::apply_diff(&updated, &destdir, &diff, Some(&opts))
+                .with_context(|| format!("repairing {}", esp))?;
+        }
+        if self.is_booted() {
+            r.unrepaired.extend(validate_boot_entries(currentf)?);
+        }
+        Ok(r)
+    }
+
+    fn uninstall(&self, current: &InstalledContent) -> Result<()> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        // Only ESPs known to be ours; an unrecorded one holding the same
+        // files may belong to another installation.
+        for esp in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Recorded,
+            true,
+        )? {
+            let destdir = esp.efidir()?;
+            validate_esp(&destdir)?;
+            check_esp_writable(&esp, &destdir)?;
+            uninstall_esp(&esp, &destdir, currentf)?;
+        }
+        Ok(())
+    }
+
+    fn prune(
+        &self,
+        current: &InstalledContent,
+        previous: Option<&InstalledContent>,
+        keep: usize,
+    ) -> Result<PruneResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let mut r = PruneResult::default();
+        for (i, esp) in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Recorded,
+            true,
+        )?
+        .into_iter()
+        .enumerate()
+        {
+            // rollback() restores from the primary ESP's copy
+            if previous.is_some() && i == 0 && !esp.path.join(PREVIOUS_DIR).exists() {
+                r.previous_missing = true;
+            }
+            // Without a rollback, none of the retained payloads is needed
+            let keep = if previous.is_some() { keep.max(1) } else { 0 };
+            let generations: Vec<usize> = retained_generations(&esp.path)?
+                .into_iter()
+                .filter(|&g| g > keep)
+                .collect();
+            if generations.is_empty() {
+                continue;
+            }
+            check_esp_writable(&esp, &esp.efidir()?)?;
+            for generation in generations {
+                if let Some(freed) = remove_retained(&esp.path, generation)? {
+                    let path = esp.path.join(generation_dir(generation));
+                    r.pruned.push(path.display().to_string());
+                    r.freed += freed;
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        source: &dyn PayloadSource,
+        os_version: Option<&str>,
+    ) -> Result<ContentMetadata> {
         let ostreebootdir = Path::new(sysroot_path).join(ostreeutil::BOOT_PREFIX);
         let dest_efidir = component_updatedir(sysroot_path, self);
 
-        if ostreebootdir.exists() {
+        if !is_sysroot(source, sysroot_path)? {
+            let efisrc = find_payload(source)?;
+            if dest_efidir.exists() {
+                std::fs::remove_dir_all(&dest_efidir)?;
+            }
+            let parent = dest_efidir
+                .parent()
+                .ok_or_else(|| anyhow::anyhow!("Expected parent directory"))?;
+            std::fs::create_dir_all(&parent)?;
+            source.copy_dir(&efisrc, &dest_efidir)?;
+        } else if ostreebootdir.exists() {
             let cruft = ["loader", "grub2"];
             for p in cruft.iter() {
                 let p = ostreebootdir.join(p);
@@ -111,75 +730,161 @@ impl Component for EFI {
         // Query the rpm database and list the package and build times for all the
         // files in the EFI system partition. If any files are not owned it is considered
         // and error condition.
-        let rpmout = {
-            let mut c = ostreeutil::rpm_cmd(sysroot_path);
-            c.args(&["-q", "--queryformat", "%{nevra},%{buildtime} ", "-f"]);
-            c.args(util::filenames(&src_efidir)?.drain().map(|mut f| {
+        let files: Vec<_> = util::filenames(&src_efidir)?
+            .into_iter()
+            .map(|mut f| {
                 f.insert_str(0, "/boot/efi/EFI/");
-                f
-            }));
-            c
-        }
-        .output()?;
-        if !rpmout.status.success() {
-            std::io::stderr().write_all(&rpmout.stderr)?;
-            bail!("Failed to invoke rpm -qf");
-        }
-        let pkgs = std::str::from_utf8(&rpmout.stdout)?
-            .split_whitespace()
-            .map(|s| -> Result<_> {
-                let parts: Vec<_> = s.splitn(2, ',').collect();
-                let name = parts[0];
-                if let Some(ts) = parts.get(1) {
-                    let nt = NaiveDateTime::parse_from_str(ts, "%s")
-                        .context("Failed to parse rpm buildtime")?;
-                    Ok((name, DateTime::<Utc>::from_utc(nt, Utc)))
-                } else {
-                    bail!("Failed to parse: {}", s);
-                }
+                PathBuf::from(f)
             })
-            .collect::<Result<BTreeMap<&str, DateTime<Utc>>>>()?;
-        if pkgs.is_empty() {
-            bail!("Failed to find any RPM packages matching files in source efidir");
-        }
-        let timestamps: BTreeSet<&DateTime<Utc>> = pkgs.values().collect();
-        // Unwrap safety: We validated pkgs has at least one value above
-        let largest_timestamp = timestamps.iter().last().unwrap();
-        let version = pkgs.keys().fold("".to_string(), |mut s, n| {
-            if !s.is_empty() {
-                s.push(',');
-            }
-            s.push_str(n);
-            s
-        });
-
-        let meta = ContentMetadata {
-            timestamp: **largest_timestamp,
-            version: version,
-        };
+            .collect();
+        let mut meta = source.query_files(&files)?;
+        let ft = filetree::FileTree::new_from_dir(&src_efidir)?;
+        meta.digest = Some(ft.digest());
+        meta.source = os_version.map(|s| s.to_string());
         write_update_metadata(sysroot_path, self, &meta)?;
         Ok(meta)
     }
 
     fn query_update(&self) -> Result<Option<ContentMetadata>> {
-        get_component_update("/", self)
+        get_component_update(&self.sysroot, self)
+    }
+
+    fn query_update_filetree(&self) -> Result<Option<filetree::FileTree>> {
+        let updatedir = component_updatedir(&self.sysroot, self);
+        if !updatedir.exists() {
+            return Ok(None);
+        }
+        let updated = openat::Dir::open(&updatedir).context("opening update dir")?;
+        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        Ok(Some(updatef))
     }
 
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        // The stored inventory lets us check each file against its
+        // checksum; without one, derive it from a matching payload.
+        let derived;
+        let currentf = if let Some(f) = current.filetree.as_ref() {
+            f
+        } else {
+            derived = self.derive_filetree(current)?;
+            &derived
+        };
+        let mut errs = Vec::new();
+        // Firmware enforcing Secure Boot refuses to run unsigned loaders.
+        // We only know the firmware of the root we booted from; another
+        // root may well be booted elsewhere.
+        let enforcing = self.is_booted()
+            && bootupd::secure_boot(Path::new(bootupd::SYSFS_EFI_PATH)) == Some(true);
+        for (i, esp) in find_esps(
+            Path::new(&self.sysroot),
+            current,
+            currentf,
+            Mirrors::Discover,
+            false,
+        )?
+        .iter()
+        .enumerate()
+        {
+            let efidir = esp.efidir()?;
+            if let Some(fstype) = check_esp_fstype(&efidir)? {
+                errs.push(ValidationError::other(
+                    self.name(),
+                    format!(
+                        "ESP {} is not a FAT filesystem, but {}; firmware may not read it",
+                        esp, fstype
+                    ),
+                ));
+            }
+            // ESPs we mounted ourselves are read-only for validation
+            if esp.mount.is_none() && is_read_only(&efidir)? {
+                errs.push(ValidationError::other(
+                    self.name(),
+                    format!("ESP {} is mounted read-only; remount rw to update", esp),
+                ));
+            }
+            let diff = currentf.relative_diff_to(&efidir)?;
+            let location = esp_location(i, esp);
+            errs.extend(content_errors(
+                currentf,
+                &efidir,
+                &diff,
+                location.as_deref(),
+            )?);
+            if enforcing {
+                for (f, malformed) in unsigned_binaries(&efidir, currentf, &diff.removals)? {
+                    let detail = malformed.map(|e| {
+                        let at = location.as_ref().map(|l| format!("{}: ", l));
+                        format!(
+                            "Unverifiable signature: {}{}: {}",
+                            at.unwrap_or_default(),
+                            f,
+                            e
+                        )
+                    });
+                    errs.push(ValidationError {
+                        location: location.clone(),
+                        detail,
+                        ..ValidationError::file(self.name(), ValidationErrorKind::Unsigned, &f)
+                    });
+                }
+            }
+            // Anything else next to our files was likely left behind by
+            // an earlier version, e.g. a stale loader.
+            for f in currentf.untracked_in(&efidir)? {
+                let name = f.rsplit('/').next().unwrap_or(&f);
+                if !UNTRACKED_FILES.contains(&name) {
+                    errs.push(ValidationError {
+                        location: location.clone(),
+                        ..ValidationError::file(self.name(), ValidationErrorKind::Extra, &f)
+                    });
+                }
+            }
+        }
+        if enforcing {
+            if let Some(updatef) = self.query_update_filetree()? {
+                let updated = openat::Dir::open(&component_updatedir(&self.sysroot, self))
+                    .context("opening update dir")?;
+                for (f, malformed) in unsigned_binaries(&updated, &updatef, &HashSet::new())? {
+                    let detail = match malformed {
+                        Some(e) => {
+                            format!("Unverifiable signature in available update: {}: {}", f, e)
+                        }
+                        None => format!("Unsigned in available update: {}", f),
+                    };
+                    errs.push(ValidationError {
+                        detail: Some(detail),
+                        ..ValidationError::file(self.name(), ValidationErrorKind::Unsigned, &f)
+                    });
+                }
+            }
+        }
+        if self.is_booted() {
+            errs.extend(
+                validate_boot_entries(currentf)?
+                    .into_iter()
+                    .map(|e| ValidationError::other(self.name(), e)),
+            );
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn verify_install(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        // Firmware boot entries and Secure Boot describe the system we're
+        // running on rather than the one installed into, so only check
+        // the copied files.  Those were only written to the ESP under the
+        // target root; any other ESP belongs to the host or the installer.
         let currentf = current
             .filetree
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
-        let efidir = openat::Dir::open(&Path::new("/").join(MOUNT_PATH).join("EFI"))?;
+        let efidir = openat::Dir::open(&Path::new(&self.sysroot).join(MOUNT_PATH).join("EFI"))
+            .context("opening EFI dir")?;
         let diff = currentf.relative_diff_to(&efidir)?;
-        let mut errs = Vec::new();
-        for f in diff.changes.iter() {
-            errs.push(format!("Changed: {}", f));
-        }
-        for f in diff.removals.iter() {
-            errs.push(format!("Removed: {}", f));
-        }
-        assert_eq!(diff.additions.len(), 0);
+        let errs = content_errors(currentf, &efidir, &diff, None)?;
         if !errs.is_empty() {
             Ok(ValidationResult::Errors(errs))
         } else {
@@ -188,11 +893,1493 @@ impl Component for EFI {
     }
 }
 
+/// Remove the files of `currentf` from `esp`, whose `EFI` directory is
+/// `destdir`, along with directories left empty and any retained or
+/// staged payload.  Other files are left alone.
+fn uninstall_esp(esp: &Esp, destdir: &openat::Dir, currentf: &filetree::FileTree) -> Result<()> {
+    let mut dirs = BTreeSet::new();
+    for path in currentf.children.keys() {
+        if destdir.exists(path.as_str())? {
+            destdir
+                .remove_file(path.as_str())
+                .with_context(|| format!("removing {} from {}", path, esp))?;
+        }
+        let mut p = path.as_str();
+        while let Some(i) = p.rfind('/') {
+            p = &p[..i];
+            dirs.insert(p);
+        }
+    }
+    // Children sort after their parents, so this removes the
+    // deepest directories first.
+    for d in dirs.iter().rev() {
+        if let Some(subdir) = destdir.sub_dir_optional(*d)? {
+            if subdir.list_dir(".")?.next().is_none() {
+                destdir.remove_dir(*d)?;
+            }
+        }
+    }
+    let mut dirs: Vec<String> = retained_generations(&esp.path)?
+        .into_iter()
+        .map(generation_dir)
+        .collect();
+    dirs.push(STAGED_DIR.to_string());
+    for d in dirs {
+        let path = esp.path.join(d);
+        if path.exists() {
+            std::fs::remove_dir_all(&path).with_context(|| format!("removing {:?}", path))?;
+        }
+    }
+    filetree::syncfs(destdir)?;
+    Ok(())
+}
+
+/// Errors on the primary ESP are reported as bare paths; mirrors are
+/// located by their device so drift can be found.
+fn esp_location(i: usize, esp: &Esp) -> Option<String> {
+    if i == 0 {
+        None
+    } else {
+        Some(esp.to_string())
+    }
+}
+
+/// Validation errors for the files in `efidir` which differ from the
+/// installed inventory `current`, as found by `diff`
+fn content_errors(
+    current: &filetree::FileTree,
+    efidir: &openat::Dir,
+    diff: &filetree::FileTreeDiff,
+    location: Option<&str>,
+) -> Result<Vec<ValidationError>> {
+    assert_eq!(diff.additions.len(), 0);
+    let mut changes: Vec<_> = diff.changes.iter().collect();
+    changes.sort();
+    let mut removals: Vec<_> = diff.removals.iter().collect();
+    removals.sort();
+    let expected = |f: &str| current.children.get(f).map(|m| m.sha512.to_string());
+    let mut errs = Vec::new();
+    for f in changes {
+        let actual = filetree::FileMetadata::new_from_path(efidir, f.as_str())
+            .with_context(|| format!("reading {}", f))?;
+        errs.push(ValidationError {
+            location: location.map(|s| s.to_string()),
+            expected: expected(f),
+            actual: Some(actual.sha512.to_string()),
+            ..ValidationError::file("EFI", ValidationErrorKind::ContentMismatch, f)
+        });
+    }
+    for f in removals {
+        errs.push(ValidationError {
+            location: location.map(|s| s.to_string()),
+            expected: expected(f),
+            ..ValidationError::file("EFI", ValidationErrorKind::Missing, f)
+        });
+    }
+    Ok(errs)
+}
+
+/// An ESP to operate on
+struct Esp {
+    /// Block device backing the ESP, if known
+    device: Option<String>,
+    /// Mount point of the ESP
+    path: PathBuf,
+    /// Set if the ESP was mounted by us
+    mount: Option<TempMount>,
+}
+
+impl Esp {
+    /// The ESP on `device`, mounted on a temporary directory if it isn't
+    /// mounted already
+    fn open(device: &str, writable: bool) -> Result<Self> {
+        let (path, mount) = if let Some(path) = find_mountpoint(device)? {
+            (path, None)
+        } else {
+            let mount = TempMount::new(device, writable)?;
+            (mount.dir.path().to_path_buf(), Some(mount))
+        };
+        Ok(Self {
+            device: Some(device.to_string()),
+            path,
+            mount,
+        })
+    }
+
+    /// Whether the ESP holds every file of `current` unchanged.  Sharing
+    /// directories isn't enough: the fallback `EFI/BOOT` in particular is
+    /// on every ESP, including those of other operating systems.
+    fn holds(&self, current: &filetree::FileTree) -> Result<bool> {
+        if current.children.is_empty() {
+            return Ok(false);
+        }
+        let efidir = match openat::Dir::open(&self.path.join("EFI")) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| format!("opening EFI dir on {}", self)),
+        };
+        let diff = current.relative_diff_to(&efidir)?;
+        Ok(diff.changes.is_empty() && diff.removals.is_empty())
+    }
+
+    fn efidir(&self) -> Result<openat::Dir> {
+        openat::Dir::open(&self.path.join("EFI"))
+            .with_context(|| format!("opening EFI dir on {}", self))
+    }
+}
+
+impl std::fmt::Display for Esp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.device.as_ref() {
+            Some(device) => write!(f, "{}", device),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// An ESP mounted on a temporary directory; unmounted on drop
+struct TempMount {
+    dir: tempfile::TempDir,
+}
+
+impl TempMount {
+    fn new(device: &str, writable: bool) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let mut c = Command::new("mount");
+        if !writable {
+            c.args(&["-o", "ro"]);
+        }
+        c.arg(device).arg(dir.path()).run()?;
+        Ok(Self { dir })
+    }
+
+    fn make_writable(&self) -> Result<()> {
+        Command::new("mount")
+            .args(&["-o", "remount,rw"])
+            .arg(self.dir.path())
+            .run()
+    }
+}
+
+impl Drop for TempMount {
+    fn drop(&mut self) {
+        if let Err(e) = Command::new("umount").arg(self.dir.path()).run() {
+            log::warn!("Failed to unmount {:?}: {}", self.dir.path(), e);
+        }
+    }
+}
+
+/// Return the current mount point of `device`, if any
+fn find_mountpoint(device: &str) -> Result<Option<PathBuf>> {
+    let out = Command::new("findmnt")
+        .args(&[
+            "--noheadings",
+            "--first-only",
+            "--output",
+            "TARGET",
+            "--source",
+        ])
+        .arg(device)
+        .output()?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+    let target = String::from_utf8(out.stdout)?;
+    let target = target.trim();
+    Ok(if target.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(target))
+    })
+}
+
+/// List the block devices whose partition type is the EFI System Partition
+fn list_esp_devices() -> Result<Vec<String>> {
+    let out = util::cmd_output(Command::new("lsblk").args(&[
+        "--paths",
+        "--list",
+        "--noheadings",
+        "--output",
+        "NAME,PARTTYPE",
+    ]))?;
+    Ok(out
+        .lines()
+        .filter_map(|l| {
+            let mut parts = l.split_whitespace();
+            let name = parts.next()?;
+            let parttype = parts.next()?;
+            if parttype.eq_ignore_ascii_case(ESP_TYPE_GUID) {
+                Some(name.to_string())
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+/// Return the device mounted at `path`, if it's a mount point
+fn mount_source(path: &Path) -> Option<String> {
+    util::cmd_output(
+        Command::new("findmnt")
+            .args(&["--noheadings", "--output", "SOURCE", "--mountpoint"])
+            .arg(path),
+    )
+    .ok()
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+}
+
+/// Which ESPs `find_esps()` returns besides the primary one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mirrors {
+    /// Only the mirrors recorded in the installed content
+    Recorded,
+    /// Also any other ESP holding exactly the installed files, as found by
+    /// partition type.  Any ESP the system can see might match, e.g. an
+    /// unrelated install on another disk, so this only applies to the
+    /// booted root, with `DISCOVER_MIRRORS_ENV` set; see `discovers_mirrors()`.
+    Discover,
+}
+
+/// Whether `find_esps()` looks for unrecorded mirrors of the ESP of `root`
+/// in `mode`, given whether `DISCOVER_MIRRORS_ENV` is set.  The ESPs found
+/// would belong to the host rather than to a target root.
+fn discovers_mirrors(root: &Path, mode: Mirrors, opted_in: bool) -> bool {
+    mode == Mirrors::Discover && opted_in && root == Path::new("/")
+}
+
+/// An ESP `find_esps()` considers, in order
+#[derive(Debug, PartialEq, Eq)]
+enum EspCandidate {
+    /// Recorded in the installed content, so used as is
+    Recorded(String),
+    /// Only used if it holds exactly the installed files
+    Unrecorded(String),
+}
+
+/// Order the ESPs to consider besides the one mounted under the root, whose
+/// device is `mounted`: the recorded primary ESP if nothing is mounted, then
+/// the recorded mirrors, then the other `devices` found by partition type.
+fn esp_candidates(
+    mounted: Option<&str>,
+    current: &InstalledContent,
+    devices: &[String],
+) -> Vec<EspCandidate> {
+    let mut seen: Vec<&str> = mounted.into_iter().collect();
+    let mut ret = Vec::new();
+    let recorded = if mounted.is_none() {
+        current.device.as_deref()
+    } else {
+        None
+    };
+    for device in recorded
+        .into_iter()
+        .chain(current.mirrors.iter().map(|d| d.as_str()))
+    {
+        if !seen.contains(&device) {
+            seen.push(device);
+            ret.push(EspCandidate::Recorded(device.to_string()));
+        }
+    }
+    for device in devices {
+        if !seen.contains(&device.as_str()) {
+            seen.push(device);
+            ret.push(EspCandidate::Unrecorded(device.clone()));
+        }
+    }
+    ret
+}
+
+/// Find the ESPs to operate on: the primary ESP mounted under `root`, followed
+/// by the mirrors recorded in `current` and, if `discovers_mirrors()`, any
+/// other ESP holding exactly the files of `currentf`.  Unmounted ESPs are
+/// mounted temporarily; unrecorded ones read-only until they're identified.
+/// If nothing is mounted under `root`, the primary ESP is instead the
+/// recorded device, or otherwise the first mirror.
+fn find_esps(
+    root: &Path,
+    current: &InstalledContent,
+    currentf: &filetree::FileTree,
+    mirrors: Mirrors,
+    writable: bool,
+) -> Result<Vec<Esp>> {
+    let primary = root.join(MOUNT_PATH);
+    let primary_device = mount_source(&primary);
+    let mut ret = Vec::new();
+    if primary_device.is_some() {
+        ret.push(Esp {
+            device: primary_device.clone(),
+            path: primary.clone(),
+            mount: None,
+        });
+    }
+    let opted_in = util::getenv_utf8(DISCOVER_MIRRORS_ENV)?.as_deref() == Some("1");
+    let devices = if discovers_mirrors(root, mirrors, opted_in) {
+        list_esp_devices().unwrap_or_else(|e| {
+            log::warn!("Failed to enumerate ESPs: {:#}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    for candidate in esp_candidates(primary_device.as_deref(), current, &devices) {
+        let esp = match candidate {
+            EspCandidate::Recorded(device) => {
+                if !Path::new(&device).exists() {
+                    log::warn!("Recorded ESP {} not found; skipping", device);
+                    continue;
+                }
+                Esp::open(&device, writable)?
+            }
+            EspCandidate::Unrecorded(device) => {
+                let esp = Esp::open(&device, false)?;
+                if !esp.holds(currentf)? {
+                    continue;
+                }
+                if writable {
+                    if let Some(mount) = esp.mount.as_ref() {
+                        mount.make_writable()?;
+                    }
+                }
+                esp
+            }
+        };
+        if ret.is_empty() {
+            log::info!("{} is not mounted; using ESP {}", primary.display(), esp);
+        }
+        ret.push(esp);
+    }
+    if ret.is_empty() {
+        ret.push(Esp {
+            device: None,
+            path: primary,
+            mount: None,
+        });
+    }
+    Ok(ret)
+}
+
+/// Prefix for the paths of files changed on the `i`th ESP, as reported
+/// by an update; like validation errors, only mirrors are qualified.
+fn changed_prefix(i: usize, esp: &Esp) -> String {
+    if i == 0 {
+        "".to_string()
+    } else {
+        format!("{}: ", esp)
+    }
+}
+
+/// Space used by the files at `paths` in `tree`, rounding each up to
+/// a multiple of `blocksize`.
+fn space_used<'a>(
+    tree: &filetree::FileTree,
+    paths: impl IntoIterator<Item = &'a String>,
+    blocksize: u64,
+) -> u64 {
+    paths
+        .into_iter()
+        .filter_map(|p| tree.children.get(p))
+        .map(|m| (m.size + blocksize - 1) / blocksize * blocksize)
+        .sum()
+}
+
+/// Ensure there is enough space on `esp` to write the added and changed
+/// files of `diff` from `updatef`, alongside the files which aren't yet
+/// replaced, and if `retain` is set, to retain its files in `PREVIOUS_DIR`.
+/// If `stage` is set, also allow for a full copy of `updatef` in
+/// `STAGED_DIR`.
+fn check_esp_space(
+    esp: &Esp,
+    efidir: &openat::Dir,
+    diff: &filetree::FileTreeDiff,
+    updatef: &filetree::FileTree,
+    retain: Option<&filetree::FileTree>,
+    stage: bool,
+) -> Result<()> {
+    let stat = nix::sys::statvfs::fstatvfs(efidir)?;
+    let blocksize = stat.fragment_size().max(1) as u64;
+    let available = stat.blocks_available() as u64 * blocksize;
+    let mut needed = space_used(
+        updatef,
+        diff.additions.iter().chain(diff.changes.iter()),
+        blocksize,
+    );
+    if let Some(current) = retain {
+        // Earlier generations are kept until pruned
+        needed += space_used(current, current.children.keys(), blocksize);
+    }
+    if stage {
+        needed += space_used(updatef, updatef.children.keys(), blocksize);
+        // As is any existing staged payload
+        let stagedpath = esp.path.join(STAGED_DIR);
+        if stagedpath.exists() {
+            let stageddir = openat::Dir::open(&stagedpath)?;
+            let stagedf = filetree::FileTree::new_from_dir(&stageddir)?;
+            let freed = space_used(&stagedf, stagedf.children.keys(), blocksize);
+            needed = needed.saturating_sub(freed);
+        }
+    }
+    if needed > available {
+        bail!(
+            "insufficient space on ESP {}: need {} bytes, have {}",
+            esp,
+            needed,
+            available
+        );
+    }
+    Ok(())
+}
+
+/// The directory holding generation `generation` of the retained payloads;
+/// generation 1, in `PREVIOUS_DIR`, is the one `rollback()` restores.
+fn generation_dir(generation: usize) -> String {
+    match generation {
+        0 | 1 => PREVIOUS_DIR.to_string(),
+        n => format!("{}.{}", PREVIOUS_DIR, n),
+    }
+}
+
+/// The generations of payloads retained on the ESP mounted at `esppath`,
+/// oldest last.
+fn retained_generations(esppath: &Path) -> Result<Vec<usize>> {
+    let mut generations = Vec::new();
+    for entry in std::fs::read_dir(esppath)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name == PREVIOUS_DIR {
+            generations.push(1);
+        } else if let Some(n) = name
+            .strip_prefix(PREVIOUS_DIR)
+            .and_then(|n| n.strip_prefix('.'))
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|&n| n > 1)
+        {
+            generations.push(n);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// Once generation 1 has been restored and removed from the ESP mounted
+/// at `esppath`, move the earlier generations up to take its place.
+fn promote_generations(esppath: &Path) -> Result<()> {
+    let esp = openat::Dir::open(esppath)?;
+    for generation in retained_generations(esppath)? {
+        esp.local_rename(
+            generation_dir(generation).as_str(),
+            generation_dir(generation - 1).as_str(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Remove generation `generation` of the payloads retained on the ESP
+/// mounted at `esppath`, returning the space freed, or `None` if there
+/// was none.
+fn remove_retained(esppath: &Path, generation: usize) -> Result<Option<u64>> {
+    let prevpath = esppath.join(generation_dir(generation));
+    if !prevpath.exists() {
+        return Ok(None);
+    }
+    let freed = {
+        let prevdir = openat::Dir::open(&prevpath)?;
+        let stat = nix::sys::statvfs::fstatvfs(&prevdir)?;
+        let blocksize = stat.fragment_size().max(1) as u64;
+        let prevf = filetree::FileTree::new_from_dir(&prevdir)?;
+        space_used(&prevf, prevf.children.keys(), blocksize)
+    };
+    std::fs::remove_dir_all(&prevpath).with_context(|| format!("removing {:?}", prevpath))?;
+    Ok(Some(freed))
+}
+
+/// Copy the files tracked by `current` out of the EFI directory into
+/// `PREVIOUS_DIR`, moving anything retained from earlier updates down a
+/// generation.  Tracked files which have gone missing are skipped.
+fn retain_previous(
+    esppath: &Path,
+    efidir: &openat::Dir,
+    current: &filetree::FileTree,
+) -> Result<()> {
+    let esp = openat::Dir::open(esppath)?;
+    // Copied aside first, so that an interrupted copy isn't mistaken
+    // for a generation
+    let tmpname = format!("{}.tmp", PREVIOUS_DIR);
+    if esp.exists(tmpname.as_str())? {
+        std::fs::remove_dir_all(esppath.join(&tmpname))?;
+    }
+    esp.create_dir(tmpname.as_str(), 0o755)?;
+    let prevdir = esp.sub_dir(tmpname.as_str())?;
+    let mut additions = HashSet::new();
+    for path in current.children.keys() {
+        if efidir.exists(path.as_str())? {
+            additions.insert(path.clone());
+        }
+    }
+    let diff = filetree::FileTreeDiff {
+        additions,
+        removals: HashSet::new(),
+        changes: HashSet::new(),
+    };
+    filetree::apply_diff(efidir, &prevdir, &diff, None)?;
+    for generation in retained_generations(esppath)?.into_iter().rev() {
+        esp.local_rename(
+            generation_dir(generation).as_str(),
+            generation_dir(generation + 1).as_str(),
+        )?;
+    }
+    esp.local_rename(tmpname.as_str(), PREVIOUS_DIR)?;
+    Ok(())
+}
+
+/// The changes needed to bring the ESP at `destdir` to `updatef`, given
+/// the changes `basediff` from the installed inventory.  Files whose
+/// content on disk already matches the update are skipped, e.g. those
+/// written before an interruption, while any which have drifted are
+/// rewritten, so that reinstalling the same version repairs a damaged ESP.
+fn esp_diff(
+    basediff: &filetree::FileTreeDiff,
+    updatef: &filetree::FileTree,
+    destdir: &openat::Dir,
+) -> Result<filetree::FileTreeDiff> {
+    let drift = updatef.relative_diff_to(destdir)?;
+    let stale: HashSet<&String> = drift.changes.iter().chain(drift.removals.iter()).collect();
+    let mut diff = basediff.clone();
+    diff.additions.retain(|p| stale.contains(p));
+    diff.changes.retain(|p| stale.contains(p));
+    for path in stale {
+        if !diff.additions.contains(path) {
+            diff.changes.insert(path.clone());
+        }
+    }
+    Ok(diff)
+}
+
+/// Whether `source` is the root `sysroot_path` itself, whose payload is
+/// then already in place rather than to be copied over it.
+fn is_sysroot(source: &dyn PayloadSource, sysroot_path: &str) -> Result<bool> {
+    match source.local_root() {
+        Some(root) => util::same_file(Path::new(root), Path::new(sysroot_path))
+            .with_context(|| format!("comparing {} to {}", root, sysroot_path)),
+        None => Ok(false),
+    }
+}
+
+/// Find the EFI directory to use as the update payload in `source`,
+/// checking that it contains at least one EFI binary.
+fn find_payload(source: &dyn PayloadSource) -> Result<PathBuf> {
+    let candidates = [
+        Path::new(ostreeutil::BOOT_PREFIX).join("efi/EFI"),
+        Path::new(BOOTUPD_UPDATES_DIR).join("EFI"),
+        Path::new(MOUNT_PATH).join("EFI"),
+    ];
+    let mut efisrc = None;
+    for p in candidates.iter() {
+        if source.exists(p)? {
+            efisrc = Some(p.clone());
+            break;
+        }
+    }
+    let efisrc =
+        efisrc.ok_or_else(|| anyhow::anyhow!("No EFI payload found in {}", source.describe()))?;
+    let has_binary = source
+        .list_files(&efisrc)?
+        .iter()
+        .any(|f| f.to_ascii_lowercase().ends_with(".efi"));
+    if !has_binary {
+        bail!(
+            "No EFI bootloader found in {:?} of {}",
+            efisrc,
+            source.describe()
+        );
+    }
+    Ok(efisrc)
+}
+
+/// Filesystems an ESP might be mistakenly formatted with, for messages
+const FSTYPE_NAMES: &[(FsType, &str)] = &[
+    (statfs::MSDOS_SUPER_MAGIC, "vfat"),
+    (statfs::EXT4_SUPER_MAGIC, "ext4"),
+    (statfs::ISOFS_SUPER_MAGIC, "iso9660"),
+    (statfs::TMPFS_MAGIC, "tmpfs"),
+    (statfs::NFS_SUPER_MAGIC, "nfs"),
+];
+
+/// A human-readable name for `fstype`
+fn fstype_name(fstype: FsType) -> String {
+    FSTYPE_NAMES
+        .iter()
+        .find(|(t, _)| *t == fstype)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("{:?}", fstype))
+}
+
+/// Firmware generally only reads FAT, so an ESP formatted with anything
+/// else accepts updates which will never be booted.  Returns the name of
+/// the filesystem containing `dir` if it isn't FAT.
+fn check_esp_fstype(dir: &openat::Dir) -> Result<Option<String>> {
+    let fstype = statfs::fstatfs(dir)?.filesystem_type();
+    if fstype == statfs::MSDOS_SUPER_MAGIC {
+        Ok(None)
+    } else {
+        Ok(Some(fstype_name(fstype)))
+    }
+}
+
 fn validate_esp(dir: &openat::Dir) -> Result<()> {
-    let stat = nix::sys::statfs::fstatfs(dir)?;
-    let fstype = stat.filesystem_type();
-    if fstype != nix::sys::statfs::MSDOS_SUPER_MAGIC {
-        bail!("EFI mount is not a msdos filesystem, but is {:?}", fstype);
+    if let Some(fstype) = check_esp_fstype(dir)? {
+        bail!("EFI mount is not a FAT filesystem, but {}", fstype);
     };
     Ok(())
 }
+
+/// Whether the filesystem containing `dir` is mounted read-only
+fn is_read_only(dir: &openat::Dir) -> Result<bool> {
+    let stat = nix::sys::statvfs::fstatvfs(dir)?;
+    Ok(stat.flags().contains(nix::sys::statvfs::FsFlags::ST_RDONLY))
+}
+
+/// Fail early if `esp` can't be written, rather than with EROFS
+/// partway through a change.
+fn check_esp_writable(esp: &Esp, dir: &openat::Dir) -> Result<()> {
+    if is_read_only(dir)? {
+        bail!("ESP {} is mounted read-only; remount rw to update", esp);
+    }
+    Ok(())
+}
+
+/// Offset of the PE header offset in the DOS header
+const PE_OFFSET_FIELD: usize = 0x3c;
+/// Size of the PE signature plus the COFF file header
+const PE_COFF_HEADER_SIZE: usize = 4 + 20;
+/// Optional header magic of PE32 and PE32+ images
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+/// Index of the certificate table among the data directories
+const PE_SECURITY_DIRECTORY: usize = 4;
+/// `WIN_CERTIFICATE` type of an Authenticode PKCS#7 signature
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+fn read_u16(buf: &[u8], off: usize) -> Option<u16> {
+    let b = buf.get(off..off + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(buf: &[u8], off: usize) -> Option<u32> {
+    let b = buf.get(off..off + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Whether the PE image in `buf` carries an Authenticode signature.
+/// This only checks that a signature is embedded; whether it chains to
+/// a key in the firmware's `db` is left to the firmware and shim.
+fn has_authenticode_signature(buf: &[u8]) -> Result<bool> {
+    if !buf.starts_with(b"MZ") {
+        bail!("Not a PE image");
+    }
+    let pe = read_u32(buf, PE_OFFSET_FIELD).context("Truncated DOS header")? as usize;
+    if buf.get(pe..pe + 4) != Some(b"PE\0\0") {
+        bail!("Missing PE signature");
+    }
+    let opt = pe + PE_COFF_HEADER_SIZE;
+    // Offsets of NumberOfRvaAndSizes and the data directories
+    let (count_off, dirs_off) = match read_u16(buf, opt).context("Truncated PE header")? {
+        PE32_MAGIC => (opt + 92, opt + 96),
+        PE32_PLUS_MAGIC => (opt + 108, opt + 112),
+        m => bail!("Unknown PE optional header magic {:#x}", m),
+    };
+    let count = read_u32(buf, count_off).context("Truncated PE header")? as usize;
+    if count <= PE_SECURITY_DIRECTORY {
+        return Ok(false);
+    }
+    let entry = dirs_off + PE_SECURITY_DIRECTORY * 8;
+    // Unlike the other directories, this one holds a file offset
+    let offset = read_u32(buf, entry).context("Truncated PE header")? as usize;
+    let size = read_u32(buf, entry + 4).context("Truncated PE header")? as usize;
+    if offset == 0 || size == 0 {
+        return Ok(false);
+    }
+    if offset.checked_add(size).map_or(true, |end| end > buf.len()) {
+        bail!("Certificate table extends past end of image");
+    }
+    Ok(read_u16(buf, offset + 6) == Some(WIN_CERT_TYPE_PKCS_SIGNED_DATA))
+}
+
+/// The EFI binaries of `tree` in `dir` which aren't signed, skipping
+/// those in `missing`.  Binaries too malformed to tell are included, with
+/// the reason.
+fn unsigned_binaries(
+    dir: &openat::Dir,
+    tree: &filetree::FileTree,
+    missing: &HashSet<String>,
+) -> Result<Vec<(String, Option<String>)>> {
+    let mut r = Vec::new();
+    for path in tree.children.keys() {
+        if !path.to_lowercase().ends_with(".efi") || missing.contains(path) {
+            continue;
+        }
+        let mut buf = Vec::new();
+        dir.open_file(path.as_str())
+            .with_context(|| format!("opening {}", path))?
+            .read_to_end(&mut buf)
+            .with_context(|| format!("reading {}", path))?;
+        match has_authenticode_signature(&buf) {
+            Ok(true) => {}
+            Ok(false) => r.push((path.clone(), None)),
+            Err(e) => r.push((path.clone(), Some(format!("{:#}", e)))),
+        }
+    }
+    Ok(r)
+}
+
+/// A firmware boot entry as reported by `efibootmgr`
+#[derive(Debug, PartialEq, Eq)]
+struct BootEntry {
+    /// Hex boot number, e.g. `0001` for `Boot0001`
+    num: String,
+    /// Human readable label
+    label: String,
+    /// Loader path relative to the ESP's `EFI` directory, lowercased
+    loader: Option<String>,
+}
+
+/// Boot variables as reported by `efibootmgr -v`
+#[derive(Debug, Default)]
+struct BootVars {
+    order: Vec<String>,
+    entries: Vec<BootEntry>,
+}
+
+/// Parse the output of `efibootmgr -v`.  Loader paths appear either as
+/// `File(\EFI\...)` or, in newer versions, as a bare `\EFI\...`.
+fn parse_efibootmgr(output: &str) -> BootVars {
+    let mut ret = BootVars::default();
+    for line in output.lines() {
+        if let Some(order) = line.strip_prefix("BootOrder:") {
+            ret.order = order.trim().split(',').map(|s| s.to_string()).collect();
+            continue;
+        }
+        let rest = match line.strip_prefix("Boot") {
+            Some(rest) => rest,
+            None => continue,
+        };
+        if rest.len() < 4 || !rest[..4].chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        let num = rest[..4].to_string();
+        let rest = rest[4..].trim_start_matches('*').trim_start();
+        let (label, devpath) = match rest.find('\t') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => match rest.find("HD(") {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            },
+        };
+        let devpath = devpath.to_ascii_lowercase();
+        let loader = devpath.find("\\efi\\").map(|i| {
+            let path = &devpath[i + "\\efi\\".len()..];
+            let end = path.find(|c| c == ')' || c == '\t' || c == ' ');
+            path[..end.unwrap_or(path.len())].replace('\\', "/")
+        });
+        ret.entries.push(BootEntry {
+            num,
+            label: label.trim().to_string(),
+            loader,
+        });
+    }
+    ret
+}
+
+/// The UEFI word size of the firmware, if we were booted via EFI.  Some
+/// x86_64 machines, e.g. tablets, have 32-bit firmware.
+fn firmware_platform_size(sysfs_efi: &Path) -> Option<u32> {
+    let buf = std::fs::read_to_string(sysfs_efi.join(FW_PLATFORM_SIZE)).ok()?;
+    buf.trim().parse().ok()
+}
+
+/// Why the payload `update` can't boot on firmware of `platform_size`
+/// bits, if it can't: 32-bit firmware only runs IA32 loaders.
+fn check_firmware_compat(
+    platform_size: Option<u32>,
+    update: &filetree::FileTree,
+) -> Option<String> {
+    if platform_size != Some(32) {
+        return None;
+    }
+    let has_ia32 = update
+        .children
+        .keys()
+        .any(|k| k.to_ascii_lowercase().ends_with("ia32.efi"));
+    if has_ia32 {
+        None
+    } else {
+        Some("The firmware is 32-bit UEFI, but the update has no IA32 loader".into())
+    }
+}
+
+/// The loaders in `current` which a firmware boot entry should point to:
+/// shim if present, otherwise any EFI binary outside the removable-media
+/// `BOOT` directory.
+fn installed_loaders(current: &filetree::FileTree) -> Vec<String> {
+    let binaries: Vec<String> = current
+        .children
+        .keys()
+        .map(|k| k.to_ascii_lowercase())
+        .filter(|k| k.ends_with(".efi") && k.contains('/') && !k.starts_with("boot/"))
+        .collect();
+    let shims: Vec<String> = binaries
+        .iter()
+        .filter(|k| {
+            k.rsplit('/')
+                .next()
+                .map_or(false, |f| f.starts_with("shim"))
+        })
+        .cloned()
+        .collect();
+    if shims.is_empty() {
+        binaries
+    } else {
+        shims
+    }
+}
+
+/// The removable-media fallback loaders in `current`, e.g.
+/// `boot/bootx64.efi`, which firmware can boot without a boot entry
+fn fallback_loaders(current: &filetree::FileTree) -> Vec<String> {
+    current
+        .children
+        .keys()
+        .map(|k| k.to_ascii_lowercase())
+        .filter(|k| k.starts_with("boot/boot") && k.ends_with(".efi"))
+        .collect()
+}
+
+/// Check that a firmware boot entry in `BootOrder` points at one of the
+/// loaders installed by `current`.  Skipped if we were not booted via EFI,
+/// or with a warning if the boot entries can't be read.
+fn validate_boot_entries(current: &filetree::FileTree) -> Result<Vec<String>> {
+    let sysfs_efi = Path::new(bootupd::SYSFS_EFI_PATH);
+    if bootupd::boot_method(sysfs_efi) != bootupd::BootMethod::EFI {
+        return Ok(Vec::new());
+    }
+    if !sysfs_efi.join("efivars").exists() {
+        log::warn!("EFI variables are not available; not checking boot entries");
+        return Ok(Vec::new());
+    }
+    let output = match util::cmd_output(Command::new("efibootmgr").arg("-v")) {
+        Ok(o) => o,
+        Err(e)
+            if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                == Some(std::io::ErrorKind::NotFound) =>
+        {
+            log::warn!("efibootmgr is not installed; not checking boot entries");
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e).context("Querying EFI boot entries"),
+    };
+    Ok(check_boot_entries(
+        &parse_efibootmgr(&output),
+        &installed_loaders(current),
+        &fallback_loaders(current),
+    ))
+}
+
+/// Check that an entry in `BootOrder` points at one of `loaders`, or at
+/// one of the `fallbacks` installed alongside them.  Nothing is needed if
+/// only fallback loaders are installed: firmware finds those by itself.
+fn check_boot_entries(vars: &BootVars, loaders: &[String], fallbacks: &[String]) -> Vec<String> {
+    if loaders.is_empty() {
+        return Vec::new();
+    }
+    let matching: Vec<&BootEntry> = vars
+        .entries
+        .iter()
+        .filter(|e| {
+            e.loader
+                .as_ref()
+                .map_or(false, |l| loaders.contains(l) || fallbacks.contains(l))
+        })
+        .collect();
+    if matching.is_empty() {
+        return vec![format!(
+            "No EFI boot entry found for {}",
+            loaders.join(", ")
+        )];
+    }
+    if !matching.iter().any(|e| vars.order.contains(&e.num)) {
+        return matching
+            .iter()
+            .map(|e| {
+                format!(
+                    "EFI boot entry Boot{} ({}) is not in BootOrder",
+                    e.num, e.label
+                )
+            })
+            .collect();
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::FilesystemSource;
+
+    const EFIBOOTMGR_OUTPUT: &str = "BootCurrent: 0001
+Timeout: 0 seconds
+BootOrder: 0001,0000
+Boot0000* UiApp\tFvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0001* Fedora\tHD(1,GPT,8a1a2c5f-8a6e-4b6a-9b1c-2e0e1d1c5a2b,0x800,0x12c000)/File(\\EFI\\fedora\\shimx64.efi)
+Boot0002* Other HD(1,GPT,8a1a2c5f-8a6e-4b6a-9b1c-2e0e1d1c5a2b,0x800,0x12c000)/\\EFI\\other\\grubx64.efi
+";
+
+    #[test]
+    fn test_fstype_name() {
+        assert_eq!(fstype_name(statfs::MSDOS_SUPER_MAGIC), "vfat");
+        assert_eq!(fstype_name(statfs::EXT4_SUPER_MAGIC), "ext4");
+        assert!(fstype_name(statfs::PROC_SUPER_MAGIC).starts_with("FsType("));
+    }
+
+    /// A minimal PE32+ image, with a certificate table if `cert_type` is set
+    fn synthesize_pe(cert_type: Option<u16>) -> Vec<u8> {
+        let pe = 0x40;
+        let opt = pe + PE_COFF_HEADER_SIZE;
+        let mut buf = vec![0u8; opt + 112 + 16 * 8];
+        buf[0..2].copy_from_slice(b"MZ");
+        buf[PE_OFFSET_FIELD..PE_OFFSET_FIELD + 4].copy_from_slice(&(pe as u32).to_le_bytes());
+        buf[pe..pe + 4].copy_from_slice(b"PE\0\0");
+        buf[opt..opt + 2].copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+        buf[opt + 108..opt + 112].copy_from_slice(&16u32.to_le_bytes());
+        if let Some(t) = cert_type {
+            let offset = buf.len();
+            let entry = opt + 112 + PE_SECURITY_DIRECTORY * 8;
+            buf[entry..entry + 4].copy_from_slice(&(offset as u32).to_le_bytes());
+            buf[entry + 4..entry + 8].copy_from_slice(&16u32.to_le_bytes());
+            buf.extend_from_slice(&16u32.to_le_bytes());
+            buf.extend_from_slice(&0x0200u16.to_le_bytes());
+            buf.extend_from_slice(&t.to_le_bytes());
+            buf.extend_from_slice(&[0u8; 8]);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_authenticode_signature() -> Result<()> {
+        assert!(has_authenticode_signature(&synthesize_pe(Some(
+            WIN_CERT_TYPE_PKCS_SIGNED_DATA
+        )))?);
+        assert!(!has_authenticode_signature(&synthesize_pe(Some(1)))?);
+        assert!(!has_authenticode_signature(&synthesize_pe(None))?);
+        let mut truncated = synthesize_pe(Some(WIN_CERT_TYPE_PKCS_SIGNED_DATA));
+        truncated.truncate(truncated.len() - 4);
+        assert!(has_authenticode_signature(&truncated).is_err());
+        assert!(has_authenticode_signature(b"not an image").is_err());
+
+        let td = tempfile::tempdir()?;
+        std::fs::write(td.path().join("shimx64.efi"), synthesize_pe(None))?;
+        std::fs::write(
+            td.path().join("grubx64.efi"),
+            synthesize_pe(Some(WIN_CERT_TYPE_PKCS_SIGNED_DATA)),
+        )?;
+        std::fs::write(td.path().join("grub.cfg"), "set timeout=1")?;
+        let dir = openat::Dir::open(td.path())?;
+        let tree = filetree::FileTree::new_from_dir(&dir)?;
+        assert_eq!(
+            unsigned_binaries(&dir, &tree, &HashSet::new())?,
+            vec![("shimx64.efi".to_string(), None)]
+        );
+        let missing: HashSet<_> = vec!["shimx64.efi".to_string()].into_iter().collect();
+        assert!(unsigned_binaries(&dir, &tree, &missing)?.is_empty());
+
+        // A malformed binary is reported rather than failing the check
+        std::fs::write(td.path().join("mmx64.efi"), "not an image")?;
+        let tree = filetree::FileTree::new_from_dir(&dir)?;
+        assert_eq!(
+            unsigned_binaries(&dir, &tree, &missing)?,
+            vec![("mmx64.efi".to_string(), Some("Not a PE image".to_string()))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_efibootmgr() {
+        let vars = parse_efibootmgr(EFIBOOTMGR_OUTPUT);
+        assert_eq!(vars.order, vec!["0001", "0000"]);
+        assert_eq!(vars.entries.len(), 3);
+        assert_eq!(vars.entries[0].loader, None);
+        assert_eq!(
+            vars.entries[1],
+            BootEntry {
+                num: "0001".into(),
+                label: "Fedora".into(),
+                loader: Some("fedora/shimx64.efi".into()),
+            }
+        );
+        assert_eq!(vars.entries[2].label, "Other");
+        assert_eq!(vars.entries[2].loader.as_deref(), Some("other/grubx64.efi"));
+    }
+
+    #[test]
+    fn test_space_used() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("fedora"))?;
+        std::fs::write(td.path().join("fedora/a.efi"), vec![0u8; 10])?;
+        std::fs::write(td.path().join("fedora/b.efi"), vec![0u8; 5000])?;
+        let tree = filetree::FileTree::new_from_dir(&openat::Dir::open(td.path())?)?;
+        assert_eq!(space_used(&tree, tree.children.keys(), 4096), 4096 * 3);
+        let a = "fedora/a.efi".to_string();
+        let missing = "fedora/missing.efi".to_string();
+        assert_eq!(space_used(&tree, vec![&a, &missing], 512), 512);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_retained() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(remove_retained(td.path(), 1)?, None);
+        let prevpath = td.path().join(PREVIOUS_DIR).join("fedora");
+        std::fs::create_dir_all(&prevpath)?;
+        std::fs::write(prevpath.join("shimx64.efi"), vec![0u8; 10])?;
+        let freed = remove_retained(td.path(), 1)?.expect("retained payload");
+        assert!(freed >= 10);
+        assert!(!td.path().join(PREVIOUS_DIR).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retained_generations() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = td.path();
+        let efidir = esp.join("EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        let efi = openat::Dir::open(&esp.join("EFI"))?;
+        for version in &["1", "2", "3"] {
+            std::fs::write(efidir.join("shimx64.efi"), version)?;
+            let currentf = filetree::FileTree::new_from_dir(&efi)?;
+            retain_previous(esp, &efi, &currentf)?;
+        }
+        // Neither an interrupted copy nor anything else counts
+        std::fs::create_dir(esp.join(format!("{}.tmp", PREVIOUS_DIR)))?;
+        std::fs::create_dir(esp.join(format!("{}.x", PREVIOUS_DIR)))?;
+        assert_eq!(retained_generations(esp)?, [1, 2, 3]);
+        let retained = |generation| {
+            std::fs::read_to_string(
+                esp.join(generation_dir(generation))
+                    .join("fedora/shimx64.efi"),
+            )
+        };
+        assert_eq!(retained(1)?, "3");
+        assert_eq!(retained(3)?, "1");
+        remove_retained(esp, 1)?;
+        promote_generations(esp)?;
+        assert_eq!(retained_generations(esp)?, [1, 2]);
+        assert_eq!(retained(1)?, "2");
+        assert_eq!(retained(2)?, "1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_generations() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = td.path().join(MOUNT_PATH);
+        std::fs::create_dir_all(esp.join("EFI/fedora"))?;
+        std::fs::write(esp.join("EFI/fedora/shimx64.efi"), "shim")?;
+        let currentf = filetree::FileTree::new_from_dir(&openat::Dir::open(&esp.join("EFI"))?)?;
+        for generation in 1..=3 {
+            let d = esp.join(generation_dir(generation)).join("fedora");
+            std::fs::create_dir_all(&d)?;
+            std::fs::write(d.join("shimx64.efi"), generation.to_string())?;
+        }
+        let current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "1".to_string(),
+                digest: None,
+                source: None,
+            },
+            filetree: Some(currentf),
+            applied: None,
+            device: None,
+            boot_code: None,
+            mirrors: Vec::new(),
+        };
+        let efi = EFI::new(td.path().to_str().unwrap());
+        let r = efi.prune(&current, Some(&current), 2)?;
+        assert_eq!(
+            r.pruned,
+            [esp.join(generation_dir(3)).display().to_string()]
+        );
+        assert!(r.freed > 0);
+        assert!(!r.previous_missing);
+        assert_eq!(retained_generations(&esp)?, [1, 2]);
+        // The payload needed for rollback is always kept
+        efi.prune(&current, Some(&current), 0)?;
+        assert_eq!(retained_generations(&esp)?, [1]);
+        // None is needed without a rollback
+        efi.prune(&current, None, 1)?;
+        assert!(retained_generations(&esp)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_booted() {
+        assert!(EFI::new("/").is_booted());
+        assert!(!EFI::new("/mnt/target").is_booted());
+    }
+
+    #[test]
+    fn test_discovers_mirrors() {
+        let booted = Path::new("/");
+        assert!(discovers_mirrors(booted, Mirrors::Discover, true));
+        assert!(!discovers_mirrors(booted, Mirrors::Discover, false));
+        assert!(!discovers_mirrors(booted, Mirrors::Recorded, true));
+        // The host's ESPs are never taken for those of a target root
+        let target = Path::new("/mnt/target");
+        assert!(!discovers_mirrors(target, Mirrors::Discover, true));
+    }
+
+    #[test]
+    fn test_find_esps_of_target() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path().join(MOUNT_PATH).join("EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        std::fs::write(efidir.join("shimx64.efi"), "shim")?;
+        let currentf = filetree::FileTree::new_from_dir(&openat::Dir::open(
+            &td.path().join(MOUNT_PATH).join("EFI"),
+        )?)?;
+        let current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "1".to_string(),
+                digest: None,
+                source: None,
+            },
+            filetree: Some(currentf.clone()),
+            applied: None,
+            device: None,
+            boot_code: None,
+            mirrors: Vec::new(),
+        };
+        // Even if the host has an ESP holding the same files, only the
+        // target's own is used
+        let esps = find_esps(td.path(), &current, &currentf, Mirrors::Discover, true)?;
+        assert_eq!(esps.len(), 1);
+        assert_eq!(esps[0].path, td.path().join(MOUNT_PATH));
+        assert!(esps[0].device.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp_candidates() {
+        use EspCandidate::*;
+        let mut current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "1".to_string(),
+                digest: None,
+                source: None,
+            },
+            filetree: None,
+            applied: None,
+            device: Some("/dev/vda2".to_string()),
+            boot_code: None,
+            mirrors: vec!["/dev/vdb2".to_string()],
+        };
+        let devices: Vec<String> = ["/dev/vda2", "/dev/vdb2", "/dev/vdc1"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        // The mounted primary ESP is never considered again
+        assert_eq!(
+            esp_candidates(Some("/dev/vda2"), &current, &devices),
+            vec![
+                Recorded("/dev/vdb2".to_string()),
+                Unrecorded("/dev/vdc1".to_string())
+            ]
+        );
+        // Unmounted, the recorded primary ESP comes first
+        assert_eq!(
+            esp_candidates(None, &current, &devices),
+            vec![
+                Recorded("/dev/vda2".to_string()),
+                Recorded("/dev/vdb2".to_string()),
+                Unrecorded("/dev/vdc1".to_string())
+            ]
+        );
+        // Without discovery, only recorded ESPs are considered
+        assert_eq!(
+            esp_candidates(Some("/dev/vda2"), &current, &[]),
+            vec![Recorded("/dev/vdb2".to_string())]
+        );
+        current.device = None;
+        current.mirrors.clear();
+        assert_eq!(
+            esp_candidates(None, &current, &devices),
+            vec![
+                Unrecorded("/dev/vda2".to_string()),
+                Unrecorded("/dev/vdb2".to_string()),
+                Unrecorded("/dev/vdc1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_esp_holds() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        for d in &["installed/BOOT", "installed/fedora", "esp/EFI/BOOT"] {
+            std::fs::create_dir_all(p.join(d))?;
+        }
+        std::fs::write(p.join("installed/BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::write(p.join("installed/fedora/shimx64.efi"), "shim")?;
+        let currentf = filetree::FileTree::new_from_dir(&openat::Dir::open(&p.join("installed"))?)?;
+        let esp = Esp {
+            device: None,
+            path: p.join("esp"),
+            mount: None,
+        };
+        // Another OS's ESP with only the fallback directory
+        std::fs::write(p.join("esp/EFI/BOOT/BOOTX64.EFI"), "other")?;
+        assert!(!esp.holds(&currentf)?);
+        // Same fallback loader, but the rest is missing
+        std::fs::write(p.join("esp/EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        assert!(!esp.holds(&currentf)?);
+        std::fs::create_dir_all(p.join("esp/EFI/fedora"))?;
+        std::fs::write(p.join("esp/EFI/fedora/shimx64.efi"), "shim")?;
+        assert!(esp.holds(&currentf)?);
+        // Files we don't track don't matter
+        std::fs::write(p.join("esp/EFI/fedora/extra.cfg"), "x")?;
+        assert!(esp.holds(&currentf)?);
+        let missing = Esp {
+            device: None,
+            path: p.join("nonexistent"),
+            mount: None,
+        };
+        assert!(!missing.holds(&currentf)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_install() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().to_str().unwrap();
+        let fedora = td.path().join(MOUNT_PATH).join("EFI/fedora");
+        std::fs::create_dir_all(&fedora)?;
+        std::fs::write(fedora.join("shimx64.efi"), "shim")?;
+        let efidir = openat::Dir::open(&td.path().join(MOUNT_PATH).join("EFI"))?;
+        let current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "1".to_string(),
+                digest: None,
+                source: None,
+            },
+            filetree: Some(filetree::FileTree::new_from_dir(&efidir)?),
+            applied: None,
+            // Neither exists; only the ESP under the root is checked
+            device: Some("/dev/nonexistent-esp".to_string()),
+            boot_code: None,
+            mirrors: vec!["/dev/nonexistent-mirror".to_string()],
+        };
+        let efi = EFI::new(sysroot);
+        assert!(matches!(
+            efi.verify_install(&current)?,
+            ValidationResult::Valid
+        ));
+        std::fs::write(fedora.join("shimx64.efi"), "corrupt")?;
+        match efi.verify_install(&current)? {
+            ValidationResult::Errors(errs) => {
+                assert_eq!(errs.len(), 1);
+                assert_eq!(errs[0].kind, ValidationErrorKind::ContentMismatch);
+                assert!(errs[0].location.is_none());
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_uninstall_esp() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        for d in &["EFI/BOOT", "EFI/fedora/fw", "EFI/other", PREVIOUS_DIR] {
+            std::fs::create_dir_all(p.join(d))?;
+        }
+        std::fs::write(p.join("EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::write(p.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(p.join("EFI/fedora/fw/fwupx64.efi"), "fwup")?;
+        let efidir = openat::Dir::open(&p.join("EFI"))?;
+        let currentf = filetree::FileTree::new_from_dir(&efidir)?;
+        // Not ours: another OS's loader, and a file added alongside ours
+        std::fs::write(p.join("EFI/other/grubx64.efi"), "grub")?;
+        std::fs::write(p.join("EFI/BOOT/fbx64.efi"), "fb")?;
+        let esp = Esp {
+            device: None,
+            path: p.to_path_buf(),
+            mount: None,
+        };
+        uninstall_esp(&esp, &efidir, &currentf)?;
+        assert!(!p.join("EFI/fedora").exists());
+        assert!(!p.join("EFI/BOOT/BOOTX64.EFI").exists());
+        assert!(p.join("EFI/BOOT/fbx64.efi").exists());
+        assert!(p.join("EFI/other/grubx64.efi").exists());
+        assert!(!p.join(PREVIOUS_DIR).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp_diff() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        for d in &["old/fedora", "new/fedora", "esp/fedora"] {
+            std::fs::create_dir_all(p.join(d))?;
+        }
+        std::fs::write(p.join("old/fedora/shimx64.efi"), "shim v1")?;
+        std::fs::write(p.join("old/fedora/grubx64.efi"), "grub v1")?;
+        std::fs::write(p.join("old/fedora/mmx64.efi"), "mm")?;
+        std::fs::write(p.join("new/fedora/shimx64.efi"), "shim v2")?;
+        std::fs::write(p.join("new/fedora/grubx64.efi"), "grub v2")?;
+        std::fs::write(p.join("new/fedora/mmx64.efi"), "mm")?;
+        std::fs::write(p.join("new/fedora/fbx64.efi"), "fb")?;
+        // grubx64.efi was written, and fbx64.efi added, before an
+        // interruption; mmx64.efi has drifted
+        std::fs::write(p.join("esp/fedora/shimx64.efi"), "shim v1")?;
+        std::fs::write(p.join("esp/fedora/grubx64.efi"), "grub v2")?;
+        std::fs::write(p.join("esp/fedora/mmx64.efi"), "corrupt")?;
+        std::fs::write(p.join("esp/fedora/fbx64.efi"), "fb")?;
+        let currentf = filetree::FileTree::new_from_dir(&openat::Dir::open(&p.join("old"))?)?;
+        let updatef = filetree::FileTree::new_from_dir(&openat::Dir::open(&p.join("new"))?)?;
+        let basediff = currentf.diff(&updatef)?;
+        assert_eq!(basediff.additions.len() + basediff.changes.len(), 3);
+        let diff = esp_diff(&basediff, &updatef, &openat::Dir::open(&p.join("esp"))?)?;
+        assert!(diff.additions.is_empty());
+        let mut changes: Vec<_> = diff.changes.into_iter().collect();
+        changes.sort();
+        assert_eq!(changes, vec!["fedora/mmx64.efi", "fedora/shimx64.efi"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_errors() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        std::fs::create_dir_all(p.join("fedora"))?;
+        std::fs::write(p.join("fedora/shimx64.efi"), "shim")?;
+        std::fs::write(p.join("fedora/grubx64.efi"), "grub")?;
+        let dir = openat::Dir::open(p)?;
+        let currentf = filetree::FileTree::new_from_dir(&dir)?;
+        std::fs::write(p.join("fedora/shimx64.efi"), "corrupt")?;
+        std::fs::remove_file(p.join("fedora/grubx64.efi"))?;
+        let diff = currentf.relative_diff_to(&dir)?;
+        let errs = content_errors(&currentf, &dir, &diff, Some("/dev/vdb2"))?;
+        assert_eq!(errs.len(), 2);
+        let (changed, removed) = (&errs[0], &errs[1]);
+        assert_eq!(changed.kind, ValidationErrorKind::ContentMismatch);
+        assert_eq!(
+            changed.expected.as_ref(),
+            Some(&currentf.children["fedora/shimx64.efi"].sha512.to_string())
+        );
+        let actual = filetree::FileMetadata::new_from_path(&dir, "fedora/shimx64.efi")?;
+        assert_eq!(changed.actual, Some(actual.sha512.to_string()));
+        assert_eq!(
+            changed.to_string(),
+            "Changed: /dev/vdb2: fedora/shimx64.efi"
+        );
+        assert_eq!(removed.kind, ValidationErrorKind::Missing);
+        assert_eq!(removed.actual, None);
+        assert_eq!(
+            removed.to_string(),
+            "Removed: /dev/vdb2: fedora/grubx64.efi"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_sysroot() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().join("sysroot");
+        std::fs::create_dir(&sysroot)?;
+        std::os::unix::fs::symlink(&sysroot, td.path().join("link"))?;
+        let sysroot_path = sysroot.to_str().unwrap();
+        let spellings = [
+            format!("{}/", sysroot_path),
+            format!("{}/.", sysroot_path),
+            format!("{}/../sysroot", sysroot_path),
+            td.path().join("link").to_str().unwrap().to_string(),
+        ];
+        for root in spellings.iter() {
+            assert!(
+                is_sysroot(&FilesystemSource::new(root), sysroot_path)?,
+                "{}",
+                root
+            );
+        }
+        let other = td.path().to_str().unwrap();
+        assert!(!is_sysroot(&FilesystemSource::new(other), sysroot_path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_payload() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        let root = &FilesystemSource::new(root);
+        assert!(find_payload(root).is_err());
+        let efidir = td.path().join("boot/efi/EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        std::fs::write(efidir.join("BOOTX64.CSV"), "shimx64.efi")?;
+        let e = find_payload(root).unwrap_err();
+        assert!(e.to_string().starts_with("No EFI bootloader found"));
+        std::fs::write(efidir.join("shimx64.efi"), "shim")?;
+        assert_eq!(find_payload(root)?, Path::new("boot/efi/EFI"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_boot_entries() {
+        let vars = parse_efibootmgr(EFIBOOTMGR_OUTPUT);
+        let ok = vec!["fedora/shimx64.efi".to_string()];
+        assert!(check_boot_entries(&vars, &ok, &[]).is_empty());
+        let missing = vec!["centos/shimx64.efi".to_string()];
+        assert_eq!(check_boot_entries(&vars, &missing, &[]).len(), 1);
+        let unordered = vec!["other/grubx64.efi".to_string()];
+        let errs = check_boot_entries(&vars, &unordered, &[]);
+        assert_eq!(
+            errs,
+            vec!["EFI boot entry Boot0002 (Other) is not in BootOrder"]
+        );
+        // Booting the installed fallback loader is fine too
+        let removable = parse_efibootmgr(
+            "BootOrder: 0003\nBoot0003* UEFI OS\tHD(1,GPT,8a1a2c5f-8a6e-4b6a-9b1c-2e0e1d1c5a2b,0x800,0x12c000)/File(\\EFI\\BOOT\\BOOTX64.EFI)\n",
+        );
+        let fallbacks = vec!["boot/bootx64.efi".to_string()];
+        assert!(check_boot_entries(&removable, &ok, &fallbacks).is_empty());
+        assert_eq!(check_boot_entries(&removable, &ok, &[]).len(), 1);
+        // With only fallback loaders, no entry is needed
+        assert!(check_boot_entries(&removable, &[], &fallbacks).is_empty());
+    }
+
+    #[test]
+    fn test_check_firmware_compat() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(firmware_platform_size(td.path()), None);
+        std::fs::write(td.path().join(FW_PLATFORM_SIZE), "32\n")?;
+        assert_eq!(firmware_platform_size(td.path()), Some(32));
+
+        std::fs::create_dir(td.path().join("BOOT"))?;
+        std::fs::write(td.path().join("BOOT/BOOTX64.EFI"), "shim")?;
+        let tree = || filetree::FileTree::new_from_dir(&openat::Dir::open(td.path()).unwrap());
+        assert_eq!(check_firmware_compat(None, &tree()?), None);
+        assert_eq!(check_firmware_compat(Some(64), &tree()?), None);
+        assert_eq!(
+            check_firmware_compat(Some(32), &tree()?).unwrap(),
+            "The firmware is 32-bit UEFI, but the update has no IA32 loader"
+        );
+        std::fs::write(td.path().join("BOOT/BOOTIA32.EFI"), "shim")?;
+        assert_eq!(check_firmware_compat(Some(32), &tree()?), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Error returned by daemon operations.  This is serialized over IPC
+/// so that clients can tell expected conditions apart from other failures.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BootupdError {
+    /// The named component is not installed
+    ComponentNotInstalled(String),
+    /// There is no saved state, i.e. bootupd has not been installed
+    NoState,
+    /// Another bootupd operation holds the write lock; the holder's
+    /// PID is included if known
+    LockContended(Option<u32>),
+    /// The preview environment variable was not set for a write operation
+    PreviewNotAccepted,
+    /// Any other failure
+    Failed(String),
+}
+
+impl BootupdError {
+    /// The process exit code used when a client operation fails with this error
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            BootupdError::ComponentNotInstalled(_) => 3,
+            BootupdError::NoState => 4,
+            BootupdError::LockContended(_) => 5,
+            BootupdError::PreviewNotAccepted => 6,
+            BootupdError::Failed(_) => libc::EXIT_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for BootupdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootupdError::ComponentNotInstalled(name) => {
+                write!(f, "Component {} is not installed", name)
+            }
+            BootupdError::NoState => write!(f, "No saved state found; bootupd is not installed"),
+            BootupdError::LockContended(Some(pid)) => {
+                write!(f, "Another bootupd operation is in progress (pid {})", pid)
+            }
+            BootupdError::LockContended(None) => {
+                write!(f, "Another bootupd operation is in progress")
+            }
+            BootupdError::PreviewNotAccepted => write!(
+                f,
+                "bootupd is currently alpha; set BOOTUPD_ACCEPT_PREVIEW=1 in environment to continue"
+            ),
+            BootupdError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BootupdError {}
+
+impl From<anyhow::Error> for BootupdError {
+    /// Recover a typed error raised via anyhow, or flatten anything else
+    /// into `Failed`.
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<BootupdError>() {
+            Ok(e) => e,
+            Err(e) => BootupdError::Failed(format!("{:#}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_from_anyhow() -> anyhow::Result<()> {
+        let e: anyhow::Result<()> = Err(BootupdError::ComponentNotInstalled("EFI".into()).into());
+        let e = BootupdError::from(e.context("updating").unwrap_err());
+        let e: BootupdError = bincode::deserialize(&bincode::serialize(&e)?)?;
+        match e {
+            BootupdError::ComponentNotInstalled(ref n) if n == "EFI" => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let e = BootupdError::from(anyhow::anyhow!("some failure").context("updating"));
+        assert_eq!(e.to_string(), "updating: some failure");
+        assert_eq!(e.exit_code(), libc::EXIT_FAILURE);
+        Ok(())
+    }
+}
@@ -0,0 +1,567 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Components described by a descriptor file rather than implemented
+//! here, so that distributions can have bootupd manage additional
+//! content such as a firmware blob.  A descriptor is a JSON file named
+//! after the component in `COMPONENTS_DIR`, e.g.
+//!
+//! ```json
+//! {
+//!   "name": "rpi-firmware",
+//!   "type": "file-copy",
+//!   "source": "usr/share/rpi-firmware",
+//!   "destination": "boot/efi",
+//!   "priority": 50
+//! }
+//! ```
+//!
+//! A `file-copy` component's payload is the files under `source` in the
+//! OS tree, which are copied below `destination` in the target root.
+
+use std::collections::BTreeMap;
+use std::path::{Component as PathComponent, Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::component::*;
+use crate::filetree;
+use crate::model::*;
+use crate::payload::PayloadSource;
+use crate::util;
+
+/// Directory holding component descriptors, relative to the sysroot
+pub(crate) const COMPONENTS_DIR: &str = "usr/lib/bootupd/components.d";
+/// Extension of descriptor files; others in `COMPONENTS_DIR` are ignored
+const DESCRIPTOR_SUFFIX: &str = ".json";
+
+/// Set by `set_components_dir()`
+static COMPONENTS_DIR_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Load descriptors from `dir` instead of `COMPONENTS_DIR` in the
+/// sysroot, for the rest of the process.
+pub(crate) fn set_components_dir(dir: &Path) {
+    *COMPONENTS_DIR_OVERRIDE.lock().unwrap() = Some(dir.to_path_buf());
+}
+
+/// The directory holding descriptors for components of `sysroot`
+fn components_dir(sysroot: &str) -> PathBuf {
+    match COMPONENTS_DIR_OVERRIDE.lock().unwrap().as_ref() {
+        Some(d) => d.clone(),
+        None => Path::new(sysroot).join(COMPONENTS_DIR),
+    }
+}
+
+/// How a described component is installed and updated
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DescriptorType {
+    /// The payload is a directory of files copied into place
+    FileCopy,
+}
+
+/// The contents of a descriptor file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Descriptor {
+    /// Name of the component, which must match the file name
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: DescriptorType,
+    /// Directory holding the payload in the OS tree, relative to its root
+    pub(crate) source: String,
+    /// Directory the payload is copied to, relative to the target root
+    pub(crate) destination: String,
+    /// See `Component::priority()`
+    #[serde(default)]
+    pub(crate) priority: Option<u32>,
+}
+
+/// Check that `path` is a relative path which stays below its root
+fn check_relative_path(field: &str, path: &str) -> Result<()> {
+    if path.is_empty() {
+        bail!("{} must not be empty", field);
+    }
+    for c in Path::new(path).components() {
+        match c {
+            PathComponent::Normal(_) | PathComponent::CurDir => {}
+            _ => bail!("{} must be a relative path without \"..\": {}", field, path),
+        }
+    }
+    Ok(())
+}
+
+impl Descriptor {
+    /// Parse the descriptor in `buf`, read from a file with the stem
+    /// `filename`, and check that it is usable.
+    pub(crate) fn parse(filename: &str, buf: &[u8]) -> Result<Self> {
+        let d: Self = serde_json::from_slice(buf)?;
+        if d.name.is_empty()
+            || !d
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "Invalid component name {:?}; use letters, digits, '-' and '_'",
+                d.name
+            );
+        }
+        if d.name != filename {
+            bail!(
+                "Component name {} doesn't match the file name {}{}",
+                d.name,
+                filename,
+                DESCRIPTOR_SUFFIX
+            );
+        }
+        if crate::bootupd::is_builtin_component(&d.name) {
+            bail!("Component {} is built in and can't be redefined", d.name);
+        }
+        check_relative_path("source", &d.source)?;
+        check_relative_path("destination", &d.destination)?;
+        Ok(d)
+    }
+}
+
+/// Read the descriptors for components of `sysroot`, in name order, each
+/// keyed by the stem of its file name.
+fn read_descriptors(sysroot: &str) -> Result<Vec<(String, Result<Descriptor>)>> {
+    let dir = components_dir(sysroot);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {:?}", dir)),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry.with_context(|| format!("reading {:?}", dir))?.path();
+        if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.ends_with(DESCRIPTOR_SUFFIX))
+            .unwrap_or(false)
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths
+        .iter()
+        .map(|path| {
+            let filename = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.trim_end_matches(DESCRIPTOR_SUFFIX))
+                .expect("checked suffix");
+            let d = std::fs::read(path)
+                .with_context(|| format!("reading {:?}", path))
+                .and_then(|buf| {
+                    Descriptor::parse(filename, &buf)
+                        .with_context(|| format!("parsing component descriptor {:?}", path))
+                });
+            (filename.to_string(), d)
+        })
+        .collect())
+}
+
+/// Load every descriptor for components of `sysroot`, in name order.  One
+/// which can't be read or parsed is skipped with a warning, so that it
+/// only breaks the operations naming that component; see
+/// `load_descriptor()`.
+pub(crate) fn load_descriptors(sysroot: &str) -> Result<Vec<Descriptor>> {
+    Ok(read_descriptors(sysroot)?
+        .into_iter()
+        .filter_map(|(name, d)| match d {
+            Ok(d) => Some(d),
+            Err(e) => {
+                log::warn!("Ignoring component {}: {:#}", name, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// The components of `sysroot` whose descriptors can't be read or parsed,
+/// each mapped to why, so that clients can report what
+/// `load_descriptors()` skips.
+pub(crate) fn invalid_descriptors(sysroot: &str) -> Result<BTreeMap<String, String>> {
+    Ok(read_descriptors(sysroot)?
+        .into_iter()
+        .filter_map(|(name, d)| d.err().map(|e| (name, format!("{:#}", e))))
+        .collect())
+}
+
+/// Load the descriptor for component `name` of `sysroot`, if there is one
+pub(crate) fn load_descriptor(sysroot: &str, name: &str) -> Result<Option<Descriptor>> {
+    read_descriptors(sysroot)?
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, d)| d)
+        .transpose()
+}
+
+/// The components described in `COMPONENTS_DIR` of `descriptor_root`,
+/// usually `sysroot` itself, managing the system at `sysroot`
+pub(crate) fn get_components(
+    sysroot: &str,
+    descriptor_root: &str,
+) -> Result<Vec<Box<dyn Component>>> {
+    Ok(load_descriptors(descriptor_root)?
+        .into_iter()
+        .map(|d| Box::new(FileCopy::new(sysroot, d)) as Box<dyn Component>)
+        .collect())
+}
+
+/// A component whose payload is copied into a fixed directory
+pub(crate) struct FileCopy {
+    /// Root of the system being managed
+    sysroot: String,
+    descriptor: Descriptor,
+}
+
+impl FileCopy {
+    /// Manage the component described by `descriptor` on the system at
+    /// `sysroot`
+    pub(crate) fn new(sysroot: &str, descriptor: Descriptor) -> Self {
+        Self {
+            sysroot: sysroot.to_string(),
+            descriptor,
+        }
+    }
+
+    /// Open the destination directory in `root`
+    fn destdir(&self, root: &str) -> Result<openat::Dir> {
+        let path = Path::new(root).join(&self.descriptor.destination);
+        openat::Dir::open(&path).with_context(|| format!("opening {:?}", path))
+    }
+
+    fn installed_filetree<'a>(
+        &self,
+        current: &'a InstalledContent,
+    ) -> Result<&'a filetree::FileTree> {
+        current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed {} found!", self.name()))
+    }
+
+    /// Read the payload in `root`, checking it against the digest in `meta`
+    fn open_payload(
+        &self,
+        root: &str,
+        meta: &ContentMetadata,
+    ) -> Result<(openat::Dir, filetree::FileTree)> {
+        let srcd =
+            openat::Dir::open(&component_updatedir(root, self)).context("opening update dir")?;
+        let ft = filetree::FileTree::new_from_dir(&srcd).context("reading update dir")?;
+        if let Some(digest) = meta.digest.as_ref() {
+            ft.verify_digest(digest)
+                .context("verifying update payload")?;
+        } else if !crate::signature::load_keys(&self.sysroot)?.is_empty() {
+            bail!(
+                "Signed update metadata for {} has no payload digest",
+                self.name()
+            );
+        }
+        Ok((srcd, ft))
+    }
+}
+
+impl Component for FileCopy {
+    fn name(&self) -> &str {
+        &self.descriptor.name
+    }
+
+    fn install(&self, src_root: &str, dest_root: &str, verbose: bool) -> Result<InstalledContent> {
+        let meta = if let Some(meta) = get_component_update(src_root, self)? {
+            meta
+        } else {
+            bail!("No update metadata for component {} found", self.name());
+        };
+        let (srcd, ft) = self.open_payload(src_root, &meta)?;
+        let destpath = Path::new(dest_root).join(&self.descriptor.destination);
+        std::fs::create_dir_all(&destpath)?;
+        let destd = self.destdir(dest_root)?;
+        let diff = filetree::FileTree::default().diff(&ft)?;
+        filetree::apply_diff(&srcd, &destd, &diff, None).context("copying payload")?;
+        if verbose {
+            for path in ft.children.keys() {
+                println!("{:?}", destpath.join(path));
+            }
+        }
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            applied: None,
+            device: None,
+            boot_code: None,
+            mirrors: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        source: &dyn PayloadSource,
+        os_version: Option<&str>,
+    ) -> Result<ContentMetadata> {
+        let src = Path::new(&self.descriptor.source);
+        if !source.exists(src)? {
+            bail!("Failed to find {:?} in {}", src, source.describe());
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)?;
+        }
+        std::fs::create_dir_all(Path::new(sysroot_path).join(BOOTUPD_UPDATES_DIR))?;
+        source.copy_dir(src, &dest)?;
+        let destd = openat::Dir::open(&dest)?;
+        let files: Vec<_> = util::filenames(&destd)?
+            .into_iter()
+            .map(|f| Path::new("/").join(src).join(f.trim_start_matches('/')))
+            .collect();
+        if files.is_empty() {
+            bail!("No files found in {:?} of {}", src, source.describe());
+        }
+        let mut meta = source.query_files(&files)?;
+        meta.digest = Some(filetree::FileTree::new_from_dir(&destd)?.digest());
+        meta.source = os_version.map(|s| s.to_string());
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self) -> Result<Option<ContentMetadata>> {
+        get_component_update(&self.sysroot, self)
+    }
+
+    fn query_update_filetree(&self) -> Result<Option<filetree::FileTree>> {
+        let updatedir = component_updatedir(&self.sysroot, self);
+        if !updatedir.exists() {
+            return Ok(None);
+        }
+        let updated = openat::Dir::open(&updatedir).context("opening update dir")?;
+        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        Ok(Some(updatef))
+    }
+
+    fn priority(&self) -> u32 {
+        self.descriptor.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    fn run_update(
+        &self,
+        current: &InstalledContent,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        let currentf = self.installed_filetree(current)?;
+        let updatemeta =
+            get_verified_component_update(&self.sysroot, self)?.expect("update available");
+        let (updated, updatef) = self.open_payload(&self.sysroot, &updatemeta)?;
+        let destd = self.destdir(&self.sysroot)?;
+        let mut diff = currentf.diff(&updatef)?;
+        diff.additions.retain(|p| !ctx.completed.contains(p));
+        diff.changes.retain(|p| !ctx.completed.contains(p));
+        diff.removals.retain(|p| !ctx.completed.contains(p));
+        (ctx.planned)(ctx.completed.len() + diff.count());
+        (ctx.progress)(&format!(
+            "Updating {} files in {}",
+            diff.count(),
+            self.descriptor.destination
+        ));
+        let mark_completed = |path: &str| {
+            (ctx.mark_completed)(path);
+            (ctx.changed)(path);
+        };
+        let opts = filetree::ApplyUpdateOptions {
+            progress: Some(ctx.progress),
+            completed: Some(&mark_completed),
+            retries: filetree::write_retries()?,
+            cancelled: Some(ctx.cancelled),
+            ..Default::default()
+        };
+        filetree::apply_diff(&updated, &destd, &diff, Some(&opts))
+            .with_context(|| format!("applying changes to {}", self.descriptor.destination))?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(updatef),
+            applied: None,
+            device: None,
+            boot_code: None,
+            mirrors: Vec::new(),
+        })
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let currentf = self.installed_filetree(current)?;
+        let destd = self.destdir(&self.sysroot)?;
+        let diff = currentf.relative_diff_to(&destd)?;
+        let mut errs = Vec::new();
+        let mut changes: Vec<_> = diff.changes.iter().collect();
+        changes.sort();
+        for f in changes {
+            errs.push(ValidationError::file(
+                self.name(),
+                ValidationErrorKind::ContentMismatch,
+                f,
+            ));
+        }
+        let mut removals: Vec<_> = diff.removals.iter().collect();
+        removals.sort();
+        for f in removals {
+            errs.push(ValidationError::file(
+                self.name(),
+                ValidationErrorKind::Missing,
+                f,
+            ));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn uninstall(&self, current: &InstalledContent) -> Result<()> {
+        let currentf = self.installed_filetree(current)?;
+        let destd = self.destdir(&self.sysroot)?;
+        for path in currentf.children.keys() {
+            if destd.exists(path.as_str())? {
+                destd
+                    .remove_file(path.as_str())
+                    .with_context(|| format!("removing {}", path))?;
+            }
+        }
+        filetree::syncfs(&destd)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::mock_installed;
+
+    #[test]
+    fn test_parse_descriptor() -> Result<()> {
+        let d = Descriptor::parse(
+            "firmware",
+            br#"{"name": "firmware", "type": "file-copy", "source": "usr/share/fw", "destination": "boot/fw"}"#,
+        )?;
+        assert_eq!(d.kind, DescriptorType::FileCopy);
+        assert_eq!(d.priority, None);
+        let c = FileCopy::new("/", d);
+        assert_eq!(c.name(), "firmware");
+        assert_eq!(c.resource_key(&mock_installed("v1"))?, "firmware");
+        assert_eq!(c.priority(), DEFAULT_PRIORITY);
+
+        for (buf, msg) in &[
+            (
+                r#"{"name": "other", "type": "file-copy", "source": "a", "destination": "b"}"#,
+                "Component name other doesn't match the file name firmware.json",
+            ),
+            (
+                r#"{"name": "firmware", "type": "file-copy", "source": "a", "destination": "/boot"}"#,
+                "destination must be a relative path without \"..\": /boot",
+            ),
+            (
+                r#"{"name": "firmware", "type": "file-copy", "source": "../a", "destination": "b"}"#,
+                "source must be a relative path without \"..\": ../a",
+            ),
+        ] {
+            let e = Descriptor::parse("firmware", buf.as_bytes()).unwrap_err();
+            assert_eq!(e.to_string(), *msg);
+        }
+        // Unknown types and fields are rejected by serde
+        assert!(Descriptor::parse(
+            "firmware",
+            br#"{"name": "firmware", "type": "script", "source": "a", "destination": "b"}"#
+        )
+        .is_err());
+        assert!(Descriptor::parse(
+            "firmware",
+            br#"{"name": "firmware", "type": "file-copy", "source": "a", "destination": "b", "exec": "x"}"#
+        )
+        .is_err());
+        let e = Descriptor::parse(
+            "EFI",
+            br#"{"name": "EFI", "type": "file-copy", "source": "a", "destination": "b"}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Component EFI is built in and can't be redefined"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_copy() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        let descdir = td.path().join(COMPONENTS_DIR);
+        std::fs::create_dir_all(&descdir)?;
+        std::fs::write(
+            descdir.join("firmware.json"),
+            r#"{"name": "firmware", "type": "file-copy", "source": "usr/share/fw", "destination": "boot/fw", "priority": 50}"#,
+        )?;
+        std::fs::write(descdir.join("README"), "ignored")?;
+        std::fs::write(descdir.join("broken.json"), "{")?;
+        let components = get_components(root, root)?;
+        assert_eq!(components.len(), 1);
+        let invalid = invalid_descriptors(root)?;
+        assert_eq!(invalid.keys().collect::<Vec<_>>(), ["broken"]);
+        assert!(invalid["broken"].starts_with("parsing component descriptor"));
+        let c = &components[0];
+        assert_eq!(c.priority(), 50);
+
+        // Stand in for a generated payload, as rpm isn't available
+        let updatedir = component_updatedir(root, c.as_ref());
+        std::fs::create_dir_all(updatedir.join("sub"))?;
+        std::fs::write(updatedir.join("blob.bin"), "v1")?;
+        std::fs::write(updatedir.join("sub/config.txt"), "config")?;
+        let ft = filetree::FileTree::new_from_dir(&openat::Dir::open(&updatedir)?)?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "fw-1".into(),
+            digest: Some(ft.digest()),
+            source: None,
+        };
+        write_update_metadata(root, c.as_ref(), &meta)?;
+
+        let installed = c.install(root, root, false)?;
+        let destdir = td.path().join("boot/fw");
+        assert_eq!(std::fs::read(destdir.join("sub/config.txt"))?, b"config");
+        assert!(matches!(c.validate(&installed)?, ValidationResult::Valid));
+        std::fs::write(destdir.join("blob.bin"), "v0")?;
+        std::fs::remove_file(destdir.join("sub/config.txt"))?;
+        match c.validate(&installed)? {
+            ValidationResult::Errors(errs) => assert_eq!(
+                describe_errors(&errs, "; "),
+                "Changed: blob.bin; Removed: sub/config.txt"
+            ),
+            r => panic!("unexpected {:?}", r),
+        }
+        c.uninstall(&installed)?;
+        assert!(!destdir.join("blob.bin").exists());
+
+        // A broken descriptor only breaks its own component
+        std::fs::write(descdir.join("broken.json"), "{")?;
+        assert_eq!(get_components(root, root)?.len(), 1);
+        assert!(load_descriptor(root, "firmware")?.is_some());
+        assert!(load_descriptor(root, "missing")?.is_none());
+        let e = load_descriptor(root, "broken").unwrap_err();
+        assert!(
+            format!("{:#}", e).starts_with("parsing component descriptor"),
+            "{:#}",
+            e
+        );
+        Ok(())
+    }
+}
@@ -5,17 +5,24 @@
  */
 
 use anyhow::{bail, Context, Result};
+use nix::sys::time::{TimeSpec, TimeValLike};
 use openat_ext::OpenatDirExt;
 use openssl::hash::{Hasher, MessageDigest};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::os::linux::fs::MetadataExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 
 /// The prefix we apply to our temporary files.
 pub(crate) const TMP_PREFIX: &str = ".btmp.";
+/// How many times to retry writing a file after a transient error
+const WRITE_RETRIES: u32 = 3;
+/// Environment variable overriding `WRITE_RETRIES`
+const WRITE_RETRIES_ENV: &str = "BOOTUPD_WRITE_RETRIES";
+/// Delay before the first retry; doubled for each subsequent one
+const WRITE_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
 
 use crate::sha512string::SHA512String;
 
@@ -30,20 +37,19 @@ pub(crate) struct FileMetadata {
     pub(crate) sha512: SHA512String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct FileTree {
     pub(crate) children: BTreeMap<String, FileMetadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct FileTreeDiff {
     pub(crate) additions: HashSet<String>,
     pub(crate) removals: HashSet<String>,
     pub(crate) changes: HashSet<String>,
 }
 
-#[cfg(test)]
 impl FileTreeDiff {
     pub(crate) fn count(&self) -> usize {
         self.additions.len() + self.removals.len() + self.changes.len()
@@ -117,6 +123,30 @@ impl FileTree {
         Ok(Self { children })
     }
 
+    /// Compute a SHA-256 digest over the paths, sizes and checksums of all
+    /// files in the tree, in the form `sha256:<hex>`.
+    pub(crate) fn digest(&self) -> String {
+        let mut hasher =
+            Hasher::new(MessageDigest::sha256()).expect("openssl sha256 hasher creation failed");
+        for (path, meta) in self.children.iter() {
+            let entry = format!("{}\0{}\0{}\n", path, meta.size, meta.sha512);
+            hasher.update(entry.as_bytes()).expect("hashing");
+        }
+        format!(
+            "sha256:{}",
+            hex::encode(hasher.finish().expect("completing hash"))
+        )
+    }
+
+    /// Verify the tree matches `expected`, as generated by `digest()`.
+    pub(crate) fn verify_digest(&self, expected: &str) -> Result<()> {
+        let found = self.digest();
+        if found != expected {
+            bail!("Digest mismatch: expected {}, found {}", expected, found);
+        }
+        Ok(())
+    }
+
     /// Determine the changes *from* self to the updated tree
     pub(crate) fn diff(&self, updated: &Self) -> Result<FileTreeDiff> {
         self.diff_impl(updated, true)
@@ -168,6 +198,8 @@ impl FileTree {
 
     /// Create a diff from a target directory.  This will ignore
     /// any files or directories that are not part of the original tree.
+    /// Files are compared against the stored sizes and checksums; a file
+    /// whose size differs is known to have changed without reading it.
     pub(crate) fn relative_diff_to(&self, dir: &openat::Dir) -> Result<FileTreeDiff> {
         let mut removals = HashSet::new();
         let mut changes = HashSet::new();
@@ -177,6 +209,9 @@ impl FileTree {
 
             if let Some(meta) = dir.metadata_optional(path)? {
                 match meta.simple_type() {
+                    openat::SimpleType::File if meta.stat().st_size as u64 != info.size => {
+                        changes.insert(path.clone());
+                    }
                     openat::SimpleType::File => {
                         let target_info = FileMetadata::new_from_path(dir, path)?;
                         if info != &target_info {
@@ -198,6 +233,51 @@ impl FileTree {
             changes,
         })
     }
+
+    /// Find files in `dir` which are not tracked by self, but live in a
+    /// directory that contains tracked files.  Directories without any
+    /// tracked files are assumed to belong to someone else and skipped.
+    pub(crate) fn untracked_in(&self, dir: &openat::Dir) -> Result<BTreeSet<String>> {
+        let owned: BTreeSet<&str> = self
+            .children
+            .keys()
+            .map(|k| k.rfind('/').map(|i| &k[..i]).unwrap_or(""))
+            .collect();
+        let mut ret = BTreeSet::new();
+        for d in owned {
+            let subdir = if d.is_empty() {
+                dir.sub_dir(".")?
+            } else {
+                match dir.sub_dir_optional(d)? {
+                    Some(subdir) => subdir,
+                    None => continue,
+                }
+            };
+            for entry in subdir.list_dir(".")? {
+                let entry = entry?;
+                let name = if let Some(name) = entry.file_name().to_str() {
+                    name
+                } else {
+                    bail!("Invalid UTF-8 filename: {:?}", entry.file_name())
+                };
+                if name.starts_with(TMP_PREFIX) {
+                    continue;
+                }
+                if subdir.get_file_type(&entry)? == openat::SimpleType::Dir {
+                    continue;
+                }
+                let path = if d.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", d, name)
+                };
+                if !self.children.contains_key(&path) {
+                    ret.insert(path);
+                }
+            }
+        }
+        Ok(ret)
+    }
 }
 
 // Recursively remove all files in the directory that start with our TMP_PREFIX
@@ -228,9 +308,71 @@ fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
 }
 
 #[derive(Default, Clone)]
-pub(crate) struct ApplyUpdateOptions {
+pub(crate) struct ApplyUpdateOptions<'a> {
     pub(crate) skip_removals: bool,
     pub(crate) skip_sync: bool,
+    /// Called with a message as each file is written
+    pub(crate) progress: Option<&'a dyn Fn(&str)>,
+    /// Called with the path of each added, changed, or removed file once
+    /// the change has been synced to disk
+    pub(crate) completed: Option<&'a dyn Fn(&str)>,
+    /// Called with the path of each written file, and of each directory
+    /// containing one, after it has been fsynced
+    pub(crate) synced: Option<&'a dyn Fn(&str)>,
+    /// How many times to retry writing a file after a transient error,
+    /// see `write_retries()`
+    pub(crate) retries: u32,
+    /// Checked before each file is written or removed; once it returns
+    /// true, stop with an error
+    pub(crate) cancelled: Option<&'a dyn Fn() -> bool>,
+}
+
+/// Parse the number of write retries from the environment, if set.
+pub(crate) fn write_retries() -> Result<u32> {
+    if let Some(v) = crate::util::getenv_utf8(WRITE_RETRIES_ENV)? {
+        v.parse()
+            .with_context(|| format!("Parsing {}={}", WRITE_RETRIES_ENV, v))
+    } else {
+        Ok(WRITE_RETRIES)
+    }
+}
+
+/// Whether an I/O error may succeed if retried, as opposed to e.g. a
+/// full or read-only filesystem
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EIO) | Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::EINTR)
+    )
+}
+
+/// Run `f`, retrying up to `retries` times with exponential backoff if
+/// it fails with a transient error.
+fn retry_transient<T>(
+    what: &str,
+    retries: u32,
+    mut f: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = WRITE_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                log::warn!(
+                    "{}: {}; retrying in {:?} ({} of {})",
+                    what,
+                    e,
+                    delay,
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            r => return r,
+        }
+    }
 }
 
 /// A bit like std::fs::copy but operates dirfd-relative
@@ -239,13 +381,37 @@ fn copy_file_at<SP: AsRef<Path>, DP: AsRef<Path>>(
     destdir: &openat::Dir,
     srcp: SP,
     destp: DP,
-) -> Result<()> {
+) -> std::io::Result<std::fs::File> {
     use openat_ext::FileExt as OpenatFileExt;
     let srcp = srcp.as_ref();
     let srcf = srcdir.open_file(srcp)?;
-    let destf = destdir.write_file(destp.as_ref(), srcf.metadata()?.st_mode())?;
+    let meta = srcf.metadata()?;
+    let destf = destdir.write_file(destp.as_ref(), meta.st_mode())?;
     srcf.copy_to(&destf)?;
+    // Carry over the modification time too, so that repeated copies are
+    // identical; FAT stores it at a coarser granularity.
+    let mtime = TimeSpec::nanoseconds(meta.st_mtime() * 1_000_000_000 + meta.st_mtime_nsec());
+    nix::sys::stat::futimens(destf.as_raw_fd(), &mtime, &mtime).map_err(|e| {
+        std::io::Error::from(e.as_errno().unwrap_or(nix::errno::Errno::UnknownErrno))
+    })?;
+
+    Ok(destf)
+}
 
+/// fsync the directory `path` relative to `dir`, persisting its entries.
+/// `openat::Dir` uses `O_PATH`, which can't be synced, so open it again.
+fn fsync_dir(dir: &openat::Dir, path: &str) -> Result<()> {
+    use nix::fcntl::OFlag;
+    let fd = nix::fcntl::openat(
+        dir.as_raw_fd(),
+        path,
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )
+    .with_context(|| format!("opening {}", path))?;
+    // Safety: we own the newly opened fd
+    let d = unsafe { std::fs::File::from_raw_fd(fd) };
+    d.sync_all().with_context(|| format!("syncing {}", path))?;
     Ok(())
 }
 
@@ -289,31 +455,91 @@ pub(crate) fn apply_diff(
     };
     let opts = opts.unwrap_or(&default_opts);
     cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    // Work in a fixed order, so that copies of the same payload are
+    // laid out identically.
+    let writes: BTreeSet<&str> = diff
+        .additions
+        .iter()
+        .chain(diff.changes.iter())
+        .map(|s| s.as_str())
+        .collect();
+    let removals: BTreeSet<&str> = diff.removals.iter().map(|s| s.as_str()).collect();
 
     // Write new and changed files
-    for pathstr in diff.additions.iter().chain(diff.changes.iter()) {
+    let total = diff.additions.len() + diff.changes.len();
+    let check_cancelled = || -> Result<()> {
+        if opts.cancelled.map_or(false, |c| c()) {
+            bail!("Update cancelled");
+        }
+        Ok(())
+    };
+    for (i, &pathstr) in writes.iter().enumerate() {
+        check_cancelled()?;
+        if let Some(progress) = opts.progress {
+            progress(&format!("Copying file {} of {}: {}", i + 1, total, pathstr));
+        }
         let path = Path::new(pathstr);
-        if let Some(parent) = path.parent() {
+        // Files at the top level have an empty parent, which can't be created
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
             // TODO: care about directory modes?  We don't for FAT.
             destdir.ensure_dir_all(parent, 0o755)?;
         }
         let destp = tmpname_for_path(path);
-        copy_file_at(srcdir, destdir, path, destp.as_path())
-            .with_context(|| format!("writing {}", &pathstr))?;
+        // FAT has no journal, so don't leave it to syncfs() to flush a
+        // loader before we rename it into place.  If syncing fails the
+        // written data may be lost, so retry the entire copy.
+        retry_transient(&format!("writing {}", pathstr), opts.retries, || {
+            let destf = copy_file_at(srcdir, destdir, path, destp.as_path())?;
+            if !opts.skip_sync {
+                destf.sync_all()?;
+            }
+            Ok(())
+        })
+        .with_context(|| format!("writing {}", &pathstr))?;
+        if !opts.skip_sync {
+            if let Some(synced) = opts.synced {
+                synced(pathstr);
+            }
+        }
     }
     // Ensure all of the new files are written persistently to disk
     if !opts.skip_sync {
         syncfs(destdir)?;
     }
-    // Now move them all into place (TODO track interruption)
-    for path in diff.additions.iter().chain(diff.changes.iter()) {
+    // Now move them all into place
+    for &path in writes.iter() {
         let pathtmp = tmpname_for_path(path);
         destdir
             .local_rename(&pathtmp, path)
             .with_context(|| format!("renaming {}", path))?;
     }
+    if !opts.skip_sync {
+        let dirs: BTreeSet<&str> = diff
+            .additions
+            .iter()
+            .chain(diff.changes.iter())
+            .map(|p| p.rfind('/').map(|i| &p[..i]).unwrap_or("."))
+            .collect();
+        for d in dirs {
+            fsync_dir(destdir, d)?;
+            if let Some(synced) = opts.synced {
+                synced(d);
+            }
+        }
+    }
+    if let Some(completed) = opts.completed {
+        // Sync the renames before reporting them, so that an interrupted
+        // update doesn't skip files which were never persisted.
+        if !opts.skip_sync {
+            syncfs(destdir)?;
+        }
+        for &path in writes.iter() {
+            completed(path);
+        }
+    }
     if !opts.skip_removals {
-        for path in diff.removals.iter() {
+        for &path in removals.iter() {
+            check_cancelled()?;
             destdir
                 .remove_file(path)
                 .with_context(|| format!("removing {}", path))?;
@@ -324,6 +550,13 @@ pub(crate) fn apply_diff(
     if !opts.skip_sync {
         syncfs(destdir)?;
     }
+    if let Some(completed) = opts.completed {
+        if !opts.skip_removals {
+            for &path in removals.iter() {
+                completed(path);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -455,6 +688,227 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_apply_reproducible() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("src/fedora"))?;
+        fs::create_dir_all(p.join("src/BOOT"))?;
+        fs::write(p.join("src/fedora/shimx64.efi"), "shim")?;
+        fs::write(p.join("src/fedora/grubx64.efi"), "grub")?;
+        fs::write(p.join("src/BOOT/BOOTX64.EFI"), "shim")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let srctree = FileTree::new_from_dir(&src)?;
+        let diff = FileTree::default().diff(&srctree)?;
+        let mut installs = Vec::new();
+        for name in &["a", "b"] {
+            fs::create_dir(p.join(name))?;
+            let dest = openat::Dir::open(&p.join(name))?;
+            let written = std::cell::RefCell::new(Vec::new());
+            let record = |path: &str| written.borrow_mut().push(path.to_string());
+            let opts = ApplyUpdateOptions {
+                completed: Some(&record),
+                ..Default::default()
+            };
+            apply_diff(&src, &dest, &diff, Some(&opts))?;
+            assert_eq!(FileTree::new_from_dir(&dest)?, srctree);
+            let attrs = srctree
+                .children
+                .keys()
+                .map(|k| {
+                    let m = fs::metadata(p.join(name).join(k))?;
+                    Ok((m.st_mode(), m.st_mtime(), m.st_mtime_nsec()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            installs.push((written.into_inner(), attrs));
+        }
+        assert_eq!(
+            installs[0].0,
+            vec![
+                "BOOT/BOOTX64.EFI",
+                "fedora/grubx64.efi",
+                "fedora/shimx64.efi"
+            ]
+        );
+        assert_eq!(installs[0], installs[1]);
+        let m = fs::metadata(p.join("src/fedora/shimx64.efi"))?;
+        assert_eq!(
+            installs[0].1[2],
+            (m.st_mode(), m.st_mtime(), m.st_mtime_nsec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_completed() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("src/fedora"))?;
+        fs::create_dir_all(p.join("dest/fedora"))?;
+        fs::write(p.join("src/fedora/shim.x64"), "new shim")?;
+        fs::write(p.join("dest/fedora/shim.x64"), "old shim")?;
+        fs::write(p.join("dest/fedora/old.efi"), "old")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let dest = openat::Dir::open(&p.join("dest"))?;
+        let diff = FileTree::new_from_dir(&dest)?.diff(&FileTree::new_from_dir(&src)?)?;
+        let completed = std::cell::RefCell::new(Vec::new());
+        let record = |path: &str| completed.borrow_mut().push(path.to_string());
+        let opts = ApplyUpdateOptions {
+            completed: Some(&record),
+            ..Default::default()
+        };
+        apply_diff(&src, &dest, &diff, Some(&opts))?;
+        assert_eq!(
+            completed.into_inner(),
+            vec!["fedora/shim.x64".to_string(), "fedora/old.efi".to_string()]
+        );
+        assert_eq!(
+            fs::read_to_string(p.join("dest/fedora/shim.x64"))?,
+            "new shim"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let eio = || std::io::Error::from_raw_os_error(libc::EIO);
+        let calls = std::cell::Cell::new(0);
+        let r = retry_transient("test", 2, || {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(eio())
+            } else {
+                Ok(calls.get())
+            }
+        });
+        assert_eq!(r.unwrap(), 3);
+
+        // Too many failures
+        calls.set(0);
+        let r: std::io::Result<()> = retry_transient("test", 1, || {
+            calls.set(calls.get() + 1);
+            Err(eio())
+        });
+        assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(calls.get(), 2);
+
+        // Permanent errors aren't retried
+        calls.set(0);
+        let r: std::io::Result<()> = retry_transient("test", 3, || {
+            calls.set(calls.get() + 1);
+            Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+        });
+        assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_apply_synced() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("src/fedora"))?;
+        fs::create_dir_all(p.join("dest"))?;
+        fs::write(p.join("src/fedora/shim.x64"), "shim")?;
+        fs::write(p.join("src/fedora/grub.x64"), "grub")?;
+        fs::create_dir_all(p.join("src/BOOT"))?;
+        fs::write(p.join("src/BOOT/BOOTX64.EFI"), "shim")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let dest = openat::Dir::open(&p.join("dest"))?;
+        let diff = FileTree::new_from_dir(&dest)?.diff(&FileTree::new_from_dir(&src)?)?;
+        let synced = std::cell::RefCell::new(BTreeSet::new());
+        let record = |path: &str| {
+            assert!(synced.borrow_mut().insert(path.to_string()));
+        };
+        let opts = ApplyUpdateOptions {
+            synced: Some(&record),
+            ..Default::default()
+        };
+        apply_diff(&src, &dest, &diff, Some(&opts))?;
+        let synced: Vec<_> = synced.into_inner().into_iter().collect();
+        assert_eq!(
+            synced,
+            vec![
+                "BOOT",
+                "BOOT/BOOTX64.EFI",
+                "fedora",
+                "fedora/grub.x64",
+                "fedora/shim.x64"
+            ]
+        );
+
+        // Nothing is synced if syncing is skipped
+        let dest2 = p.join("dest2");
+        fs::create_dir(&dest2)?;
+        let dest2 = openat::Dir::open(&dest2)?;
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            synced: Some(&|_: &str| panic!("unexpected sync")),
+            ..Default::default()
+        };
+        apply_diff(&src, &dest2, &diff, Some(&opts))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_untracked_in() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("fedora/fonts"))?;
+        fs::create_dir_all(p.join("vendor"))?;
+        fs::write(p.join("fedora/shim.x64"), "shim")?;
+        fs::write(p.join("fedora/fonts/unicode.pf2"), "font")?;
+        let d = openat::Dir::open(p)?;
+        let ft = FileTree::new_from_dir(&d)?;
+        assert!(ft.untracked_in(&d)?.is_empty());
+        fs::write(p.join("fedora/grubx64-old.efi"), "stale")?;
+        fs::write(p.join("fedora/.btmp.shim.x64"), "tmp")?;
+        fs::write(p.join("vendor/loader.efi"), "other")?;
+        fs::write(p.join("top.efi"), "top")?;
+        let untracked: Vec<_> = ft.untracked_in(&d)?.into_iter().collect();
+        assert_eq!(untracked, vec!["fedora/grubx64-old.efi".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_diff_byte_flip() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("fedora"))?;
+        fs::write(p.join("fedora/shim.x64"), "shim data")?;
+        fs::write(p.join("fedora/grub.x64"), "grub data")?;
+        let d = openat::Dir::open(p)?;
+        let t = FileTree::new_from_dir(&d)?;
+        assert_eq!(t.relative_diff_to(&d)?.count(), 0);
+        // Same size, so only the checksum can tell
+        let mut data = fs::read(p.join("fedora/shim.x64"))?;
+        data[0] ^= 0x01;
+        fs::write(p.join("fedora/shim.x64"), &data)?;
+        let diff = t.relative_diff_to(&d)?;
+        assert_eq!(diff.count(), 1);
+        assert!(diff.changes.contains("fedora/shim.x64"));
+        fs::write(p.join("fedora/grub.x64"), "grub data, longer")?;
+        let diff = t.relative_diff_to(&d)?;
+        assert_eq!(diff.changes.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("fedora"))?;
+        fs::write(p.join("fedora/shim.x64"), "shim data")?;
+        let d = openat::Dir::open(p)?;
+        let t = FileTree::new_from_dir(&d)?;
+        let digest = t.digest();
+        assert!(digest.starts_with("sha256:"));
+        t.verify_digest(&digest)?;
+        fs::write(p.join("fedora/shim.x64"), "shim dAta")?;
+        let t = FileTree::new_from_dir(&d)?;
+        assert!(t.verify_digest(&digest).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_filetree2() -> Result<()> {
         let tmpd = tempfile::tempdir()?;
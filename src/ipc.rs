@@ -4,24 +4,120 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+use crate::bootupd::ClientRequest;
+use crate::error::BootupdError;
 use anyhow::{bail, Context, Result};
 use nix::sys::socket as nixsocket;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::os::unix::io::RawFd;
 
 pub(crate) const BOOTUPD_SOCKET: &str = "/run/bootupd.sock";
 pub(crate) const MSGSIZE: usize = 1_048_576;
-/// Sent between processes along with SCM credentials
-pub(crate) const BOOTUPD_HELLO_MSG: &str = "bootupd-hello\n";
+/// Sent between processes along with SCM credentials, followed by the
+/// client's protocol version
+pub(crate) const BOOTUPD_HELLO_PREFIX: &str = "bootupd-hello";
+/// Version of the messages exchanged with the daemon; bump this when
+/// `ClientRequest` or a reply changes incompatibly.  That includes adding
+/// a field to a struct they contain, e.g. `UpdateOptions`, as bincode
+/// doesn't encode field names; `test_encoding` pins the most common.
+/// Clients which predate versioning send a bare hello, and are treated
+/// as version 0.  Version 2 added `ClientRequest::Capabilities`; newly
+/// added requests are advertised there instead.  Version 3 added fields
+/// to `UpdateOptions`, `ComponentStatus`, `Status` and other replies,
+/// and to the `Prune` and `RegenerateState` requests.
+pub(crate) const PROTOCOL_VERSION: u32 = 3;
+/// The oldest client version the daemon still serves.  Requests are only
+/// ever appended, so a client which predates some of them is understood;
+/// raise this when the encoding of a request or reply older clients use
+/// changes.  Version 2 clients can't decode the `Status` reply of version 3.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// The hello message sent by a client speaking protocol `version`
+fn hello_msg(version: u32) -> String {
+    format!("{} {}\n", BOOTUPD_HELLO_PREFIX, version)
+}
+
+/// Parse the protocol version from a client's hello message
+fn parse_hello(msg: &str) -> Result<u32> {
+    let rest = msg
+        .strip_prefix(BOOTUPD_HELLO_PREFIX)
+        .and_then(|s| s.strip_suffix('\n'))
+        .ok_or_else(|| anyhow::anyhow!("Didn't receive correct hello message, found: {:?}", msg))?;
+    if rest.is_empty() {
+        return Ok(0);
+    }
+    rest.strip_prefix(' ')
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid protocol version in hello message: {:?}", msg))
+}
+
+/// The daemon's reply to a client speaking protocol `version`: the
+/// version the connection then speaks, i.e. the client's if the daemon
+/// can serve it, or an error otherwise.  Clients which predate versioning
+/// never read this reply; they take it for the reply to their first
+/// request, which they fail to decode, or find the connection closed.
+fn negotiate(version: u32) -> DaemonToClientReply<u32> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        DaemonToClientReply::Success(version)
+    } else {
+        DaemonToClientReply::Failure(BootupdError::Failed(format!(
+            "protocol version mismatch: client speaks {}, daemon speaks {}",
+            version, PROTOCOL_VERSION
+        )))
+    }
+}
+
+/// Check the daemon's reply to our hello message.  A daemon which
+/// predates versioning rejects the hello and closes the connection.
+fn check_handshake_reply(buf: &[u8]) -> Result<()> {
+    if buf.is_empty() {
+        bail!(
+            "protocol version mismatch: daemon closed the connection; it may predate protocol version {}",
+            PROTOCOL_VERSION
+        );
+    }
+    let reply: DaemonToClientReply<u32> =
+        bincode::deserialize(buf).context("client parsing handshake")?;
+    match reply {
+        DaemonToClientReply::Success(v) if v == PROTOCOL_VERSION => Ok(()),
+        DaemonToClientReply::Success(v) => bail!(
+            "protocol version mismatch: client speaks {}, daemon speaks {}",
+            PROTOCOL_VERSION,
+            v
+        ),
+        DaemonToClientReply::Failure(e) => Err(anyhow::anyhow!("{}", e)),
+        DaemonToClientReply::Progress(_) => bail!("Unexpected progress message in handshake"),
+    }
+}
+
+/// Fail unless a daemon reporting `capabilities` can handle `req`.  An
+/// older daemon would drop the connection on receiving a request it
+/// doesn't know.
+fn check_capability(req: &ClientRequest, capabilities: &BTreeSet<String>) -> Result<()> {
+    match req.capability() {
+        Some(c) if !capabilities.contains(c) => bail!(
+            "The bootupd daemon doesn't support {}; it may be older than this client",
+            c
+        ),
+        _ => Ok(()),
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum DaemonToClientReply<T> {
     Success(T),
-    Failure(String),
+    Failure(BootupdError),
+    /// Sent zero or more times before the final reply to a request
+    Progress(String),
 }
 
 pub(crate) struct ClientToDaemonConnection {
     fd: i32,
+    /// The sysroot for requests handled in-process, if not using the daemon
+    direct: Option<String>,
+    /// Reported by the daemon, once needed
+    capabilities: Option<BTreeSet<String>>,
 }
 
 impl Drop for ClientToDaemonConnection {
@@ -34,11 +130,30 @@ impl Drop for ClientToDaemonConnection {
 
 impl ClientToDaemonConnection {
     pub(crate) fn new() -> Self {
-        Self { fd: -1 }
+        Self {
+            fd: -1,
+            direct: None,
+            capabilities: None,
+        }
+    }
+
+    /// A connection which handles requests in the client process rather
+    /// than sending them to the daemon, e.g. for debugging when socket
+    /// activation is broken.  Only read-only requests are permitted,
+    /// since the daemon is what ensures operations don't run concurrently.
+    pub(crate) fn new_direct() -> Self {
+        Self {
+            fd: -1,
+            direct: Some("/".to_string()),
+            capabilities: None,
+        }
     }
 
     pub(crate) fn connect(&mut self) -> Result<()> {
         use nix::sys::uio::IoVec;
+        if self.direct.is_some() {
+            return Ok(());
+        }
         self.fd = nixsocket::socket(
             nixsocket::AddressFamily::Unix,
             nixsocket::SockType::SeqPacket,
@@ -55,45 +170,99 @@ impl ClientToDaemonConnection {
         };
         let creds = nixsocket::UnixCredentials::from(creds);
         let creds = nixsocket::ControlMessage::ScmCredentials(&creds);
+        let hello = hello_msg(PROTOCOL_VERSION);
         let _ = nixsocket::sendmsg(
             self.fd,
-            &[IoVec::from_slice(BOOTUPD_HELLO_MSG.as_bytes())],
+            &[IoVec::from_slice(hello.as_bytes())],
             &[creds],
             nixsocket::MsgFlags::MSG_CMSG_CLOEXEC,
             None,
         )?;
-        Ok(())
+        let mut buf = [0u8; 1024];
+        let n = match nixsocket::recv(self.fd, &mut buf, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC) {
+            Ok(n) => n,
+            // Older daemons drop the connection without reading all of it
+            Err(nix::Error::Sys(nix::errno::Errno::ECONNRESET)) => 0,
+            Err(e) => return Err(e).context("client receiving handshake"),
+        };
+        check_handshake_reply(&buf[0..n])
     }
 
     pub(crate) fn send<S: serde::ser::Serialize, T: serde::de::DeserializeOwned>(
         &mut self,
         msg: &S,
     ) -> Result<T> {
-        {
-            let serialized = bincode::serialize(msg)?;
-            let _ = nixsocket::send(self.fd, &serialized, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)
-                .context("client sending request")?;
+        self.send_with_progress(msg, |_| {})
+    }
+
+    /// Like `send`, but pass any progress messages from the daemon to `progress`
+    /// as they arrive.
+    pub(crate) fn send_with_progress<S, T, F>(&mut self, msg: &S, progress: F) -> Result<T>
+    where
+        S: serde::ser::Serialize,
+        T: serde::de::DeserializeOwned,
+        F: Fn(&str),
+    {
+        let serialized = bincode::serialize(msg)?;
+        let req: ClientRequest = bincode::deserialize(&serialized)?;
+        if let Some(sysroot) = self.direct.as_mut() {
+            if !req.is_read_only() {
+                bail!("Only read-only operations can be run without the daemon");
+            }
+            let reply = crate::daemon::handle_request(sysroot, req, &progress)?;
+            return Self::parse_reply(&reply, &progress)?
+                .ok_or_else(|| anyhow::anyhow!("Request sent no reply"));
         }
-        let reply: DaemonToClientReply<T> = {
-            let mut buf = [0u8; MSGSIZE];
+        if req.capability().is_some() {
+            if self.capabilities.is_none() {
+                let caps: Vec<String> = self.send(&ClientRequest::Capabilities)?;
+                self.capabilities = Some(caps.into_iter().collect());
+            }
+            check_capability(&req, self.capabilities.as_ref().expect("capabilities"))?;
+        }
+        let _ = nixsocket::send(self.fd, &serialized, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)
+            .context("client sending request")?;
+        let mut buf = vec![0u8; MSGSIZE];
+        loop {
             let n = nixsocket::recv(self.fd, &mut buf, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)
                 .context("client recv")?;
             let buf = &buf[0..n];
             if buf.is_empty() {
                 bail!("Server sent an empty reply");
             }
-            bincode::deserialize(&buf).context("client parsing reply")?
-        };
+            if let Some(r) = Self::parse_reply(buf, &progress)? {
+                return Ok(r);
+            }
+        }
+    }
+
+    /// Parse a single message sent in reply to a request, returning the
+    /// result if it isn't a progress message.
+    fn parse_reply<T, F>(buf: &[u8], progress: &F) -> Result<Option<T>>
+    where
+        T: serde::de::DeserializeOwned,
+        F: Fn(&str),
+    {
+        let reply: DaemonToClientReply<T> =
+            bincode::deserialize(buf).context("client parsing reply")?;
         match reply {
-            DaemonToClientReply::Success::<T>(r) => Ok(r),
-            DaemonToClientReply::Failure(buf) => {
+            DaemonToClientReply::Success::<T>(r) => Ok(Some(r)),
+            DaemonToClientReply::Failure(BootupdError::Failed(buf)) => {
                 // For now we just prefix server
                 anyhow::bail!("internal error: {}", buf);
             }
+            DaemonToClientReply::Failure(e) => Err(e.into()),
+            DaemonToClientReply::Progress(msg) => {
+                progress(&msg);
+                Ok(None)
+            }
         }
     }
 
     pub(crate) fn shutdown(&mut self) -> Result<()> {
+        if self.direct.is_some() {
+            return Ok(());
+        }
         nixsocket::shutdown(self.fd, nixsocket::Shutdown::Both)?;
         Ok(())
     }
@@ -133,13 +302,22 @@ impl UnauthenticatedClient {
             if creds.uid() != 0 {
                 bail!("unauthorized pid:{} uid:{}", creds.pid(), creds.uid())
             }
-            println!("Connection from pid:{}", creds.pid());
+            log::info!("Connection from pid:{}", creds.pid());
         } else {
             bail!("No SCM credentials provided");
         }
         let hello = String::from_utf8_lossy(&buf[0..msg.bytes]);
-        if hello != BOOTUPD_HELLO_MSG {
-            bail!("Didn't receive correct hello message, found: {:?}", &hello);
+        let version = parse_hello(&hello)?;
+        let reply = negotiate(version);
+        let failed = !matches!(reply, DaemonToClientReply::Success(_));
+        let reply = bincode::serialize(&reply)?;
+        nixsocket::send(fd, &reply, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)?;
+        if failed {
+            bail!(
+                "protocol version mismatch: client speaks {}, daemon speaks {}",
+                version,
+                PROTOCOL_VERSION
+            );
         }
         let r = AuthenticatedClient { fd: self.fd };
         self.fd = -1;
@@ -159,6 +337,18 @@ pub(crate) struct AuthenticatedClient {
     pub(crate) fd: RawFd,
 }
 
+impl AuthenticatedClient {
+    /// Send a progress message for the request currently being processed.
+    pub(crate) fn send_progress(&self, msg: &str) -> Result<()> {
+        let r = bincode::serialize(&DaemonToClientReply::<()>::Progress(msg.to_string()))?;
+        let written = nixsocket::send(self.fd, &r, nixsocket::MsgFlags::MSG_CMSG_CLOEXEC)?;
+        if written != r.len() {
+            bail!("wrote {} bytes to client, expected {}", written, r.len());
+        }
+        Ok(())
+    }
+}
+
 impl Drop for AuthenticatedClient {
     fn drop(&mut self) {
         if self.fd != -1 {
@@ -166,3 +356,157 @@ impl Drop for AuthenticatedClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootupd::UpdateOptions;
+    use crate::model::{
+        ComponentStatus, ComponentUpdatable, ContentMetadata, RemoteUpdate, UpdateProgress,
+    };
+
+    /// Changing these encodings breaks older clients; see `PROTOCOL_VERSION`
+    #[test]
+    fn test_encoding() -> Result<()> {
+        let opts = UpdateOptions {
+            dry_run: true,
+            timeout: Some(5),
+            ignore_min_interval: true,
+            min_interval: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(
+            bincode::serialize(&opts)?,
+            [
+                1, 0, 0, // dry_run, force, allow_downgrade
+                1, 5, 0, 0, 0, 0, 0, 0, 0, // timeout
+                0, 1, // ignore_preconditions, ignore_min_interval
+                1, 7, 0, 0, 0, 0, 0, 0, 0, // min_interval
+            ]
+        );
+
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "v1".into(),
+            digest: None,
+            source: None,
+        };
+        let m = bincode::serialize(&meta)?;
+        // The timestamp, then the version, digest and source
+        assert!(m.ends_with(&[2, 0, 0, 0, 0, 0, 0, 0, b'v', b'1', 0, 0]));
+        let status = ComponentStatus {
+            installed: meta.clone(),
+            applied: None,
+            interrupted: None,
+            interrupted_progress: Some(UpdateProgress {
+                completed: 1,
+                total: 2,
+            }),
+            interrupted_bootable: Some(true),
+            staged: None,
+            update: Some(meta),
+            updatable: ComponentUpdatable::NotChecked,
+            devices: vec!["/dev/vda2".into()],
+            space: None,
+            active: true,
+            remote: Some(RemoteUpdate::Unknown),
+        };
+        let mut expected = m.clone();
+        expected.extend(&[0, 0]); // applied, interrupted
+        expected.push(1);
+        expected.extend(&1u64.to_le_bytes());
+        expected.extend(&2u64.to_le_bytes());
+        expected.extend(&[1, 1]); // interrupted_bootable
+        expected.push(0); // staged
+        expected.push(1);
+        expected.extend(&m);
+        expected.extend(&4u32.to_le_bytes()); // NotChecked
+        expected.extend(&1u64.to_le_bytes());
+        expected.extend(&9u64.to_le_bytes());
+        expected.extend(b"/dev/vda2");
+        expected.extend(&[0, 1]); // space, active
+        expected.push(1);
+        expected.extend(&1u32.to_le_bytes()); // RemoteUpdate::Unknown
+        assert_eq!(bincode::serialize(&status)?, expected);
+        Ok(())
+    }
+
+    /// Run both ends of the handshake over a socket pair, as the client
+    /// speaking `version` and the daemon.
+    fn handshake(version: u32) -> Result<()> {
+        let (client, daemon) = nixsocket::socketpair(
+            nixsocket::AddressFamily::Unix,
+            nixsocket::SockType::SeqPacket,
+            None,
+            nixsocket::SockFlag::SOCK_CLOEXEC,
+        )?;
+        let flags = nixsocket::MsgFlags::empty();
+        let hello = if version == 0 {
+            format!("{}\n", BOOTUPD_HELLO_PREFIX)
+        } else {
+            hello_msg(version)
+        };
+        nixsocket::send(client, hello.as_bytes(), flags)?;
+        let mut buf = [0u8; 1024];
+        let n = nixsocket::recv(daemon, &mut buf, flags)?;
+        let reply = negotiate(parse_hello(std::str::from_utf8(&buf[0..n])?)?);
+        nixsocket::send(daemon, &bincode::serialize(&reply)?, flags)?;
+        let n = nixsocket::recv(client, &mut buf, flags)?;
+        let r = check_handshake_reply(&buf[0..n]);
+        nix::unistd::close(client)?;
+        nix::unistd::close(daemon)?;
+        r
+    }
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            assert!(matches!(negotiate(version), DaemonToClientReply::Success(v) if v == version));
+        }
+        handshake(PROTOCOL_VERSION)?;
+        for version in &[0, MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let e = handshake(*version).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    "protocol version mismatch: client speaks {}, daemon speaks {}",
+                    version, PROTOCOL_VERSION
+                )
+            );
+        }
+        let e = check_handshake_reply(&[]).unwrap_err();
+        assert!(e.to_string().starts_with("protocol version mismatch"));
+        assert!(parse_hello("bootupd-hello x\n").is_err());
+        assert!(parse_hello("hello\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_capability() {
+        let all: BTreeSet<String> = crate::bootupd::CAPABILITIES
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let old: BTreeSet<String> = all.iter().filter(|c| *c != "prune").cloned().collect();
+        let prune = ClientRequest::Prune { keep: 1 };
+        check_capability(&prune, &all).unwrap();
+        let e = check_capability(&prune, &old).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "The bootupd daemon doesn't support prune; it may be older than this client"
+        );
+        // Core requests need no capability
+        let status = ClientRequest::Status {
+            components: Vec::new(),
+        };
+        check_capability(&status, &BTreeSet::new()).unwrap();
+        for req in &[
+            ClientRequest::Adopt,
+            ClientRequest::Check,
+            ClientRequest::Metrics,
+            ClientRequest::ListComponents,
+        ] {
+            check_capability(req, &all).unwrap();
+        }
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Structured events about component updates and validation, sent
+//! to the systemd journal so that they can be filtered with e.g.
+//! `journalctl BOOTUPD_COMPONENT=EFI`.
+
+use libsystemd::logging::{journal_send, Priority};
+
+/// Set by systemd in the environment of the services it runs
+const INVOCATION_ID_ENV: &str = "INVOCATION_ID";
+
+/// Outcome of an operation on a component, sent as `BOOTUPD_RESULT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EventResult {
+    Success,
+    Failure,
+    Valid,
+    Invalid,
+}
+
+impl EventResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventResult::Success => "success",
+            EventResult::Failure => "failure",
+            EventResult::Valid => "valid",
+            EventResult::Invalid => "invalid",
+        }
+    }
+
+    fn level(self) -> log::Level {
+        match self {
+            EventResult::Failure => log::Level::Error,
+            EventResult::Invalid => log::Level::Warn,
+            _ => log::Level::Info,
+        }
+    }
+
+    fn priority(self) -> Priority {
+        match self {
+            EventResult::Failure => Priority::Error,
+            EventResult::Invalid => Priority::Warning,
+            _ => Priority::Info,
+        }
+    }
+}
+
+/// An operation on a component
+pub(crate) struct Event<'a> {
+    pub(crate) component: &'a str,
+    pub(crate) result: EventResult,
+    pub(crate) old_version: Option<&'a str>,
+    pub(crate) new_version: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    /// An event with no versions
+    pub(crate) fn new(component: &'a str, result: EventResult) -> Self {
+        Self {
+            component,
+            result,
+            old_version: None,
+            new_version: None,
+        }
+    }
+
+    /// An event for a change of `component` from `old` to `new`
+    pub(crate) fn change(
+        component: &'a str,
+        result: EventResult,
+        old: &'a str,
+        new: &'a str,
+    ) -> Self {
+        Self {
+            component,
+            result,
+            old_version: Some(old),
+            new_version: Some(new),
+        }
+    }
+
+    /// The journal fields describing this event, besides the message
+    /// and priority
+    fn fields(&self) -> Vec<(&'static str, &'a str)> {
+        let mut fields = vec![
+            ("SYSLOG_IDENTIFIER", "bootupd"),
+            ("BOOTUPD_COMPONENT", self.component),
+            ("BOOTUPD_RESULT", self.result.as_str()),
+        ];
+        if let Some(v) = self.old_version {
+            fields.push(("BOOTUPD_OLD_VERSION", v));
+        }
+        if let Some(v) = self.new_version {
+            fields.push(("BOOTUPD_NEW_VERSION", v));
+        }
+        fields
+    }
+}
+
+/// Record `message` about `event`, falling back to plain logging when
+/// the journal isn't available.
+pub(crate) fn log_event(event: &Event, message: &str) {
+    if std::env::var_os(INVOCATION_ID_ENV).is_some() {
+        let fields = event.fields().into_iter();
+        match journal_send(event.result.priority(), message, fields) {
+            Ok(()) => return,
+            Err(e) => log::debug!("Failed to send to journal: {}", e),
+        }
+    }
+    log::log!(event.result.level(), "{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let event = Event::change("EFI", EventResult::Success, "v1", "v2");
+        assert_eq!(
+            event.fields(),
+            vec![
+                ("SYSLOG_IDENTIFIER", "bootupd"),
+                ("BOOTUPD_COMPONENT", "EFI"),
+                ("BOOTUPD_RESULT", "success"),
+                ("BOOTUPD_OLD_VERSION", "v1"),
+                ("BOOTUPD_NEW_VERSION", "v2"),
+            ]
+        );
+        let event = Event::new("BIOS", EventResult::Invalid);
+        assert_eq!(event.fields().len(), 3);
+        assert!(matches!(event.result.priority(), Priority::Warning));
+    }
+}
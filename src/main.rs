@@ -2,10 +2,10 @@
 **Boot**loader **upd**ater.
 
 This is an early prototype hidden/not-yet-standardized mechanism
-which just updates EFI for now (x86_64/aarch64 only).
+which just updates EFI (x86_64/aarch64) and the BIOS MBR (x86_64) for now.
 
 But in the future will hopefully gain some independence from
-ostree and also support e.g. updating the MBR etc.
+ostree and also support other architecture bootloaders.
 
 Refs:
  * <https://github.com/coreos/fedora-coreos-tracker/issues/510>
@@ -13,17 +13,29 @@ Refs:
 
 #![deny(unused_must_use)]
 
+#[cfg(target_arch = "x86_64")]
+mod bios;
 mod bootupd;
 mod cli;
 mod component;
+mod compress;
 mod daemon;
 #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 mod efi;
+mod error;
+mod filecopy;
 mod filetree;
 mod ipc;
+mod journal;
 mod model;
 mod ostreeutil;
+mod packagesystem;
+mod payload;
+mod remote;
 mod sha512string;
+mod signature;
+#[cfg(test)]
+mod testutil;
 mod util;
 
 use structopt::clap::crate_name;
@@ -49,11 +61,13 @@ fn run_cli() -> i32 {
 
     // Dispatch CLI subcommand.
     match cli_opts.run() {
-        Ok(_) => libc::EXIT_SUCCESS,
+        Ok(code) => code,
         Err(e) => {
             // Use the alternative formatter to get everything on a single line... it reads better.
             eprintln!("error: {:#}", e);
-            libc::EXIT_FAILURE
+            e.downcast_ref::<error::BootupdError>()
+                .map(|e| e.exit_code())
+                .unwrap_or(libc::EXIT_FAILURE)
         }
     }
 }
@@ -4,23 +4,38 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+use anyhow::{anyhow, bail, Result};
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 
 /// The directory where updates are stored
 pub(crate) const BOOTUPD_UPDATES_DIR: &str = "usr/lib/bootupd/updates";
 
+/// The current version of the `SavedState` schema; bump this and extend
+/// `SavedState::migrate()` when making a change that older versions of
+/// bootupd could not safely round-trip.
+pub(crate) const STATE_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 pub(crate) struct ContentMetadata {
     /// The timestamp, which is used to determine update availability
     pub(crate) timestamp: DateTime<Utc>,
     /// Human readable version number, like ostree it is not ever parsed, just displayed
     pub(crate) version: String,
+    /// SHA-256 digest of the update payload, see `FileTree::digest()`
+    #[serde(default)]
+    pub(crate) digest: Option<String>,
+    /// The OS release which produced the payload, for provenance only
+    #[serde(default)]
+    pub(crate) source: Option<String>,
 }
 
 impl ContentMetadata {
-    /// Returns `true` if `target` is different and chronologically newer
+    /// Returns `true` if `target` is different and chronologically newer.
+    /// This is the default ordering for components; see
+    /// `Component::can_upgrade`.
     pub(crate) fn can_upgrade_to(&self, target: &Self) -> bool {
         if self.version == target.version {
             return false;
@@ -33,21 +48,143 @@ impl ContentMetadata {
 pub(crate) struct InstalledContent {
     /// Associated metadata
     pub(crate) meta: ContentMetadata,
-    /// Human readable version number, like ostree it is not ever parsed, just displayed
+    /// Inventory of the files placed by this component, with their sizes
+    /// and checksums; absent for components which don't install files
     pub(crate) filetree: Option<crate::filetree::FileTree>,
+    /// When this content was installed or updated by bootupd; unknown for
+    /// content that was adopted or recorded by older versions
+    pub(crate) applied: Option<DateTime<Utc>>,
+    /// The block device the content was written to, e.g. the primary ESP;
+    /// used to find it again if it isn't mounted
+    #[serde(default)]
+    pub(crate) device: Option<String>,
+    /// Digest of the boot code written outside of a filesystem, e.g. to
+    /// the MBR, in the form `sha256:<hex>`; unknown for content recorded
+    /// by older versions
+    #[serde(default)]
+    pub(crate) boot_code: Option<String>,
+    /// Block devices of the other ESPs mirroring the primary one, which
+    /// updates also write
+    #[serde(default)]
+    pub(crate) mirrors: Vec<String>,
+}
+
+/// An update which was started but has not completed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PendingUpdate {
+    /// The content being installed
+    #[serde(flatten)]
+    pub(crate) meta: ContentMetadata,
+    /// Parts of the update known to be complete, see
+    /// `component::UpdateContext::completed`
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) completed: BTreeSet<String>,
+    /// How many parts the update has in all, once known; see
+    /// `component::UpdateContext::planned`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) total: Option<u64>,
+    /// The content was staged to be applied at the next boot, rather
+    /// than being written in place
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) staged: bool,
 }
 
 /// Will be serialized into /boot/bootupd-state.json
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct SavedState {
+    /// Schema version, see `STATE_VERSION`.  Files written before
+    /// versioning was introduced lack this and are treated as version 0.
+    #[serde(default)]
+    pub(crate) version: u32,
     /// Maps a component name to its currently installed version
     pub(crate) installed: BTreeMap<String, InstalledContent>,
     /// Maps a component name to an in progress update
-    pub(crate) pending: Option<BTreeMap<String, ContentMetadata>>,
+    pub(crate) pending: Option<BTreeMap<String, PendingUpdate>>,
+    /// Maps a component name to the version it was updated from, if
+    /// that payload has been retained for rollback
+    pub(crate) previous: Option<BTreeMap<String, InstalledContent>>,
+    /// Maps a component name to the versions retained before `previous`,
+    /// newest first, until they're pruned
+    #[serde(default)]
+    pub(crate) retained: Option<BTreeMap<String, Vec<InstalledContent>>>,
+    /// Activity counters, see `bootupctl metrics`
+    #[serde(default)]
+    pub(crate) counters: Counters,
+    /// Version of the bootupd which last wrote this state; unknown for
+    /// states written by older versions
+    #[serde(default)]
+    pub(crate) written_by: Option<String>,
 }
 
-/// The status of an individual component.
+/// Counts of operations performed, kept in the saved state so that
+/// monitoring can assess bootupd activity without scraping logs
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Counters {
+    /// Component updates successfully applied
+    pub(crate) updates_applied: u64,
+    /// Updates which completed after a previous attempt was interrupted
+    pub(crate) interrupted_recovered: u64,
+    /// When the last update was applied
+    pub(crate) last_update: Option<DateTime<Utc>>,
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        SavedState {
+            version: STATE_VERSION,
+            installed: Default::default(),
+            pending: Default::default(),
+            previous: Default::default(),
+            retained: Default::default(),
+            counters: Default::default(),
+            written_by: None,
+        }
+    }
+}
+
+impl SavedState {
+    /// Parse a saved state, upgrading older layouts to the current schema.
+    /// A state written by a newer bootupd is rejected, since rewriting it
+    /// would drop fields we don't know about.
+    pub(crate) fn from_json(mut v: serde_json::Value) -> Result<Self> {
+        let version = match v.get("version") {
+            Some(n) => n
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| anyhow!("Invalid state version: {}", n))?,
+            None => 0,
+        };
+        if version > STATE_VERSION {
+            bail!(
+                "State file has version {}, but this bootupd only supports up to version {}; refusing to modify it",
+                version,
+                STATE_VERSION
+            );
+        }
+        Self::migrate(&mut v, version)?;
+        Ok(serde_json::from_value(v)?)
+    }
+
+    /// Upgrade the JSON representation of a state from `version` to
+    /// `STATE_VERSION` in place.
+    fn migrate(v: &mut serde_json::Value, version: u32) -> Result<()> {
+        let obj = v
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("State is not a JSON object"))?;
+        // Version 0 to 1: only the version field was added; fields added
+        // before versioning all have serde defaults.
+        if version < 1 {
+            obj.insert("version".into(), 1.into());
+        }
+        Ok(())
+    }
+}
+
+/// The status of an individual component.  The serialized names are
+/// part of the `bootupctl status --json` format and must remain stable.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum ComponentUpdatable {
@@ -55,15 +192,23 @@ pub(crate) enum ComponentUpdatable {
     AtLatestVersion,
     Upgradable,
     WouldDowngrade,
+    /// Updates weren't looked for; see `ClientRequest::StatusInstalled`
+    NotChecked,
 }
 
 impl ComponentUpdatable {
-    pub(crate) fn from_metadata(from: &ContentMetadata, to: Option<&ContentMetadata>) -> Self {
+    /// Classify moving `component` from `from` to `to`, using the
+    /// component's own version ordering.
+    pub(crate) fn from_metadata(
+        component: &dyn crate::component::Component,
+        from: &ContentMetadata,
+        to: Option<&ContentMetadata>,
+    ) -> Self {
         match to {
             Some(to) => {
                 if from.version == to.version {
                     ComponentUpdatable::AtLatestVersion
-                } else if from.can_upgrade_to(to) {
+                } else if component.can_upgrade(from, to) {
                     ComponentUpdatable::Upgradable
                 } else {
                     ComponentUpdatable::WouldDowngrade
@@ -80,12 +225,95 @@ impl ComponentUpdatable {
 pub(crate) struct ComponentStatus {
     /// Currently installed version
     pub(crate) installed: ContentMetadata,
+    /// When the installed version was applied, if known
+    pub(crate) applied: Option<DateTime<Utc>>,
     /// In progress update that was interrupted
     pub(crate) interrupted: Option<ContentMetadata>,
+    /// How far the `interrupted` update got; unknown if it was
+    /// interrupted before working out what to write, or by an older
+    /// bootupd
+    #[serde(default)]
+    pub(crate) interrupted_progress: Option<UpdateProgress>,
+    /// Whether the files of the installed version were found intact
+    /// despite the `interrupted` update, so that it can still boot;
+    /// unknown if they couldn't be checked
+    #[serde(default)]
+    pub(crate) interrupted_bootable: Option<bool>,
+    /// Update staged to be applied at the next boot
+    #[serde(default)]
+    pub(crate) staged: Option<ContentMetadata>,
     /// Update in the deployed filesystem tree
     pub(crate) update: Option<ContentMetadata>,
     /// Is true if the version in `update` is different from `installed`
     pub(crate) updatable: ComponentUpdatable,
+    /// Block devices written by the component, e.g. ESPs or the disk
+    /// holding the MBR
+    #[serde(default)]
+    pub(crate) devices: Vec<String>,
+    /// Space on the filesystem holding the component, e.g. the primary
+    /// ESP; absent for components which aren't stored in a filesystem
+    #[serde(default)]
+    pub(crate) space: Option<SpaceUsage>,
+    /// Whether the running system was booted from this component; see
+    /// `bootupd::is_active()`
+    #[serde(default)]
+    pub(crate) active: bool,
+    /// What the remote update source offers; only probed for
+    /// `ClientRequest::StatusRemote`
+    #[serde(default)]
+    pub(crate) remote: Option<RemoteUpdate>,
+}
+
+/// What a remote update source offers for a component; see
+/// `remote::check_remote()`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RemoteUpdate {
+    /// The source has `update`, which compares to the installed version
+    /// as `updatable`
+    Found {
+        update: ContentMetadata,
+        updatable: ComponentUpdatable,
+    },
+    /// The source couldn't be reached, or its reply wasn't understood
+    Unknown,
+}
+
+impl PendingUpdate {
+    /// How far the update got, if its total is known
+    pub(crate) fn progress(&self) -> Option<UpdateProgress> {
+        self.total.map(|total| UpdateProgress {
+            completed: self.completed.len() as u64,
+            total,
+        })
+    }
+}
+
+/// Parts of an update, e.g. files written, which were completed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UpdateProgress {
+    pub(crate) completed: u64,
+    pub(crate) total: u64,
+}
+
+/// Capacity of a filesystem, in bytes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SpaceUsage {
+    pub(crate) available: u64,
+    pub(crate) total: u64,
+}
+
+impl SpaceUsage {
+    /// Percentage of the filesystem in use, rounded up
+    pub(crate) fn percent_used(&self) -> u64 {
+        if self.total == 0 {
+            return 100;
+        }
+        let used = self.total.saturating_sub(self.available);
+        (used * 100 + self.total - 1) / self.total
+    }
 }
 
 /// Representation of bootupd's worldview at a point in time.
@@ -97,6 +325,25 @@ pub(crate) struct ComponentStatus {
 pub(crate) struct Status {
     /// Maps a component name to status
     pub(crate) components: BTreeMap<String, ComponentStatus>,
+    /// Whether Secure Boot is enabled; unknown if not booted via EFI, or
+    /// for a root other than the booted one
+    #[serde(default)]
+    pub(crate) secure_boot: Option<bool>,
+    /// Version of the bootupd which last wrote the saved state
+    #[serde(default)]
+    pub(crate) state_written_by: Option<String>,
+    /// How the running system was booted, e.g. `EFI`; only reported for
+    /// the booted root
+    #[serde(default)]
+    pub(crate) boot_method: Option<String>,
+    /// Set if components are installed, but none of them is the one the
+    /// system booted with, so updates won't affect how it boots
+    #[serde(default)]
+    pub(crate) boot_method_unmanaged: bool,
+    /// Maps the name of a component whose descriptor can't be loaded to
+    /// why; such components are otherwise ignored
+    #[serde(default)]
+    pub(crate) invalid_descriptors: BTreeMap<String, String>,
 }
 
 #[cfg(test)]
@@ -110,12 +357,165 @@ mod test {
         let a = ContentMetadata {
             timestamp: t,
             version: "v1".into(),
+            digest: None,
+            source: None,
         };
         let b = ContentMetadata {
             timestamp: t + Duration::seconds(1),
             version: "v2".into(),
+            digest: None,
+            source: None,
         };
         assert!(a.can_upgrade_to(&b));
         assert!(!b.can_upgrade_to(&a));
     }
+
+    #[test]
+    fn test_old_state() -> anyhow::Result<()> {
+        let data = r#"{"installed":{"EFI":{"meta":{"timestamp":"2020-09-15T13:01:21Z","version":"grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"},"filetree":null}},"pending":null}"#;
+        let state = SavedState::from_json(serde_json::from_str(data)?)?;
+        assert_eq!(state.version, STATE_VERSION);
+        let efi = state.installed.get("EFI").expect("EFI");
+        assert!(efi.applied.is_none());
+        assert!(efi.meta.digest.is_none());
+        assert!(state.previous.is_none());
+        assert_eq!(state.counters.updates_applied, 0);
+        assert!(state.written_by.is_none());
+        assert!(state.counters.last_update.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_compat() -> anyhow::Result<()> {
+        // Pending entries used to be bare metadata
+        let data = r#"{"installed":{},"pending":{"EFI":{"timestamp":"2020-09-15T13:01:21Z","version":"v2"}}}"#;
+        let state = SavedState::from_json(serde_json::from_str(data)?)?;
+        let mut pending = state.pending.expect("pending");
+        let efi = pending.get_mut("EFI").expect("EFI");
+        assert_eq!(efi.meta.version, "v2");
+        assert!(efi.completed.is_empty());
+
+        efi.completed.insert("/dev/vda2:fedora/shimx64.efi".into());
+        assert!(!efi.staged);
+        let v = serde_json::to_value(&*efi)?;
+        assert_eq!(v["version"], "v2");
+        assert_eq!(v["completed"][0], "/dev/vda2:fedora/shimx64.efi");
+        assert!(v.get("staged").is_none());
+
+        efi.staged = true;
+        let v = serde_json::to_value(&*efi)?;
+        let efi: PendingUpdate = serde_json::from_value(v)?;
+        assert!(efi.staged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_version() -> anyhow::Result<()> {
+        let state = SavedState::default();
+        let v = serde_json::to_value(&state)?;
+        assert_eq!(v["version"], STATE_VERSION);
+        let state = SavedState::from_json(v)?;
+        assert_eq!(state.version, STATE_VERSION);
+
+        let future = serde_json::json!({
+            "version": STATE_VERSION + 1,
+            "installed": {},
+            "some-new-field": true,
+        });
+        let e = SavedState::from_json(future).unwrap_err();
+        assert!(e.to_string().contains("refusing to modify"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_json() -> anyhow::Result<()> {
+        let names = [
+            (ComponentUpdatable::NoUpdateAvailable, "no-update-available"),
+            (ComponentUpdatable::AtLatestVersion, "at-latest-version"),
+            (ComponentUpdatable::Upgradable, "upgradable"),
+            (ComponentUpdatable::WouldDowngrade, "would-downgrade"),
+            (ComponentUpdatable::NotChecked, "not-checked"),
+        ];
+        for (v, name) in names.iter() {
+            assert_eq!(serde_json::to_value(v)?, serde_json::json!(name));
+        }
+
+        let t = Utc::now();
+        let meta = ContentMetadata {
+            timestamp: t,
+            version: "v1".into(),
+            digest: None,
+            source: None,
+        };
+        let mut status = Status::default();
+        status.components.insert(
+            "EFI".into(),
+            ComponentStatus {
+                installed: meta.clone(),
+                applied: None,
+                interrupted: None,
+                interrupted_progress: None,
+                interrupted_bootable: None,
+                staged: None,
+                update: Some(meta),
+                updatable: ComponentUpdatable::AtLatestVersion,
+                devices: vec!["/dev/vda2".into()],
+                active: true,
+                remote: None,
+                space: Some(SpaceUsage {
+                    available: 60,
+                    total: 100,
+                }),
+            },
+        );
+        let v = serde_json::to_value(&status)?;
+        let efi = &v["components"]["EFI"];
+        for k in &["installed", "interrupted", "update", "updatable"] {
+            assert!(efi.get(k).is_some(), "missing {}", k);
+        }
+        assert_eq!(v["secure-boot"], serde_json::Value::Null);
+        assert_eq!(efi["installed"]["version"], "v1");
+        assert_eq!(efi["updatable"], "at-latest-version");
+        assert_eq!(efi["devices"], serde_json::json!(["/dev/vda2"]));
+        assert_eq!(efi["space"]["available"], 60);
+        assert_eq!(efi["space"]["total"], 100);
+        assert_eq!(efi["active"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_percent_used() {
+        let space = |available, total| SpaceUsage { available, total };
+        assert_eq!(space(60, 100).percent_used(), 40);
+        assert_eq!(space(100, 100).percent_used(), 0);
+        assert_eq!(space(0, 100).percent_used(), 100);
+        assert_eq!(space(2, 3).percent_used(), 34);
+        assert_eq!(space(0, 0).percent_used(), 100);
+    }
+
+    #[test]
+    fn test_reply_encoding() -> anyhow::Result<()> {
+        // Replies are sent with bincode, which isn't self-describing, so
+        // decoding fails unless every field is always serialized
+        let meta = ContentMetadata {
+            timestamp: Utc::now(),
+            version: "v1".into(),
+            digest: None,
+            source: None,
+        };
+        let decoded: ContentMetadata = bincode::deserialize(&bincode::serialize(&meta)?)?;
+        assert_eq!(decoded, meta);
+        let installed = InstalledContent {
+            meta,
+            filetree: None,
+            applied: None,
+            device: None,
+            boot_code: None,
+            mirrors: Vec::new(),
+        };
+        let decoded: InstalledContent = bincode::deserialize(&bincode::serialize(&installed)?)?;
+        assert_eq!(decoded.meta, installed.meta);
+        assert_eq!(decoded.device, None);
+        Ok(())
+    }
 }
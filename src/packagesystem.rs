@@ -0,0 +1,247 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::prelude::*;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+
+use crate::model::*;
+use crate::ostreeutil;
+
+/// Query the rpm database and list the package and build times for all the
+/// given files.  If any files are not owned it is considered an error condition.
+/// The returned metadata uses the newest build time as its timestamp, and
+/// the set of packages as its version.
+pub(crate) fn query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let rpmout = {
+        let mut c = ostreeutil::rpm_cmd(sysroot_path);
+        c.args(&["-q", "--queryformat", "%{nevra},%{buildtime} ", "-f"]);
+        for arg in paths {
+            c.arg(arg.as_ref());
+        }
+        c
+    }
+    .output()?;
+    if !rpmout.status.success() {
+        std::io::stderr().write_all(&rpmout.stderr)?;
+        bail!("Failed to invoke rpm -qf");
+    }
+    let pkgs = std::str::from_utf8(&rpmout.stdout)?
+        .split_whitespace()
+        .map(|s| -> Result<_> {
+            let parts: Vec<_> = s.splitn(2, ',').collect();
+            let name = parts[0];
+            if let Some(ts) = parts.get(1) {
+                let nt = NaiveDateTime::parse_from_str(ts, "%s")
+                    .context("Failed to parse rpm buildtime")?;
+                Ok((name, DateTime::<Utc>::from_utc(nt, Utc)))
+            } else {
+                bail!("Failed to parse: {}", s);
+            }
+        })
+        .collect::<Result<BTreeMap<&str, DateTime<Utc>>>>()?;
+    if pkgs.is_empty() {
+        bail!("Failed to find any RPM packages matching files");
+    }
+    let timestamps: BTreeSet<&DateTime<Utc>> = pkgs.values().collect();
+    // Unwrap safety: We validated pkgs has at least one value above
+    let largest_timestamp = timestamps.iter().last().unwrap();
+    let version = pkgs.keys().fold("".to_string(), |mut s, n| {
+        if !s.is_empty() {
+            s.push(',');
+        }
+        s.push_str(n);
+        s
+    });
+
+    Ok(ContentMetadata {
+        timestamp: **largest_timestamp,
+        version,
+        digest: None,
+        source: None,
+    })
+}
+
+/// The parts of a package NEVRA, e.g. `grub2-tools-1:2.04-31.fc33.x86_64`
+#[derive(Debug, PartialEq, Eq)]
+struct Nevra<'a> {
+    name: &'a str,
+    epoch: u64,
+    version: &'a str,
+    release: &'a str,
+}
+
+impl<'a> Nevra<'a> {
+    fn parse(s: &'a str) -> Option<Self> {
+        let (rest, relarch) = s.split_at(s.rfind('-')?);
+        let relarch = &relarch[1..];
+        let (name, ev) = rest.split_at(rest.rfind('-')?);
+        let ev = &ev[1..];
+        let release = &relarch[..relarch.rfind('.')?];
+        let (epoch, version) = match ev.find(':') {
+            Some(i) => (ev[..i].parse().ok()?, &ev[i + 1..]),
+            None => (0, ev),
+        };
+        if name.is_empty() || version.is_empty() || release.is_empty() {
+            return None;
+        }
+        Some(Self {
+            name,
+            epoch,
+            version,
+            release,
+        })
+    }
+}
+
+/// Compare two version or release strings the way rpm does: runs of
+/// digits compare numerically and sort after runs of letters, other
+/// characters only separate segments, and `~` sorts before everything,
+/// even the end of the string.
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    let is_sep = |c: u8| !c.is_ascii_alphanumeric() && c != b'~';
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        while a.first().map(|&c| is_sep(c)).unwrap_or(false) {
+            a = &a[1..];
+        }
+        while b.first().map(|&c| is_sep(c)).unwrap_or(false) {
+            b = &b[1..];
+        }
+        match (a.first(), b.first()) {
+            (Some(b'~'), Some(b'~')) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (Some(b'~'), _) => return Ordering::Less,
+            (_, Some(b'~')) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            _ => {}
+        }
+        let numeric = a[0].is_ascii_digit();
+        let seglen = |s: &[u8]| {
+            s.iter()
+                .take_while(|c| {
+                    if numeric {
+                        c.is_ascii_digit()
+                    } else {
+                        c.is_ascii_alphabetic()
+                    }
+                })
+                .count()
+        };
+        let (alen, blen) = (seglen(a), seglen(b));
+        // A numeric segment is newer than an alphabetic one
+        if blen == 0 {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        let (aseg, bseg) = (&a[..alen], &b[..blen]);
+        let ord = if numeric {
+            let (aseg, bseg) = (trim_zeros(aseg), trim_zeros(bseg));
+            aseg.len().cmp(&bseg.len()).then_with(|| aseg.cmp(bseg))
+        } else {
+            aseg.cmp(bseg)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        a = &a[alen..];
+        b = &b[blen..];
+    }
+}
+
+/// Strip the leading zeros from a numeric version segment
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let zeros = s.iter().take_while(|&&c| c == b'0').count();
+    &s[zeros..]
+}
+
+/// Order two NEVRAs of the same package by epoch, version and release.
+/// Returns `None` if either can't be parsed or they name different
+/// packages.
+pub(crate) fn compare_nevra(a: &str, b: &str) -> Option<Ordering> {
+    let (a, b) = (Nevra::parse(a)?, Nevra::parse(b)?);
+    if a.name != b.name {
+        return None;
+    }
+    Some(
+        a.epoch
+            .cmp(&b.epoch)
+            .then_with(|| rpmvercmp(a.version, b.version))
+            .then_with(|| rpmvercmp(a.release, b.release)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpmvercmp() {
+        use Ordering::*;
+        for (a, b, ord) in &[
+            ("1.0", "1.0", Equal),
+            ("1.0", "2.0", Less),
+            ("2.10", "2.9", Greater),
+            ("2.04", "2.4", Equal),
+            ("1.0a", "1.0", Greater),
+            ("1.0a", "1.0.1", Less),
+            ("1.0~rc1", "1.0", Less),
+            ("1.0~rc1", "1.0~rc2", Less),
+            ("31.fc33", "31.fc32", Greater),
+            ("1_0", "1.0", Equal),
+        ] {
+            assert_eq!(rpmvercmp(a, b), *ord, "{} vs {}", a, b);
+            assert_eq!(rpmvercmp(b, a), ord.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn test_compare_nevra() {
+        assert_eq!(
+            Nevra::parse("grub2-tools-1:2.04-31.fc33.x86_64"),
+            Some(Nevra {
+                name: "grub2-tools",
+                epoch: 1,
+                version: "2.04",
+                release: "31.fc33",
+            })
+        );
+        assert_eq!(Nevra::parse("grub2"), None);
+        let older = "grub2-tools-1:2.04-31.fc33.x86_64";
+        assert_eq!(
+            compare_nevra(older, "grub2-tools-1:2.06-2.fc33.x86_64"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_nevra(older, "grub2-tools-1:2.04-4.fc33.x86_64"),
+            Some(Ordering::Greater)
+        );
+        // The epoch wins over the version
+        assert_eq!(
+            compare_nevra(older, "grub2-tools-2.02-100.fc33.x86_64"),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(compare_nevra(older, "shim-x64-15-8.x86_64"), None);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Sources of the bootloader payloads from which updates are generated.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::model::*;
+use crate::packagesystem;
+use crate::util;
+use crate::util::CommandRunExt;
+
+/// Where a component's payload is taken from when generating an update,
+/// e.g. an OS tree on disk.  Paths are relative to the root of the source.
+pub(crate) trait PayloadSource {
+    /// Describe the source for messages
+    fn describe(&self) -> String;
+
+    /// The root of the source, if it is a directory on the local filesystem
+    fn local_root(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether `path` exists in the source
+    fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// The files under the directory `path`, relative to it
+    fn list_files(&self, path: &Path) -> Result<Vec<String>>;
+
+    /// Copy the directory `path` to `dest`, which must not exist
+    fn copy_dir(&self, path: &Path, dest: &Path) -> Result<()>;
+
+    /// Read the file at `path`, if it exists
+    fn read_file(&self, path: &Path) -> Result<Option<Vec<u8>>>;
+
+    /// Query the packages owning `files`, given as absolute paths within
+    /// the source.  See `packagesystem::query_files()`.
+    fn query_files(&self, files: &[PathBuf]) -> Result<ContentMetadata>;
+}
+
+/// A source rooted at a directory, such as the booted root or a
+/// mounted image
+pub(crate) struct FilesystemSource {
+    root: String,
+}
+
+impl FilesystemSource {
+    pub(crate) fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+        }
+    }
+}
+
+impl PayloadSource for FilesystemSource {
+    fn describe(&self) -> String {
+        self.root.clone()
+    }
+
+    fn local_root(&self) -> Option<&str> {
+        Some(&self.root)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(Path::new(&self.root).join(path).exists())
+    }
+
+    fn list_files(&self, path: &Path) -> Result<Vec<String>> {
+        let path = Path::new(&self.root).join(path);
+        let dir = openat::Dir::open(&path).with_context(|| format!("opening {:?}", path))?;
+        let mut r: Vec<_> = util::filenames(&dir)?
+            .into_iter()
+            .map(|f| f.trim_start_matches('/').to_string())
+            .collect();
+        r.sort();
+        Ok(r)
+    }
+
+    fn copy_dir(&self, path: &Path, dest: &Path) -> Result<()> {
+        // Copy rather than move, as the source may be a read-only image
+        Command::new("cp")
+            .args(&["-rp", "--reflink=auto"])
+            .arg(Path::new(&self.root).join(path))
+            .arg(dest)
+            .run()
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let path = Path::new(&self.root).join(path);
+        match std::fs::read(&path) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {:?}", path)),
+        }
+    }
+
+    fn query_files(&self, files: &[PathBuf]) -> Result<ContentMetadata> {
+        packagesystem::query_files(&self.root, files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filesystem_source() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().expect("utf-8 tempdir");
+        let efidir = td.path().join("usr/lib/bootupd/updates/EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        std::fs::write(efidir.join("shimx64.efi"), "shim")?;
+        std::fs::write(efidir.join("grubx64.efi"), "grub")?;
+        let source = FilesystemSource::new(root);
+        assert_eq!(source.local_root(), Some(root));
+
+        let payload = Path::new("usr/lib/bootupd/updates/EFI");
+        assert!(source.exists(payload)?);
+        assert!(!source.exists(Path::new("boot/efi/EFI"))?);
+        assert_eq!(
+            source.list_files(payload)?,
+            vec!["fedora/grubx64.efi", "fedora/shimx64.efi"]
+        );
+        assert_eq!(
+            source.read_file(&payload.join("fedora/shimx64.efi"))?,
+            Some(b"shim".to_vec())
+        );
+        assert_eq!(source.read_file(Path::new("etc/os-release"))?, None);
+
+        let dest = td.path().join("copy");
+        source.copy_dir(payload, &dest)?;
+        assert_eq!(std::fs::read(dest.join("fedora/grubx64.efi"))?, b"grub");
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Probing a remote update source, for deployments which fetch payloads
+//! from e.g. a registry rather than shipping them in the OS tree.  The
+//! source is configured in `REMOTE_CONFIG`, e.g.
+//!
+//! ```json
+//! {
+//!   "url": "https://example.com/bootupd/{component}.json"
+//! }
+//! ```
+//!
+//! where each URL serves the component's update metadata, as written to
+//! `BOOTUPD_UPDATES_DIR` by `generate-update-metadata`.  This only
+//! reports what's available upstream; updates are still applied from the
+//! local payload.
+
+use std::collections::{btree_map, BTreeMap};
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::model::*;
+use crate::util;
+
+/// Configuration of the remote update source, relative to the sysroot
+pub(crate) const REMOTE_CONFIG: &str = "etc/bootupd/remote.json";
+/// Metadata fetched from the remote source, relative to the sysroot
+const REMOTE_CACHE: &str = "run/bootupd-remote-cache.json";
+/// How long fetched metadata is reused before probing again
+const CACHE_TTL_SECS: i64 = 15 * 60;
+/// Give up on a remote source which takes longer than this to respond
+const FETCH_TIMEOUT_SECS: u32 = 30;
+/// Replaced by the component name in `RemoteConfig::url`
+const COMPONENT_PLACEHOLDER: &str = "{component}";
+
+/// The contents of `REMOTE_CONFIG`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemoteConfig {
+    /// URL of the update metadata for each component, containing
+    /// `COMPONENT_PLACEHOLDER`
+    url: String,
+}
+
+impl RemoteConfig {
+    /// Read the configuration of the system at `sysroot`
+    fn load(sysroot: &str) -> Result<Self> {
+        let path = Path::new(sysroot).join(REMOTE_CONFIG);
+        let buf = match std::fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("No remote update source configured in /{}", REMOTE_CONFIG)
+            }
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let config: Self =
+            serde_json::from_slice(&buf).with_context(|| format!("parsing {}", path.display()))?;
+        if !config.url.contains(COMPONENT_PLACEHOLDER) {
+            bail!(
+                "Remote update source URL {} doesn't contain {}",
+                config.url,
+                COMPONENT_PLACEHOLDER
+            );
+        }
+        Ok(config)
+    }
+
+    /// The URL of the update metadata for component `name`
+    fn url_for(&self, name: &str) -> String {
+        self.url.replace(COMPONENT_PLACEHOLDER, name)
+    }
+}
+
+/// Metadata fetched from a URL, in `REMOTE_CACHE`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CachedMetadata {
+    fetched: DateTime<Utc>,
+    meta: ContentMetadata,
+}
+
+/// Cached metadata by URL
+type Cache = BTreeMap<String, CachedMetadata>;
+
+/// Read the cache at `path`, dropping entries which expired by `now`.
+/// A missing or unreadable cache is treated as empty.
+fn load_cache(path: &Path, now: DateTime<Utc>) -> Cache {
+    let mut cache: Cache = std::fs::read(path)
+        .ok()
+        .and_then(|buf| serde_json::from_slice(&buf).ok())
+        .unwrap_or_default();
+    cache.retain(|_, c| {
+        let age = now.signed_duration_since(c.fetched).num_seconds();
+        (0..CACHE_TTL_SECS).contains(&age)
+    });
+    cache
+}
+
+/// Replace the cache at `path`
+fn write_cache(path: &Path, cache: &Cache) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(cache)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Fetch `url` with curl
+fn fetch(url: &str) -> Result<String> {
+    util::cmd_output(
+        Command::new("curl")
+            .args(&["--fail", "--silent", "--show-error", "--location"])
+            .arg("--max-time")
+            .arg(FETCH_TIMEOUT_SECS.to_string())
+            .arg("--")
+            .arg(url),
+    )
+}
+
+/// Fill in what the remote update source offers for each component in
+/// `status` of the system at `sysroot`.  Fails if no source is
+/// configured; a component whose metadata can't be fetched is reported
+/// as `RemoteUpdate::Unknown`.
+pub(crate) fn check_remote(sysroot: &str, status: &mut Status) -> Result<()> {
+    check_remote_with(sysroot, status, Utc::now(), &fetch)
+}
+
+/// Implementation of `check_remote()`, fetching with `fetch` and
+/// considering cached metadata fresh as of `now`
+fn check_remote_with(
+    sysroot: &str,
+    status: &mut Status,
+    now: DateTime<Utc>,
+    fetch: &dyn Fn(&str) -> Result<String>,
+) -> Result<()> {
+    let config = RemoteConfig::load(sysroot)?;
+    let cache_path = Path::new(sysroot).join(REMOTE_CACHE);
+    let mut cache = load_cache(&cache_path, now);
+    let mut fetched = false;
+    for (name, cs) in status.components.iter_mut() {
+        let url = config.url_for(name);
+        let meta = match cache.get(&url) {
+            Some(c) => Ok(c.meta.clone()),
+            None => fetch(&url)
+                .and_then(|buf| Ok(serde_json::from_str::<ContentMetadata>(&buf)?))
+                .with_context(|| format!("fetching {}", url)),
+        };
+        let update = match meta {
+            Ok(meta) => meta,
+            Err(e) => {
+                log::warn!("Failed to probe remote update source for {}: {:#}", name, e);
+                cs.remote = Some(RemoteUpdate::Unknown);
+                continue;
+            }
+        };
+        if let btree_map::Entry::Vacant(e) = cache.entry(url) {
+            e.insert(CachedMetadata {
+                fetched: now,
+                meta: update.clone(),
+            });
+            fetched = true;
+        }
+        let component = crate::component::new_from_name(sysroot, name)?;
+        let updatable =
+            ComponentUpdatable::from_metadata(component.as_ref(), &cs.installed, Some(&update));
+        cs.remote = Some(RemoteUpdate::Found { update, updatable });
+    }
+    if fetched {
+        if let Err(e) = write_cache(&cache_path, &cache) {
+            log::warn!("Failed to write {}: {:#}", cache_path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_check_remote() -> Result<()> {
+        let name = "mock-remote";
+        register_mock(name, MockBehavior::default());
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().to_str().unwrap();
+        std::fs::create_dir_all(td.path().join("run"))?;
+        let installed = mock_meta("v1");
+        let status = || {
+            let mut status = Status::default();
+            status.components.insert(
+                name.into(),
+                ComponentStatus {
+                    installed: installed.clone(),
+                    applied: None,
+                    interrupted: None,
+                    interrupted_progress: None,
+                    interrupted_bootable: None,
+                    staged: None,
+                    update: None,
+                    updatable: ComponentUpdatable::NoUpdateAvailable,
+                    devices: Vec::new(),
+                    space: None,
+                    active: false,
+                    remote: None,
+                },
+            );
+            status
+        };
+        let urls = RefCell::new(Vec::new());
+        let reply: RefCell<Result<String, String>> =
+            RefCell::new(Ok(serde_json::to_string(&mock_meta("v2"))?));
+        let fetch = |url: &str| -> Result<String> {
+            urls.borrow_mut().push(url.to_string());
+            match &*reply.borrow() {
+                Ok(s) => Ok(s.clone()),
+                Err(e) => bail!("{}", e),
+            }
+        };
+
+        let mut s = status();
+        let e = check_remote_with(sysroot, &mut s, Utc::now(), &fetch).unwrap_err();
+        assert!(
+            e.to_string().starts_with("No remote update source"),
+            "{}",
+            e
+        );
+
+        std::fs::create_dir_all(td.path().join("etc/bootupd"))?;
+        let config = td.path().join(REMOTE_CONFIG);
+        std::fs::write(&config, r#"{"url": "https://example.com/updates.json"}"#)?;
+        assert!(check_remote_with(sysroot, &mut s, Utc::now(), &fetch).is_err());
+        std::fs::write(
+            &config,
+            r#"{"url": "https://example.com/{component}.json"}"#,
+        )?;
+
+        // Fetched once, then served from the cache until it expires
+        let now = Utc::now();
+        for _ in 0..2 {
+            let mut s = status();
+            check_remote_with(sysroot, &mut s, now, &fetch)?;
+            match s.components[name].remote.as_ref().unwrap() {
+                RemoteUpdate::Found { update, updatable } => {
+                    assert_eq!(update.version, "v2");
+                    assert!(matches!(updatable, ComponentUpdatable::Upgradable));
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        assert_eq!(*urls.borrow(), vec!["https://example.com/mock-remote.json"]);
+
+        // Network errors aren't fatal, and aren't cached
+        *reply.borrow_mut() = Err("Connection refused".into());
+        let later = now + chrono::Duration::seconds(CACHE_TTL_SECS);
+        for _ in 0..2 {
+            let mut s = status();
+            check_remote_with(sysroot, &mut s, later, &fetch)?;
+            assert!(matches!(
+                s.components[name].remote,
+                Some(RemoteUpdate::Unknown)
+            ));
+        }
+        assert_eq!(urls.borrow().len(), 3);
+
+        // Nor are unparseable replies
+        *reply.borrow_mut() = Ok("<html>".into());
+        let mut s = status();
+        check_remote_with(sysroot, &mut s, later, &fetch)?;
+        assert!(matches!(
+            s.components[name].remote,
+            Some(RemoteUpdate::Unknown)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Verification of detached signatures on update metadata.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+
+/// Directory of PEM public keys, relative to the root.  If it contains
+/// any `.pem` files, update metadata must be signed by one of them.
+pub(crate) const KEYS_DIR: &str = "etc/bootupd/keys";
+/// Suffix of the detached signature alongside a metadata file
+pub(crate) const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// Load the configured public keys; an empty list means verification
+/// is disabled.
+pub(crate) fn load_keys(sysroot: &str) -> Result<Vec<PKey<Public>>> {
+    let keysdir = Path::new(sysroot).join(KEYS_DIR);
+    if !keysdir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(&keysdir).with_context(|| format!("reading {:?}", keysdir))? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |e| e == "pem") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+        .iter()
+        .map(|p| {
+            let pem = std::fs::read(p).with_context(|| format!("reading {:?}", p))?;
+            PKey::public_key_from_pem(&pem).with_context(|| format!("parsing key {:?}", p))
+        })
+        .collect()
+}
+
+fn verify_one(key: &PKey<Public>, data: &[u8], signature: &[u8]) -> Result<bool> {
+    // EdDSA keys sign the message directly rather than a digest of it
+    let r = match key.id() {
+        Id::ED25519 | Id::ED448 => {
+            Verifier::new_without_digest(key)?.verify_oneshot(signature, data)?
+        }
+        _ => {
+            let mut v = Verifier::new(MessageDigest::sha256(), key)?;
+            v.update(data)?;
+            v.verify(signature)?
+        }
+    };
+    Ok(r)
+}
+
+/// Verify that `signature` over `data` was made by one of `keys`.
+pub(crate) fn verify_detached(keys: &[PKey<Public>], data: &[u8], signature: &[u8]) -> Result<()> {
+    for key in keys {
+        // An error here usually means a signature of the wrong type for
+        // this key; another key may still match.
+        if verify_one(key, data, signature).unwrap_or(false) {
+            return Ok(());
+        }
+    }
+    bail!("Signature does not match any key in /{}", KEYS_DIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::sign::Signer;
+
+    #[test]
+    fn test_verify_detached() -> Result<()> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let private = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem()?)?;
+        let other = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let other = PKey::public_key_from_pem(&other.public_key_to_pem()?)?;
+
+        let data = br#"{"timestamp":"2020-09-15T13:01:21Z","version":"v1"}"#;
+        let mut signer = Signer::new(MessageDigest::sha256(), &private)?;
+        signer.update(data)?;
+        let sig = signer.sign_to_vec()?;
+
+        verify_detached(&[other.clone(), public.clone()], data, &sig)?;
+        assert!(verify_detached(&[other], data, &sig).is_err());
+        let keys = [public];
+        assert!(verify_detached(&keys, b"tampered", &sig).is_err());
+        assert!(verify_detached(&keys, data, b"garbage").is_err());
+        Ok(())
+    }
+}
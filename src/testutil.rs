@@ -0,0 +1,253 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Test support: a component whose behavior is configured by the test,
+//! so that update and status logic can be exercised without an ESP.
+//! Components are instantiated by name, possibly on another thread, so
+//! the configuration is registered globally under the component's name;
+//! each test should use a name of its own.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+
+use crate::component::*;
+use crate::model::*;
+use crate::payload::PayloadSource;
+
+/// `component::new_from_name()` creates a `MockComponent` for names with
+/// this prefix
+pub(crate) const MOCK_PREFIX: &str = "mock-";
+
+/// Behavior of a `MockComponent`, and what was asked of it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MockBehavior {
+    /// Returned by `priority`, if not `DEFAULT_PRIORITY`
+    pub(crate) priority: Option<u32>,
+    /// Returned by `query_update`
+    pub(crate) update: Option<ContentMetadata>,
+    /// Identifiers of the parts written by `run_update`, in order
+    pub(crate) parts: Vec<String>,
+    /// Make `run_update` fail once this many parts have been completed,
+    /// counting those completed by an earlier attempt
+    pub(crate) fail_after: Option<usize>,
+    /// Make `run_update` wait once this many parts have been completed,
+    /// until it's cancelled; it then completes the part it was writing
+    /// and fails
+    pub(crate) hang_after: Option<usize>,
+    /// Files reported as changed by `validate`
+    pub(crate) invalid: Vec<String>,
+    /// Make `validate` fail outright
+    pub(crate) fail_validate: bool,
+    /// Returned by `check_precondition`
+    pub(crate) precondition: Option<String>,
+    /// The parts already completed, as passed to each call of `run_update`
+    pub(crate) runs: Vec<BTreeSet<String>>,
+    /// Number of calls to `install`
+    pub(crate) installs: usize,
+    /// Support staging, applying the staged update as `run_update` would
+    pub(crate) stageable: bool,
+    /// Whether an update is staged
+    pub(crate) staged: bool,
+    /// The versions restored by each call of `rollback`
+    pub(crate) rollbacks: Vec<String>,
+}
+
+static MOCKS: Mutex<BTreeMap<String, MockBehavior>> = Mutex::new(BTreeMap::new());
+
+/// Configure the mock component `name`, replacing any earlier behavior
+pub(crate) fn register_mock(name: &str, behavior: MockBehavior) {
+    assert!(name.starts_with(MOCK_PREFIX), "{}", name);
+    MOCKS.lock().unwrap().insert(name.to_string(), behavior);
+}
+
+/// Change the behavior of the mock component `name`
+pub(crate) fn modify_mock(name: &str, f: impl FnOnce(&mut MockBehavior)) {
+    f(MOCKS
+        .lock()
+        .unwrap()
+        .get_mut(name)
+        .expect("registered mock"));
+}
+
+/// The current behavior of the mock component `name`
+pub(crate) fn mock_behavior(name: &str) -> MockBehavior {
+    MOCKS.lock().unwrap()[name].clone()
+}
+
+/// Metadata for version `version` of a mock payload
+pub(crate) fn mock_meta(version: &str) -> ContentMetadata {
+    ContentMetadata {
+        timestamp: chrono::Utc::now(),
+        version: version.into(),
+        digest: None,
+        source: None,
+    }
+}
+
+/// Version `version` of a mock payload, as installed
+pub(crate) fn mock_installed(version: &str) -> InstalledContent {
+    InstalledContent {
+        meta: mock_meta(version),
+        filetree: None,
+        applied: None,
+        device: None,
+        boot_code: None,
+        mirrors: Vec::new(),
+    }
+}
+
+/// A component behaving as registered with `register_mock()`
+pub(crate) struct MockComponent {
+    name: String,
+}
+
+impl MockComponent {
+    pub(crate) fn new(name: &str) -> Result<Self> {
+        if !MOCKS.lock().unwrap().contains_key(name) {
+            bail!("No component {}", name);
+        }
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    /// Write the parts of the available update, for `run_update` and
+    /// `apply_staged`
+    fn write_parts(&self, ctx: &UpdateContext) -> Result<InstalledContent> {
+        modify_mock(&self.name, |b| b.runs.push(ctx.completed.clone()));
+        let b = mock_behavior(&self.name);
+        (ctx.planned)(b.parts.len());
+        let mut done = ctx.completed.len();
+        for part in b.parts.iter().filter(|p| !ctx.completed.contains(*p)) {
+            if b.fail_after == Some(done) {
+                bail!("Injected failure after {} parts", done);
+            }
+            if b.hang_after == Some(done) {
+                while !(ctx.cancelled)() {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                (ctx.mark_completed)(part);
+                bail!("Update cancelled");
+            }
+            (ctx.mark_completed)(part);
+            (ctx.changed)(part);
+            done += 1;
+        }
+        if b.fail_after == Some(done) {
+            bail!("Injected failure after {} parts", done);
+        }
+        Ok(InstalledContent {
+            meta: b.update.expect("update available"),
+            ..mock_installed("")
+        })
+    }
+}
+
+impl Component for MockComponent {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn priority(&self) -> u32 {
+        mock_behavior(&self.name)
+            .priority
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    /// Installs the available update
+    fn install(&self, _: &str, _: &str, _: bool) -> Result<InstalledContent> {
+        modify_mock(&self.name, |b| b.installs += 1);
+        let meta = mock_behavior(&self.name).update.expect("update available");
+        Ok(InstalledContent {
+            meta,
+            ..mock_installed("")
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        _: &str,
+        _: &dyn PayloadSource,
+        _: Option<&str>,
+    ) -> Result<ContentMetadata> {
+        bail!(
+            "Generating update metadata is not supported by mock {}",
+            self.name
+        )
+    }
+
+    fn query_update(&self) -> Result<Option<ContentMetadata>> {
+        Ok(mock_behavior(&self.name).update)
+    }
+
+    fn check_precondition(
+        &self,
+        _: &InstalledContent,
+        _: &ContentMetadata,
+    ) -> Result<Option<String>> {
+        Ok(mock_behavior(&self.name).precondition)
+    }
+
+    fn run_update(&self, _: &InstalledContent, ctx: &UpdateContext) -> Result<InstalledContent> {
+        self.write_parts(ctx)
+    }
+
+    fn can_stage(&self) -> bool {
+        mock_behavior(&self.name).stageable
+    }
+
+    fn stage(&self, _: &InstalledContent, _: &dyn Fn(&str)) -> Result<ContentMetadata> {
+        if !self.can_stage() {
+            bail!("Staging is not supported for component {}", self.name);
+        }
+        modify_mock(&self.name, |b| b.staged = true);
+        Ok(mock_behavior(&self.name).update.expect("update available"))
+    }
+
+    fn apply_staged(
+        &self,
+        _: &InstalledContent,
+        _: &ContentMetadata,
+        ctx: &UpdateContext,
+    ) -> Result<InstalledContent> {
+        assert!(mock_behavior(&self.name).staged);
+        self.write_parts(ctx)
+    }
+
+    fn discard_staged(&self, _: &InstalledContent) -> Result<()> {
+        modify_mock(&self.name, |b| b.staged = false);
+        Ok(())
+    }
+
+    fn rollback(
+        &self,
+        _: &InstalledContent,
+        previous: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        modify_mock(&self.name, |b| {
+            b.rollbacks.push(previous.meta.version.clone())
+        });
+        Ok(previous.clone())
+    }
+
+    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
+        let b = mock_behavior(&self.name);
+        if b.fail_validate {
+            bail!("Injected validation failure");
+        }
+        if b.invalid.is_empty() {
+            return Ok(ValidationResult::Valid);
+        }
+        Ok(ValidationResult::Errors(
+            b.invalid
+                .iter()
+                .map(|f| ValidationError::file(&self.name, ValidationErrorKind::ContentMismatch, f))
+                .collect(),
+        ))
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::HashSet;
+use std::io::Write;
 
 use anyhow::{bail, Result};
 use openat_ext::OpenatDirExt;
 
+use std::path::Path;
 use std::process::Command;
 
 pub(crate) trait CommandRunExt {
@@ -19,8 +21,109 @@ impl CommandRunExt for Command {
     }
 }
 
+/// Run a command, returning its stdout as a string.  The child's stderr
+/// is forwarded on failure.
+pub(crate) fn cmd_output(cmd: &mut Command) -> Result<String> {
+    let result = cmd.output()?;
+    if !result.status.success() {
+        std::io::stderr().write_all(&result.stderr)?;
+        bail!("Child [{:?}] exited: {}", cmd, result.status);
+    }
+    String::from_utf8(result.stdout)
+        .map_err(|e| anyhow::anyhow!("Child [{:?}] emitted invalid UTF-8: {}", cmd, e))
+}
+
+/// Find the block device backing the filesystem containing `path`.
+pub(crate) fn find_mount_source(path: &Path) -> Result<String> {
+    let source = cmd_output(
+        Command::new("findmnt")
+            .args(&["--noheadings", "--output", "SOURCE", "--target"])
+            .arg(path),
+    )?;
+    let source = source.trim();
+    if source.is_empty() {
+        bail!("Failed to find block device for {:?}", path);
+    }
+    Ok(source.to_string())
+}
+
+/// Find the whole-disk block device backing the filesystem containing `path`.
+pub(crate) fn find_parent_disk(path: &Path) -> Result<String> {
+    let partition = find_mount_source(path)?;
+    let partition = partition.as_str();
+    let device = cmd_output(
+        Command::new("lsblk")
+            .args(&["--paths", "--noheadings", "--nodeps", "--output", "PKNAME"])
+            .arg(partition),
+    )?;
+    let device = device.trim();
+    if device.is_empty() {
+        bail!("Failed to find parent disk of {}", partition);
+    }
+    Ok(device.to_string())
+}
+
+/// The lsblk type of the block device `device`, e.g. `disk` or `part`
+pub(crate) fn block_device_type(device: &str) -> Result<String> {
+    let t = cmd_output(
+        Command::new("lsblk")
+            .args(&["--noheadings", "--nodeps", "--output", "TYPE"])
+            .arg(device),
+    )?;
+    Ok(t.trim().to_string())
+}
+
+/// The GPT partition UUID of the block device `device`, empty if it
+/// isn't a GPT partition
+pub(crate) fn partition_uuid(device: &str) -> Result<String> {
+    let t = cmd_output(
+        Command::new("lsblk")
+            .args(&["--noheadings", "--nodeps", "--output", "PARTUUID"])
+            .arg(device),
+    )?;
+    Ok(t.trim().to_string())
+}
+
+/// The block devices which `device` is built on, including itself, e.g.
+/// a partition and the disk holding it, or the members of a RAID array.
+pub(crate) fn block_device_ancestors(device: &str) -> Result<Vec<String>> {
+    let out = cmd_output(
+        Command::new("lsblk")
+            .args(&[
+                "--paths",
+                "--noheadings",
+                "--inverse",
+                "--list",
+                "--output",
+                "NAME",
+            ])
+            .arg(device),
+    )?;
+    Ok(out.lines().map(|l| l.trim().to_string()).collect())
+}
+
+/// Whether `a` and `b` are the same file or directory, however they're
+/// spelled
+pub(crate) fn same_file(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (std::fs::metadata(a)?, std::fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// The magic number identifying the type of the filesystem containing
+/// `path`, as listed in statfs(2).  Unlike `nix::sys::statfs::FsType`,
+/// this can be compared against types nix doesn't define.
+pub(crate) fn fs_magic(path: &Path) -> Result<i64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    let r = unsafe { libc::statfs(path.as_ptr(), buf.as_mut_ptr()) };
+    nix::errno::Errno::result(r)?;
+    let buf = unsafe { buf.assume_init() };
+    Ok(buf.f_type as i64)
+}
+
 /// Parse an environment variable as UTF-8
-#[allow(dead_code)]
 pub(crate) fn getenv_utf8(n: &str) -> Result<Option<String>> {
     if let Some(v) = std::env::var_os(n) {
         Ok(Some(
@@ -65,3 +168,12 @@ pub(crate) fn filenames(dir: &openat::Dir) -> Result<HashSet<String>> {
     }
     Ok(ret)
 }
+
+/// Whether the fstab `contents` list a filesystem to be mounted at `target`
+pub(crate) fn fstab_has_mount(contents: &str, target: &str) -> bool {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('#'))
+        .any(|l| l.split_whitespace().nth(1) == Some(target))
+}